@@ -0,0 +1,160 @@
+//! Run a game for some frames with the usual interpreter and with the cached
+//! interpreter, and report how fast each runs.
+//!
+//! Usage: `cached_interpreter_benchmark [PATH_TO_ROM [FRAMES]]`
+//!
+//! Without a ROM, runs a program that never waits for anything, so nearly all
+//! the time is spent executing instructions.
+//!
+//! The cached interpreter is an `SmsZ80Runner`, so it sees memory and IO only
+//! through trait objects, while the system's own interpreter is compiled for
+//! its memory. To tell what decoding saves apart from what dynamic dispatch
+//! costs, this also runs the interpreter as an `SmsZ80Runner`.
+
+extern crate euphrates;
+
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::process;
+use std::sync::Arc;
+use std::time::Instant;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::z80::{assemble, Z80Irq, Z80Memo, Z80Run, Z80RunImpler, Z80State};
+use euphrates::memo::Inbox;
+use euphrates::systems::sms::{
+    new_headless_sms, CachedInterpreter, FakeSmsGraphics, Kind, PointerSmsMemory, SmsMemory,
+    SmsMemoryMapper, SmsPlayerInput, SmsState, SmsZ80Runner, TvSystem, TypeWrap,
+};
+
+const BUSY: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+    main:
+        ; fill a page of RAM
+        ld ix, 0C000
+        ld b, 0
+    fill:
+        ld a, b
+        rlca
+        xor b
+        ld (ix+0), a
+        inc ix
+        djnz fill
+        ; copy it
+        ld hl, 0C000
+        ld de, 0C100
+        ld bc, 100
+        ldir
+        ; and add it up
+        ld hl, 0C100
+        ld de, 0
+        ld b, 0
+    sum:
+        ld a, (hl)
+        call add
+        inc hl
+        djnz sum
+        ld (0C200), de
+        jp main
+    add:
+        add a, e
+        ld e, a
+        ld a, 0
+        adc a, d
+        ld d, a
+        ret
+";
+
+/// The interpreter, `Z80RunImpler`, as an `SmsZ80Runner`.
+struct Interpreter;
+
+impl SmsZ80Runner for Interpreter {
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        Z80RunImpler {
+            z80,
+            memory,
+            io,
+            irq,
+            inbox,
+        }.run(target_cycles);
+    }
+
+    fn invalidate(&mut self) {}
+}
+
+fn fail<E: Display>(e: E) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+fn run(name: &str, rom: &[u8], frames: usize, runner: Option<Box<dyn SmsZ80Runner + Send>>) {
+    let state = SmsState::from_rom(
+        Arc::new(rom.to_vec().into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    let mut sms = new_headless_sms(state, FakeSmsGraphics, TypeWrap::<PointerSmsMemory>::default())
+        .unwrap_or_else(|e| fail(e));
+    sms.set_z80_runner(runner);
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        sms.run_frame_without_rendering(SmsPlayerInput::default())
+            .unwrap_or_else(|e| fail(e));
+    }
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    println!(
+        "{}: {} frames in {:.3} s ({:.0} frames per second)",
+        name,
+        frames,
+        seconds,
+        frames as f64 / seconds
+    );
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 3 {
+        fail("Usage: cached_interpreter_benchmark [PATH_TO_ROM [FRAMES]]");
+    }
+
+    let rom = match args.get(1) {
+        Some(path) => fs::read(path).unwrap_or_else(|e| fail(e)),
+        None => {
+            let mut rom = assemble(BUSY).unwrap_or_else(|e| fail(e)).bytes;
+            rom.resize(0x8000, 0);
+            rom
+        }
+    };
+    let frames = match args.get(2) {
+        Some(s) => s.parse().unwrap_or_else(|e| fail(e)),
+        None => 2000,
+    };
+
+    run("interpreter", &rom, frames, None);
+    run(
+        "interpreter as a runner",
+        &rom,
+        frames,
+        Some(Box::new(Interpreter)),
+    );
+    run(
+        "cached interpreter",
+        &rom,
+        frames,
+        Some(Box::new(CachedInterpreter::new())),
+    );
+}
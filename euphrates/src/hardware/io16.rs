@@ -7,4 +7,14 @@ pub trait Io16 {
 
     /// Output a byte at the given `address`.
     fn output(&mut self, address: u16, value: u8);
+
+    /// The byte `input` would give at `address`, if reading it has no side
+    /// effects; otherwise `None`.
+    ///
+    /// This lets the Z80 skip loops that just poll a port. The default
+    /// implementation returns `None`, which is always safe.
+    #[inline]
+    fn peek(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
 }
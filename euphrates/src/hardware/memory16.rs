@@ -7,6 +7,16 @@ pub trait Memory16 {
 
     /// Write a byte.
     fn write(&mut self, logical_address: u16, value: u8);
+
+    /// If `logical_address` is mapped to ROM, where is it in the ROM?
+    ///
+    /// This is for debugging and profiling; memories without ROM, or which
+    /// don't care to say, can leave the default implementation, which returns
+    /// `None`.
+    #[inline]
+    fn rom_index(&self, _logical_address: u16) -> Option<usize> {
+        None
+    }
 }
 
 impl Memory16 for [u8; 0x10000] {
@@ -71,6 +71,27 @@ where
         value
     }
 
+    fn peek(&mut self, address: u16) -> Option<u8> {
+        match (address & 0b11000001) as u8 {
+            0b10000000 => {
+                // reading VDP data advances its address
+                None
+            }
+            0b10000001 => {
+                // reading VDP control clears the status flags, the control
+                // flag, and the line interrupt pending flag
+                let vdp = self.vdp.borrow();
+                if vdp.status_flags() == 0 && !vdp.control_flag() && !vdp.line_interrupt_pending()
+                {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+            _ => Some(self.input(address)),
+        }
+    }
+
     fn output(&mut self, address: u16, value: u8) {
         let masked = (address & 0b11000001) as u8;
 
@@ -428,6 +428,62 @@ impl SmsMemoryLoad for SmsMemoryState {
             Ok(state)
         }
     }
+
+    fn load_in_place(&mut self, state: &SmsMemoryState) -> Result<(), SmsMemoryLoadError> {
+        use self::MainCartridgeRam::*;
+
+        if let Some(e) = state.check_valid() {
+            return Err(e);
+        }
+
+        if !Arc::ptr_eq(&self.rom, &state.rom) {
+            self.rom = state.rom.clone();
+        }
+
+        if self.system_ram.len() == state.system_ram.len() {
+            self.system_ram.copy_from_slice(&state.system_ram);
+        } else {
+            self.system_ram = state.system_ram.clone();
+        }
+
+        match (&mut self.main_cartridge_ram, &state.main_cartridge_ram) {
+            (&mut Zero, &Zero) => {}
+            (&mut One(ref mut x), &One(ref y)) => **x = **y,
+            (&mut Two(ref mut x0, ref mut x1), &Two(ref y0, ref y1)) => {
+                **x0 = **y0;
+                **x1 = **y1;
+            }
+            (x, y) => *x = y.clone(),
+        }
+
+        match (&mut self.half_cartridge_ram, &state.half_cartridge_ram) {
+            (&mut Some(ref mut x), &Some(ref y)) => **x = **y,
+            (x, y) => *x = y.clone(),
+        }
+
+        self.pages = state.pages;
+        self.mapper = state.mapper;
+
+        Ok(())
+    }
+}
+
+/// Where `logical_address` is in ROM, if `memory` has ROM mapped there.
+///
+/// This is how an `SmsMemory` can implement `Memory16::rom_index`.
+pub fn mapped_rom_index<M>(memory: &M, logical_address: u16) -> Option<usize>
+where
+    M: SmsMemory + ?Sized,
+{
+    use self::MemoryPage::*;
+    let offset = logical_address as usize & 0x3FFF;
+    match memory.page((logical_address >> 14) as u8) {
+        Rom(x) => Some(x as usize * 0x4000 + offset),
+        RomButFirstKiB(_) if offset < 0x400 => Some(offset),
+        RomButFirstKiB(x) => Some(x as usize * 0x4000 + offset),
+        HalfCartridgeRam(x) if offset < 0x2000 => Some(x as usize * 0x4000 + offset),
+        _ => None,
+    }
 }
 
 impl Memory16 for SmsMemoryState {
@@ -494,6 +550,10 @@ impl Memory16 for SmsMemoryState {
             _ => {}
         }
     }
+
+    fn rom_index(&self, logical_address: u16) -> Option<usize> {
+        mapped_rom_index(self, logical_address)
+    }
 }
 
 /// Check if writing to a register and update slots/pages as necessary.
@@ -509,6 +569,34 @@ where
     }
 }
 
+/// Could writing to `logical_address` change the memory map, given the
+/// memory registers of `mapper`?
+///
+/// See `memory_register_check`.
+#[inline]
+pub fn is_memory_register(mapper: SmsMemoryMapper, logical_address: u16) -> bool {
+    match mapper {
+        SmsMemoryMapper::Sega => logical_address >= 0xFFFC,
+        SmsMemoryMapper::Codemasters => logical_address & 0x3FFF == 0 && logical_address < 0xC000,
+        SmsMemoryMapper::Sg1000(_) => false,
+    }
+}
+
+/// The slot whose ROM page is selected by writing to `logical_address`, given
+/// the memory registers of `mapper`, or `None` if that's not a page register.
+#[inline]
+pub fn memory_register_slot(mapper: SmsMemoryMapper, logical_address: u16) -> Option<u8> {
+    match (mapper, logical_address) {
+        (SmsMemoryMapper::Sega, 0xFFFD) => Some(0),
+        (SmsMemoryMapper::Sega, 0xFFFE) => Some(1),
+        (SmsMemoryMapper::Sega, 0xFFFF) => Some(2),
+        (SmsMemoryMapper::Codemasters, 0x0000)
+        | (SmsMemoryMapper::Codemasters, 0x4000)
+        | (SmsMemoryMapper::Codemasters, 0x8000) => Some((logical_address >> 14) as u8),
+        _ => None,
+    }
+}
+
 impl SmsMemory for SmsMemoryState {
     fn set_system_ram_kib(&mut self, kib: usize) {
         let len = kib * 0x400;
@@ -615,7 +703,7 @@ impl SmsMemory for SmsMemoryState {
 
     #[inline(always)]
     fn system_ram_len(&self) -> usize {
-        self.system_ram.len() * 0x400
+        self.system_ram.len()
     }
 
     #[inline(always)]
@@ -699,6 +787,15 @@ pub trait SmsMemoryLoad: Sized {
         Self::load(state.clone())
     }
 
+    /// Replace the contents of this memory with `state`, reusing existing
+    /// allocations where possible.
+    ///
+    /// The default implementation just calls `load_ref`.
+    fn load_in_place(&mut self, state: &SmsMemoryState) -> Result<(), SmsMemoryLoadError> {
+        *self = Self::load_ref(state)?;
+        Ok(())
+    }
+
     fn from_rom(rom: Box<[u8; 0x4000]>) -> Result<Self, SmsMemoryLoadError> {
         let state = SmsMemoryState {
             rom: Arc::new(rom),
@@ -851,6 +948,12 @@ mod _impl3 {
             smm.reset_pointers();
             Ok(smm)
         }
+
+        fn load_in_place(&mut self, state: &SmsMemoryState) -> Result<(), SmsMemoryLoadError> {
+            self.state_mut().load_in_place(state)?;
+            self.reset_pointers();
+            Ok(())
+        }
     }
 }
 
@@ -972,6 +1075,10 @@ impl Memory16 for PointerSmsMemory {
                 .offset(logical_address as isize & 0x3FF) = value
         }
     }
+
+    fn rom_index(&self, logical_address: u16) -> Option<usize> {
+        mapped_rom_index(self, logical_address)
+    }
 }
 
 impl SmsMemory for PointerSmsMemory {
@@ -1,6 +1,6 @@
 use failure::Error;
 
-use host_multimedia::{SimpleColor, SimpleGraphics};
+use host_multimedia::{HasLastFrame, IndexedPixel, Layer, SimpleColor, SimpleGraphics};
 use utilities;
 
 use super::*;
@@ -282,29 +282,46 @@ where
     V: SmsVdpInternal,
     G: SimpleGraphics,
 {
-    let sprites_large = s.vdp.register(1) & 2 != 0;
-    let sprites_zoom = s.vdp.register(1) & 1 != 0;
+    let v = s.vdp.v() as u32;
+    let graphics = &mut *s.graphics;
+    sprites_tms(&mut *s.vdp, &mut |x, color| {
+        graphics.paint(x as u32, v, TMS9918_PALETTE[color as usize]);
+        graphics.paint_indexed(x as u32, v, IndexedPixel::new(color, Layer::Sprite));
+    });
+    Ok(())
+}
+
+/// Go through the sprites on the current line in a TMS9918 mode, setting the
+/// overflow and collision flags, and calling `paint` with the x coordinate and
+/// color of each sprite pixel drawn.
+fn sprites_tms<V, F>(vdp: &mut V, paint: &mut F)
+where
+    V: SmsVdpInternal,
+    F: FnMut(u16, u8),
+{
+    let sprites_large = vdp.register(1) & 2 != 0;
+    let sprites_zoom = vdp.register(1) & 1 != 0;
     let sprite_size = match (sprites_large, sprites_zoom) {
         (true, true) => 32,
         (false, false) => 8,
         _ => 16,
     };
 
-    let v = s.vdp.v();
+    let v = vdp.v();
 
-    let sprite_pattern_table = ((s.vdp.register(6) & 0x7) as u16) << 11;
-    let sprite_attribute_table = ((s.vdp.register(5) & 0x7F) as u16) << 7;
+    let sprite_pattern_table = ((vdp.register(6) & 0x7) as u16) << 11;
+    let sprite_attribute_table = ((vdp.register(5) & 0x7F) as u16) << 7;
 
     let mut sprites_on_line = 0;
 
     let mut line = [false; 256];
 
     for i in 0..32 {
-        let y = s.vdp.vram(sprite_attribute_table + 4 * i).wrapping_add(1) as u16;
+        let y = vdp.vram(sprite_attribute_table + 4 * i).wrapping_add(1) as u16;
         if y == 0xD1 {
             break;
         }
-        let x = s.vdp.vram(sprite_attribute_table + 4 * i + 1) as u16;
+        let x = vdp.vram(sprite_attribute_table + 4 * i + 1) as u16;
         let sprite_line = v.wrapping_sub(y);
         if sprite_line >= sprite_size {
             continue;
@@ -312,11 +329,11 @@ where
 
         sprites_on_line += 1;
         if sprites_on_line > 4 {
-            let mut status = s.vdp.status_flags() & 0xE0;
+            let mut status = vdp.status_flags() & 0xE0;
             status |= i as u8;
             status |= SPRITE_OVERFLOW_FLAG;
-            s.vdp.set_status_flags(status);
-            return Ok(());
+            vdp.set_status_flags(status);
+            return;
         }
 
         let sprite_y = if sprites_zoom {
@@ -324,19 +341,18 @@ where
         } else {
             sprite_line
         };
-        let name = s.vdp.vram(sprite_attribute_table + 4 * i + 2) as u16
+        let name = vdp.vram(sprite_attribute_table + 4 * i + 2) as u16
             & if sprites_large { 0xFC } else { 0xFF };
 
-        let last_byte = s.vdp.vram(sprite_attribute_table + 4 * i + 3);
+        let last_byte = vdp.vram(sprite_attribute_table + 4 * i + 3);
         let early_clock = last_byte & 0x80 != 0;
         let color = last_byte & 0xF;
-        let color1 = TMS9918_PALETTE[color as usize];
 
         let line_pattern_index = sprite_pattern_table + name * 8 + sprite_y;
 
-        let pattern = s.vdp.vram(line_pattern_index);
+        let pattern = vdp.vram(line_pattern_index);
         let pattern2 = if sprites_large {
-            Some(s.vdp.vram(line_pattern_index + 16))
+            Some(vdp.vram(line_pattern_index + 16))
         } else {
             None
         };
@@ -344,11 +360,11 @@ where
         let mut render_pattern = |mut pattern: u8, mut screen_x: u16| {
             let mut draw = |x| {
                 if line[x as usize] {
-                    s.vdp.trigger_sprite_collision();
+                    vdp.trigger_sprite_collision();
                     return;
                 }
                 line[x as usize] = true;
-                s.graphics.paint(x as u32, v as u32, color1);
+                paint(x, color);
             };
             if sprites_zoom {
                 for _ in 0..8 {
@@ -383,8 +399,6 @@ where
             render_pattern(pattern2, screen_x.wrapping_add(8));
         }
     }
-
-    Ok(())
 }
 
 pub fn draw_line_graphics1<'a, V: 'a, G: 'a>(
@@ -424,6 +438,8 @@ where
                     blue: 0,
                 },
             );
+            s.graphics
+                .paint_indexed(x, v as u32, IndexedPixel::new(0, Layer::Blank));
         }
         return Ok(());
     }
@@ -436,13 +452,28 @@ where
         let color = s.vdp.vram(color_table + color_entry as u16);
         let color0 = TMS9918_PALETTE[color as usize & 0xF];
         let color1 = TMS9918_PALETTE[color as usize >> 4];
-        let mut pattern = s.vdp.vram(pattern_table + name + tile_line);
+        let mut pattern = s.vdp.vram(pattern_table + name * 8 + tile_line);
         for i in 0..8 {
+            let index = if pattern & 0x80 == 0 {
+                color & 0xF
+            } else {
+                color >> 4
+            };
+            let layer = if index == 0 {
+                Layer::Transparent
+            } else {
+                Layer::Background
+            };
             s.graphics.paint(
                 tile_x as u32 * 8 + i,
                 v as u32,
                 if pattern & 0x80 == 0 { color0 } else { color1 },
             );
+            s.graphics.paint_indexed(
+                tile_x as u32 * 8 + i,
+                v as u32,
+                IndexedPixel::new(index, layer),
+            );
             pattern <<= 1;
         }
     }
@@ -487,6 +518,8 @@ where
                     blue: 0,
                 },
             );
+            s.graphics
+                .paint_indexed(x, v as u32, IndexedPixel::new(0, Layer::Blank));
         }
         return Ok(());
     }
@@ -505,11 +538,26 @@ where
         let color1 = TMS9918_PALETTE[color as usize >> 4];
         let mut pattern = s.vdp.vram(pattern_address + name * 8 + tile_line);
         for i in 0..8 {
+            let index = if pattern & 0x80 == 0 {
+                color & 0xF
+            } else {
+                color >> 4
+            };
+            let layer = if index == 0 {
+                Layer::Transparent
+            } else {
+                Layer::Background
+            };
             s.graphics.paint(
                 tile_x as u32 * 8 + i,
                 v as u32,
                 if pattern & 0x80 == 0 { color0 } else { color1 },
             );
+            s.graphics.paint_indexed(
+                tile_x as u32 * 8 + i,
+                v as u32,
+                IndexedPixel::new(index, layer),
+            );
             pattern <<= 1;
         }
     }
@@ -529,11 +577,7 @@ where
     let v = s.vdp.v();
 
     let (display_y_start, display_y_end, display_x_start, display_x_end) =
-        if s.vdp.kind() == Kind::Gg {
-            (24, 168, 48, 208)
-        } else {
-            (0, s.vdp.active_lines(), 0, 256)
-        };
+        display_window_mode4(s.vdp);
     let height = display_y_end - display_y_start;
     let width = display_x_end - display_x_start;
 
@@ -567,6 +611,8 @@ where
                     blue: 0,
                 },
             );
+            s.graphics
+                .paint_indexed(x as u32, y, IndexedPixel::new(0, Layer::Blank));
         }
         return Ok(());
     }
@@ -585,61 +631,17 @@ where
 
     let mut line_buffer = [0x80u8; 256];
 
-    // draw sprites
-    let sprite_height = if s.vdp.tall_sprites() { 16 } else { 8 };
-    let sprites_rendered = 0u8;
-    for i in 0..64 {
-        let sprite_y = unsafe { s.vdp.sprite_y(i) } as u16;
-        if sprite_y == 0xD1 && s.vdp.resolution() == Low {
-            break;
-        }
-
-        // which line of the sprite are we rendering?
-        let sprite_line = v.wrapping_sub(sprite_y) / if s.vdp.zoomed_sprites() { 2 } else { 1 };
-        if sprite_line >= sprite_height {
-            continue;
-        }
-        if sprites_rendered == 8 {
-            s.vdp.trigger_sprite_overflow();
-            break;
-        }
+    // which layer each pixel in `line_buffer` came from
+    let mut layer_buffer = [Layer::Backdrop; 256];
 
-        let pattern_addr = unsafe { s.vdp.sprite_pattern_address(i) };
-
-        let palette_indices: [u8; 8] = unsafe {
-            s.vdp
-                .pattern_address_to_palette_indices(pattern_addr, sprite_line)
-        };
-        let sprite_x = unsafe { s.vdp.sprite_x(i) } as usize;
-        let shift_x = if s.vdp.shift_sprites() { 8 } else { 0 };
-        for j in 0..8 {
-            let index = if s.vdp.zoomed_sprites() { 2 * j } else { j };
-            let render_x = sprite_x.wrapping_add(index).wrapping_sub(shift_x);
-            if render_x < display_x_start || render_x >= display_x_end {
-                break;
-            }
-            if line_buffer[render_x] != 0x80 {
-                s.vdp.trigger_sprite_collision();
-                continue;
-            }
-            if palette_indices[j] != 0 {
-                line_buffer[render_x] = palette_indices[j] + 16;
-            }
-            if s.vdp.zoomed_sprites() {
-                let render_x2 = render_x + 1;
-                if render_x2 < display_x_start || render_x2 >= display_x_end {
-                    break;
-                }
-                if line_buffer[render_x2] != 0x80 {
-                    s.vdp.trigger_sprite_collision();
-                    continue;
-                }
-                if palette_indices[j] != 0 {
-                    line_buffer[render_x2] = palette_indices[j] + 16;
-                }
-            }
-        }
-    }
+    // draw sprites
+    sprites_mode4(
+        s.vdp,
+        display_x_start,
+        display_x_end,
+        &mut line_buffer,
+        &mut layer_buffer,
+    );
 
     // draw tiles
     let vert_scroll_locked = s.vdp.vert_scroll_locked();
@@ -693,6 +695,11 @@ where
                     || (priority && palette_indices[tile_col] as usize > 0)
                 {
                     line_buffer[x] = palette_indices[tile_col] + palette;
+                    layer_buffer[x] = if priority && palette_indices[tile_col] > 0 {
+                        Layer::PriorityBackground
+                    } else {
+                        Layer::Background
+                    };
                 }
             }
         };
@@ -721,6 +728,7 @@ where
     if s.vdp.left_column_blank() {
         for i in 0..8 {
             line_buffer[i] = 16 + s.vdp.backdrop_color_index();
+            layer_buffer[i] = Layer::Backdrop;
         }
     }
 
@@ -728,18 +736,147 @@ where
         let index = line_buffer[x as usize] as usize;
         let color = colors[index % 32];
         s.graphics.paint((x - display_x_start) as u32, y, color);
+        s.graphics.paint_indexed(
+            (x - display_x_start) as u32,
+            y,
+            IndexedPixel::new(index as u8, layer_buffer[x as usize]),
+        );
     }
 
     Ok(())
 }
 
+/// The lines and columns of the mode 4 display actually shown: `(y_start,
+/// y_end, x_start, x_end)`.
+fn display_window_mode4<V>(vdp: &V) -> (u16, u16, usize, usize)
+where
+    V: SmsVdpInternal,
+{
+    if vdp.kind() == Kind::Gg {
+        (24, 168, 48, 208)
+    } else {
+        (0, vdp.active_lines(), 0, 256)
+    }
+}
+
+/// Draw the sprites on the current line in mode 4 into `line_buffer` and
+/// `layer_buffer`, setting the overflow and collision flags.
+fn sprites_mode4<V>(
+    vdp: &mut V,
+    display_x_start: usize,
+    display_x_end: usize,
+    line_buffer: &mut [u8; 256],
+    layer_buffer: &mut [Layer; 256],
+) where
+    V: SmsVdpInternal,
+{
+    use self::Resolution::*;
+
+    let v = vdp.v();
+    let sprite_height = if vdp.tall_sprites() { 16 } else { 8 };
+    let mut sprites_rendered = 0u8;
+    for i in 0..64 {
+        let sprite_y = unsafe { vdp.sprite_y(i) } as u16;
+        if sprite_y == 0xD1 && vdp.resolution() == Low {
+            break;
+        }
+
+        // which line of the sprite are we rendering?
+        let sprite_line = v.wrapping_sub(sprite_y) / if vdp.zoomed_sprites() { 2 } else { 1 };
+        if sprite_line >= sprite_height {
+            continue;
+        }
+        if sprites_rendered == 8 {
+            vdp.trigger_sprite_overflow();
+            break;
+        }
+        sprites_rendered += 1;
+
+        let pattern_addr = unsafe { vdp.sprite_pattern_address(i) };
+
+        let palette_indices: [u8; 8] =
+            unsafe { vdp.pattern_address_to_palette_indices(pattern_addr, sprite_line) };
+        let sprite_x = unsafe { vdp.sprite_x(i) } as usize;
+        let shift_x = if vdp.shift_sprites() { 8 } else { 0 };
+        for j in 0..8 {
+            let index = if vdp.zoomed_sprites() { 2 * j } else { j };
+            let render_x = sprite_x.wrapping_add(index).wrapping_sub(shift_x);
+            if render_x < display_x_start || render_x >= display_x_end {
+                break;
+            }
+            if line_buffer[render_x] != 0x80 {
+                vdp.trigger_sprite_collision();
+                continue;
+            }
+            if palette_indices[j] != 0 {
+                line_buffer[render_x] = palette_indices[j] + 16;
+                layer_buffer[render_x] = Layer::Sprite;
+            }
+            if vdp.zoomed_sprites() {
+                let render_x2 = render_x + 1;
+                if render_x2 < display_x_start || render_x2 >= display_x_end {
+                    break;
+                }
+                if line_buffer[render_x2] != 0x80 {
+                    vdp.trigger_sprite_collision();
+                    continue;
+                }
+                if palette_indices[j] != 0 {
+                    line_buffer[render_x2] = palette_indices[j] + 16;
+                    layer_buffer[render_x2] = Layer::Sprite;
+                }
+            }
+        }
+    }
+}
+
+/// Set the sprite overflow and collision flags for the current line exactly
+/// as drawing it would, but without composing any pixels.
+pub fn sprite_flags_line<V>(vdp: &mut V)
+where
+    V: SmsVdpInternal,
+{
+    match (vdp.m1(), vdp.m2(), vdp.m3(), vdp.m4()) {
+        (_, _, _, true) => {
+            let v = vdp.v();
+            let (display_y_start, display_y_end, display_x_start, display_x_end) =
+                display_window_mode4(vdp);
+            if v < display_y_start || v >= display_y_end || !vdp.display_visible() {
+                return;
+            }
+            sprites_mode4(
+                vdp,
+                display_x_start,
+                display_x_end,
+                &mut [0x80u8; 256],
+                &mut [Layer::Backdrop; 256],
+            );
+        }
+        (false, false, false, _) | (false, true, false, _) => {
+            if vdp.v() >= 192 || !vdp.display_visible() {
+                return;
+            }
+            sprites_tms(vdp, &mut |_, _| {});
+        }
+        _ => {}
+    }
+}
+
 #[derive(Copy, Clone, Default, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize,
          Deserialize)]
 pub struct FakeSmsGraphics;
 
-impl<'a, V: 'a> SmsVdpGraphics for SmsVdpGraphicsImpler<'a, V, FakeSmsGraphics> {
+impl HasLastFrame for FakeSmsGraphics {}
+
+/// Sets the sprite overflow and collision flags as drawing a line would, so a
+/// program polling them runs the same headless as with real graphics.
+impl<'a, V: 'a> SmsVdpGraphics for SmsVdpGraphicsImpler<'a, V, FakeSmsGraphics>
+where
+    V: SmsVdpInternal,
+{
     #[inline]
     fn draw_line(&mut self) -> Result<(), SmsVdpGraphicsError> {
+        sprite_flags_line(self.vdp);
         Ok(())
     }
 }
@@ -24,9 +24,26 @@ where
     V: SmsVdpLineImpler,
 {
     x.draw_line()?;
+    finish_line(x.vdp());
+    Ok(())
+}
 
-    let vdp = x.vdp();
+/// Like `line`, but without drawing anything.
+///
+/// The VDP's timing, status flags (including sprite overflow and collision),
+/// and interrupts are exactly as they would be after `line`.
+pub fn line_without_drawing<V>(vdp: &mut V)
+where
+    V: SmsVdpInternal,
+{
+    sprite_flags_line(vdp);
+    finish_line(vdp);
+}
 
+fn finish_line<V>(vdp: &mut V)
+where
+    V: SmsVdpInternal,
+{
     let v = vdp.v();
 
     if v == vdp.active_lines() {
@@ -63,6 +80,4 @@ where
 
     let cycles = vdp.cycles();
     vdp.set_cycles(cycles + 342);
-
-    return Ok(());
 }
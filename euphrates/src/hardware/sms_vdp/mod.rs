@@ -2,6 +2,7 @@
 
 mod graphics;
 mod line;
+mod sprites;
 mod vdp_interface;
 mod vdp_internal;
 
@@ -10,6 +11,7 @@ pub mod replaceable;
 
 pub use self::graphics::*;
 pub use self::line::*;
+pub use self::sprites::*;
 pub use self::vdp_interface::*;
 pub use self::vdp_internal::*;
 
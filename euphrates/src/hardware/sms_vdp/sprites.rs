@@ -0,0 +1,126 @@
+use super::*;
+
+/// A sprite as described by the sprite attribute table.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SpriteObservation {
+    /// Position of this sprite in the sprite attribute table.
+    pub index: u8,
+
+    /// Screen x coordinate of the left edge of the sprite.
+    ///
+    /// This accounts for the VDP shifting sprites 8 pixels left (in mode 4) or
+    /// the early clock bit (in TMS9918 modes), so it may be negative.
+    pub x: i16,
+
+    /// Screen line of the top of the sprite.
+    pub y: u16,
+
+    /// Width in pixels, including zooming.
+    pub width: u8,
+
+    /// Height in pixels, including zooming.
+    pub height: u8,
+
+    /// Index of the sprite's pattern in the pattern table.
+    pub pattern: u16,
+
+    /// Was this sprite at or after the 0xD0 marker which ends the table?
+    pub terminated: bool,
+
+    /// Is any part of this sprite in the active display, and not terminated?
+    ///
+    /// A sprite with `y` near the bottom of the 256 line range would wrap onto
+    /// the top lines of the screen on real hardware, but this emulator doesn't
+    /// draw those lines, so such sprites are reported as not visible.
+    pub visible: bool,
+}
+
+/// Read every sprite out of the sprite attribute table, for the current mode.
+///
+/// This gives 64 sprites in mode 4 and 32 in the TMS9918 modes.
+pub fn sprites<V>(vdp: &V) -> Vec<SpriteObservation>
+where
+    V: SmsVdpInternal + ?Sized,
+{
+    if vdp.m4() {
+        sprites_mode4(vdp)
+    } else {
+        sprites_tms(vdp)
+    }
+}
+
+// Sprites are drawn without wrapping from line 255 to line 0, so a sprite is
+// on screen vertically exactly when its top line is.
+fn visible(x: i16, y: u16, width: u8, lines: u16) -> bool {
+    x + (width as i16) > 0 && x < 256 && y < lines
+}
+
+fn sprites_mode4<V>(vdp: &V) -> Vec<SpriteObservation>
+where
+    V: SmsVdpInternal + ?Sized,
+{
+    let zoom = if vdp.zoomed_sprites() { 2 } else { 1 };
+    let width = 8 * zoom;
+    let height = if vdp.tall_sprites() { 16 } else { 8 } * zoom;
+    let shift_x = if vdp.shift_sprites() { 8 } else { 0 };
+    let lines = vdp.active_lines();
+
+    // the 0xD0 marker only ends the table in the low resolution mode
+    let can_terminate = vdp.resolution() == Resolution::Low;
+
+    let mut terminated = false;
+    let mut result = Vec::with_capacity(64);
+    for i in 0..64 {
+        let y = unsafe { vdp.sprite_y(i) } as u16;
+        if can_terminate && y == 0xD1 {
+            terminated = true;
+        }
+        let x = unsafe { vdp.sprite_x(i) } as i16 - shift_x;
+        let pattern = unsafe { vdp.sprite_pattern_address(i) } / 32;
+        result.push(SpriteObservation {
+            index: i as u8,
+            x,
+            y,
+            width,
+            height,
+            pattern,
+            terminated,
+            visible: !terminated && visible(x, y, width, lines),
+        });
+    }
+    result
+}
+
+fn sprites_tms<V>(vdp: &V) -> Vec<SpriteObservation>
+where
+    V: SmsVdpInternal + ?Sized,
+{
+    let large = vdp.register(1) & 2 != 0;
+    let zoom = if vdp.register(1) & 1 != 0 { 2 } else { 1 };
+    let size = if large { 16 } else { 8 } * zoom;
+    let sprite_attribute_table = ((vdp.register(5) & 0x7F) as u16) << 7;
+
+    let mut terminated = false;
+    let mut result = Vec::with_capacity(32);
+    for i in 0..32u16 {
+        let address = sprite_attribute_table + 4 * i;
+        let y = vdp.vram(address).wrapping_add(1) as u16;
+        if y == 0xD1 {
+            terminated = true;
+        }
+        let early_clock = vdp.vram(address + 3) & 0x80 != 0;
+        let x = vdp.vram(address + 1) as i16 - if early_clock { 32 } else { 0 };
+        let pattern = vdp.vram(address + 2) as u16 & if large { 0xFC } else { 0xFF };
+        result.push(SpriteObservation {
+            index: i as u8,
+            x,
+            y,
+            width: size,
+            height: size,
+            pattern,
+            terminated,
+            visible: !terminated && visible(x, y, size, 192),
+        });
+    }
+    result
+}
@@ -8,7 +8,8 @@ use super::*;
 use self::Reg16::*;
 use self::Reg8::*;
 
-pub fn outid_help<Z>(z: &mut Z, inc: u16)
+/// Returns the byte output.
+pub fn outid_help<Z>(z: &mut Z, inc: u16) -> u8
 where
     Z: Z80IoT + ?Sized,
 {
@@ -17,9 +18,17 @@ where
     B.change(z, new_b);
     let addr = BC.view(z);
     let hl = HL.view(z);
-    let x = Address(hl).view(z);
+    let x = z.memory().read(hl);
     z.io().output(addr, x);
-    HL.change(z, hl.wrapping_add(inc));
+    let new_hl = hl.wrapping_add(inc);
+    HL.change(z, new_hl);
+    z.z80().set_memptr(addr.wrapping_add(inc));
+
+    // see Young for this `k`
+    let k = x as u16 + (new_hl as u8) as u16;
+    inout_flags(z.z80(), x, k);
+
+    x
 }
 
 pub fn in_help<Z, T1>(z: &mut Z, arg: T1) -> u8
@@ -31,15 +40,18 @@ where
     let address_hi = B.view(z);
     let address = utilities::to16(address_lo, address_hi);
     let x = z.io().input(address);
+    z.z80().set_memptr(address.wrapping_add(1));
 
     z.z80().set_parity(x);
     z.z80().set_sign(x);
     z.z80().set_zero(x);
+    z.z80().set_xy(x);
     z.z80().clear_flag(HF | NF);
 
     x
 }
 
+/// Returns the byte input.
 pub fn inid_help<Z>(z: &mut Z, inc: u16) -> u8
 where
     Z: Z80IoT + ?Sized,
@@ -48,10 +60,73 @@ where
     let hl = HL.view(z);
     let addr = BC.view(z);
     let x = z.io().input(addr);
-    Address(hl).change(z, x);
+    z.memory().write(hl, x);
     B.change(z, b.wrapping_sub(1));
     HL.change(z, hl.wrapping_add(inc));
-    b.wrapping_sub(1)
+    z.z80().set_memptr(addr.wrapping_add(inc));
+
+    // see Young for this `k`
+    let c = (addr as u8).wrapping_add(inc as u8);
+    let k = x as u16 + c as u16;
+    inout_flags(z.z80(), x, k);
+
+    x
+}
+
+/// Flags after `ini`, `ind`, `outi`, or `outd` transfers `x`.
+fn inout_flags<Z>(z: &mut Z, x: u8, k: u16)
+where
+    Z: Z80Internal + ?Sized,
+{
+    let b = z.reg8(B);
+    z.set_sign(b);
+    z.set_zero(b);
+    z.set_xy(b);
+    z.set_flag_by(HF | CF, k > 0xFF);
+    z.set_parity((k as u8 & 7) ^ b);
+    z.set_flag_by(NF, x & 0x80 != 0);
+}
+
+/// Move PC back to the start of a block instruction so it will repeat.
+///
+/// While it repeats, the undocumented flags come from the high byte of the
+/// instruction's address. Returns that address.
+pub fn repeat_help<Z>(z: &mut Z) -> u16
+where
+    Z: Z80Internal + ?Sized,
+{
+    let pc = z.reg16(PC).wrapping_sub(2);
+    z.set_reg16(PC, pc);
+    z.set_xy((pc >> 8) as u8);
+    z.inc_cycles(21);
+    pc
+}
+
+/// Adjust the flags when `inir`, `indr`, `otir`, or `otdr` repeats after
+/// transferring `x`.
+///
+/// This is how NMOS Z80s behave, as worked out by David Banks.
+pub fn inout_repeat_flags<Z>(z: &mut Z, x: u8)
+where
+    Z: Z80Internal + ?Sized,
+{
+    fn odd_parity(x: u8) -> bool {
+        x.count_ones() % 2 == 1
+    }
+
+    let b = z.reg8(B);
+    let pf = z.is_set_flag(PF);
+    if z.is_set_flag(CF) {
+        if x & 0x80 != 0 {
+            z.set_flag_by(PF, pf ^ odd_parity(b.wrapping_sub(1) & 7));
+            z.set_flag_by(HF, b & 0xF == 0);
+        } else {
+            z.set_flag_by(PF, pf ^ odd_parity(b.wrapping_add(1) & 7));
+            z.set_flag_by(HF, b & 0xF == 0xF);
+        }
+    } else {
+        z.set_flag_by(PF, pf ^ odd_parity(b & 7));
+    }
 }
 
 pub fn add16_help<Z>(z: &mut Z, x: u16, y: u16, cf: u16) -> u16
@@ -69,6 +144,7 @@ where
     let hf = (x ^ y ^ result16) & (1 << 12) != 0;
     z.set_flag_by(HF, hf);
 
+    z.set_xy((result16 >> 8) as u8);
     z.clear_flag(NF);
 
     result16
@@ -116,6 +192,7 @@ where
 
     z.set_zero(result8);
     z.set_sign(result8);
+    z.set_xy(result8);
 
     z.set_flag_by(CF, result16 & (1 << 8) != 0);
 
@@ -149,9 +226,20 @@ where
     z.set_parity(result);
     z.set_sign(result);
     z.set_zero(result);
+    z.set_xy(result);
     z.clear_flag(HF | NF | CF);
 }
 
+/// `ldi` and `cpi` and their relatives take YF from bit 1 of `n` and XF from
+/// bit 3.
+fn block_xy<Z>(z: &mut Z, n: u8)
+where
+    Z: Z80Internal + ?Sized,
+{
+    z.set_flag_by(YF, n & 0x02 != 0);
+    z.set_flag_by(XF, n & 0x08 != 0);
+}
+
 pub fn cpid<Z>(z: &mut Z, inc: u16)
 where
     Z: Z80MemT + ?Sized,
@@ -166,11 +254,16 @@ where
     HL.change(z, hl.wrapping_add(inc));
     BC.change(z, bc.wrapping_sub(1));
 
+    let hf = phl & 0xF > a & 0xF;
     z.z80().set_sign(result);
     z.z80().set_zero(result);
-    z.z80().set_flag_by(HF, phl & 0xF > a & 0xF);
+    z.z80().set_flag_by(HF, hf);
     z.z80().set_flag_by(PF, bc != 1);
     z.z80().set_flag(NF);
+    block_xy(z.z80(), result.wrapping_sub(hf as u8));
+
+    let memptr = z.z80().memptr().wrapping_add(inc);
+    z.z80().set_memptr(memptr);
 }
 
 pub fn ldid<Z>(z: &mut Z, inc: u16)
@@ -182,7 +275,7 @@ where
     let bc = BC.view(z);
 
     let phl = z.memory().read(hl);
-    Address(de).change(z, phl);
+    z.memory().write(de, phl);
 
     HL.change(z, hl.wrapping_add(inc));
     DE.change(z, de.wrapping_add(inc));
@@ -190,6 +283,8 @@ where
 
     z.z80().clear_flag(HF | NF);
     z.z80().set_flag_by(PF, bc != 1);
+    let a = A.view(z);
+    block_xy(z.z80(), a.wrapping_add(phl));
 }
 
 /// Most of the functions in the rotate and shift group have similar addressing modes,
@@ -206,6 +301,7 @@ macro_rules! rotate_shift_functions_noa_help {
             let result = $fn_help(z.z80(), a);
             arg.change(z, result);
             z.z80().clear_flag(HF | NF);
+            z.z80().set_xy(result);
             result
         }
 
@@ -0,0 +1,1330 @@
+//! An assembler for the Z80.
+//!
+//! It understands the syntax of `FullMnemonic`'s `Display` implementation, so
+//! that the text of any disassembled instruction assembles to an instruction
+//! with the same mnemonic. It also accepts the usual syntax of other
+//! assemblers where that differs: `jp (hl)`, `in a, (n)`, `out (c), a`, and
+//! `(ix)` for `(ix+0)`.
+//!
+//! Source is one statement per line, and `;` begins a comment. A line may
+//! begin with a label like `loop:`. Statements are instructions or these
+//! directives:
+//!
+//! * `org` followed by an address, where the following code goes. Gaps are
+//!   filled with zeros, and addresses can't go backwards.
+//! * `db` followed by bytes or strings in double quotes, separated by commas.
+//! * `dw` followed by 16 bit words, which are stored little endian.
+//!
+//! `defb`, `defw`, and the directives with a leading `.` also work.
+//!
+//! Numbers are hexadecimal, as the disassembler prints them: `10` is 16. They
+//! may also be written with a `$` or `0x` prefix or an `h` suffix. A word made
+//! only of the digits `0` to `9` and `A` to `F` is a number rather than a label,
+//! as is anything beginning with a digit, so `ld a, FF` loads 0xFF. Write a
+//! number that looks like a register name with a leading 0, like `0DE`.
+//! Characters in single quotes are numbers too.
+//!
+//! Expressions may use labels, `$` for the address of the current statement,
+//! parentheses, and the operators `+ - * / % & | ^ ~ << >>` with C's
+//! precedence. Operands entirely in parentheses are memory operands.
+//!
+//! Operands of `jr` and `djnz` are targets, except that an operand beginning
+//! with `+` or `-` is the displacement itself, as the disassembler prints it.
+//! So `jr loop` and `jr +FE` both work.
+//!
+//! Where more than one opcode has the same mnemonic, the first in the
+//! instruction list is used, which is the shortest and documented one.
+
+use std::collections::BTreeMap;
+
+use utilities;
+
+use super::*;
+
+use self::ConditionCode::*;
+use self::Reg16::*;
+use self::Reg8::*;
+
+// This superfluous module with the `allow` attribute is necessary until the
+// `fail` crate begins using `dyn trait` syntax
+#[allow(bare_trait_objects)]
+mod assembler_error {
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    pub enum AssemblerError {
+        #[fail(display = "Line {}: {}", line, message)]
+        Syntax { line: usize, message: String },
+
+        #[fail(display = "Line {}: no instruction {}", line, text)]
+        NoInstruction { line: usize, text: String },
+
+        #[fail(display = "Line {}: unknown label {}", line, label)]
+        UnknownLabel { line: usize, label: String },
+
+        #[fail(display = "Line {}: label {} defined twice", line, label)]
+        DuplicateLabel { line: usize, label: String },
+
+        #[fail(display = "Line {}: value {:X} out of range", line, value)]
+        OutOfRange { line: usize, value: i64 },
+
+        #[fail(display = "Line {}: org {:04X} is before the current address", line, address)]
+        OrgBackwards { line: usize, address: i64 },
+
+        #[fail(display = "Line {}: past the end of memory", line)]
+        PastEnd { line: usize },
+    }
+}
+
+pub use self::assembler_error::AssemblerError;
+
+type Result<T> = ::std::result::Result<T, AssemblerError>;
+
+/// Assembled code.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Assembly {
+    /// Where `bytes` begins.
+    pub origin: u16,
+
+    pub bytes: Vec<u8>,
+
+    /// The address of each label.
+    pub labels: BTreeMap<String, u16>,
+}
+
+/// Assemble `source`.
+pub fn assemble(source: &str) -> Result<Assembly> {
+    let lines = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_line(text, i + 1))
+        .collect::<Result<Vec<Line>>>()?;
+
+    let mut assembly = Assembly::default();
+
+    // the first pass finds labels, and the second emits bytes
+    for &first_pass in [true, false].iter() {
+        let mut address: u32 = 0;
+        let mut started = false;
+        assembly.bytes.clear();
+
+        for line in lines.iter() {
+            if first_pass {
+                if let Some(ref label) = line.label {
+                    if assembly.labels.contains_key(label) {
+                        return Err(AssemblerError::DuplicateLabel {
+                            line: line.number,
+                            label: label.clone(),
+                        });
+                    }
+                    assembly.labels.insert(label.clone(), address as u16);
+                }
+            }
+
+            let statement = match line.statement {
+                Some(ref statement) => statement,
+                None => continue,
+            };
+
+            let context = Context {
+                labels: &assembly.labels,
+                here: address as u16,
+                line: line.number,
+                first_pass,
+            };
+
+            let bytes = match *statement {
+                Statement::Org(ref expr) => {
+                    // labels defined later can't be allowed, since their
+                    // addresses would depend on this
+                    let value = context.eval(expr)?.ok_or_else(|| context.forward(expr))?;
+                    if !(0..=0xFFFF).contains(&value) || (started && value < address as i64) {
+                        return Err(AssemblerError::OrgBackwards {
+                            line: line.number,
+                            address: value,
+                        });
+                    }
+                    if started {
+                        assembly
+                            .bytes
+                            .resize(assembly.bytes.len() + value as usize - address as usize, 0);
+                    } else {
+                        assembly.origin = value as u16;
+                    }
+                    address = value as u32;
+                    continue;
+                }
+                Statement::Db(ref items) => {
+                    let mut bytes = Vec::new();
+                    for item in items.iter() {
+                        match *item {
+                            Item::Str(ref s) => bytes.extend_from_slice(s),
+                            Item::Expr(ref expr) => {
+                                let value = context.eval(expr)?.unwrap_or(0);
+                                bytes.push(context.byte(value)?);
+                            }
+                        }
+                    }
+                    bytes
+                }
+                Statement::Dw(ref exprs) => {
+                    let mut bytes = Vec::new();
+                    for expr in exprs.iter() {
+                        let value = context.eval(expr)?.unwrap_or(0);
+                        let (lo, hi) = utilities::to8(context.word(value)?);
+                        bytes.push(lo);
+                        bytes.push(hi);
+                    }
+                    bytes
+                }
+                Statement::Instruction(mnemonic, ref operands) => context
+                    .instruction(mnemonic, operands, &line.text)?
+                    .bytes()
+                    .to_vec(),
+            };
+
+            started = true;
+            address += bytes.len() as u32;
+            if address > 0x10000 {
+                return Err(AssemblerError::PastEnd { line: line.number });
+            }
+            assembly.bytes.extend_from_slice(&bytes);
+        }
+    }
+
+    Ok(assembly)
+}
+
+/// Assemble the single instruction `text`, to be placed at `address`.
+///
+/// There are no labels, but `$` is `address`.
+pub fn assemble_instruction(text: &str, address: u16) -> Result<Opcode> {
+    let line = parse_line(text, 1)?;
+    let labels = BTreeMap::new();
+    let context = Context {
+        labels: &labels,
+        here: address,
+        line: 1,
+        first_pass: false,
+    };
+    match line {
+        Line {
+            label: None,
+            statement: Some(Statement::Instruction(mnemonic, ref operands)),
+            ..
+        } => context.instruction(mnemonic, operands, text.trim()),
+        _ => Err(context.syntax("expected an instruction")),
+    }
+}
+
+impl FullMnemonic {
+    /// The opcode of this instruction.
+    ///
+    /// Where more than one opcode has this mnemonic, this is the first in the
+    /// instruction list. Returns `None` if there's no such instruction, like
+    /// `ld hl, a`.
+    pub fn opcode(&self) -> Option<Opcode> {
+        use self::FullMnemonic::*;
+        let (mnemonic, parameters) = match *self {
+            ZeroParameters(m) => (m, vec![]),
+            OneParameter(m, p1) => (m, vec![p1]),
+            TwoParameters(m, p1, p2) => (m, vec![p1, p2]),
+            ThreeParameters(m, p1, p2, p3) => (m, vec![p1, p2, p3]),
+        };
+        find_template(|template| {
+            if template.mnemonic == mnemonic {
+                template.encode(&parameters)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Opcode {
+    /// Is this a documented instruction?
+    ///
+    /// Undocumented instructions and duplicate encodings, like `ed 4c` for
+    /// `neg`, are not, and most assemblers won't produce them.
+    pub fn documented(&self) -> bool {
+        find_template(|template| {
+            if template.matches(self.bytes()) {
+                Some(!template.undocumented)
+            } else {
+                None
+            }
+        }).unwrap_or(false)
+    }
+}
+
+/// What a parameter in the instruction list can be.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Pattern {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Cc(ConditionCode),
+    AddressReg16(Reg16),
+
+    /// `(nn)`
+    AddressU16,
+
+    /// `(IX+d)` or `(IY+d)`
+    Shift(Reg16),
+
+    /// `n`
+    U8,
+
+    /// `d` or `e`
+    I8,
+
+    /// `nn`
+    U16,
+
+    /// A number that's part of the opcode, like the bit in `bit 3, a`.
+    Literal(u8),
+
+    /// The address of a `rst` instruction.
+    Rst(u16),
+}
+
+impl From<Reg8> for Pattern {
+    fn from(x: Reg8) -> Self {
+        Pattern::Reg8(x)
+    }
+}
+
+impl From<Reg16> for Pattern {
+    fn from(x: Reg16) -> Self {
+        Pattern::Reg16(x)
+    }
+}
+
+impl From<ConditionCode> for Pattern {
+    fn from(x: ConditionCode) -> Self {
+        Pattern::Cc(x)
+    }
+}
+
+/// An entry in the instruction list.
+struct Template<'a> {
+    mnemonic: Mnemonic,
+    patterns: &'a [Pattern],
+
+    /// The opcode, with 0 in place of `d`, `e`, `n`, and `nn`.
+    bytes: &'a [u8],
+
+    /// Where `d` or `e` goes.
+    d: Option<usize>,
+
+    /// Where `n` goes.
+    n: Option<usize>,
+
+    /// Where `nn` goes.
+    nn: Option<usize>,
+
+    /// Is this instruction undocumented?
+    undocumented: bool,
+}
+
+impl<'a> Template<'a> {
+    /// The opcode for this template with `parameters`, if they match.
+    fn encode(&self, parameters: &[Parameter]) -> Option<Opcode> {
+        if parameters.len() != self.patterns.len() {
+            return None;
+        }
+        let mut bytes = [0u8; 4];
+        bytes[..self.bytes.len()].copy_from_slice(self.bytes);
+        for (&pattern, &parameter) in self.patterns.iter().zip(parameters.iter()) {
+            match (pattern, parameter) {
+                (Pattern::Reg8(x), Parameter::Reg8(y)) if x == y => {}
+                (Pattern::Reg16(x), Parameter::Reg16(y)) if x == y => {}
+                (Pattern::Cc(x), Parameter::Cc(y)) if x == y => {}
+                (Pattern::AddressReg16(x), Parameter::AddressReg16(Address(y))) if x == y => {}
+                (Pattern::Shift(x), Parameter::Shift(Shift(y, d))) if x == y => {
+                    bytes[self.d?] = d as u8;
+                }
+                (Pattern::I8, Parameter::I8(e)) => bytes[self.d?] = e as u8,
+                (Pattern::U8, Parameter::U8(n)) => bytes[self.n?] = n,
+                (Pattern::U16, Parameter::U16(nn))
+                | (Pattern::AddressU16, Parameter::AddressU16(Address(nn))) => {
+                    let i = self.nn?;
+                    let (lo, hi) = utilities::to8(nn);
+                    bytes[i] = lo;
+                    bytes[i + 1] = hi;
+                }
+                (Pattern::Literal(x), Parameter::U8(y)) if x == y => {}
+                (Pattern::Rst(x), Parameter::U16(y)) if x == y => {}
+                _ => return None,
+            }
+        }
+        Some(Opcode::from_bytes(&bytes[..self.bytes.len()]))
+    }
+
+    /// Is `bytes` an opcode for this template, with any parameters?
+    fn matches(&self, bytes: &[u8]) -> bool {
+        let parameter = |i: usize| {
+            Some(i) == self.d
+                || Some(i) == self.n
+                || self.nn.is_some_and(|j| i == j || i == j + 1)
+        };
+        bytes.len() == self.bytes.len()
+            && bytes
+                .iter()
+                .zip(self.bytes.iter())
+                .enumerate()
+                .all(|(i, (x, y))| x == y || parameter(i))
+    }
+}
+
+/// Call `f` with each entry in the instruction list in order, until it returns
+/// something.
+fn find_template<F, T>(mut f: F) -> Option<T>
+where
+    F: FnMut(&Template) -> Option<T>,
+{
+    macro_rules! pattern {
+        ((IX + d)) => {
+            Pattern::Shift(IX)
+        };
+        ((IY + d)) => {
+            Pattern::Shift(IY)
+        };
+        ((nn)) => {
+            Pattern::AddressU16
+        };
+        (($x:ident)) => {
+            Pattern::AddressReg16($x)
+        };
+        (n) => {
+            Pattern::U8
+        };
+        (d) => {
+            Pattern::I8
+        };
+        (e) => {
+            Pattern::I8
+        };
+        (nn) => {
+            Pattern::U16
+        };
+        ($x:ident) => {
+            Pattern::from($x)
+        };
+        ($x:expr) => {
+            Pattern::Literal($x)
+        };
+    }
+
+    macro_rules! visit {
+        (
+            @ $mnemonic:expr,
+            [$($arg:tt),*],
+            $bytes:expr,
+            $d:expr,
+            $n:expr,
+            $nn:expr,
+            $is_undoc:expr
+        ) => {
+            let template = Template {
+                mnemonic: $mnemonic,
+                patterns: &[$(pattern!($arg)),*],
+                bytes: &$bytes,
+                d: $d,
+                n: $n,
+                nn: $nn,
+                undocumented: $is_undoc,
+            };
+            if let Some(x) = f(&template) {
+                return Some(x);
+            }
+        };
+        ([$code:expr]; rst; [$arg:expr]; $req:ident; $t_states:expr; $is_undoc:expr) => {
+            let template = Template {
+                mnemonic: Mnemonic::Rst,
+                patterns: &[Pattern::Rst($arg)],
+                bytes: &[$code],
+                d: None,
+                n: None,
+                nn: None,
+                undocumented: $is_undoc,
+            };
+            if let Some(x) = f(&template) {
+                return Some(x);
+            }
+        };
+        (
+            [$code:expr,n,n];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code, 0, 0],
+                None,
+                None,
+                Some(1),
+                $is_undoc
+            );
+        };
+        (
+            [$code:expr,e];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code, 0],
+                Some(1),
+                None,
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code:expr,d];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code, 0],
+                Some(1),
+                None,
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code:expr,n];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code, 0],
+                None,
+                Some(1),
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code1:expr, $code2:expr,n];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code1, $code2, 0],
+                None,
+                Some(2),
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code1:expr, $code2:expr,d];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code1, $code2, 0],
+                Some(2),
+                None,
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code1:expr, $code2:expr,d,n];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code1, $code2, 0, 0],
+                Some(2),
+                Some(3),
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code1:expr, $code2:expr,n,n];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code1, $code2, 0, 0],
+                None,
+                None,
+                Some(2),
+                $is_undoc
+            );
+        };
+        (
+            [$code1:expr, $code2:expr,d, $code3:expr];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code1, $code2, 0, $code3],
+                Some(2),
+                None,
+                None,
+                $is_undoc
+            );
+        };
+        (
+            [$code:expr];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(@ function_to_mnemonic!($mnemonic), $args, [$code], None, None, None, $is_undoc);
+        };
+        (
+            [$code1:expr, $code2:expr];
+            $mnemonic:ident;
+            $args:tt;
+            $req:ident;
+            $t_states:expr;
+            $is_undoc:expr
+        ) => {
+            visit!(
+                @ function_to_mnemonic!($mnemonic),
+                $args,
+                [$code1, $code2],
+                None,
+                None,
+                None,
+                $is_undoc
+            );
+        };
+    }
+
+    euphrates_process_instructions!(visit, d, e, n, nn);
+
+    None
+}
+
+fn mnemonic(name: &str) -> Option<Mnemonic> {
+    use self::Mnemonic::*;
+    Some(match name {
+        "ld" => Ld,
+        "push" => Push,
+        "pop" => Pop,
+        "ex" => Ex,
+        "exx" => Exx,
+        "ldi" => Ldi,
+        "ldir" => Ldir,
+        "ldd" => Ldd,
+        "lddr" => Lddr,
+        "cpi" => Cpi,
+        "cpir" => Cpir,
+        "cpd" => Cpd,
+        "cpdr" => Cpdr,
+        "add" => Add,
+        "adc" => Adc,
+        "sub" => Sub,
+        "sbc" => Sbc,
+        "and" => And,
+        "or" => Or,
+        "xor" => Xor,
+        "cp" => Cp,
+        "inc" => Inc,
+        "dec" => Dec,
+        "daa" => Daa,
+        "cpl" => Cpl,
+        "neg" => Neg,
+        "ccf" => Ccf,
+        "scf" => Scf,
+        "nop" => Nop,
+        "halt" => Halt,
+        "di" => Di,
+        "ei" => Ei,
+        "im" => Im,
+        "rlca" => Rlca,
+        "rla" => Rla,
+        "rrca" => Rrca,
+        "rra" => Rra,
+        "rlc" => Rlc,
+        "rl" => Rl,
+        "rrc" => Rrc,
+        "rr" => Rr,
+        "sla" => Sla,
+        "sra" => Sra,
+        "sll" => Sll,
+        "srl" => Srl,
+        "rld" => Rld,
+        "rrd" => Rrd,
+        "bit" => Bit,
+        "set" => Set,
+        "res" => Res,
+        "jp" => Jp,
+        "jr" => Jr,
+        "djnz" => Djnz,
+        "call" => Call,
+        "ret" => Ret,
+        "reti" => Reti,
+        "retn" => Retn,
+        "rst" => Rst,
+        "in" => In,
+        "ini" => Ini,
+        "inir" => Inir,
+        "ind" => Ind,
+        "indr" => Indr,
+        "out" => Out,
+        "outi" => Outi,
+        "otir" => Otir,
+        "outd" => Outd,
+        "otdr" => Otdr,
+        _ => return None,
+    })
+}
+
+/// Names of registers and condition codes, which can't be labels.
+const NAMES: &[&str] = &[
+    "a", "b", "c", "d", "e", "h", "l", "i", "r", "ixh", "ixl", "iyh", "iyl", "af", "af'", "bc",
+    "de", "hl", "ix", "iy", "sp", "nz", "z", "nc", "po", "pe", "p", "m",
+];
+
+fn is_name(s: &str) -> bool {
+    NAMES.contains(&s)
+}
+
+/// Is `s` a number like `FF`, rather than a label?
+fn is_hex_word(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_hexdigit() && !c.is_lowercase())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Word(String),
+    Number(i64),
+    Str(Vec<u8>),
+
+    /// `$`
+    Here,
+
+    Symbol(&'static str),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    Number(i64),
+    Label(String),
+    Here,
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Operand {
+    /// A register or condition code, in lowercase.
+    Name(String),
+
+    /// A register in parentheses.
+    Indirect(String),
+
+    /// `(ix+d)` or `(iy+d)`.
+    Indexed(String, Expr),
+
+    /// An expression in parentheses.
+    IndirectExpr(Expr),
+
+    Expr(Expr),
+
+    /// An expression beginning with `+` or `-`.
+    Displacement(Expr),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Item {
+    Expr(Expr),
+    Str(Vec<u8>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Statement {
+    Org(Expr),
+    Db(Vec<Item>),
+    Dw(Vec<Expr>),
+    Instruction(Mnemonic, Vec<Operand>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Line {
+    number: usize,
+    text: String,
+    label: Option<String>,
+    statement: Option<Statement>,
+}
+
+fn syntax(line: usize, message: &str) -> AssemblerError {
+    AssemblerError::Syntax {
+        line,
+        message: message.to_owned(),
+    }
+}
+
+fn parse_number(s: &str, line: usize) -> Result<i64> {
+    let digits = if s.starts_with("0x") || s.starts_with("0X") {
+        &s[2..]
+    } else if s.ends_with('h') || s.ends_with('H') {
+        &s[..s.len() - 1]
+    } else {
+        s
+    };
+    i64::from_str_radix(digits, 16)
+        .ok()
+        .filter(|&x| x <= 0xFFFF_FFFF)
+        .ok_or_else(|| syntax(line, &format!("bad number {}", s)))
+}
+
+fn lex(text: &str, line: usize) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            let end = (i + 1..chars.len())
+                .find(|&j| chars[j] == '"')
+                .ok_or_else(|| syntax(line, "unterminated string"))?;
+            let s: String = chars[i + 1..end].iter().collect();
+            tokens.push(Token::Str(s.into_bytes()));
+            i = end + 1;
+        } else if c == '\'' {
+            if i + 2 >= chars.len() || chars[i + 2] != '\'' || !chars[i + 1].is_ascii() {
+                return Err(syntax(line, "bad character"));
+            }
+            tokens.push(Token::Number(chars[i + 1] as i64));
+            i += 3;
+        } else if c == '$' {
+            let end = (i + 1..chars.len())
+                .find(|&j| !chars[j].is_ascii_hexdigit())
+                .unwrap_or(chars.len());
+            if end == i + 1 {
+                tokens.push(Token::Here);
+            } else {
+                let s: String = chars[i + 1..end].iter().collect();
+                tokens.push(Token::Number(parse_number(&s, line)?));
+            }
+            i = end;
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let mut end = (i..chars.len())
+                .find(|&j| !(chars[j].is_alphanumeric() || chars[j] == '_' || chars[j] == '.'))
+                .unwrap_or(chars.len());
+            let s: String = chars[i..end].iter().collect();
+            if c.is_ascii_digit() {
+                tokens.push(Token::Number(parse_number(&s, line)?));
+            } else {
+                // af'
+                let mut s = s;
+                if end < chars.len() && chars[end] == '\'' {
+                    s.push('\'');
+                    end += 1;
+                }
+                tokens.push(Token::Word(s));
+            }
+            i = end;
+        } else {
+            let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = ["<<", ">>"]
+                .iter()
+                .chain(["(", ")", ",", ":", "+", "-", "*", "/", "%", "&", "|", "^", "~"].iter())
+                .find(|s| two.starts_with(*s))
+                .ok_or_else(|| syntax(line, &format!("unexpected {}", c)))?;
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Operators in increasing order of precedence.
+const BINARY_OPERATORS: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    line: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn parse(tokens: &'a [Token], line: usize) -> Result<Expr> {
+        let mut parser = ExprParser {
+            tokens,
+            position: 0,
+            line,
+        };
+        let expr = parser.binary(0)?;
+        if parser.position < tokens.len() {
+            return Err(syntax(line, "unexpected token in expression"));
+        }
+        Ok(expr)
+    }
+
+    fn symbol(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(&Token::Symbol(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, precedence: usize) -> Result<Expr> {
+        if precedence == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+        let mut expr = self.binary(precedence + 1)?;
+        while let Some(s) = self.symbol() {
+            if !BINARY_OPERATORS[precedence].contains(&s) {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(precedence + 1)?;
+            expr = Expr::Binary(s, Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| syntax(self.line, "expected an expression"))?;
+        self.position += 1;
+        match token {
+            Token::Symbol(s) if s == "+" || s == "-" || s == "~" => {
+                Ok(Expr::Unary(s, Box::new(self.unary()?)))
+            }
+            Token::Symbol("(") => {
+                let expr = self.binary(0)?;
+                if self.symbol() != Some(")") {
+                    return Err(syntax(self.line, "expected )"));
+                }
+                self.position += 1;
+                Ok(expr)
+            }
+            Token::Number(x) => Ok(Expr::Number(x)),
+            Token::Here => Ok(Expr::Here),
+            Token::Word(ref s) if is_hex_word(s) => Ok(Expr::Number(parse_number(s, self.line)?)),
+            Token::Word(s) => Ok(Expr::Label(s)),
+            _ => Err(syntax(self.line, "expected an expression")),
+        }
+    }
+}
+
+/// Split `tokens` at commas outside parentheses.
+fn split_commas(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            Token::Symbol("(") => depth += 1,
+            Token::Symbol(")") => depth -= 1,
+            Token::Symbol(",") if depth == 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts
+}
+
+/// If `tokens` is entirely in one pair of parentheses, what's inside.
+fn parenthesized(tokens: &[Token]) -> Option<&[Token]> {
+    if tokens.len() < 2 || tokens[0] != Token::Symbol("(") {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            Token::Symbol("(") => depth += 1,
+            Token::Symbol(")") => {
+                depth -= 1;
+                if depth == 0 {
+                    return if i == tokens.len() - 1 {
+                        Some(&tokens[1..i])
+                    } else {
+                        None
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn name(tokens: &[Token]) -> Option<String> {
+    match *tokens {
+        [Token::Word(ref s)] if is_name(&s.to_lowercase()) => Some(s.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn parse_operand(tokens: &[Token], line: usize) -> Result<Operand> {
+    if tokens.is_empty() {
+        return Err(syntax(line, "missing operand"));
+    }
+    if let Some(name) = name(tokens) {
+        return Ok(Operand::Name(name));
+    }
+    if let Some(inner) = parenthesized(tokens) {
+        if let Some(name) = name(inner) {
+            return Ok(Operand::Indirect(name));
+        }
+        if let Some(name) = inner.get(..1).and_then(name) {
+            if (name == "ix" || name == "iy")
+                && (inner[1] == Token::Symbol("+") || inner[1] == Token::Symbol("-"))
+            {
+                return Ok(Operand::Indexed(name, ExprParser::parse(&inner[1..], line)?));
+            }
+        }
+        return Ok(Operand::IndirectExpr(ExprParser::parse(inner, line)?));
+    }
+    let expr = ExprParser::parse(tokens, line)?;
+    match tokens[0] {
+        Token::Symbol("+") | Token::Symbol("-") => Ok(Operand::Displacement(expr)),
+        _ => Ok(Operand::Expr(expr)),
+    }
+}
+
+fn parse_line(text: &str, line: usize) -> Result<Line> {
+    let tokens = lex(text, line)?;
+    let mut rest = &tokens[..];
+
+    let label = match *rest {
+        [Token::Word(ref s), Token::Symbol(":"), ..] => {
+            if is_name(&s.to_lowercase()) || is_hex_word(s) {
+                return Err(syntax(line, &format!("{} can't be a label", s)));
+            }
+            Some(s.clone())
+        }
+        _ => None,
+    };
+    if label.is_some() {
+        rest = &rest[2..];
+    }
+
+    let keyword = match rest.first() {
+        None => {
+            return Ok(Line {
+                number: line,
+                text: text.trim().to_owned(),
+                label,
+                statement: None,
+            })
+        }
+        Some(&Token::Word(ref s)) => s.to_lowercase(),
+        Some(_) => return Err(syntax(line, "expected an instruction")),
+    };
+    let parts = if rest.len() == 1 {
+        Vec::new()
+    } else {
+        split_commas(&rest[1..])
+    };
+
+    let statement = match keyword.trim_start_matches('.') {
+        "org" => {
+            if parts.len() != 1 {
+                return Err(syntax(line, "org takes one address"));
+            }
+            Statement::Org(ExprParser::parse(parts[0], line)?)
+        }
+        "db" | "defb" => Statement::Db(
+            parts
+                .iter()
+                .map(|part| match **part {
+                    [Token::Str(ref s)] => Ok(Item::Str(s.clone())),
+                    _ => Ok(Item::Expr(ExprParser::parse(part, line)?)),
+                }).collect::<Result<_>>()?,
+        ),
+        "dw" | "defw" => Statement::Dw(
+            parts
+                .iter()
+                .map(|part| ExprParser::parse(part, line))
+                .collect::<Result<_>>()?,
+        ),
+        _ => {
+            let mnemonic = mnemonic(&keyword)
+                .ok_or_else(|| syntax(line, &format!("unknown instruction {}", keyword)))?;
+            let operands = parts
+                .iter()
+                .map(|part| parse_operand(part, line))
+                .collect::<Result<_>>()?;
+            Statement::Instruction(mnemonic, operands)
+        }
+    };
+
+    Ok(Line {
+        number: line,
+        text: text.trim().to_owned(),
+        label,
+        statement: Some(statement),
+    })
+}
+
+/// What's needed to evaluate expressions in a statement.
+struct Context<'a> {
+    labels: &'a BTreeMap<String, u16>,
+    here: u16,
+    line: usize,
+
+    /// In the first pass, labels may be defined later, so unknown labels just
+    /// have no value yet.
+    first_pass: bool,
+}
+
+impl<'a> Context<'a> {
+    fn syntax(&self, message: &str) -> AssemblerError {
+        syntax(self.line, message)
+    }
+
+    /// The error for `expr` needing a label that isn't defined yet.
+    fn forward(&self, expr: &Expr) -> AssemblerError {
+        let mut label = String::new();
+        let mut exprs = vec![expr];
+        while let Some(expr) = exprs.pop() {
+            match *expr {
+                Expr::Label(ref s) if !self.labels.contains_key(s) => {
+                    label = s.clone();
+                    break;
+                }
+                Expr::Unary(_, ref x) => exprs.push(x),
+                Expr::Binary(_, ref x, ref y) => {
+                    exprs.push(y);
+                    exprs.push(x);
+                }
+                _ => {}
+            }
+        }
+        AssemblerError::UnknownLabel {
+            line: self.line,
+            label,
+        }
+    }
+
+    /// The value of `expr`, or `None` if it uses a label not yet defined.
+    fn eval(&self, expr: &Expr) -> Result<Option<i64>> {
+        let value = match *expr {
+            Expr::Number(x) => x,
+            Expr::Here => self.here as i64,
+            Expr::Label(ref s) => match self.labels.get(s) {
+                Some(&x) => x as i64,
+                None if self.first_pass => return Ok(None),
+                None => return Err(self.forward(expr)),
+            },
+            Expr::Unary(op, ref x) => {
+                let x = match self.eval(x)? {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                match op {
+                    "-" => x.wrapping_neg(),
+                    "~" => !x,
+                    _ => x,
+                }
+            }
+            Expr::Binary(op, ref x, ref y) => {
+                let (x, y) = match (self.eval(x)?, self.eval(y)?) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => return Ok(None),
+                };
+                match op {
+                    "+" => x.wrapping_add(y),
+                    "-" => x.wrapping_sub(y),
+                    "*" => x.wrapping_mul(y),
+                    "/" | "%" if y == 0 => return Err(self.syntax("division by zero")),
+                    "/" => x.wrapping_div(y),
+                    "%" => x.wrapping_rem(y),
+                    "&" => x & y,
+                    "|" => x | y,
+                    "^" => x ^ y,
+                    "<<" => x.wrapping_shl(y as u32),
+                    ">>" => x.wrapping_shr(y as u32),
+                    _ => unreachable!(),
+                }
+            }
+        };
+        Ok(Some(value))
+    }
+
+    fn out_of_range(&self, value: i64) -> AssemblerError {
+        AssemblerError::OutOfRange {
+            line: self.line,
+            value,
+        }
+    }
+
+    fn byte(&self, value: i64) -> Result<u8> {
+        if !(-0x80..=0xFF).contains(&value) {
+            return Err(self.out_of_range(value));
+        }
+        Ok(value as u8)
+    }
+
+    fn word(&self, value: i64) -> Result<u16> {
+        if !(-0x8000..=0xFFFF).contains(&value) {
+            return Err(self.out_of_range(value));
+        }
+        Ok(value as u16)
+    }
+
+    /// The parameter for `operand` if it can match `pattern`.
+    ///
+    /// `next` is the address of the next instruction, which relative jumps
+    /// are from.
+    fn parameter(
+        &self,
+        mnemonic: Mnemonic,
+        pattern: Pattern,
+        operand: &Operand,
+        next: u16,
+    ) -> Result<Option<Parameter>> {
+        use self::Operand::*;
+        let in_out = mnemonic == Mnemonic::In || mnemonic == Mnemonic::Out;
+        let parameter = match (pattern, operand) {
+            (Pattern::Reg8(r), &Name(ref s)) if r.to_string() == *s => Parameter::Reg8(r),
+            // in a, (c)
+            (Pattern::Reg8(C), &Indirect(ref s)) if in_out && s == "c" => Parameter::Reg8(C),
+            (Pattern::Reg16(r), &Name(ref s)) if r.to_string() == *s => Parameter::Reg16(r),
+            // jp (hl)
+            (Pattern::Reg16(r), &Indirect(ref s))
+                if mnemonic == Mnemonic::Jp && r.to_string() == *s =>
+            {
+                Parameter::Reg16(r)
+            }
+            (Pattern::Cc(c), &Name(ref s)) if c.to_string() == *s => Parameter::Cc(c),
+            (Pattern::AddressReg16(r), &Indirect(ref s)) if r.to_string() == *s => {
+                Parameter::AddressReg16(Address(r))
+            }
+            (Pattern::Shift(r), &Indirect(ref s)) if r.to_string() == *s => {
+                Parameter::Shift(Shift(r, 0))
+            }
+            (Pattern::Shift(r), &Indexed(ref s, ref expr)) if r.to_string() == *s => {
+                let d = self.eval(expr)?.unwrap_or(0);
+                Parameter::Shift(Shift(r, self.byte(d)? as i8))
+            }
+            (Pattern::AddressU16, &IndirectExpr(ref expr)) => {
+                let nn = self.eval(expr)?.unwrap_or(0);
+                Parameter::AddressU16(Address(self.word(nn)?))
+            }
+            (Pattern::I8, &Displacement(ref expr)) => {
+                let e = self.eval(expr)?.unwrap_or(0);
+                Parameter::I8(self.byte(e)? as i8)
+            }
+            (Pattern::I8, &Expr(ref expr)) => {
+                let target = self.eval(expr)?.unwrap_or(next as i64);
+                let e = target - next as i64;
+                if !(-0x80..=0x7F).contains(&e) {
+                    return Err(self.out_of_range(e));
+                }
+                Parameter::I8(e as i8)
+            }
+            // out (n), a
+            (Pattern::U8, &IndirectExpr(ref expr)) if in_out => {
+                let n = self.eval(expr)?.unwrap_or(0);
+                Parameter::U8(self.byte(n)?)
+            }
+            (Pattern::U8, &Expr(ref expr)) | (Pattern::U8, &Displacement(ref expr)) => {
+                let n = self.eval(expr)?.unwrap_or(0);
+                Parameter::U8(self.byte(n)?)
+            }
+            (Pattern::U16, &Expr(ref expr)) | (Pattern::U16, &Displacement(ref expr)) => {
+                let nn = self.eval(expr)?.unwrap_or(0);
+                Parameter::U16(self.word(nn)?)
+            }
+            (Pattern::Literal(x), &Expr(ref expr)) => match self.eval(expr)? {
+                Some(y) if y != x as i64 => return Ok(None),
+                _ => Parameter::U8(x),
+            },
+            (Pattern::Rst(x), &Expr(ref expr)) => match self.eval(expr)? {
+                Some(y) if y != x as i64 => return Ok(None),
+                _ => Parameter::U16(x),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(parameter))
+    }
+
+    fn instruction(&self, mnemonic: Mnemonic, operands: &[Operand], text: &str) -> Result<Opcode> {
+        let result = find_template(|template| {
+            if template.mnemonic != mnemonic || template.patterns.len() != operands.len() {
+                return None;
+            }
+            let next = self.here.wrapping_add(template.bytes.len() as u16);
+            let mut parameters = Vec::with_capacity(operands.len());
+            for (&pattern, operand) in template.patterns.iter().zip(operands.iter()) {
+                match self.parameter(mnemonic, pattern, operand, next) {
+                    Ok(Some(parameter)) => parameters.push(parameter),
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            template.encode(&parameters).map(Ok)
+        });
+        result.unwrap_or_else(|| {
+            Err(AssemblerError::NoInstruction {
+                line: self.line,
+                text: text.to_owned(),
+            })
+        })
+    }
+}
@@ -0,0 +1,274 @@
+use hardware::io16::Io16;
+use hardware::memory16::Memory16;
+
+use super::instruction::instruction_traits::*;
+use super::instruction::Z80Emulator;
+use super::*;
+
+use self::ConditionCode::*;
+use self::Reg16::*;
+use self::Reg8::*;
+
+/// The immediate operands of an instruction, whichever of them it has.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+struct Immediates {
+    d: i8,
+    e: i8,
+    n: u8,
+    nn: u16,
+}
+
+/// An instruction's operation, with its register and address operands built
+/// in, to be given its immediate operands.
+type Operation = fn(&mut Operating, Immediates);
+
+/// The `Z80Emulator` an `Operation` runs on.
+///
+/// An `Operation` has no bytes left to read, so this has no PC to read from.
+struct Operating<'a> {
+    z80: &'a mut Z80State,
+    memory: &'a mut dyn Memory16,
+    io: &'a mut dyn Io16,
+}
+
+impl<'a> Z80Emulator for Operating<'a> {
+    type No = Z80NoImpler<Z80State>;
+
+    type Mem = Z80MemImpler<Z80State, dyn Memory16 + 'a>;
+
+    type Io = Z80IoImpler<Z80State, dyn Memory16 + 'a, dyn Io16 + 'a>;
+
+    #[inline]
+    fn no<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::No),
+    {
+        f(unsafe { &mut Z80NoImpler::new(self.z80) });
+    }
+
+    #[inline]
+    fn mem<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::Mem),
+    {
+        f(unsafe { &mut Z80MemImpler::new(self.z80, self.memory) });
+    }
+
+    #[inline]
+    fn io<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::Io),
+    {
+        f(unsafe { &mut Z80IoImpler::new(self.z80, self.memory, self.io) });
+    }
+
+    fn read_pc(&mut self) -> u8 {
+        unreachable!("Decoded instructions don't read their bytes")
+    }
+
+    fn inc_pc(&mut self) {
+        unreachable!("Decoded instructions don't read their bytes")
+    }
+
+    #[inline]
+    fn inc_cycles(&mut self, c: u64) {
+        self.z80.inc_cycles(c);
+    }
+}
+
+/// One byte of an instruction in the instruction list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum Code {
+    Byte(u8),
+    D,
+    E,
+    N,
+}
+
+/// If `bytes` are an instruction with the `codes`, its immediate operands.
+///
+/// Two `N`s are the low and high bytes of `nn`.
+fn immediates(bytes: &[u8], codes: &[Code]) -> Option<Immediates> {
+    if bytes.len() != codes.len() {
+        return None;
+    }
+    let mut immediates = Immediates::default();
+    let mut shift = 0;
+    for (&byte, &code) in bytes.iter().zip(codes) {
+        match code {
+            Code::Byte(x) => if x != byte {
+                return None;
+            },
+            Code::D => immediates.d = byte as i8,
+            Code::E => immediates.e = byte as i8,
+            Code::N => {
+                immediates.n = byte;
+                immediates.nn |= (byte as u16) << shift;
+                shift += 8;
+            }
+        }
+    }
+    Some(immediates)
+}
+
+/// The operation and immediate operands of the instruction that is exactly
+/// `bytes`, prefixes included.
+#[allow(unused_variables)]
+fn decode(bytes: &[u8]) -> Option<(Operation, Immediates)> {
+    macro_rules! code {
+        (d) => {
+            Code::D
+        };
+        (e) => {
+            Code::E
+        };
+        (n) => {
+            Code::N
+        };
+        ($x:expr) => {
+            Code::Byte($x)
+        };
+    }
+
+    macro_rules! operand {
+        ($i:ident, (IX + d)) => {
+            Shift(IX, $i.d)
+        };
+        ($i:ident, (IY + d)) => {
+            Shift(IY, $i.d)
+        };
+        ($i:ident, (nn)) => {
+            Address($i.nn)
+        };
+        ($i:ident, ($x:ident)) => {
+            Address($x)
+        };
+        ($i:ident, d) => {
+            $i.d
+        };
+        ($i:ident, e) => {
+            $i.e
+        };
+        ($i:ident, n) => {
+            $i.n
+        };
+        ($i:ident, nn) => {
+            $i.nn
+        };
+        ($i:ident, $x:ident) => {
+            $x
+        };
+        // bit numbers, interrupt modes, and restart addresses
+        ($i:ident, $x:tt) => {
+            $x as u8
+        };
+    }
+
+    macro_rules! operate {
+        // the list says these need neither memory nor IO, but they're only
+        // implemented for memory
+        ($z:ident, $i:ident; jp; no; $($rest:tt)*) => {
+            operate!($z, $i; jp; mem; $($rest)*)
+        };
+        ($z:ident, $i:ident; rla; no; $($rest:tt)*) => {
+            operate!($z, $i; rla; mem; $($rest)*)
+        };
+        ($z:ident, $i:ident; rlca; no; $($rest:tt)*) => {
+            operate!($z, $i; rlca; mem; $($rest)*)
+        };
+        ($z:ident, $i:ident; rra; no; $($rest:tt)*) => {
+            operate!($z, $i; rra; mem; $($rest)*)
+        };
+        ($z:ident, $i:ident; rrca; no; $($rest:tt)*) => {
+            operate!($z, $i; rrca; mem; $($rest)*)
+        };
+        ($z:ident, $i:ident; $function:ident; both; $($rest:tt)*) => {
+            operate!($z, $i; $function; io; $($rest)*)
+        };
+        ($z:ident, $i:ident; $function:ident; $req:ident; $($arg:tt),*) => {
+            $z.$req(|r| r.$function($(operand!($i, $arg)),*))
+        };
+    }
+
+    macro_rules! find_operation {
+        ([$($code:tt),*]; $function:ident; [$($arg:tt),*]; $req:ident; $t_states:expr;
+         $is_undoc:expr) => {
+            if let Some(immediates) = immediates(bytes, &[$(code!($code)),*]) {
+                let operation: Operation = |z, i| {
+                    operate!(z, i; $function; $req; $($arg),*);
+                };
+                return Some((operation, immediates));
+            }
+        };
+    }
+
+    euphrates_process_instructions!(find_operation, d, e, n, nn);
+
+    None
+}
+
+/// An instruction decoded by `Z80RunImpler::step_decoding`, to be executed
+/// again without reading its bytes or dispatching on them.
+#[derive(Clone, Copy, Debug)]
+pub struct DecodedInstruction {
+    operation: Operation,
+    immediates: Immediates,
+    len: u8,
+    /// How many opcodes, each of which increments R, are in the instruction?
+    refreshes: u8,
+    /// Cycles for the instruction's prefixes, counted before its operation.
+    prefix_cycles: u8,
+    /// Cycles counted after its operation, which may count more of its own.
+    cycles: u8,
+}
+
+impl DecodedInstruction {
+    /// Decode the instruction that is exactly `bytes`, given how the
+    /// interpreter counted its opcodes and cycles. `None` if it isn't one.
+    pub fn new(
+        bytes: &[u8],
+        refreshes: u8,
+        prefix_cycles: u8,
+        cycles: u8,
+    ) -> Option<DecodedInstruction> {
+        let (operation, immediates) = decode(bytes)?;
+        Some(DecodedInstruction {
+            operation,
+            immediates,
+            len: bytes.len() as u8,
+            refreshes,
+            prefix_cycles,
+            cycles,
+        })
+    }
+
+    /// How many bytes long is the instruction, prefixes included?
+    #[inline]
+    pub fn byte_len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// How many cycles after the instruction begins does its last step, the
+    /// one after all its prefixes, begin?
+    #[inline]
+    pub fn last_step(&self) -> u64 {
+        self.prefix_cycles as u64
+    }
+
+    /// Execute the instruction, which must be what's now at PC.
+    ///
+    /// This does exactly what the interpreter's steps through the instruction
+    /// would, so the caller must make sure every one of them would begin
+    /// before its target: that is, that the Z80's cycles plus `last_step` are
+    /// less than the target.
+    #[inline]
+    pub fn execute(&self, z80: &mut Z80State, memory: &mut dyn Memory16, io: &mut dyn Io16) {
+        z80.begin_instruction();
+        z80.inc_r(self.refreshes);
+        let pc = z80.reg16(PC);
+        z80.set_reg16(PC, pc.wrapping_add(self.len as u16));
+        z80.inc_cycles(self.prefix_cycles as u64);
+        (self.operation)(&mut Operating { z80, memory, io }, self.immediates);
+        z80.inc_cycles(self.cycles as u64);
+    }
+}
@@ -739,7 +739,6 @@ where
     use self::Reg16::*;
     use self::Reg8::*;
 
-    // z.inc_r(1); XXX
     let pc = z.read_pc();
     z.inc_pc();
     match pc {
@@ -751,7 +750,7 @@ where
         0x05 => regular!{z,      4, mem,     Dec,     dec, ([B]) },
         0x06 => n_inst! {z,  n,  7, mem,      Ld,      ld, ([B] [n]) },
         0x07 => regular!{z,      4, mem,    Rlca,    rlca, () },
-        0x08 => regular!{z,      4, mem,      Ex,      ex, ([AF] [BC0]) },
+        0x08 => regular!{z,      4, mem,      Ex,      ex, ([AF] [AF0]) },
         0x09 => regular!{z,     11,  no,   Add16,   add16, ([HL] [BC]) },
         0x0A => regular!{z,      7, mem,      Ld,      ld, ([A] [Address(BC)]) },
         0x0B => regular!{z,      6,  no,   Dec16,   dec16, ([BC]) },
@@ -759,7 +758,7 @@ where
         0x0D => regular!{z,      4, mem,     Dec,     dec, ([C]) },
         0x0E => n_inst! {z,  n,  7, mem,      Ld,      ld, ([C] [n]) },
         0x0F => regular!{z,      4, mem,    Rrca,    rrca, () },
-        0x10 => e_inst! {z,  e,  8,  no,    Djnz,    djnz, ([e]) },
+        0x10 => e_inst! {z,  e,  0,  no,    Djnz,    djnz, ([e]) },
         0x11 => nn_inst!{z, nn, 10, mem,    Ld16,    ld16, ([DE] [nn]) },
         0x12 => regular!{z,      7, mem,      Ld,      ld, ([Address(DE)] [A]) },
         0x13 => regular!{z,      6,  no,   Inc16,   inc16, ([DE]) },
@@ -948,7 +947,7 @@ where
         0xCA => nn_inst!{z, nn, 10,  no,    Jpcc,   jpcc, ([Zcc] [nn]) },
         0xCB => regular!{z,      0,  no,      Cb,     cb, () },
         0xCC => nn_inst!{z, nn,  0, mem,  Callcc, callcc, ([Zcc] [nn]) },
-        0xCD => nn_inst!{z, nn,  0, mem,    Call,   call, ([nn]) },
+        0xCD => nn_inst!{z, nn, 17, mem,    Call,   call, ([nn]) },
         0xCE => n_inst! {z,  n,  7, mem,     Adc,    adc, ([A] [n]) },
         0xCF => regular!{z,     11, mem,    Rst,     rst, ([0x08]) },
         0xD0 => regular!{z,      0, mem,  Retcc,   retcc, ([NCcc]) },
@@ -1588,7 +1587,7 @@ where
         0x0D => regular!{z,        4, mem,    Dec,    dec, ([C]) },
         0x0E => n_inst! {z,    n,  7, mem,     Ld,     ld, ([C] [n]) },
         0x0F => regular!{z,        4, mem,   Rrca,   rrca, () },
-        0x10 => e_inst! {z,    e,  8,  no,   Djnz,   djnz, ([e]) },
+        0x10 => e_inst! {z,    e,  0,  no,   Djnz,   djnz, ([e]) },
         0x11 => nn_inst!{z,   nn, 10, mem,   Ld16,   ld16, ([DE] [nn]) },
         0x12 => regular!{z,        7, mem,     Ld,     ld, ([Address(DE)] [A]) },
         0x13 => regular!{z,        6,  no,  Inc16,  inc16, ([DE]) },
@@ -1861,7 +1860,7 @@ where
         0x0D => regular!{z,        4, mem,    Dec,    dec, ([C]) },
         0x0E => n_inst! {z,    n,  7, mem,     Ld,     ld, ([C] [n]) },
         0x0F => regular!{z,        4, mem,   Rrca,   rrca, () },
-        0x10 => e_inst! {z,    e,  8,  no,   Djnz,   djnz, ([e]) },
+        0x10 => e_inst! {z,    e,  0,  no,   Djnz,   djnz, ([e]) },
         0x11 => nn_inst!{z,   nn, 10, mem,   Ld16,   ld16, ([DE] [nn]) },
         0x12 => regular!{z,        7, mem,     Ld,     ld, ([Address(DE)] [A]) },
         0x13 => regular!{z,        6,  no,  Inc16,  inc16, ([DE]) },
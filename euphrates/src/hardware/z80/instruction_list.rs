@@ -38,7 +38,7 @@ macro_rules! euphrates_process_instructions {
 [ [0x0D                ] ; dec       ; [ C               ] ; mem  ;  4 ; false ]
 [ [0x0E, n             ] ; ld        ; [ C, $n           ] ; mem  ;  7 ; false ]
 [ [0x0F                ] ; rrca      ; [                 ] ; no   ;  4 ; false ]
-[ [0x10, e             ] ; djnz      ; [ $e              ] ; no   ;  8 ; false ]
+[ [0x10, e             ] ; djnz      ; [ $e              ] ; no   ;  0 ; false ]
 [ [0x11, n, n          ] ; ld16      ; [ DE, $nn         ] ; mem  ; 10 ; false ]
 [ [0x12                ] ; ld        ; [ (DE), A         ] ; mem  ;  7 ; false ]
 [ [0x13                ] ; inc16     ; [ DE              ] ; no   ;  6 ; false ]
@@ -61,6 +61,60 @@ impl Default for InterruptStatus {
     }
 }
 
+/// Which kind of Z80 is this?
+///
+/// They all execute documented instructions the same way, but differ in a few
+/// undocumented details.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Z80Variant {
+    /// Zilog's original NMOS Z80, as in the Master System and the
+    /// ColecoVision.
+    Nmos,
+
+    /// CMOS Z80s like Zilog's Z84C00 and Toshiba's TMPZ84C00, and the Z80
+    /// built into the Game Gear.
+    Cmos,
+
+    /// NEC's NMOS clone, the µPD780C, found in many MSX computers.
+    Nec,
+}
+
+impl Default for Z80Variant {
+    #[inline]
+    fn default() -> Self {
+        Z80Variant::Nmos
+    }
+}
+
+impl Z80Variant {
+    /// What does `out (c), 0` output?
+    #[inline]
+    pub fn out_c_0(self) -> u8 {
+        match self {
+            Z80Variant::Cmos => 0xFF,
+            _ => 0,
+        }
+    }
+
+    /// Do `scf` and `ccf` use the Q register to set `XF` and `YF`?
+    ///
+    /// If so, those flags are taken from `(Q ^ F) | A`; otherwise from `A`.
+    #[inline]
+    pub fn scf_ccf_use_q(self) -> bool {
+        self != Z80Variant::Nec
+    }
+
+    /// If a maskable interrupt is accepted right after `ld a, i` or `ld a, r`,
+    /// is `PF` cleared?
+    ///
+    /// On NMOS parts it is, since IFF2 is copied into `PF` only after the
+    /// interrupt has reset it.
+    #[inline]
+    pub fn ld_a_ir_interrupt_clears_pf(self) -> bool {
+        self != Z80Variant::Cmos
+    }
+}
+
 /// A wrapper object implementing `Display`, so you can `format` any type
 /// implementing `Z80Internal`.
 ///
@@ -116,6 +170,42 @@ pub trait Z80Internal {
     fn interrupt_status(&self) -> InterruptStatus;
     fn set_interrupt_status(&mut self, interrupt_status: InterruptStatus);
 
+    /// The internal register MEMPTR, also called WZ.
+    ///
+    /// Many instructions leave an address in it, and it shows up in the
+    /// undocumented flags set by `bit n, (hl)`.
+    fn memptr(&self) -> u16;
+    fn set_memptr(&mut self, memptr: u16);
+
+    fn variant(&self) -> Z80Variant;
+    fn set_variant(&mut self, variant: Z80Variant);
+
+    /// The Q register: the flags set by the last instruction, or 0 if it didn't
+    /// set any.
+    fn q(&self) -> u8;
+    fn set_q(&mut self, q: u8);
+
+    /// Has the instruction in progress set any flags?
+    fn flags_written(&self) -> bool;
+    fn set_flags_written(&mut self, _: bool);
+
+    /// Is the instruction in progress `ld a, i` or `ld a, r`?
+    fn ld_a_ir(&self) -> bool;
+    fn set_ld_a_ir(&mut self, _: bool);
+
+    /// Call before each instruction (or interrupt) to update the Q register.
+    #[inline]
+    fn begin_instruction(&mut self) {
+        let q = if self.flags_written() {
+            self.reg8(Reg8::F)
+        } else {
+            0
+        };
+        self.set_q(q);
+        self.set_flags_written(false);
+        self.set_ld_a_ir(false);
+    }
+
     /// Increment the Z80's `cycles` by `x`.
     #[inline]
     fn inc_cycles(&mut self, x: u64) {
@@ -143,6 +233,7 @@ pub trait Z80Internal {
     fn set_flag(&mut self, x: u8) {
         let f = self.reg8(Reg8::F);
         self.set_reg8(Reg8::F, f | x);
+        self.set_flags_written(true);
     }
 
     /// Clear the bits in the F register that are set in `x`.
@@ -150,6 +241,7 @@ pub trait Z80Internal {
     fn clear_flag(&mut self, x: u8) {
         let f = self.reg8(Reg8::F);
         self.set_reg8(Reg8::F, f & !x);
+        self.set_flags_written(true);
     }
 
     /// Set or clear the bits in the F register that are set in `x` according to
@@ -193,6 +285,13 @@ pub trait Z80Internal {
         }
     }
 
+    /// Copy bits 3 and 5 of `x` into the undocumented flags XF and YF.
+    #[inline]
+    fn set_xy(&mut self, x: u8) {
+        self.clear_flag(XF | YF);
+        self.set_flag(x & (XF | YF));
+    }
+
     #[inline]
     fn state(&self) -> Z80State {
         let mut state = Z80State::default();
@@ -229,6 +328,11 @@ where
     dest.set_interrupt_mode(source.interrupt_mode());
     dest.set_prefix(source.prefix());
     dest.set_interrupt_status(source.interrupt_status());
+    dest.set_memptr(source.memptr());
+    dest.set_variant(source.variant());
+    dest.set_q(source.q());
+    dest.set_flags_written(source.flags_written());
+    dest.set_ld_a_ir(source.ld_a_ir());
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -242,6 +346,11 @@ pub struct Z80State {
     pub prefix: Prefix,
     pub interrupt_mode: InterruptMode,
     pub interrupt_status: InterruptStatus,
+    pub memptr: u16,
+    pub variant: Z80Variant,
+    pub q: u8,
+    pub flags_written: bool,
+    pub ld_a_ir: bool,
 }
 
 /// This module contains offsets for the fields of the Z80State. It's probably
@@ -301,6 +410,14 @@ pub mod offsets {
     pub const PREFIX: usize = IFF2 + 1;
 
     pub const INTERRUPT_MODE: usize = PREFIX + 1;
+
+    pub const MEMPTR: usize = ::std::mem::offset_of!(Z80State, memptr);
+
+    pub const Q: usize = ::std::mem::offset_of!(Z80State, q);
+
+    pub const FLAGS_WRITTEN: usize = ::std::mem::offset_of!(Z80State, flags_written);
+
+    pub const LD_A_IR: usize = ::std::mem::offset_of!(Z80State, ld_a_ir);
 }
 
 impl Default for Z80State {
@@ -314,6 +431,11 @@ impl Default for Z80State {
             prefix: Prefix::NoPrefix,
             interrupt_status: Default::default(),
             interrupt_mode: Default::default(),
+            memptr: 0,
+            variant: Default::default(),
+            q: 0,
+            flags_written: false,
+            ld_a_ir: false,
         };
         z80.set_reg16(Reg16::IX, 0xFFFF);
         z80.set_reg16(Reg16::IY, 0xFFFF);
@@ -424,6 +546,56 @@ impl Z80Internal for Z80State {
         self.interrupt_status = interrupt_status
     }
 
+    #[inline]
+    fn memptr(&self) -> u16 {
+        self.memptr
+    }
+
+    #[inline]
+    fn set_memptr(&mut self, memptr: u16) {
+        self.memptr = memptr
+    }
+
+    #[inline]
+    fn variant(&self) -> Z80Variant {
+        self.variant
+    }
+
+    #[inline]
+    fn set_variant(&mut self, variant: Z80Variant) {
+        self.variant = variant
+    }
+
+    #[inline]
+    fn q(&self) -> u8 {
+        self.q
+    }
+
+    #[inline]
+    fn set_q(&mut self, q: u8) {
+        self.q = q
+    }
+
+    #[inline]
+    fn flags_written(&self) -> bool {
+        self.flags_written
+    }
+
+    #[inline]
+    fn set_flags_written(&mut self, x: bool) {
+        self.flags_written = x
+    }
+
+    #[inline]
+    fn ld_a_ir(&self) -> bool {
+        self.ld_a_ir
+    }
+
+    #[inline]
+    fn set_ld_a_ir(&mut self, x: bool) {
+        self.ld_a_ir = x
+    }
+
     #[inline]
     fn state(&self) -> Z80State {
         self.clone()
@@ -1,5 +1,6 @@
 //! Handling interrupts for the Z80
 
+use hardware::io16::Io16;
 use hardware::memory16::Memory16;
 use memo::Inbox;
 use utilities;
@@ -14,20 +15,153 @@ pub trait Z80Interrupt {
     fn nonmaskable_interrupt(&mut self);
 }
 
-pub struct Z80InterruptImpler<'a, Z: 'a + ?Sized, M: 'a + ?Sized, Irq: 'a + ?Sized, I: 'a + ?Sized>
-{
+pub struct Z80InterruptImpler<
+    'a,
+    Z: 'a + ?Sized,
+    M: 'a + ?Sized,
+    Irq: 'a + ?Sized,
+    I: 'a + ?Sized,
+    Io: 'a + ?Sized,
+> {
     pub z80: &'a mut Z,
     pub memory: &'a mut M,
     pub irq: &'a mut Irq,
     pub inbox: &'a mut I,
+    pub io: &'a mut Io,
+}
+
+/// Executes the instruction supplied by an interrupting device in interrupt
+/// mode 0.
+///
+/// Its bytes are fetched from the device rather than from memory, and PC is
+/// not incremented while fetching them, so that, for instance, `rst` and
+/// `call` push the address of the instruction that was interrupted.
+struct Im0Impler<'a, Z: 'a + ?Sized, M: 'a + ?Sized, Irq: 'a + ?Sized, Io: 'a + ?Sized> {
+    z80: &'a mut Z,
+    memory: &'a mut M,
+    irq: &'a mut Irq,
+    io: &'a mut Io,
+    first_byte: u8,
+    index: u16,
+}
+
+impl<'a, Z, M, Irq, Io> instruction::Z80Emulator for Im0Impler<'a, Z, M, Irq, Io>
+where
+    Z: 'a + ?Sized + Z80Internal,
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    Io: 'a + ?Sized + Io16,
+{
+    type No = Z80NoImpler<Z>;
+
+    type Mem = Z80MemImpler<Z, M>;
+
+    type Io = Z80IoImpler<Z, M, Io>;
+
+    #[inline]
+    fn no<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::No),
+    {
+        f(unsafe { &mut Z80NoImpler::new(self.z80) });
+    }
+
+    #[inline]
+    fn mem<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::Mem),
+    {
+        f(unsafe { &mut Z80MemImpler::new(self.z80, self.memory) });
+    }
+
+    #[inline]
+    fn io<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::Io),
+    {
+        f(unsafe { &mut Z80IoImpler::new(self.z80, self.memory, self.io) });
+    }
+
+    #[inline]
+    fn read_pc(&mut self) -> u8 {
+        if self.index == 0 {
+            self.first_byte
+        } else {
+            self.irq.mi_instruction_byte(self.index)
+        }
+    }
+
+    #[inline]
+    fn inc_pc(&mut self) {
+        self.index = self.index.wrapping_add(1);
+    }
+
+    #[inline]
+    fn inc_cycles(&mut self, c: u64) {
+        let cycles = self.z80.cycles();
+        self.z80.set_cycles(cycles + c);
+    }
 }
 
-impl<'a, Z: 'a, M: 'a, Irq: 'a, I: 'a> Z80Interrupt for Z80InterruptImpler<'a, Z, M, Irq, I>
+impl<'a, Z, M, Irq, Io> Im0Impler<'a, Z, M, Irq, Io>
+where
+    Z: 'a + ?Sized + Z80Internal,
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    Io: 'a + ?Sized + Io16,
+{
+    /// Execute the whole instruction, including any prefixes.
+    ///
+    /// The first byte's fetch is the interrupt acknowledgement, which has
+    /// already incremented R.
+    fn execute(&mut self) {
+        use self::Prefix::*;
+
+        instruction::noprefix(self);
+        loop {
+            match self.z80.prefix() {
+                NoPrefix | Halt => return,
+                Cb => {
+                    self.z80.inc_r(1);
+                    self.z80.set_prefix(NoPrefix);
+                    instruction::cb(self);
+                }
+                Ed => {
+                    self.z80.inc_r(1);
+                    self.z80.set_prefix(NoPrefix);
+                    instruction::ed(self);
+                }
+                Dd => {
+                    self.z80.inc_r(1);
+                    self.z80.set_prefix(NoPrefix);
+                    instruction::dd(self);
+                }
+                Fd => {
+                    self.z80.inc_r(1);
+                    self.z80.set_prefix(NoPrefix);
+                    instruction::fd(self);
+                }
+                DdCb => {
+                    self.z80.set_prefix(NoPrefix);
+                    instruction::ddcb(self);
+                }
+                FdCb => {
+                    self.z80.set_prefix(NoPrefix);
+                    instruction::fdcb(self);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, Z: 'a, M: 'a, Irq: 'a, I: 'a, Io: 'a> Z80Interrupt
+    for Z80InterruptImpler<'a, Z, M, Irq, I, Io>
 where
     Z: Z80Internal + ?Sized,
     M: Memory16 + ?Sized,
     Irq: Z80Irq + ?Sized,
     I: Inbox<Memo = Z80Memo> + ?Sized,
+    Io: Io16 + ?Sized,
 {
     fn check_interrupts(&mut self) {
         self.z80.set_interrupt_status(InterruptStatus::NoCheck);
@@ -51,6 +185,10 @@ where
 
     fn maskable_interrupt(&mut self, x: u8) {
         if self.z80.iff1() {
+            if self.z80.ld_a_ir() && self.z80.variant().ld_a_ir_interrupt_clears_pf() {
+                self.z80.clear_flag(PF);
+            }
+            self.z80.begin_instruction();
             self.z80.inc_r(1);
 
             self.z80.set_iff1(false);
@@ -60,6 +198,20 @@ where
             use self::instruction::instruction_traits::Rst;
 
             match self.z80.interrupt_mode() {
+                InterruptMode::Im0 => {
+                    let mut im0 = Im0Impler {
+                        z80: &mut *self.z80,
+                        memory: &mut *self.memory,
+                        irq: &mut *self.irq,
+                        io: &mut *self.io,
+                        first_byte: x,
+                        index: 0,
+                    };
+                    im0.execute();
+                    // acknowledging the interrupt takes 2 extra cycles: 13 for
+                    // an `rst`, 19 for a `call`
+                    self.z80.inc_cycles(2);
+                }
                 InterruptMode::Im1 => {
                     unsafe { Z80MemImpler::new(self.z80, self.memory).rst(0x38u8) };
                     self.z80.inc_cycles(13);
@@ -70,7 +222,6 @@ where
                     unsafe { Z80MemImpler::new(self.z80, self.memory).rst(new_pc) };
                     self.z80.inc_cycles(19);
                 }
-                _ => unimplemented!(),
             }
         }
     }
@@ -78,6 +229,7 @@ where
     fn nonmaskable_interrupt(&mut self) {
         use self::instruction::instruction_traits::Rst;
 
+        self.z80.begin_instruction();
         self.z80.inc_r(1);
         self.z80.set_iff1(false);
         self.z80.set_prefix(Prefix::NoPrefix);
@@ -2,7 +2,6 @@ use hardware::io16::Io16;
 use hardware::memory16::Memory16;
 use utilities;
 
-use self::Reg16::*;
 use self::Reg8::*;
 
 use super::*;
@@ -72,10 +71,7 @@ where
     U: Z80IoT,
 {
     fn in_c(&mut self, x: Reg8, y: Reg8) {
-        let address_lo = y.view(self);
-        let address_hi = x.view(self);
-        let address = utilities::to16(address_lo, address_hi);
-        let val = self.io().input(address);
+        let val = in_help(self, y);
         x.change(self, val);
     }
 }
@@ -99,6 +95,7 @@ where
         let address = utilities::to16(address_lo, address_hi);
         let val = self.io().input(address);
         x.change(self, val);
+        self.z80().set_memptr(address.wrapping_add(1));
     }
 }
 
@@ -107,9 +104,7 @@ where
     U: Z80IoT,
 {
     fn ind(&mut self) {
-        let new_b = inid_help(self, 0xFFFF);
-        self.z80().set_zero(new_b);
-        self.z80().set_flag(NF);
+        inid_help(self, 0xFFFF);
     }
 }
 
@@ -118,11 +113,10 @@ where
     U: Z80IoT,
 {
     fn indr(&mut self) {
-        self.ind();
-        if self.z80().reg16(BC) != 0 {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+        let x = inid_help(self, 0xFFFF);
+        if self.z80().reg8(B) != 0 {
+            repeat_help(self.z80());
+            inout_repeat_flags(self.z80(), x);
         } else {
             self.z80().inc_cycles(16);
         }
@@ -134,10 +128,7 @@ where
     U: Z80IoT,
 {
     fn ini(&mut self) {
-        let new_b = inid_help(self, 1);
-
-        self.z80().set_zero(new_b);
-        self.z80().set_flag(NF);
+        inid_help(self, 1);
     }
 }
 
@@ -146,11 +137,10 @@ where
     U: Z80IoT,
 {
     fn inir(&mut self) {
-        self.ini();
-        if self.z80().reg16(BC) != 0 {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+        let x = inid_help(self, 1);
+        if self.z80().reg8(B) != 0 {
+            repeat_help(self.z80());
+            inout_repeat_flags(self.z80(), x);
         } else {
             self.z80().inc_cycles(16);
         }
@@ -162,11 +152,14 @@ where
     U: Z80IoT,
 {
     fn otdr(&mut self) {
-        self.outd();
+        let x = outid_help(self, 0xFFFF);
+
+        // our output may have triggered an interrupt
+        self.z80().set_interrupt_status(InterruptStatus::Check);
+
         if self.z80().reg8(B) != 0 {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+            repeat_help(self.z80());
+            inout_repeat_flags(self.z80(), x);
         } else {
             self.z80().inc_cycles(16);
         }
@@ -178,35 +171,56 @@ where
     U: Z80IoT,
 {
     fn otir(&mut self) {
-        self.outi();
+        let x = outid_help(self, 1);
+
+        // our output may have triggered an interrupt
+        self.z80().set_interrupt_status(InterruptStatus::Check);
 
         if self.z80().reg8(B) != 0 {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+            repeat_help(self.z80());
+            inout_repeat_flags(self.z80(), x);
         } else {
             self.z80().inc_cycles(16);
         }
     }
 }
 
-impl<U, T> OutC<Reg8, T> for U
+impl<U> OutC<Reg8, Reg8> for U
 where
     U: Z80IoT,
-    T: Viewable<u8>,
 {
-    fn out_c(&mut self, x: Reg8, y: T) {
-        let address_lo = x.view(self);
-        let address_hi = B.view(self);
-        let address = utilities::to16(address_lo, address_hi);
+    fn out_c(&mut self, x: Reg8, y: Reg8) {
         let val = y.view(self);
-        self.io().output(address, val);
+        out_c_help(self, x, val);
+    }
+}
 
-        // our output may have triggered an interrupt
-        self.z80().set_interrupt_status(InterruptStatus::Check);
+impl<U> OutC<Reg8, u8> for U
+where
+    U: Z80IoT,
+{
+    /// This is the undocumented `out (c), 0`, which outputs 0xFF instead of 0
+    /// on CMOS parts.
+    fn out_c(&mut self, x: Reg8, _y: u8) {
+        let val = self.z80().variant().out_c_0();
+        out_c_help(self, x, val);
     }
 }
 
+fn out_c_help<U>(u: &mut U, x: Reg8, val: u8)
+where
+    U: Z80IoT + ?Sized,
+{
+    let address_lo = x.view(u);
+    let address_hi = B.view(u);
+    let address = utilities::to16(address_lo, address_hi);
+    u.io().output(address, val);
+    u.z80().set_memptr(address.wrapping_add(1));
+
+    // our output may have triggered an interrupt
+    u.z80().set_interrupt_status(InterruptStatus::Check);
+}
+
 impl<U> OutN<u8, Reg8> for U
 where
     U: Z80IoT,
@@ -217,6 +231,7 @@ where
         let address = utilities::to16(address_lo, address_hi);
         let val = y.view(self);
         self.io().output(address, val);
+        self.z80().set_memptr(store_memptr(address, address_hi));
 
         // our output may have triggered an interrupt
         self.z80().set_interrupt_status(InterruptStatus::Check);
@@ -229,10 +244,6 @@ where
 {
     fn outd(&mut self) {
         outid_help(self, 0xFFFF);
-        let new_b = B.view(self);
-
-        self.z80().set_zero(new_b);
-        self.z80().set_flag(NF);
 
         // our output may have triggered an interrupt
         self.z80().set_interrupt_status(InterruptStatus::Check);
@@ -245,10 +256,6 @@ where
 {
     fn outi(&mut self) {
         outid_help(self, 1);
-        let new_b = B.view(self);
-
-        self.z80().set_zero(new_b);
-        self.z80().set_flag(NF);
 
         // our output may have triggered an interrupt
         self.z80().set_interrupt_status(InterruptStatus::Check);
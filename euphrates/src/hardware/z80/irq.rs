@@ -1,5 +1,23 @@
 pub trait Z80Irq {
+    /// If a device is requesting a maskable interrupt, the byte it puts on the
+    /// data bus.
+    ///
+    /// In interrupt mode 0 this is the first byte of an instruction to execute
+    /// (usually an `rst`), and in mode 2 it's the low byte of the address of
+    /// the interrupt vector. It's ignored in mode 1.
     fn requesting_mi(&mut self) -> Option<u8>;
+
+    /// In interrupt mode 0, the byte at position `index` of the instruction
+    /// the interrupting device supplies, where position 0 is the byte returned
+    /// by `requesting_mi`.
+    ///
+    /// Only called for multi-byte instructions, like `call nn`. The default
+    /// implementation returns 0xFF, as a floating data bus would.
+    #[inline]
+    fn mi_instruction_byte(&mut self, _index: u16) -> u8 {
+        0xFF
+    }
+
     fn requesting_nmi(&mut self) -> bool;
     fn take_nmi(&mut self);
 }
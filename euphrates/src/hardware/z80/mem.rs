@@ -157,6 +157,11 @@ impl Changeable<u16> for Address<Reg16> {
     }
 }
 
+// Byte-sized memory operands other than `(hl)` set MEMPTR the way the
+// instructions using them do: `ld a, (bc)`, `ld (nn), a`, `bit 0, (ix+d)`, and
+// so on. Instructions accessing memory for other reasons, like `push`, use
+// `Memory16` directly.
+
 impl Viewable<u8> for Address<Reg16> {
     #[inline]
     fn view<Z>(self, z: &mut Z) -> u8
@@ -164,6 +169,9 @@ impl Viewable<u8> for Address<Reg16> {
         Z: Z80MemT + ?Sized,
     {
         let addr = self.0.view(z);
+        if self.0 == BC || self.0 == DE {
+            z.z80().set_memptr(addr.wrapping_add(1));
+        }
         z.memory().read(addr)
     }
 }
@@ -175,10 +183,20 @@ impl Changeable<u8> for Address<Reg16> {
         Z: Z80MemT + ?Sized,
     {
         let addr = self.0.view(z);
+        if self.0 == BC || self.0 == DE {
+            z.z80().set_memptr(store_memptr(addr, x));
+        }
         z.memory().write(addr, x);
     }
 }
 
+/// MEMPTR after `ld (bc), a`, `ld (nn), a`, or `out (n), a` with address
+/// `addr`.
+#[inline]
+pub fn store_memptr(addr: u16, a: u8) -> u16 {
+    utilities::to16(addr.wrapping_add(1) as u8, a)
+}
+
 impl Viewable<u16> for Address<u16> {
     #[inline]
     fn view<Z>(self, z: &mut Z) -> u16
@@ -186,6 +204,7 @@ impl Viewable<u16> for Address<u16> {
         Z: Z80MemT + ?Sized,
     {
         let addr = self.0;
+        z.z80().set_memptr(addr.wrapping_add(1));
         let lo = z.memory().read(addr);
         let hi = z.memory().read(addr.wrapping_add(1));
         utilities::to16(lo, hi)
@@ -199,6 +218,7 @@ impl Changeable<u16> for Address<u16> {
         Z: Z80MemT + ?Sized,
     {
         let addr = self.0;
+        z.z80().set_memptr(addr.wrapping_add(1));
         let (lo, hi) = utilities::to8(x);
         z.memory().write(addr, lo);
         z.memory().write(addr.wrapping_add(1), hi);
@@ -211,6 +231,7 @@ impl Viewable<u8> for Address<u16> {
     where
         Z: Z80MemT + ?Sized,
     {
+        z.z80().set_memptr(self.0.wrapping_add(1));
         z.memory().read(self.0)
     }
 }
@@ -221,6 +242,7 @@ impl Changeable<u8> for Address<u16> {
     where
         Z: Z80MemT + ?Sized,
     {
+        z.z80().set_memptr(store_memptr(self.0, x));
         z.memory().write(self.0, x)
     }
 }
@@ -232,7 +254,8 @@ impl Viewable<u8> for Shift {
         Z: Z80MemT + ?Sized,
     {
         let addr = self.0.view(z).wrapping_add(self.1 as i16 as u16);
-        Address(addr).view(z)
+        z.z80().set_memptr(addr);
+        z.memory().read(addr)
     }
 }
 
@@ -243,7 +266,8 @@ impl Changeable<u8> for Shift {
         Z: Z80MemT + ?Sized,
     {
         let addr = self.0.view(z).wrapping_add(self.1 as i16 as u16);
-        Address(addr).change(z, x);
+        z.z80().set_memptr(addr);
+        z.memory().write(addr, x);
     }
 }
 
@@ -297,20 +321,53 @@ where
     }
 }
 
-impl<Z, T> Bit<u8, T> for Z
+fn bit_help<Z>(z: &mut Z, x: u8, y0: u8)
+where
+    Z: Z80Internal + ?Sized,
+{
+    let bitflag = 1 << x;
+    let y_contains = y0 & bitflag != 0;
+
+    z.set_flag_by(ZF | PF, !y_contains);
+    z.set_flag(HF);
+    z.clear_flag(NF);
+    z.set_flag_by(SF, x == 7 && y_contains);
+}
+
+impl<Z> Bit<u8, Reg8> for Z
 where
     Z: Z80MemT + ?Sized,
-    T: Viewable<u8>,
 {
-    fn bit(&mut self, x: u8, y: T) {
+    fn bit(&mut self, x: u8, y: Reg8) {
         let y0 = y.view(self);
-        let bitflag = 1 << x;
-        let y_contains = y0 & bitflag != 0;
+        bit_help(self.z80(), x, y0);
+        self.z80().set_xy(y0);
+    }
+}
 
-        self.z80().set_flag_by(ZF | PF, !y_contains);
-        self.z80().set_flag(HF);
-        self.z80().clear_flag(NF);
-        self.z80().set_flag_by(SF, x == 7 && y_contains);
+// testing a bit in memory, the undocumented flags come from MEMPTR
+
+impl<Z> Bit<u8, Address<Reg16>> for Z
+where
+    Z: Z80MemT + ?Sized,
+{
+    fn bit(&mut self, x: u8, y: Address<Reg16>) {
+        let y0 = y.view(self);
+        bit_help(self.z80(), x, y0);
+        let memptr = self.z80().memptr();
+        self.z80().set_xy((memptr >> 8) as u8);
+    }
+}
+
+impl<Z> Bit<u8, Shift> for Z
+where
+    Z: Z80MemT + ?Sized,
+{
+    fn bit(&mut self, x: u8, y: Shift) {
+        let y0 = y.view(self);
+        bit_help(self.z80(), x, y0);
+        let memptr = self.z80().memptr();
+        self.z80().set_xy((memptr >> 8) as u8);
     }
 }
 
@@ -319,13 +376,7 @@ where
     Z: Z80MemT + ?Sized,
 {
     fn call(&mut self, x: u16) {
-        let pch = PCH.view(self);
-        let pcl = PCL.view(self);
-        let sp = SP.view(self);
-        Address(sp.wrapping_sub(1)).change(self, pch);
-        Address(sp.wrapping_sub(2)).change(self, pcl);
-        SP.change(self, sp.wrapping_sub(2));
-        PC.change(self, x);
+        self.rst(x);
     }
 }
 
@@ -340,6 +391,7 @@ where
         } else {
             self.z80().inc_cycles(10);
         }
+        self.z80().set_memptr(y);
     }
 }
 
@@ -353,6 +405,8 @@ where
         let a = A.view(self);
         // cp is like a subtraction whose result we ignore
         sub_help(self.z80(), a, x0, 0);
+        // except the undocumented flags come from the operand
+        self.z80().set_xy(x0);
     }
 }
 
@@ -373,9 +427,8 @@ where
         self.cpd();
 
         if self.z80().reg16(BC) != 0 && !self.z80().is_set_flag(ZF) {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+            let pc = repeat_help(self.z80());
+            self.z80().set_memptr(pc.wrapping_add(1));
         } else {
             self.z80().inc_cycles(16);
         }
@@ -399,9 +452,8 @@ where
         self.cpi();
 
         if self.z80().reg16(BC) != 0 && !self.z80().is_set_flag(ZF) {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+            let pc = repeat_help(self.z80());
+            self.z80().set_memptr(pc.wrapping_add(1));
         } else {
             self.z80().inc_cycles(16);
         }
@@ -419,18 +471,18 @@ where
         x.change(self, result);
         self.z80().set_zero(result);
         self.z80().set_sign(result);
+        self.z80().set_xy(result);
         self.z80().set_flag_by(HF, x0 & 0xF == 0);
         self.z80().set_flag_by(PF, x0 == 0x80);
         self.z80().set_flag(NF);
     }
 }
 
-impl<Z, T> Ex<T, Reg16> for Z
+impl<Z> Ex<Reg16, Reg16> for Z
 where
     Z: Z80MemT + ?Sized,
-    T: Changeable<u16>,
 {
-    fn ex(&mut self, x: T, y: Reg16) {
+    fn ex(&mut self, x: Reg16, y: Reg16) {
         let val1 = x.view(self);
         let val2 = y.view(self);
         x.change(self, val2);
@@ -438,6 +490,19 @@ where
     }
 }
 
+impl<Z> Ex<Address<Reg16>, Reg16> for Z
+where
+    Z: Z80MemT + ?Sized,
+{
+    fn ex(&mut self, x: Address<Reg16>, y: Reg16) {
+        let val1 = x.view(self);
+        let val2 = y.view(self);
+        x.change(self, val2);
+        y.change(self, val1);
+        self.z80().set_memptr(val1);
+    }
+}
+
 impl<Z, T> Inc<T> for Z
 where
     Z: Z80MemT + ?Sized,
@@ -449,18 +514,28 @@ where
         x.change(self, result);
         self.z80().set_zero(result);
         self.z80().set_sign(result);
+        self.z80().set_xy(result);
         self.z80().set_flag_by(HF, x0 & 0xF == 0xF);
         self.z80().set_flag_by(PF, x0 == 0x7F);
         self.z80().clear_flag(NF);
     }
 }
 
-impl<Z, T> Jp<T> for Z
+impl<Z> Jp<u16> for Z
 where
     Z: Z80MemT + ?Sized,
-    T: Viewable<u16>,
 {
-    fn jp(&mut self, x: T) {
+    fn jp(&mut self, x: u16) {
+        self.z80().set_reg16(PC, x);
+        self.z80().set_memptr(x);
+    }
+}
+
+impl<Z> Jp<Reg16> for Z
+where
+    Z: Z80MemT + ?Sized,
+{
+    fn jp(&mut self, x: Reg16) {
         let addr = x.view(self);
         self.z80().set_reg16(PC, addr);
     }
@@ -507,9 +582,8 @@ where
         self.ldd();
 
         if self.z80().reg16(BC) != 0 {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+            let pc = repeat_help(self.z80());
+            self.z80().set_memptr(pc.wrapping_add(1));
         } else {
             self.z80().inc_cycles(16);
         }
@@ -533,9 +607,8 @@ where
         self.ldi();
 
         if self.z80().reg16(BC) != 0 {
-            let pc = self.z80().reg16(PC);
-            self.z80().set_reg16(PC, pc.wrapping_sub(2));
-            self.z80().inc_cycles(21);
+            let pc = repeat_help(self.z80());
+            self.z80().set_memptr(pc.wrapping_add(1));
         } else {
             self.z80().inc_cycles(16);
         }
@@ -559,8 +632,8 @@ where
 {
     fn pop(&mut self, x: Reg16) {
         let sp = SP.view(self);
-        let lo = Address(sp).view(self);
-        let hi = Address(sp.wrapping_add(1)).view(self);
+        let lo = self.memory().read(sp);
+        let hi = self.memory().read(sp.wrapping_add(1));
         x.change(self, utilities::to16(lo, hi));
         SP.change(self, sp.wrapping_add(2));
     }
@@ -573,8 +646,8 @@ where
     fn push(&mut self, x: Reg16) {
         let (lo, hi) = utilities::to8(x.view(self));
         let sp = SP.view(self);
-        Address(sp.wrapping_sub(1)).change(self, hi);
-        Address(sp.wrapping_sub(2)).change(self, lo);
+        self.memory().write(sp.wrapping_sub(1), hi);
+        self.memory().write(sp.wrapping_sub(2), lo);
         SP.change(self, sp.wrapping_sub(2));
     }
 }
@@ -610,11 +683,12 @@ where
 {
     fn ret(&mut self) {
         let sp = SP.view(self);
-        let n1 = Address(sp).view(self);
-        PCL.change(self, n1);
-        let n2 = Address(sp.wrapping_add(1)).view(self);
-        PCH.change(self, n2);
+        let n1 = self.memory().read(sp);
+        let n2 = self.memory().read(sp.wrapping_add(1));
+        let pc = utilities::to16(n1, n2);
+        PC.change(self, pc);
         SP.change(self, sp.wrapping_add(2));
+        self.z80().set_memptr(pc);
     }
 }
 
@@ -652,12 +726,7 @@ where
             self.z80().set_interrupt_status(InterruptStatus::Check);
         }
 
-        let sp = SP.view(self);
-        let pcl = Address(sp).view(self);
-        let pch = Address(sp.wrapping_add(1)).view(self);
-        PCL.change(self, pcl);
-        PCH.change(self, pch);
-        SP.change(self, sp.wrapping_add(2));
+        self.ret();
     }
 }
 
@@ -736,7 +805,10 @@ where
         self.z80().set_parity(a);
         self.z80().set_sign(a);
         self.z80().set_zero(a);
+        self.z80().set_xy(a);
         self.z80().clear_flag(HF | NF);
+        let hl = HL.view(self);
+        self.z80().set_memptr(hl.wrapping_add(1));
     }
 }
 
@@ -815,7 +887,10 @@ where
         self.z80().set_parity(a);
         self.z80().set_sign(a);
         self.z80().set_zero(a);
+        self.z80().set_xy(a);
         self.z80().clear_flag(HF | NF);
+        let hl = HL.view(self);
+        self.z80().set_memptr(hl.wrapping_add(1));
     }
 }
 
@@ -836,10 +911,11 @@ where
         let sp = SP.view(self);
         let pch = PCH.view(self);
         let pcl = PCL.view(self);
-        Address(sp.wrapping_sub(1)).change(self, pch);
-        Address(sp.wrapping_sub(2)).change(self, pcl);
+        self.memory().write(sp.wrapping_sub(1), pch);
+        self.memory().write(sp.wrapping_sub(2), pcl);
         SP.change(self, sp.wrapping_sub(2));
-        PC.change(self, x as u16);
+        PC.change(self, x);
+        self.z80().set_memptr(x);
     }
 }
 
@@ -875,7 +951,7 @@ where
     T: Changeable<u8>,
 {
     fn set_store(&mut self, x: u8, y: T, w: Reg8) {
-        self.set(x, w);
+        self.set(x, y);
 
         let y0 = y.view(self);
         w.change(self, y0);
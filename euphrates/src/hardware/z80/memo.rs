@@ -10,7 +10,17 @@ use self::Reg8::*;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Z80Memo {
-    Instruction { pc: u16, opcode: Opcode },
+    /// Sent just before each instruction is executed, but only to an inbox
+    /// that's `tracing`.
+    ///
+    /// `rom_index` is where the instruction is in ROM, if the memory says;
+    /// see `Memory16::rom_index`.
+    Instruction {
+        pc: u16,
+        opcode: Opcode,
+        registers: Z80Registers,
+        rom_index: Option<usize>,
+    },
 
     MaskableInterrupt { mode: u8, byte: u8 },
 
@@ -26,13 +36,64 @@ impl Display for Z80Memo {
                 "Maskable interrupt: mode {}, byte: {:0>2X}",
                 mode, byte
             )),
-            Instruction { pc, opcode } => {
+            Instruction { pc, opcode, .. } => {
                 f.pad(&format!("Instruction {:0>4X}: {: <11}", pc, opcode))
             }
         }
     }
 }
 
+/// A snapshot of everything in a Z80 a trace of its execution might show.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Z80Registers {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub af0: u16,
+    pub bc0: u16,
+    pub de0: u16,
+    pub hl0: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+    pub cycles: u64,
+}
+
+impl Z80Registers {
+    pub fn new<Z>(z80: &Z) -> Self
+    where
+        Z: Z80Internal + ?Sized,
+    {
+        Z80Registers {
+            af: z80.reg16(AF),
+            bc: z80.reg16(BC),
+            de: z80.reg16(DE),
+            hl: z80.reg16(HL),
+            af0: z80.reg16(AF0),
+            bc0: z80.reg16(BC0),
+            de0: z80.reg16(DE0),
+            hl0: z80.reg16(HL0),
+            ix: z80.reg16(IX),
+            iy: z80.reg16(IY),
+            sp: z80.reg16(SP),
+            pc: z80.reg16(PC),
+            i: z80.reg8(I),
+            r: z80.reg8(R),
+            iff1: z80.iff1(),
+            iff2: z80.iff2(),
+            im: z80.interrupt_mode() as u8,
+            cycles: z80.cycles(),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum Opcode {
     OneByte([u8; 1]),
@@ -189,9 +250,11 @@ impl FullMnemonic {
             OneParameter(Rst, U16(p)) => Some(p),
             // For the following instructions, e is added to the PC as it is
             // after the instruction is executed, so increase by 2
-            OneParameter(Jr, I8(e)) => Some(pc.wrapping_add(2 + e as i16 as u16)),
-            TwoParameters(Jr, _, I8(e)) => Some(pc.wrapping_add(2 + e as i16 as u16)),
-            OneParameter(Djnz, I8(e)) => Some(pc.wrapping_add(2 + e as i16 as u16)),
+            OneParameter(Jr, I8(e)) => Some(pc.wrapping_add(2).wrapping_add(e as i16 as u16)),
+            TwoParameters(Jr, _, I8(e)) => {
+                Some(pc.wrapping_add(2).wrapping_add(e as i16 as u16))
+            }
+            OneParameter(Djnz, I8(e)) => Some(pc.wrapping_add(2).wrapping_add(e as i16 as u16)),
             _ => None,
             // There are also instructions JP (HL), JP (IX), and JP (IY), but we
             // can't statically compute their targets
@@ -535,6 +598,35 @@ impl Opcode {
         }
     }
 
+    /// The opcode made of `bytes`, which must be 1 to 4 bytes long.
+    pub fn from_bytes(bytes: &[u8]) -> Opcode {
+        match bytes.len() {
+            1 => Opcode::OneByte([bytes[0]]),
+            2 => Opcode::TwoBytes([bytes[0], bytes[1]]),
+            3 => Opcode::ThreeBytes([bytes[0], bytes[1], bytes[2]]),
+            4 => Opcode::FourBytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            _ => panic!("opcode of {} bytes", bytes.len()),
+        }
+    }
+
+    /// The instruction at the beginning of `bytes`, if `bytes` is long enough
+    /// to hold all of it.
+    pub fn decode(bytes: &[u8]) -> Option<Opcode> {
+        (1..=4)
+            .take_while(|&len| len <= bytes.len())
+            .map(|len| Opcode::from_bytes(&bytes[..len]))
+            .find(|opcode| opcode.mnemonic().is_some())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match *self {
+            Opcode::OneByte(ref x) => x,
+            Opcode::TwoBytes(ref x) => x,
+            Opcode::ThreeBytes(ref x) => x,
+            Opcode::FourBytes(ref x) => x,
+        }
+    }
+
     pub fn from_payload(payload: [u8; 8]) -> Opcode {
         let bytes = payload[2];
         match bytes {
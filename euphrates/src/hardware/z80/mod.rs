@@ -24,9 +24,14 @@ mod instruction_list;
 mod internal;
 mod interrupt;
 mod irq;
+#[macro_use]
 mod memo;
+mod assembler;
+mod decoded;
 mod run;
 
+pub use self::assembler::*;
+pub use self::decoded::*;
 pub use self::internal::*;
 pub use self::interrupt::*;
 pub use self::irq::*;
@@ -253,7 +258,7 @@ impl fmt::Display for Address<Reg16> {
 
 impl fmt::Display for Address<u16> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        let s = format!("({:0<4X})", self.0);
+        let s = format!("({:0>4X})", self.0);
         f.pad(&s)
     }
 }
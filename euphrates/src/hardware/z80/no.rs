@@ -23,6 +23,22 @@ impl<Z: ?Sized> Z80NoImpler<Z> {
     }
 }
 
+/// The undocumented flags `XF` and `YF` as set by `scf` and `ccf`, which
+/// depend on the variant.
+#[inline]
+fn scf_ccf_xy<Z>(z: &Z) -> u8
+where
+    Z: Z80Internal + ?Sized,
+{
+    let a = z.reg8(A);
+    let xy = if z.variant().scf_ccf_use_q() {
+        (z.q() ^ z.reg8(F)) | a
+    } else {
+        a
+    };
+    xy & (XF | YF)
+}
+
 impl<Z> Adc16<Reg16, Reg16> for Z80NoImpler<Z>
 where
     Z: Z80Internal + ?Sized,
@@ -33,6 +49,7 @@ where
         let cf = if self.z80().is_set_flag(CF) { 1u8 } else { 0u8 };
         let result = adc16_help(self.z80(), x0, y0, cf as u16);
         self.z80().set_reg16(x, result);
+        self.z80().set_memptr(x0.wrapping_add(1));
     }
 }
 
@@ -45,6 +62,7 @@ where
         let y0 = self.z80().reg16(y);
         let result = add16_help(self.z80(), x0, y0, 0);
         self.z80().set_reg16(x, result);
+        self.z80().set_memptr(x0.wrapping_add(1));
     }
 }
 
@@ -53,10 +71,12 @@ where
     Z: Z80Internal + ?Sized,
 {
     fn ccf(&mut self) {
+        let xy = scf_ccf_xy(self.z80());
         let cf = self.z80().is_set_flag(CF);
         self.z80().set_flag_by(HF, cf);
         self.z80().set_flag_by(CF, !cf);
-        self.z80().clear_flag(NF);
+        self.z80().clear_flag(NF | XF | YF);
+        self.z80().set_flag(xy);
     }
 }
 
@@ -68,6 +88,7 @@ where
         let a = self.z80().reg8(A);
         self.z80().set_reg8(A, !a);
         self.z80().set_flag(HF | NF);
+        self.z80().set_xy(!a);
     }
 }
 
@@ -112,6 +133,7 @@ where
         self.z80().set_parity(new_a);
         self.z80().set_zero(new_a);
         self.z80().set_sign(new_a);
+        self.z80().set_xy(new_a);
         self.z80().set_flag_by(CF, new_cf != 0);
         self.z80().set_flag_by(HF, new_hf != 0);
     }
@@ -223,6 +245,7 @@ where
         if cc.check(flags) {
             self.z80().set_reg16(PC, nn);
         }
+        self.z80().set_memptr(nn);
     }
 }
 
@@ -232,7 +255,9 @@ where
 {
     fn jr(&mut self, e: i8) {
         let pc = self.z80().reg16(PC);
-        self.z80().set_reg16(PC, pc.wrapping_add(e as i16 as u16));
+        let new_pc = pc.wrapping_add(e as i16 as u16);
+        self.z80().set_reg16(PC, new_pc);
+        self.z80().set_memptr(new_pc);
     }
 }
 
@@ -261,8 +286,10 @@ where
         let iff2 = self.z80().iff2();
         self.z80().set_sign(y0);
         self.z80().set_zero(y0);
+        self.z80().set_xy(y0);
         self.z80().clear_flag(NF | HF);
         self.z80().set_flag_by(PF, iff2);
+        self.z80().set_ld_a_ir(true);
     }
 }
 
@@ -296,6 +323,7 @@ where
         let cf = if self.z80().is_set_flag(CF) { 1u8 } else { 0u8 };
         let result = adc16_help(self.z80(), x0, !y0, (1 ^ cf) as u16);
         self.z80().set_reg16(x, result);
+        self.z80().set_memptr(x0.wrapping_add(1));
         let cf = self.z80().is_set_flag(CF);
         let hf = self.z80().is_set_flag(HF);
         self.z80().set_flag_by(CF, !cf);
@@ -309,8 +337,9 @@ where
     Z: Z80Internal + ?Sized,
 {
     fn scf(&mut self) {
-        self.z80().clear_flag(HF | NF);
-        self.z80().set_flag(CF);
+        let xy = scf_ccf_xy(self.z80());
+        self.z80().clear_flag(HF | NF | XF | YF);
+        self.z80().set_flag(CF | xy);
     }
 }
 
@@ -84,6 +84,200 @@ where
     }
 }
 
+impl<'a, Z, M, Irq, I, Inb> Z80RunImpler<'a, Z, M, Irq, I, Inb>
+where
+    Z: 'a + ?Sized + Z80Internal,
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    I: 'a + ?Sized + Io16,
+    Inb: 'a + ?Sized + Inbox<Memo = Z80Memo>,
+{
+    /// Do one of the things `run` does in its loop: execute an instruction or
+    /// a prefix byte, check for interrupts, or sit halted until
+    /// `target_cycles`.
+    ///
+    /// Unlike `run`, this doesn't look at the inbox, and it doesn't first
+    /// change an `InterruptStatus` of `NoCheck` to `Check`. It's for other
+    /// implementations of `Z80Run` that only sometimes want the interpreter.
+    #[inline]
+    pub fn step(&mut self, target_cycles: u64) {
+        step(self, target_cycles)
+    }
+
+    /// Will the next `step` begin an instruction, rather than check for
+    /// interrupts, sit halted, or continue an instruction after a prefix?
+    #[inline]
+    pub fn at_instruction(&self) -> bool {
+        use self::InterruptStatus::*;
+        match (self.z80.prefix(), self.z80.interrupt_status()) {
+            (Prefix::NoPrefix, Check) => false,
+            (Prefix::NoPrefix, Ei(ei_cycles)) => self.z80.cycles() <= ei_cycles,
+            (Prefix::NoPrefix, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Execute the next instruction, prefixes and all, as repeated calls to
+    /// `step` would, and return it decoded.
+    ///
+    /// Like `step`, this only begins a step before `target_cycles`, so it may
+    /// stop after a prefix; then it returns `None`. It also returns `None` for
+    /// an instruction with so many prefixes that it's longer than 4 bytes.
+    ///
+    /// Should only be called when `at_instruction` is true.
+    pub fn step_decoding(&mut self, target_cycles: u64) -> Option<DecodedInstruction> {
+        let start_cycles = self.z80.cycles();
+        let mut recording = Recording {
+            run: self,
+            bytes: [0; 4],
+            len: 0,
+            cycles: 0,
+        };
+        let mut refreshes = 0;
+        let mut last_step;
+        loop {
+            last_step = recording.run.z80.cycles() - start_cycles;
+            match recording.run.z80.prefix() {
+                Prefix::DdCb | Prefix::FdCb => {}
+                _ => refreshes += 1,
+            }
+            recording.cycles = 0;
+            execute(&mut recording);
+            match recording.run.z80.prefix() {
+                Prefix::NoPrefix | Prefix::Halt => break,
+                _ => if recording.run.z80.cycles() >= target_cycles {
+                    return None;
+                },
+            }
+        }
+        if recording.len > 4 {
+            return None;
+        }
+        DecodedInstruction::new(
+            &recording.bytes[..recording.len],
+            refreshes,
+            last_step as u8,
+            recording.cycles as u8,
+        )
+    }
+}
+
+/// A `Z80Emulator` with a `Z80Internal` we can get at.
+trait HasZ80: instruction::Z80Emulator {
+    type Z: Z80Internal + ?Sized;
+
+    fn z80(&mut self) -> &mut Self::Z;
+}
+
+impl<'a, Z, M, Irq, I, Inb> HasZ80 for Z80RunImpler<'a, Z, M, Irq, I, Inb>
+where
+    Z: 'a + ?Sized + Z80Internal,
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    I: 'a + ?Sized + Io16,
+    Inb: 'a + ?Sized + Inbox<Memo = Z80Memo>,
+{
+    type Z = Z;
+
+    #[inline]
+    fn z80(&mut self) -> &mut Z {
+        self.z80
+    }
+}
+
+/// Wraps a `Z80RunImpler`, recording the bytes it reads at PC and the cycles
+/// the instruction tables count.
+struct Recording<
+    'b,
+    'a: 'b,
+    Z: 'a + ?Sized,
+    M: 'a + ?Sized,
+    Irq: 'a + ?Sized,
+    I: 'a + ?Sized,
+    Inb: 'a + ?Sized,
+> {
+    run: &'b mut Z80RunImpler<'a, Z, M, Irq, I, Inb>,
+    bytes: [u8; 4],
+    len: usize,
+    cycles: u64,
+}
+
+impl<'b, 'a, Z, M, Irq, I, Inb> instruction::Z80Emulator for Recording<'b, 'a, Z, M, Irq, I, Inb>
+where
+    Z: 'a + ?Sized + Z80Internal,
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    I: 'a + ?Sized + Io16,
+    Inb: 'a + ?Sized + Inbox<Memo = Z80Memo>,
+{
+    type No = Z80NoImpler<Z>;
+
+    type Mem = Z80MemImpler<Z, M>;
+
+    type Io = Z80IoImpler<Z, M, I>;
+
+    #[inline]
+    fn no<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::No),
+    {
+        self.run.no(f)
+    }
+
+    #[inline]
+    fn mem<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::Mem),
+    {
+        self.run.mem(f)
+    }
+
+    #[inline]
+    fn io<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self::Io),
+    {
+        self.run.io(f)
+    }
+
+    #[inline]
+    fn read_pc(&mut self) -> u8 {
+        let x = self.run.read_pc();
+        if self.len < 4 {
+            self.bytes[self.len] = x;
+        }
+        x
+    }
+
+    #[inline]
+    fn inc_pc(&mut self) {
+        self.len += 1;
+        self.run.inc_pc();
+    }
+
+    #[inline]
+    fn inc_cycles(&mut self, c: u64) {
+        self.cycles += c;
+        self.run.inc_cycles(c);
+    }
+}
+
+impl<'b, 'a, Z, M, Irq, I, Inb> HasZ80 for Recording<'b, 'a, Z, M, Irq, I, Inb>
+where
+    Z: 'a + ?Sized + Z80Internal,
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    I: 'a + ?Sized + Io16,
+    Inb: 'a + ?Sized + Inbox<Memo = Z80Memo>,
+{
+    type Z = Z;
+
+    #[inline]
+    fn z80(&mut self) -> &mut Z {
+        self.run.z80
+    }
+}
+
 macro_rules! interrupt {
     ($x:expr; $var:ident; $($rest:tt)*) => {{
         let mut $var = Z80InterruptImpler {
@@ -91,6 +285,7 @@ macro_rules! interrupt {
             memory: $x.memory,
             irq: $x.irq,
             inbox: $x.inbox,
+            io: $x.io,
         };
         {
             $($rest)*
@@ -108,9 +303,6 @@ fn run<'a, Z: 'a, M: 'a, Irq: 'a, I: 'a, Inb: 'a>(
     I: Io16 + ?Sized,
     Inb: Inbox<Memo = Z80Memo> + ?Sized,
 {
-    use self::InterruptStatus::*;
-    use self::Prefix::*;
-
     if z.z80.interrupt_status() == InterruptStatus::NoCheck {
         z.z80.set_interrupt_status(InterruptStatus::Check);
     }
@@ -119,52 +311,128 @@ fn run<'a, Z: 'a, M: 'a, Irq: 'a, I: 'a, Inb: 'a>(
         if z.inbox.holding() {
             return;
         }
-        let prefix = z.z80.prefix();
-        let interrupt_status = z.z80.interrupt_status();
-        let z80_cycles = z.z80.cycles();
-        match (prefix, interrupt_status) {
-            (Halt, NoCheck) => {
-                use std::cmp::max;
-                let current_cycles = z.z80.cycles();
-                z.z80.set_cycles(max(current_cycles, cycles));
-            }
-            (Halt, _) => {
-                interrupt!{z; i; i.check_interrupts()};
-            }
-            (NoPrefix, Check) => {
-                interrupt!{z; i; i.check_interrupts()};
-            }
-            (NoPrefix, Ei(ei_cycles)) if z80_cycles > ei_cycles => {
-                interrupt!{z; i; i.check_interrupts()};
-            }
-            (NoPrefix, _) => instruction::noprefix(z),
-            (Cb, _) => {
-                z.z80.set_prefix(NoPrefix);
-                instruction::cb(z);
-            }
-            (Ed, _) => {
-                z.z80.set_prefix(NoPrefix);
-                instruction::ed(z);
-            }
-            (Dd, _) => {
-                z.z80.inc_r(1);
-                z.z80.set_prefix(NoPrefix);
-                instruction::dd(z);
-            }
-            (Fd, _) => {
-                z.z80.inc_r(1);
-                z.z80.set_prefix(NoPrefix);
-                instruction::fd(z);
-            }
-            (DdCb, _) => {
-                z.z80.set_prefix(NoPrefix);
-                instruction::ddcb(z);
-            }
-            (FdCb, _) => {
-                z.z80.set_prefix(NoPrefix);
-                instruction::fdcb(z);
+        step(z, cycles);
+    }
+}
+
+fn step<'a, Z: 'a, M: 'a, Irq: 'a, I: 'a, Inb: 'a>(
+    z: &mut Z80RunImpler<'a, Z, M, Irq, I, Inb>,
+    cycles: u64,
+) where
+    Z: Z80Internal + ?Sized,
+    M: Memory16 + ?Sized,
+    Irq: Z80Irq + ?Sized,
+    I: Io16 + ?Sized,
+    Inb: Inbox<Memo = Z80Memo> + ?Sized,
+{
+    use self::InterruptStatus::*;
+    use self::Prefix::*;
+
+    let prefix = z.z80.prefix();
+    let interrupt_status = z.z80.interrupt_status();
+    let z80_cycles = z.z80.cycles();
+    match (prefix, interrupt_status) {
+        (Halt, NoCheck) => {
+            // halted, we execute `nop`s, each taking 4 cycles and refreshing
+            // once
+            z.z80.begin_instruction();
+            let current_cycles = z.z80.cycles();
+            if current_cycles < cycles {
+                let nops = (cycles - current_cycles).div_ceil(4);
+                z.z80.set_cycles(current_cycles + 4 * nops);
+                z.z80.inc_r(nops as u8);
             }
         }
+        (Halt, _) => {
+            interrupt!{z; i; i.check_interrupts()};
+        }
+        (NoPrefix, Check) => {
+            interrupt!{z; i; i.check_interrupts()};
+        }
+        (NoPrefix, Ei(ei_cycles)) if z80_cycles > ei_cycles => {
+            interrupt!{z; i; i.check_interrupts()};
+        }
+        (NoPrefix, _) if z.inbox.tracing() => {
+            send_instruction(z);
+            execute(z);
+        }
+        _ => execute(z),
+    }
+}
+
+/// Send a `Z80Memo::Instruction` for the instruction about to be executed.
+fn send_instruction<'a, Z: 'a, M: 'a, Irq: 'a, I: 'a, Inb: 'a>(
+    z: &mut Z80RunImpler<'a, Z, M, Irq, I, Inb>,
+) where
+    Z: Z80Internal + ?Sized,
+    M: Memory16 + ?Sized,
+    Irq: Z80Irq + ?Sized,
+    I: Io16 + ?Sized,
+    Inb: Inbox<Memo = Z80Memo> + ?Sized,
+{
+    let pc = z.z80.reg16(PC);
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = z.memory.read(pc.wrapping_add(i as u16));
+    }
+    // if it doesn't decode, at least show its first byte
+    let opcode = Opcode::decode(&bytes).unwrap_or(Opcode::OneByte([bytes[0]]));
+    let registers = Z80Registers::new(z.z80);
+    let rom_index = z.memory.rom_index(pc);
+    z.inbox.receive(Z80Memo::Instruction {
+        pc,
+        opcode,
+        registers,
+        rom_index,
+    });
+}
+
+/// Execute an instruction, or the rest of one after its latest prefix.
+///
+/// R is incremented once for each opcode fetch: for the first byte, and for
+/// the byte after each CB, ED, DD, or FD prefix, but not for the opcode of a
+/// DDCB or FDCB instruction, which is read like an operand.
+fn execute<E>(z: &mut E)
+where
+    E: HasZ80,
+{
+    use self::Prefix::*;
+
+    match z.z80().prefix() {
+        NoPrefix => {
+            z.z80().begin_instruction();
+            z.z80().inc_r(1);
+            instruction::noprefix(z);
+        }
+        Cb => {
+            z.z80().inc_r(1);
+            z.z80().set_prefix(NoPrefix);
+            instruction::cb(z);
+        }
+        Ed => {
+            z.z80().inc_r(1);
+            z.z80().set_prefix(NoPrefix);
+            instruction::ed(z);
+        }
+        Dd => {
+            z.z80().inc_r(1);
+            z.z80().set_prefix(NoPrefix);
+            instruction::dd(z);
+        }
+        Fd => {
+            z.z80().inc_r(1);
+            z.z80().set_prefix(NoPrefix);
+            instruction::fd(z);
+        }
+        DdCb => {
+            z.z80().set_prefix(NoPrefix);
+            instruction::ddcb(z);
+        }
+        FdCb => {
+            z.z80().set_prefix(NoPrefix);
+            instruction::fdcb(z);
+        }
+        Halt => unreachable!(),
     }
 }
 
@@ -11,6 +11,59 @@ pub struct SimpleColor {
     pub blue: u8,
 }
 
+/// Which layer of the display a pixel came from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Layer {
+    /// The display was turned off.
+    Blank = 0,
+
+    /// Nothing was drawn here, or a blanked column, so the backdrop color shows.
+    Backdrop = 1,
+
+    /// A background tile.
+    Background = 2,
+
+    /// A background tile drawn in front of sprites.
+    PriorityBackground = 3,
+
+    Sprite = 4,
+
+    /// In a TMS9918 mode, a background pixel of color 0, which is transparent.
+    /// It's painted black, not in the backdrop color.
+    Transparent = 5,
+}
+
+/// A pixel described by its palette index and layer rather than its color.
+///
+/// Packed into one byte: the palette index is in the low 5 bits and the
+/// `Layer` in the high 3 bits.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct IndexedPixel(pub u8);
+
+impl IndexedPixel {
+    #[inline]
+    pub fn new(index: u8, layer: Layer) -> Self {
+        IndexedPixel((index & 0x1F) | (layer as u8) << 5)
+    }
+
+    #[inline]
+    pub fn index(self) -> u8 {
+        self.0 & 0x1F
+    }
+
+    #[inline]
+    pub fn layer(self) -> Layer {
+        match self.0 >> 5 {
+            1 => Layer::Backdrop,
+            2 => Layer::Background,
+            3 => Layer::PriorityBackground,
+            4 => Layer::Sprite,
+            5 => Layer::Transparent,
+            _ => Layer::Blank,
+        }
+    }
+}
+
 pub trait SimpleGraphics {
     fn set_resolution(&mut self, width: u32, height: u32) -> Result<()>;
 
@@ -26,6 +79,13 @@ pub trait SimpleGraphics {
     /// will be returned in the next call to `render`.
     fn get(&self, x: u32, y: u32) -> SimpleColor;
 
+    /// Record where the pixel most recently `paint`ed at x and y came from.
+    ///
+    /// Implementations that only care about colors can ignore this; the
+    /// default implementation does nothing.
+    #[inline]
+    fn paint_indexed(&mut self, _x: u32, _y: u32, _pixel: IndexedPixel) {}
+
     /// Display the pixels that have been `paint`ed. Any pixel position that has
     /// not been `paint`ed since the last call to `render` may show arbitrary
     /// results.
@@ -68,6 +128,257 @@ impl SimpleGraphics for FakeGraphics {
     }
 }
 
+/// How pixels are laid out in a `FrameBuffer`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum PixelFormat {
+    /// Three bytes per pixel: red, green, blue.
+    Rgb24,
+
+    /// Four bytes per pixel: red, green, blue, and alpha (always 0xFF).
+    Rgba,
+}
+
+impl PixelFormat {
+    #[inline]
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba => 4,
+        }
+    }
+}
+
+impl Default for PixelFormat {
+    #[inline]
+    fn default() -> Self {
+        PixelFormat::Rgb24
+    }
+}
+
+/// A borrowed view of a completed frame.
+///
+/// Pixels are stored in row major order, with no padding between rows.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FrameView<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub data: &'a [u8],
+}
+
+impl<'a> FrameView<'a> {
+    /// Panics if x and y are outside the bounds of the frame.
+    pub fn get(&self, x: u32, y: u32) -> SimpleColor {
+        if x >= self.width || y >= self.height {
+            panic!(
+                "Point ({}, {}) out of bounds for frame size ({}, {})",
+                x, y, self.width, self.height
+            );
+        }
+        let idx = self.format.bytes_per_pixel() * (y as usize * self.width as usize + x as usize);
+        SimpleColor {
+            red: self.data[idx],
+            green: self.data[idx + 1],
+            blue: self.data[idx + 2],
+        }
+    }
+}
+
+/// A borrowed view of a completed frame of `IndexedPixel`s, one byte each.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct IndexedFrameView<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> IndexedFrameView<'a> {
+    /// Panics if x and y are outside the bounds of the frame.
+    pub fn get(&self, x: u32, y: u32) -> IndexedPixel {
+        if x >= self.width || y >= self.height {
+            panic!(
+                "Point ({}, {}) out of bounds for frame size ({}, {})",
+                x, y, self.width, self.height
+            );
+        }
+        IndexedPixel(self.data[y as usize * self.width as usize + x as usize])
+    }
+}
+
+/// Graphics which may hold on to the last frame they rendered.
+pub trait HasLastFrame {
+    /// The last frame completed by a call to `render`, if this implementation
+    /// keeps it.
+    #[inline]
+    fn last_frame(&self) -> Option<FrameView<'_>> {
+        None
+    }
+
+    /// The palette indices and layers of the last frame completed by a call to
+    /// `render`, if this implementation keeps them.
+    #[inline]
+    fn last_indexed_frame(&self) -> Option<IndexedFrameView<'_>> {
+        None
+    }
+}
+
+impl HasLastFrame for FakeGraphics {}
+
+/// `SimpleGraphics` that renders into memory rather than onto a screen.
+///
+/// Pixels are `paint`ed into a working buffer; `render` copies that buffer
+/// into the completed frame available through `last_frame`. If requested with
+/// `set_record_indices`, the same is done for `paint_indexed`, and the result
+/// is available through `last_indexed_frame`.
+#[derive(Clone, Default, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct FrameBuffer {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    frame_width: u32,
+    frame_height: u32,
+    frame: Vec<u8>,
+    record_indices: bool,
+    indices: Vec<u8>,
+    indexed_frame: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(format: PixelFormat) -> Self {
+        FrameBuffer {
+            format,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    #[inline]
+    pub fn record_indices(&self) -> bool {
+        self.record_indices
+    }
+
+    /// Should `IndexedPixel`s be kept in addition to colors?
+    pub fn set_record_indices(&mut self, record: bool) {
+        self.record_indices = record;
+        if record {
+            self.indices.resize(self.width as usize * self.height as usize, 0);
+        } else {
+            self.indices = Vec::new();
+            self.indexed_frame = Vec::new();
+        }
+    }
+}
+
+impl SimpleGraphics for FrameBuffer {
+    fn set_resolution(&mut self, width: u32, height: u32) -> Result<()> {
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            let len = width as usize * height as usize * self.format.bytes_per_pixel();
+            self.pixels.clear();
+            self.pixels.resize(len, 0);
+            if self.record_indices {
+                self.indices.clear();
+                self.indices.resize(width as usize * height as usize, 0);
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    #[inline]
+    fn paint(&mut self, x: u32, y: u32, color: SimpleColor) {
+        if x >= self.width || y >= self.height {
+            panic!(
+                "Point ({}, {}) out of bounds for frame size ({}, {})",
+                x, y, self.width, self.height
+            );
+        }
+        let bpp = self.format.bytes_per_pixel();
+        let idx = bpp * (y as usize * self.width as usize + x as usize);
+        self.pixels[idx] = color.red;
+        self.pixels[idx + 1] = color.green;
+        self.pixels[idx + 2] = color.blue;
+        if bpp == 4 {
+            self.pixels[idx + 3] = 0xFF;
+        }
+    }
+
+    #[inline]
+    fn paint_indexed(&mut self, x: u32, y: u32, pixel: IndexedPixel) {
+        debug_assert!(
+            x < self.width && y < self.height,
+            "Point ({}, {}) out of bounds for frame size ({}, {})",
+            x,
+            y,
+            self.width,
+            self.height
+        );
+        if self.record_indices && x < self.width {
+            let idx = y as usize * self.width as usize + x as usize;
+            if let Some(index) = self.indices.get_mut(idx) {
+                *index = pixel.0;
+            }
+        }
+    }
+
+    #[inline]
+    fn get(&self, x: u32, y: u32) -> SimpleColor {
+        FrameView {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            data: &self.pixels,
+        }.get(x, y)
+    }
+
+    fn render(&mut self) -> Result<()> {
+        self.frame_width = self.width;
+        self.frame_height = self.height;
+        self.frame.clone_from(&self.pixels);
+        if self.record_indices {
+            self.indexed_frame.clone_from(&self.indices);
+        }
+        Ok(())
+    }
+}
+
+impl HasLastFrame for FrameBuffer {
+    #[inline]
+    fn last_frame(&self) -> Option<FrameView<'_>> {
+        if self.frame.is_empty() {
+            return None;
+        }
+        Some(FrameView {
+            width: self.frame_width,
+            height: self.frame_height,
+            format: self.format,
+            data: &self.frame,
+        })
+    }
+
+    #[inline]
+    fn last_indexed_frame(&self) -> Option<IndexedFrameView<'_>> {
+        if self.indexed_frame.is_empty() {
+            return None;
+        }
+        Some(IndexedFrameView {
+            width: self.frame_width,
+            height: self.frame_height,
+            data: &self.indexed_frame,
+        })
+    }
+}
+
 pub trait SimpleAudio {
     fn configure(&mut self, frequency: u32, buffer_size: u16) -> Result<()>;
 
@@ -26,6 +26,16 @@ pub trait Inbox {
     fn holding(&self) -> bool {
         false
     }
+
+    /// Does this inbox want memos that are costly to send, like one for every
+    /// instruction executed?
+    ///
+    /// Devices only send those memos when this is true, and an alternative to
+    /// the Z80 interpreter may run the interpreter instead so they're sent.
+    #[inline(always)]
+    fn tracing(&self) -> bool {
+        false
+    }
 }
 
 /// An Inbox that throws away its memos.
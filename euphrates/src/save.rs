@@ -1,6 +1,7 @@
 /// Functions for saving and loading
 
-const MAX_BYTES: u64 = 0x10000000; // 256 MiB
+// The most bytes that will be serialized or deserialized.
+pub const MAX_BYTES: u64 = 0x10000000; // 256 MiB
 
 use std;
 use std::fs::File;
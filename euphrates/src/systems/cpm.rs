@@ -0,0 +1,956 @@
+//! A minimal CP/M 2.2 machine, enough to run .COM programs like the ZEXDOC and
+//! ZEXALL instruction exercisers.
+//!
+//! There is no BDOS or BIOS code in memory. Instead `CpmMachine` traps calls to
+//! their entry points and carries them out on the host: the console is a pair
+//! of streams, and drive A is a host directory. Only drive A and user 0 exist.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use hardware::io16::Io16;
+use hardware::z80::*;
+use memo::NothingInbox;
+
+/// Where programs are loaded, and where they begin executing.
+pub const TPA: u16 = 0x0100;
+
+/// Where address 0x0005 jumps to. This is also the top of the memory available
+/// to programs, which many read from address 0x0006.
+pub const BDOS: u16 = 0xFE00;
+
+/// The BIOS jump table.
+pub const BIOS: u16 = 0xFF00;
+
+/// How many entries the BIOS jump table has.
+const BIOS_ENTRIES: u16 = 17;
+
+const DEFAULT_DMA: u16 = 0x0080;
+
+const FCB1: u16 = 0x005C;
+
+const FCB2: u16 = 0x006C;
+
+/// File offsets in an FCB.
+const EX: u16 = 12;
+const S2: u16 = 14;
+const RC: u16 = 15;
+const CR: u16 = 32;
+const R0: u16 = 33;
+
+const RECORD_SIZE: usize = 128;
+
+/// The character that pads out the last record of a text file.
+const EOF: u8 = 0x1A;
+
+// This superfluous module with the `allow` attribute is necessary until the
+// `fail` crate begins using `dyn trait` syntax
+#[allow(bare_trait_objects)]
+mod cpm_error {
+    use super::*;
+
+    #[derive(Debug, Fail)]
+    pub enum CpmError {
+        #[fail(display = "IO Error {}", _0)]
+        IoError(#[cause] io::Error),
+        #[fail(display = "Program of {} bytes doesn't fit in the TPA", _0)]
+        ProgramTooLarge(usize),
+        #[fail(display = "Halted at {:04X}, with no interrupts to resume", _0)]
+        Halted(u16),
+    }
+}
+
+pub use self::cpm_error::CpmError;
+
+impl From<io::Error> for CpmError {
+    fn from(x: io::Error) -> Self {
+        CpmError::IoError(x)
+    }
+}
+
+/// No devices on the IO bus.
+struct NoIo;
+
+impl Io16 for NoIo {
+    #[inline]
+    fn input(&mut self, _address: u16) -> u8 {
+        0xFF
+    }
+
+    #[inline]
+    fn output(&mut self, _address: u16, _value: u8) {}
+
+    #[inline]
+    fn peek(&mut self, _address: u16) -> Option<u8> {
+        Some(0xFF)
+    }
+}
+
+/// Nothing ever interrupts.
+struct NoIrq;
+
+impl Z80Irq for NoIrq {
+    #[inline]
+    fn requesting_mi(&mut self) -> Option<u8> {
+        None
+    }
+
+    #[inline]
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn take_nmi(&mut self) {}
+}
+
+/// A Z80 with 64 KiB of RAM, running CP/M programs.
+///
+/// Console input comes from `input` and console output goes to `output`.
+pub struct CpmMachine<R, W> {
+    pub z80: Z80State,
+    pub memory: Box<[u8; 0x10000]>,
+    directory: PathBuf,
+    input: R,
+    output: W,
+    dma: u16,
+
+    /// Names of files matched by "search for first" that "search for next"
+    /// hasn't returned yet.
+    search: Vec<String>,
+}
+
+impl<R, W> CpmMachine<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// A machine whose drive A is `directory`, with no program loaded.
+    pub fn new<P: AsRef<Path>>(directory: P, input: R, output: W) -> Self {
+        CpmMachine {
+            z80: Default::default(),
+            memory: Box::new([0u8; 0x10000]),
+            directory: directory.as_ref().to_owned(),
+            input,
+            output,
+            dma: DEFAULT_DMA,
+            search: Vec::new(),
+        }
+    }
+
+    /// Reset the machine and load a .COM file, as the CCP would for the
+    /// command line `PROGRAM args`.
+    ///
+    /// The first two arguments are also parsed into the FCBs at 0x005C and
+    /// 0x006C.
+    pub fn load(&mut self, program: &[u8], args: &[&str]) -> Result<(), CpmError> {
+        if program.len() > (BDOS - TPA) as usize - 2 {
+            return Err(CpmError::ProgramTooLarge(program.len()));
+        }
+
+        self.z80 = Default::default();
+        *self.memory = [0u8; 0x10000];
+        self.dma = DEFAULT_DMA;
+        self.search.clear();
+
+        let tpa = TPA as usize;
+        self.memory[tpa..tpa + program.len()].copy_from_slice(program);
+
+        // warm boot, IOBYTE, current drive, and the jump to the BDOS
+        self.write(0x0000, &[0xC3, 0x03, (BIOS >> 8) as u8, 0, 0]);
+        self.write(0x0005, &[0xC3, BDOS as u8, (BDOS >> 8) as u8]);
+
+        // each trap returns to the caller after it's carried out
+        self.memory[BDOS as usize] = 0xC9;
+        for i in 0..BIOS_ENTRIES {
+            self.memory[(BIOS + 3 * i) as usize] = 0xC9;
+        }
+
+        let args: Vec<String> = args.iter().map(|s| s.to_uppercase()).collect();
+        for &(fcb, i) in [(FCB1, 0), (FCB2, 1)].iter() {
+            let arg = args.get(i).map(|s| s.as_str()).unwrap_or("");
+            self.set_fcb_name(fcb, arg);
+        }
+        let mut tail = String::new();
+        for arg in args.iter() {
+            tail.push(' ');
+            tail.push_str(arg);
+        }
+        let tail = &tail.as_bytes()[..tail.len().min(RECORD_SIZE - 1)];
+        self.memory[DEFAULT_DMA as usize] = tail.len() as u8;
+        self.write(DEFAULT_DMA + 1, tail);
+
+        // a program may end by returning to the CCP, which would warm boot
+        self.z80.set_reg16(Reg16::SP, BDOS - 2);
+        self.z80.set_reg16(Reg16::PC, TPA);
+        Ok(())
+    }
+
+    /// Run the loaded program until it exits.
+    ///
+    /// A program exits by calling BDOS function 0, by jumping to address 0,
+    /// or by returning from its entry point.
+    pub fn run(&mut self) -> Result<(), CpmError> {
+        let result = self.run_help();
+        self.output.flush()?;
+        result
+    }
+
+    fn run_help(&mut self) -> Result<(), CpmError> {
+        loop {
+            let pc = self.z80.reg16(Reg16::PC);
+            let entry = pc.wrapping_sub(BIOS) / 3;
+            let running = match self.z80.prefix() {
+                Prefix::NoPrefix if pc == BDOS => self.bdos()?,
+                Prefix::NoPrefix if entry < BIOS_ENTRIES && BIOS + 3 * entry == pc => {
+                    self.bios(entry)?
+                }
+                Prefix::Halt => return Err(CpmError::Halted(pc)),
+                _ => true,
+            };
+            if !running {
+                return Ok(());
+            }
+            self.step();
+        }
+    }
+
+    /// Execute one instruction.
+    fn step(&mut self) {
+        let cycles = self.z80.cycles();
+        Z80RunImpler {
+            z80: &mut self.z80,
+            memory: &mut *self.memory,
+            io: &mut NoIo,
+            irq: &mut NoIrq,
+            inbox: &mut NothingInbox::<Z80Memo>::default(),
+        }.run(cycles + 1);
+    }
+
+    fn write(&mut self, address: u16, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.memory[address.wrapping_add(i as u16) as usize] = b;
+        }
+    }
+
+    #[inline]
+    fn byte(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    #[inline]
+    fn set_byte(&mut self, address: u16, x: u8) {
+        self.memory[address as usize] = x;
+    }
+
+    /// Return a byte from a BDOS or BIOS call, in A and L.
+    fn return8(&mut self, x: u8) {
+        self.return16(x as u16);
+    }
+
+    /// Return a word from a BDOS or BIOS call, in HL and also in B and A.
+    fn return16(&mut self, x: u16) {
+        self.z80.set_reg16(Reg16::HL, x);
+        self.z80.set_reg8(Reg8::A, x as u8);
+        self.z80.set_reg8(Reg8::B, (x >> 8) as u8);
+    }
+
+    /// Read a console character, or `EOF` if there's no more input.
+    fn console_in(&mut self) -> io::Result<u8> {
+        self.output.flush()?;
+        let mut buf = [0u8];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return Ok(EOF),
+                Ok(_) => return Ok(buf[0]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[inline]
+    fn console_out(&mut self, x: u8) -> io::Result<()> {
+        self.output.write_all(&[x])
+    }
+
+    /// Carry out the BIOS call with entry number `entry`.
+    ///
+    /// Returns false if the program is done.
+    fn bios(&mut self, entry: u16) -> Result<bool, CpmError> {
+        match entry {
+            // cold and warm boot
+            0 | 1 => return Ok(false),
+            // console status: we never know if a character is ready without
+            // blocking, so say there isn't one
+            2 => self.return8(0),
+            3 => {
+                let x = self.console_in()?;
+                self.return8(x);
+            }
+            4 => {
+                let x = self.z80.reg8(Reg8::C);
+                self.console_out(x)?;
+            }
+            // the list, punch, and reader devices, and the disk routines,
+            // which programs calling the BDOS file functions don't need
+            _ => self.return8(0xFF),
+        }
+        Ok(true)
+    }
+
+    /// Carry out the BDOS call with the function number in C.
+    ///
+    /// Returns false if the program is done.
+    fn bdos(&mut self) -> Result<bool, CpmError> {
+        let de = self.z80.reg16(Reg16::DE);
+        let e = de as u8;
+        match self.z80.reg8(Reg8::C) {
+            0 => return Ok(false),
+            1 => {
+                let x = self.console_in()?;
+                self.console_out(x)?;
+                self.return8(x);
+            }
+            2 => {
+                self.console_out(e)?;
+                self.return8(0);
+            }
+            // reader input
+            3 => self.return8(EOF),
+            // punch and list output
+            4 | 5 => self.return8(0),
+            6 => match e {
+                0xFF => {
+                    let x = self.console_in()?;
+                    self.return8(x);
+                }
+                0xFE => self.return8(0),
+                _ => {
+                    self.console_out(e)?;
+                    self.return8(0);
+                }
+            },
+            7 => {
+                let x = self.byte(0x0003);
+                self.return8(x);
+            }
+            8 => {
+                self.set_byte(0x0003, e);
+                self.return8(0);
+            }
+            9 => {
+                let mut address = de;
+                loop {
+                    let x = self.byte(address);
+                    if x == b'$' {
+                        break;
+                    }
+                    self.console_out(x)?;
+                    address = address.wrapping_add(1);
+                }
+                self.return8(0);
+            }
+            10 => {
+                self.read_console_buffer(de)?;
+                self.return8(0);
+            }
+            11 => self.return8(0),
+            12 => self.return16(0x0022),
+            13 => {
+                self.dma = DEFAULT_DMA;
+                self.set_byte(0x0004, 0);
+                self.return8(0);
+            }
+            14 => self.return8(if e == 0 { 0 } else { 0xFF }),
+            function @ 15..=23 | function @ 33..=36 | function @ 40 => {
+                let x = self.file_function(function, de).unwrap_or(0xFF);
+                self.return8(x);
+            }
+            // login vector: only drive A
+            24 => self.return16(1),
+            25 => {
+                let x = self.byte(0x0004);
+                self.return8(x);
+            }
+            26 => {
+                self.dma = de;
+                self.return8(0);
+            }
+            // write protect, R/O vector, and set file attributes
+            28..=30 => self.return16(0),
+            32 => self.return8(0),
+            // reset drive
+            37 => self.return8(0),
+            _ => self.return16(0xFFFF),
+        }
+        Ok(true)
+    }
+
+    /// BDOS function 10: read a line into the buffer at `address`, whose
+    /// first byte is its capacity.
+    fn read_console_buffer(&mut self, address: u16) -> io::Result<()> {
+        let capacity = self.byte(address);
+        let mut count = 0u8;
+        while count < capacity {
+            match self.console_in()? {
+                b'\n' | EOF => break,
+                b'\r' => {}
+                x => {
+                    self.set_byte(address.wrapping_add(2 + count as u16), x);
+                    count += 1;
+                }
+            }
+        }
+        self.set_byte(address.wrapping_add(1), count);
+        Ok(())
+    }
+
+    /// Fill in the drive, name, and extension of the FCB at `fcb` from
+    /// `arg`, and zero the rest of its first 16 bytes.
+    fn set_fcb_name(&mut self, fcb: u16, arg: &str) {
+        let (drive, arg) = match arg.find(':') {
+            Some(1) => ((arg.as_bytes()[0] & 0x1F), &arg[2..]),
+            _ => (0, arg),
+        };
+        let (name, extension) = match arg.rfind('.') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => (arg, ""),
+        };
+        self.set_byte(fcb, drive);
+        self.write(fcb + 1, &fcb_field(name, 8));
+        self.write(fcb + 9, &fcb_field(extension, 3));
+        self.write(fcb + EX, &[0; 4]);
+    }
+
+    /// The 11 byte name and extension in the FCB at `fcb`, without
+    /// attribute bits.
+    fn fcb_pattern(&self, fcb: u16) -> [u8; 11] {
+        let mut pattern = [0u8; 11];
+        for (i, x) in pattern.iter_mut().enumerate() {
+            *x = self.byte(fcb.wrapping_add(1 + i as u16)) & 0x7F;
+        }
+        pattern
+    }
+
+    /// The host file name for the FCB at `fcb`.
+    fn fcb_file_name(&self, fcb: u16) -> String {
+        let pattern = self.fcb_pattern(fcb);
+        host_file_name(&pattern)
+    }
+
+    /// The path of the file called `name` in our directory, ignoring case.
+    ///
+    /// If there's no such file, this is where to create it.
+    fn path(&self, name: &str) -> PathBuf {
+        if let Ok(entries) = fs::read_dir(&self.directory) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if entry.file_name().to_string_lossy().eq_ignore_ascii_case(name) {
+                    return entry.path();
+                }
+            }
+        }
+        self.directory.join(name)
+    }
+
+    /// Host file names matching the possibly ambiguous FCB at `fcb`, in
+    /// order.
+    fn matching_files(&self, fcb: u16) -> io::Result<Vec<String>> {
+        let pattern = self.fcb_pattern(fcb);
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let matches = cpm_name(&name).is_some_and(|cpm| {
+                pattern
+                    .iter()
+                    .zip(cpm.iter())
+                    .all(|(&p, &c)| p == b'?' || p == c)
+            });
+            if matches {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The sequential position in the FCB at `fcb`, as a record number.
+    fn current_record(&self, fcb: u16) -> u32 {
+        let cr = self.byte(fcb + CR) as u32 & 0x7F;
+        let ex = self.byte(fcb + EX) as u32 & 0x1F;
+        let s2 = self.byte(fcb + S2) as u32 & 0x3F;
+        s2 << 12 | ex << 7 | cr
+    }
+
+    fn set_current_record(&mut self, fcb: u16, record: u32) {
+        self.set_byte(fcb + CR, (record & 0x7F) as u8);
+        self.set_byte(fcb + EX, (record >> 7 & 0x1F) as u8);
+        self.set_byte(fcb + S2, (record >> 12 & 0x3F) as u8);
+    }
+
+    fn random_record(&self, fcb: u16) -> u32 {
+        let r = |i| self.byte(fcb + R0 + i) as u32;
+        r(0) | r(1) << 8 | r(2) << 16
+    }
+
+    fn set_random_record(&mut self, fcb: u16, record: u32) {
+        self.write(
+            fcb + R0,
+            &[record as u8, (record >> 8) as u8, (record >> 16) as u8],
+        );
+    }
+
+    /// Set the record count of the FCB at `fcb` from the size of the file,
+    /// for the extent it's in.
+    fn set_record_count(&mut self, fcb: u16, size: u64) {
+        let records = records(size);
+        let extent = (self.current_record(fcb) >> 7) as u64;
+        let count = records.saturating_sub(extent * 128).min(128);
+        self.set_byte(fcb + RC, count as u8);
+    }
+
+    /// Read a record into the DMA buffer.
+    ///
+    /// Returns 1 if the record is past the end of the file.
+    fn read_record(&mut self, fcb: u16, record: u32) -> io::Result<u8> {
+        let mut file = File::open(self.path(&self.fcb_file_name(fcb)))?;
+        file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64))?;
+        let mut buf = [EOF; RECORD_SIZE];
+        let mut read = 0;
+        while read < RECORD_SIZE {
+            match file.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if read == 0 {
+            return Ok(1);
+        }
+        let dma = self.dma;
+        self.write(dma, &buf);
+        Ok(0)
+    }
+
+    /// Write the DMA buffer to a record.
+    fn write_record(&mut self, fcb: u16, record: u32) -> io::Result<u8> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(self.path(&self.fcb_file_name(fcb)))?;
+        file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64))?;
+        let mut buf = [0u8; RECORD_SIZE];
+        for (i, x) in buf.iter_mut().enumerate() {
+            *x = self.byte(self.dma.wrapping_add(i as u16));
+        }
+        file.write_all(&buf)?;
+        let size = file.metadata()?.len();
+        self.set_record_count(fcb, size);
+        Ok(0)
+    }
+
+    /// Fill the DMA buffer with a directory entry for the file `name`, and
+    /// return its position in the buffer.
+    fn directory_entry(&mut self, name: &str) -> io::Result<u8> {
+        let size = fs::metadata(self.directory.join(name))?.len();
+        let records = records(size);
+        let mut entry = [0u8; 32];
+        entry[1..12].copy_from_slice(&cpm_name(name).unwrap_or([b' '; 11]));
+        entry[15] = records.min(128) as u8;
+        let dma = self.dma;
+        self.write(dma, &entry);
+        Ok(0)
+    }
+
+    /// Carry out one of the BDOS file functions on the FCB at `fcb`.
+    ///
+    /// Errors from the host are reported to the program as 0xFF.
+    fn file_function(&mut self, function: u8, fcb: u16) -> io::Result<u8> {
+        match function {
+            // open
+            15 => {
+                let path = self.path(&self.fcb_file_name(fcb));
+                let size = fs::metadata(&path)?.len();
+                self.set_byte(fcb + S2, 0);
+                self.set_record_count(fcb, size);
+                Ok(0)
+            }
+            // close
+            16 => {
+                fs::metadata(self.path(&self.fcb_file_name(fcb)))?;
+                Ok(0)
+            }
+            // search for first
+            17 => {
+                let mut names = self.matching_files(fcb)?;
+                names.reverse();
+                self.search = names;
+                self.search_next()
+            }
+            // search for next
+            18 => self.search_next(),
+            // delete
+            19 => {
+                let names = self.matching_files(fcb)?;
+                if names.is_empty() {
+                    return Ok(0xFF);
+                }
+                for name in names.iter() {
+                    fs::remove_file(self.directory.join(name))?;
+                }
+                Ok(0)
+            }
+            // read sequential
+            20 => {
+                let record = self.current_record(fcb);
+                let result = self.read_record(fcb, record)?;
+                if result == 0 {
+                    self.set_current_record(fcb, record + 1);
+                }
+                Ok(result)
+            }
+            // write sequential
+            21 => {
+                let record = self.current_record(fcb);
+                self.write_record(fcb, record)?;
+                self.set_current_record(fcb, record + 1);
+                Ok(0)
+            }
+            // make
+            22 => {
+                let name = self.fcb_file_name(fcb);
+                if name.is_empty() || name.contains('?') {
+                    return Ok(0xFF);
+                }
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(self.path(&name))?;
+                self.write(fcb + EX, &[0; 4]);
+                Ok(0)
+            }
+            // rename
+            23 => {
+                let from = self.path(&self.fcb_file_name(fcb));
+                let to = self.directory.join(self.fcb_file_name(fcb + 16));
+                fs::metadata(&from)?;
+                fs::rename(from, to)?;
+                Ok(0)
+            }
+            // read random
+            33 => {
+                let record = self.random_record(fcb);
+                if record > 0xFFFF {
+                    return Ok(6);
+                }
+                self.set_current_record(fcb, record);
+                self.read_record(fcb, record)
+            }
+            // write random, and write random with zero fill
+            34 | 40 => {
+                let record = self.random_record(fcb);
+                if record > 0xFFFF {
+                    return Ok(6);
+                }
+                self.set_current_record(fcb, record);
+                self.write_record(fcb, record)
+            }
+            // compute file size
+            35 => {
+                let size = fs::metadata(self.path(&self.fcb_file_name(fcb)))?.len();
+                let records = records(size);
+                self.set_random_record(fcb, records as u32);
+                Ok(0)
+            }
+            // set random record
+            36 => {
+                let record = self.current_record(fcb);
+                self.set_random_record(fcb, record);
+                Ok(0)
+            }
+            _ => Ok(0xFF),
+        }
+    }
+
+    fn search_next(&mut self) -> io::Result<u8> {
+        match self.search.pop() {
+            Some(name) => self.directory_entry(&name),
+            None => Ok(0xFF),
+        }
+    }
+}
+
+/// How many records a file of `size` bytes takes up.
+#[inline]
+fn records(size: u64) -> u64 {
+    size.div_ceil(RECORD_SIZE as u64)
+}
+
+/// `s`, uppercase and padded with spaces to `len` bytes, or truncated to it.
+///
+/// A `*` fills the rest of the field with `?`.
+fn fcb_field(s: &str, len: usize) -> Vec<u8> {
+    let mut field = Vec::with_capacity(len);
+    for c in s.bytes() {
+        if field.len() == len {
+            break;
+        }
+        if c == b'*' {
+            field.resize(len, b'?');
+            break;
+        }
+        field.push(c.to_ascii_uppercase());
+    }
+    field.resize(len, b' ');
+    field
+}
+
+/// The 11 byte CP/M name and extension of the host file called `name`, if
+/// it has a valid CP/M name.
+fn cpm_name(name: &str) -> Option<[u8; 11]> {
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    let valid = |s: &str, len| {
+        let legal = |c: u8| c.is_ascii_graphic() && !b".:*?<>,;=[]".contains(&c);
+        s.len() <= len && s.bytes().all(legal)
+    };
+    if stem.is_empty() || !valid(stem, 8) || !valid(extension, 3) {
+        return None;
+    }
+    let mut cpm = [0u8; 11];
+    cpm[..8].copy_from_slice(&fcb_field(stem, 8));
+    cpm[8..].copy_from_slice(&fcb_field(extension, 3));
+    Some(cpm)
+}
+
+/// The host file name for a CP/M name and extension.
+fn host_file_name(pattern: &[u8; 11]) -> String {
+    let field = |bytes: &[u8]| -> String {
+        bytes
+            .iter()
+            .filter(|&&c| c != b' ')
+            .map(|&c| c as char)
+            .collect()
+    };
+    let stem = field(&pattern[..8]);
+    let extension = field(&pattern[8..]);
+    if extension.is_empty() {
+        stem
+    } else {
+        format!("{}.{}", stem, extension)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn fcb_fields() {
+        assert_eq!(fcb_field("foo", 8), b"FOO     ");
+        assert_eq!(fcb_field("", 3), b"   ");
+        assert_eq!(fcb_field("toolongname", 8), b"TOOLONGN");
+        assert_eq!(fcb_field("a*", 8), b"A???????");
+        assert_eq!(fcb_field("*", 3), b"???");
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(cpm_name("hello.txt"), Some(*b"HELLO   TXT"));
+        assert_eq!(cpm_name("README"), Some(*b"README     "));
+        assert_eq!(cpm_name("a.b.c"), None, "the stem can't have a dot");
+        assert_eq!(cpm_name(".hidden"), None);
+        assert_eq!(cpm_name("toolongname.txt"), None);
+        assert_eq!(cpm_name("a.text"), None);
+        assert_eq!(cpm_name("a?b"), None);
+        assert_eq!(cpm_name("a b"), None);
+
+        assert_eq!(host_file_name(b"HELLO   TXT"), "HELLO.TXT");
+        assert_eq!(host_file_name(b"README     "), "README");
+        assert_eq!(host_file_name(b"A       B  "), "A.B");
+    }
+
+    #[test]
+    fn name_round_trip() {
+        for &name in ["ZEXDOC.COM", "A.B", "README", "12345678.123", "X-Y_Z.$$$"].iter() {
+            let cpm = cpm_name(name).unwrap();
+            assert_eq!(host_file_name(&cpm), name);
+            assert_eq!(cpm_name(&host_file_name(&cpm)), Some(cpm));
+        }
+        assert_eq!(host_file_name(&cpm_name("zexdoc.com").unwrap()), "ZEXDOC.COM");
+    }
+
+    fn machine(directory: &TempDir) -> CpmMachine<&'static [u8], Vec<u8>> {
+        let mut machine = CpmMachine::new(directory.path(), &b""[..], Vec::new());
+        machine.load(&[0xC9], &[]).unwrap();
+        machine
+    }
+
+    /// Call BDOS function `function` with `de`, and return A.
+    fn call<R: Read, W: Write>(machine: &mut CpmMachine<R, W>, function: u8, de: u16) -> u8 {
+        machine.z80.set_reg8(Reg8::C, function);
+        machine.z80.set_reg16(Reg16::DE, de);
+        assert!(machine.bdos().unwrap());
+        machine.z80.reg8(Reg8::A)
+    }
+
+    fn dma<R: Read, W: Write>(machine: &CpmMachine<R, W>) -> &[u8] {
+        &machine.memory[DEFAULT_DMA as usize..DEFAULT_DMA as usize + RECORD_SIZE]
+    }
+
+    fn fill_dma<R: Read, W: Write>(machine: &mut CpmMachine<R, W>, x: u8) {
+        machine.write(DEFAULT_DMA, &[x; RECORD_SIZE]);
+    }
+
+    #[test]
+    fn load_arguments() {
+        let directory = TempDir::new("cpm").unwrap();
+        let mut machine = CpmMachine::new(directory.path(), &b""[..], Vec::new());
+        machine.load(&[0xC9], &["foo.txt", "b:*.com"]).unwrap();
+        assert_eq!(machine.byte(FCB1), 0);
+        assert_eq!(machine.fcb_pattern(FCB1), *b"FOO     TXT");
+        assert_eq!(machine.byte(FCB2), 2);
+        assert_eq!(machine.fcb_pattern(FCB2), *b"????????COM");
+        let tail = b" FOO.TXT B:*.COM";
+        assert_eq!(machine.byte(DEFAULT_DMA), tail.len() as u8);
+        assert_eq!(&dma(&machine)[1..tail.len() + 1], tail);
+        assert_eq!(machine.z80.reg16(Reg16::PC), TPA);
+    }
+
+    #[test]
+    fn sequential_records() {
+        let directory = TempDir::new("cpm").unwrap();
+        let mut machine = machine(&directory);
+        machine.set_fcb_name(FCB1, "DATA.BIN");
+
+        assert_eq!(call(&mut machine, 22, FCB1), 0);
+        for i in 0..3 {
+            fill_dma(&mut machine, i + 1);
+            assert_eq!(call(&mut machine, 21, FCB1), 0);
+        }
+        assert_eq!(machine.byte(FCB1 + CR), 3);
+        assert_eq!(machine.byte(FCB1 + RC), 3);
+        assert_eq!(call(&mut machine, 16, FCB1), 0);
+
+        let contents = fs::read(directory.path().join("DATA.BIN")).unwrap();
+        assert_eq!(contents.len(), 3 * RECORD_SIZE);
+        assert!(contents[..RECORD_SIZE].iter().all(|&x| x == 1));
+        assert!(contents[2 * RECORD_SIZE..].iter().all(|&x| x == 3));
+
+        // open it again, from the start
+        machine.set_fcb_name(FCB1, "data.bin");
+        assert_eq!(call(&mut machine, 15, FCB1), 0);
+        assert_eq!(machine.byte(FCB1 + RC), 3);
+        machine.set_byte(FCB1 + CR, 0);
+        for i in 0..3 {
+            assert_eq!(call(&mut machine, 20, FCB1), 0);
+            assert!(dma(&machine).iter().all(|&x| x == i + 1));
+        }
+        assert_eq!(call(&mut machine, 20, FCB1), 1, "end of file");
+        assert_eq!(machine.byte(FCB1 + CR), 3);
+    }
+
+    #[test]
+    fn short_last_record() {
+        let directory = TempDir::new("cpm").unwrap();
+        fs::write(directory.path().join("TEXT.TXT"), vec![b'x'; RECORD_SIZE + 2]).unwrap();
+        let mut machine = machine(&directory);
+        machine.set_fcb_name(FCB1, "TEXT.TXT");
+        assert_eq!(call(&mut machine, 15, FCB1), 0);
+        assert_eq!(machine.byte(FCB1 + RC), 2);
+        machine.set_byte(FCB1 + CR, 1);
+        assert_eq!(call(&mut machine, 20, FCB1), 0);
+        assert_eq!(&dma(&machine)[..3], b"xx\x1A");
+        assert!(dma(&machine)[2..].iter().all(|&x| x == EOF));
+    }
+
+    #[test]
+    fn random_records() {
+        let directory = TempDir::new("cpm").unwrap();
+        let mut machine = machine(&directory);
+        machine.set_fcb_name(FCB1, "RANDOM.DAT");
+        assert_eq!(call(&mut machine, 22, FCB1), 0);
+
+        // write records 2 and 0, leaving a gap
+        for &record in [2u32, 0].iter() {
+            machine.set_random_record(FCB1, record);
+            fill_dma(&mut machine, 0x10 + record as u8);
+            assert_eq!(call(&mut machine, 34, FCB1), 0);
+            assert_eq!(machine.current_record(FCB1), record);
+        }
+        assert_eq!(call(&mut machine, 35, FCB1), 0);
+        assert_eq!(machine.random_record(FCB1), 3);
+
+        machine.set_random_record(FCB1, 2);
+        assert_eq!(call(&mut machine, 33, FCB1), 0);
+        assert!(dma(&machine).iter().all(|&x| x == 0x12));
+        machine.set_random_record(FCB1, 1);
+        assert_eq!(call(&mut machine, 33, FCB1), 0);
+        assert!(dma(&machine).iter().all(|&x| x == 0));
+
+        // a random read sets the sequential position, but doesn't advance it
+        assert_eq!(machine.current_record(FCB1), 1);
+        assert_eq!(call(&mut machine, 20, FCB1), 0);
+        assert!(dma(&machine).iter().all(|&x| x == 0));
+        assert_eq!(call(&mut machine, 36, FCB1), 0);
+        assert_eq!(machine.random_record(FCB1), 2);
+
+        machine.set_random_record(FCB1, 3);
+        assert_eq!(call(&mut machine, 33, FCB1), 1, "past the end");
+        machine.set_random_record(FCB1, 0x10000);
+        assert_eq!(call(&mut machine, 33, FCB1), 6, "out of range");
+
+        // records past the first extent
+        machine.set_random_record(FCB1, 200);
+        fill_dma(&mut machine, 0x55);
+        assert_eq!(call(&mut machine, 34, FCB1), 0);
+        assert_eq!(machine.byte(FCB1 + EX), 1);
+        assert_eq!(machine.byte(FCB1 + CR), 200 - 128);
+        assert_eq!(machine.byte(FCB1 + RC), 201 - 128);
+        let size = fs::metadata(directory.path().join("RANDOM.DAT")).unwrap().len();
+        assert_eq!(size, 201 * RECORD_SIZE as u64);
+    }
+
+    #[test]
+    fn directory_functions() {
+        let directory = TempDir::new("cpm").unwrap();
+        for &name in ["B.COM", "a.com", "C.TXT"].iter() {
+            fs::write(directory.path().join(name), b"").unwrap();
+        }
+        let mut machine = machine(&directory);
+
+        machine.set_fcb_name(FCB1, "*.COM");
+        assert_eq!(call(&mut machine, 17, FCB1), 0);
+        assert_eq!(&dma(&machine)[1..12], b"B       COM");
+        assert_eq!(call(&mut machine, 18, FCB1), 0);
+        assert_eq!(&dma(&machine)[1..12], b"A       COM");
+        assert_eq!(call(&mut machine, 18, FCB1), 0xFF);
+
+        machine.set_fcb_name(FCB1, "C.TXT");
+        machine.set_fcb_name(FCB1 + 16, "D.TXT");
+        assert_eq!(call(&mut machine, 23, FCB1), 0);
+        assert!(directory.path().join("D.TXT").exists());
+        assert_eq!(call(&mut machine, 15, FCB1), 0xFF, "C.TXT is gone");
+
+        machine.set_fcb_name(FCB1, "?.COM");
+        assert_eq!(call(&mut machine, 19, FCB1), 0);
+        assert_eq!(call(&mut machine, 19, FCB1), 0xFF);
+        assert!(directory.path().join("D.TXT").exists());
+        assert!(!directory.path().join("a.com").exists());
+    }
+}
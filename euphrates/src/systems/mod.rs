@@ -1 +1,2 @@
+pub mod cpm;
 pub mod sms;
@@ -0,0 +1,349 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::*;
+
+/// The result of stepping one environment: the observation, reward, whether
+/// the episode is done, and extra information.
+pub type StepResult = (SmsState, f64, bool, StepInfo);
+
+enum Request {
+    Reset,
+    Step(Vec<usize>),
+    Finish,
+}
+
+enum Response {
+    Reset(Vec<Result<SmsState, SmsMemoryLoadError>>),
+    Step(Vec<Result<StepResult, SmsEmulationError>>),
+}
+
+struct Worker {
+    /// The size of each environment's action space.
+    action_counts: Vec<usize>,
+    requests: Sender<Request>,
+    responses: Receiver<Response>,
+    handle: Option<JoinHandle<Vec<SmsEnvironment>>>,
+}
+
+impl Worker {
+    fn new(mut environments: Vec<SmsEnvironment>) -> Self {
+        let action_counts = environments
+            .iter()
+            .map(|e| e.action_space().len())
+            .collect();
+        let (requests, request_receiver) = channel();
+        let (response_sender, responses) = channel();
+        let handle = thread::spawn(move || {
+            for request in request_receiver.iter() {
+                let response = match request {
+                    Request::Reset => {
+                        Response::Reset(environments.iter_mut().map(|e| e.reset()).collect())
+                    }
+                    Request::Step(actions) => Response::Step(
+                        environments
+                            .iter_mut()
+                            .zip(actions)
+                            .map(|(e, a)| e.step(a))
+                            .collect(),
+                    ),
+                    Request::Finish => break,
+                };
+                if response_sender.send(response).is_err() {
+                    break;
+                }
+            }
+            environments
+        });
+        Worker {
+            action_counts,
+            requests,
+            responses,
+            handle: Some(handle),
+        }
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.action_counts.len()
+    }
+
+    /// Stop the thread and take back its environments, or `None` if it
+    /// panicked.
+    fn finish(&mut self) -> Option<Vec<SmsEnvironment>> {
+        let _ = self.requests.send(Request::Finish);
+        match self.handle.take() {
+            Some(handle) => handle.join().ok(),
+            None => Some(Vec::new()),
+        }
+    }
+}
+
+// This superfluous module with the `allow` attribute is necessary until the
+// `fail` crate begins using `dyn trait` syntax
+#[allow(bare_trait_objects)]
+mod batch_error {
+    use super::*;
+
+    #[derive(Debug, Fail)]
+    pub enum BatchError {
+        #[fail(display = "memory load error: {}", _0)]
+        MemoryLoadError(#[cause] SmsMemoryLoadError),
+
+        #[fail(display = "emulation error: {}", _0)]
+        EmulationError(#[cause] SmsEmulationError),
+
+        #[fail(
+            display = "action {} not in action space of size {} of environment {}",
+            action, action_count, environment
+        )]
+        InvalidAction {
+            environment: usize,
+            action: usize,
+            action_count: usize,
+        },
+
+        #[fail(display = "a BatchEnvironment worker thread panicked")]
+        WorkerPanicked,
+    }
+}
+
+pub use self::batch_error::BatchError;
+
+impl From<SmsMemoryLoadError> for BatchError {
+    fn from(x: SmsMemoryLoadError) -> Self {
+        BatchError::MemoryLoadError(x)
+    }
+}
+
+impl From<SmsEmulationError> for BatchError {
+    fn from(x: SmsEmulationError) -> Self {
+        BatchError::EmulationError(x)
+    }
+}
+
+/// Many `SmsEnvironment`s stepped in lockstep on a pool of threads.
+///
+/// The environments are divided as evenly as possible among the threads, and
+/// each thread owns its environments until `into_environments` is called or
+/// the `BatchEnvironment` is dropped. Observations, rewards, and done flags are
+/// returned in the same order as the environments were given.
+///
+/// Environments created from clones of the same `SmsState` share a single copy
+/// of the ROM.
+pub struct BatchEnvironment {
+    workers: Vec<Worker>,
+}
+
+impl BatchEnvironment {
+    /// Distribute `environments` among `threads` threads.
+    ///
+    /// Panics if `threads` is 0.
+    pub fn new(environments: Vec<SmsEnvironment>, threads: usize) -> Self {
+        assert!(threads > 0, "a BatchEnvironment needs at least one thread");
+        let len = environments.len();
+        let threads = threads.min(len.max(1));
+        let mut environments = environments.into_iter();
+        let workers = (0..threads)
+            .map(|i| {
+                // the first `len % threads` workers get one extra environment
+                let count = len / threads + if i < len % threads { 1 } else { 0 };
+                Worker::new(environments.by_ref().take(count).collect())
+            })
+            .collect();
+        BatchEnvironment { workers }
+    }
+
+    /// Create `count` environments all starting from `initial_state`, set up
+    /// by `configure`, and distribute them among `threads` threads.
+    ///
+    /// `configure` is called with each environment and its index, and can be
+    /// used to set the action space, reward function, and so on.
+    pub fn from_state<Memory, F>(
+        initial_state: SmsState,
+        count: usize,
+        threads: usize,
+        _mem: TypeWrap<Memory>,
+        mut configure: F,
+    ) -> Result<Self, SmsCreationError>
+    where
+        Memory: SmsMemory + SmsMemoryLoad + Send + 'static,
+        F: FnMut(usize, &mut SmsEnvironment),
+    {
+        let mut environments = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut env =
+                SmsEnvironment::new(initial_state.clone(), TypeWrap::<Memory>::default())?;
+            configure(i, &mut env);
+            environments.push(env);
+        }
+        Ok(BatchEnvironment::new(environments, threads))
+    }
+
+    /// How many environments are in the batch?
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.workers.iter().map(|w| w.count()).sum()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many threads are the environments running on?
+    #[inline]
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Send each worker its request, and collect every worker's response
+    /// before looking at any of them, so that no response is left behind to
+    /// be mistaken for the answer to a later request.
+    fn exchange<F>(&self, mut request: F) -> Result<Vec<Response>, BatchError>
+    where
+        F: FnMut(&Worker) -> Request,
+    {
+        let sent: Vec<bool> = self
+            .workers
+            .iter()
+            .map(|w| w.requests.send(request(w)).is_ok())
+            .collect();
+        let mut responses = Vec::with_capacity(self.workers.len());
+        let mut panicked = false;
+        for (worker, sent) in self.workers.iter().zip(sent) {
+            if !sent {
+                panicked = true;
+                continue;
+            }
+            match worker.responses.recv() {
+                Ok(response) => responses.push(response),
+                Err(_) => panicked = true,
+            }
+        }
+        if panicked {
+            Err(BatchError::WorkerPanicked)
+        } else {
+            Ok(responses)
+        }
+    }
+
+    /// Reset every environment, returning the first observation of each.
+    ///
+    /// If any environment fails to reset, the first such error is returned.
+    /// If a worker thread has panicked, `BatchError::WorkerPanicked` is
+    /// returned, and will be by every later `reset` and `step`.
+    pub fn reset(&mut self) -> Result<Vec<SmsState>, BatchError> {
+        let mut result = Vec::with_capacity(self.len());
+        let mut error = None;
+        for response in self.exchange(|_| Request::Reset)? {
+            match response {
+                Response::Reset(states) => {
+                    for state in states {
+                        match state {
+                            Ok(s) => result.push(s),
+                            Err(e) => if error.is_none() {
+                                error = Some(e)
+                            },
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(result),
+        }
+    }
+
+    /// Step every environment, with `actions[i]` for environment `i`.
+    ///
+    /// If an action is not in the corresponding environment's action space,
+    /// `BatchError::InvalidAction` is returned and no environment is stepped.
+    /// If any environment fails to step, the first such error is returned, but
+    /// all the other environments will still have been stepped. If a worker
+    /// thread has panicked, `BatchError::WorkerPanicked` is returned, and will
+    /// be by every later `reset` and `step`.
+    ///
+    /// Panics if `actions.len()` isn't `self.len()`.
+    pub fn step(&mut self, actions: &[usize]) -> Result<Vec<StepResult>, BatchError> {
+        assert_eq!(
+            actions.len(),
+            self.len(),
+            "need exactly one action per environment"
+        );
+        let action_counts = self.workers.iter().flat_map(|w| w.action_counts.iter());
+        for (environment, (&action, &action_count)) in actions.iter().zip(action_counts).enumerate()
+        {
+            if action >= action_count {
+                return Err(BatchError::InvalidAction {
+                    environment,
+                    action,
+                    action_count,
+                });
+            }
+        }
+
+        let mut start = 0;
+        let responses = self.exchange(|worker| {
+            let end = start + worker.count();
+            let request = Request::Step(actions[start..end].to_vec());
+            start = end;
+            request
+        })?;
+        let mut result = Vec::with_capacity(self.len());
+        let mut error = None;
+        for response in responses {
+            match response {
+                Response::Step(steps) => {
+                    for step in steps {
+                        match step {
+                            Ok(s) => result.push(s),
+                            Err(e) => if error.is_none() {
+                                error = Some(e)
+                            },
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(result),
+        }
+    }
+
+    /// Stop the threads and take back the environments, in their original
+    /// order.
+    ///
+    /// If a worker thread panicked, its environments are lost, and
+    /// `BatchError::WorkerPanicked` is returned once all the threads have
+    /// stopped.
+    pub fn into_environments(mut self) -> Result<Vec<SmsEnvironment>, BatchError> {
+        let mut result = Vec::with_capacity(self.len());
+        let mut panicked = false;
+        for worker in self.workers.iter_mut() {
+            match worker.finish() {
+                Some(environments) => result.extend(environments),
+                None => panicked = true,
+            }
+        }
+        if panicked {
+            Err(BatchError::WorkerPanicked)
+        } else {
+            Ok(result)
+        }
+    }
+}
+
+impl Drop for BatchEnvironment {
+    fn drop(&mut self) {
+        // a worker's panic has already been reported by `reset` or `step`, and
+        // panicking again here could abort while unwinding
+        for worker in self.workers.iter_mut() {
+            let _ = worker.finish();
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use memo::Inbox;
+
+use super::*;
+
+/// Where the byte at `address` is in ROM, and how many bytes from there on
+/// are contiguous in both logical memory and ROM, or `None` if it's not ROM.
+///
+/// `pages` are the pages mapped to each slot.
+#[inline]
+fn rom_location(pages: &[MemoryPage; 4], address: u16) -> Option<(usize, usize)> {
+    use self::MemoryPage::*;
+    let offset = address as usize & 0x3FFF;
+    match pages[address as usize >> 14] {
+        Rom(x) => Some((x as usize * 0x4000 + offset, 0x4000 - offset)),
+        RomButFirstKiB(_) if offset < 0x400 => Some((offset, 0x400 - offset)),
+        RomButFirstKiB(x) => Some((x as usize * 0x4000 + offset, 0x4000 - offset)),
+        HalfCartridgeRam(x) if offset < 0x2000 => {
+            Some((x as usize * 0x4000 + offset, 0x2000 - offset))
+        }
+        _ => None,
+    }
+}
+
+/// Memory that remembers whether one of its memory registers has been
+/// written, which may have changed the memory map.
+struct Watched<'a, M: 'a + ?Sized> {
+    memory: &'a mut M,
+    mapper: SmsMemoryMapper,
+    mapped: bool,
+}
+
+impl<'a, M> Memory16 for Watched<'a, M>
+where
+    M: 'a + Memory16 + ?Sized,
+{
+    #[inline]
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory.read(address)
+    }
+
+    #[inline]
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory.write(address, value);
+        if is_memory_register(self.mapper, address) {
+            self.mapped = true;
+        }
+    }
+
+    #[inline]
+    fn rom_index(&self, address: u16) -> Option<usize> {
+        self.memory.rom_index(address)
+    }
+}
+
+/// An interpreter that decodes each instruction in ROM only once.
+///
+/// The first time an instruction in ROM is executed, it's decoded into its
+/// operation and immediate operands and remembered by where it is in ROM (not
+/// where it's mapped). After that, executing it is a call to that operation,
+/// without reading its bytes from memory or dispatching on each of its
+/// prefixes. Anything not in ROM is left to the usual interpreter, as are the
+/// few prefixed opcodes that aren't in the instruction list.
+///
+/// Timing is exactly as with the usual interpreter.
+///
+/// Like any `SmsZ80Runner`, it gets at memory and IO only through trait
+/// objects, and that costs more than decoding saves: it's faster than the
+/// interpreter run as an `SmsZ80Runner`, but slower than a system's own
+/// interpreter. `examples/cached_interpreter_benchmark.rs` compares the three.
+///
+/// Since ROM never changes, a `CachedInterpreter` only needs to be told with
+/// `invalidate` if it's going to be used with a different ROM, or if the ROM
+/// was written with `SmsMemory::rom_write`.
+#[derive(Clone, Debug, Default)]
+pub struct CachedInterpreter {
+    /// Decoded instructions, by the index in ROM of their first byte.
+    instructions: Vec<Option<DecodedInstruction>>,
+}
+
+impl CachedInterpreter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Forget all decoded instructions.
+    pub fn invalidate(&mut self) {
+        self.instructions.clear();
+    }
+
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        if z80.interrupt_status() == InterruptStatus::NoCheck {
+            z80.set_interrupt_status(InterruptStatus::Check);
+        }
+
+        if self.instructions.len() != memory.rom_len() {
+            self.instructions = vec![None; memory.rom_len()];
+        }
+
+        let mut pages = [MemoryPage::default(); 4];
+        let mut memory = Watched {
+            mapper: memory.mapper(),
+            memory,
+            mapped: true,
+        };
+
+        // a tracing inbox wants a memo for each instruction, which only
+        // `step` sends
+        let tracing = inbox.tracing();
+
+        while z80.cycles() < target_cycles {
+            if inbox.holding() {
+                return;
+            }
+
+            if memory.mapped {
+                for (slot, page) in pages.iter_mut().enumerate() {
+                    *page = memory.memory.page(slot as u8);
+                }
+                memory.mapped = false;
+            }
+
+            let mut run = Z80RunImpler {
+                z80: &mut *z80,
+                memory: &mut memory,
+                io: &mut *io,
+                irq: &mut *irq,
+                inbox: &mut *inbox,
+            };
+
+            if tracing || !run.at_instruction() {
+                run.step(target_cycles);
+                continue;
+            }
+
+            let pc = run.z80.reg16(Reg16::PC);
+            if let Some(instruction) = self.decoded(&pages, pc) {
+                if run.z80.cycles() + instruction.last_step() >= target_cycles {
+                    run.step(target_cycles);
+                    continue;
+                }
+                instruction.execute(run.z80, run.memory, run.io);
+
+                // decoded instructions send no memos, and leave the pages
+                // alone unless they write a memory register, so keep going
+                // without looking at either
+                while run.z80.cycles() < target_cycles && !run.memory.mapped && run.at_instruction()
+                {
+                    let pc = run.z80.reg16(Reg16::PC);
+                    match self.decoded(&pages, pc) {
+                        Some(x) if run.z80.cycles() + x.last_step() < target_cycles => {
+                            x.execute(run.z80, run.memory, run.io)
+                        }
+                        _ => break,
+                    }
+                }
+                continue;
+            }
+
+            let (index, contiguous) = match rom_location(&pages, pc) {
+                Some(x) => x,
+                None => {
+                    run.step(target_cycles);
+                    continue;
+                }
+            };
+            match self.instructions.get_mut(index) {
+                Some(cached @ &mut None) => {
+                    let decoded = run.step_decoding(target_cycles);
+                    if let Some(instruction) = decoded {
+                        if instruction.byte_len() <= contiguous {
+                            *cached = Some(instruction);
+                        }
+                    }
+                }
+                // decoded where more of it was in ROM than is now
+                _ => run.step(target_cycles),
+            }
+        }
+    }
+
+    /// The decoded instruction at `address`, if there is one and all of it is
+    /// still mapped in ROM.
+    #[inline]
+    fn decoded(&self, pages: &[MemoryPage; 4], address: u16) -> Option<&DecodedInstruction> {
+        let (index, contiguous) = rom_location(pages, address)?;
+        match self.instructions.get(index) {
+            Some(Some(instruction)) if instruction.byte_len() <= contiguous => {
+                Some(instruction)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SmsZ80Runner for CachedInterpreter {
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        CachedInterpreter::run(self, z80, memory, io, irq, inbox, target_cycles);
+    }
+
+    #[inline]
+    fn invalidate(&mut self) {
+        CachedInterpreter::invalidate(self);
+    }
+}
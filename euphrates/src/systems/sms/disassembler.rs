@@ -0,0 +1,384 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+
+use super::*;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Where the paths we're following think each ROM bank is mapped, by slot.
+///
+/// `None` means we don't know.
+type Mapping = [Option<usize>; 3];
+
+/// The slot a bank is assembled for.
+///
+/// Banks 0 and 1 stay in slots 0 and 1 in just about every game, and any other
+/// bank has to be mapped into slot 2 to be used.
+fn bank_slot(bank: usize) -> usize {
+    min(bank, 2)
+}
+
+/// The logical address of ROM index `index`, in the slot its bank is assembled
+/// for.
+fn logical_address(index: usize) -> u16 {
+    (bank_slot(index / BANK_SIZE) * BANK_SIZE + index % BANK_SIZE) as u16
+}
+
+/// Does `mnemonic` never continue to the next instruction?
+fn ends_flow(mnemonic: FullMnemonic) -> bool {
+    use self::FullMnemonic::*;
+    use self::Mnemonic::*;
+    matches!(
+        mnemonic,
+        ZeroParameters(Ret) | ZeroParameters(Reti) | ZeroParameters(Retn)
+            | OneParameter(Jp, _)
+            | OneParameter(Jr, _)
+    )
+}
+
+/// A static disassembly of a whole ROM image.
+///
+/// Code is found by recursive descent from the reset and interrupt vectors,
+/// following the targets of jumps, calls, and `rst`s, and everything else is
+/// taken to be data. Everything is kept by where it is in ROM rather than by
+/// logical address, so the same address in different banks isn't confused.
+///
+/// To follow jumps into banks other than 0 and 1, the paths through the code
+/// keep track of which bank is mapped into each slot, noticing the usual
+/// `ld a, n` followed by `ld (nn), a` to one of the mapper's registers. A jump
+/// into a slot whose bank isn't known isn't followed.
+///
+/// Its `Display` implementation gives source in the syntax of the WLA-DX
+/// assembler, with a label at every jump target found, meant to assemble to
+/// exactly the same ROM. The tests check that with our own assembler, and
+/// with WLA-DX itself only when `wla-z80` and `wlalink` are installed.
+/// Instructions that assemblers won't produce, like undocumented instructions
+/// and duplicate encodings, are given as `.db` with the instruction in a
+/// comment.
+#[derive(Clone, Debug)]
+pub struct RomDisassembly<'a> {
+    rom: &'a [u8],
+
+    mapper: SmsMemoryMapper,
+
+    /// The length of the instruction starting at each index, or 0 if none
+    /// does.
+    lengths: Vec<u8>,
+
+    /// Is each byte part of an instruction?
+    code: Vec<bool>,
+
+    /// The index of the target of each instruction with a target we could
+    /// find, by the index of the instruction.
+    targets: BTreeMap<usize, usize>,
+
+    /// Indices of instructions that are entry points or jump targets.
+    labels: BTreeSet<usize>,
+}
+
+impl<'a> RomDisassembly<'a> {
+    /// Disassemble `rom`, which is to be used with `mapper`.
+    ///
+    /// `rom` should be the image as it is, without the header some dumps have.
+    pub fn new(rom: &'a [u8], mapper: SmsMemoryMapper) -> Self {
+        let mut disassembly = RomDisassembly {
+            rom,
+            mapper,
+            lengths: vec![0; rom.len()],
+            code: vec![false; rom.len()],
+            targets: BTreeMap::new(),
+            labels: BTreeSet::new(),
+        };
+
+        let mapping = match mapper {
+            SmsMemoryMapper::Codemasters => [Some(0), Some(1), Some(0)],
+            _ => [Some(0), Some(1), Some(2)],
+        };
+        for &entry in [0x0000, 0x0038, 0x0066].iter() {
+            if entry < rom.len() {
+                disassembly.trace(entry, mapping);
+                if disassembly.lengths[entry] != 0 {
+                    disassembly.labels.insert(entry);
+                }
+            }
+        }
+
+        let lengths = &disassembly.lengths;
+        let targets = disassembly.targets.values().filter(|&&t| lengths[t] != 0);
+        disassembly.labels.extend(targets);
+
+        disassembly
+    }
+
+    /// The number of 16 KiB banks in the ROM, including a last partial bank.
+    pub fn banks(&self) -> usize {
+        self.rom.len().div_ceil(BANK_SIZE)
+    }
+
+    /// Is the byte at `index` part of an instruction?
+    pub fn is_code(&self, index: usize) -> bool {
+        self.code.get(index).cloned().unwrap_or(false)
+    }
+
+    /// The instruction starting at `index`, if one does.
+    pub fn instruction(&self, index: usize) -> Option<Opcode> {
+        match self.lengths.get(index) {
+            Some(&len) if len != 0 => {
+                Some(Opcode::from_bytes(&self.rom[index..index + len as usize]))
+            }
+            _ => None,
+        }
+    }
+
+    /// The label of the instruction at `index`, if it has one.
+    ///
+    /// Labels are named by bank and logical address, so `B03_8123` is at
+    /// logical address `8123` in bank 3.
+    pub fn label(&self, index: usize) -> Option<String> {
+        if self.labels.contains(&index) {
+            Some(format!(
+                "B{:0>2X}_{:0>4X}",
+                index / BANK_SIZE,
+                logical_address(index)
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// The ROM index of logical address `address`, if `mapping` says where it
+    /// is and it's in the slot its bank is assembled for.
+    fn resolve(&self, mapping: &Mapping, address: u16) -> Option<usize> {
+        let slot = address as usize / BANK_SIZE;
+        if slot > 2 {
+            return None;
+        }
+        let bank = if self.mapper == SmsMemoryMapper::Sega && address < 0x400 {
+            // the first KiB is always the beginning of page 0
+            0
+        } else {
+            mapping[slot]?
+        };
+        let index = bank * BANK_SIZE + address as usize % BANK_SIZE;
+        if bank_slot(bank) == slot && index < self.rom.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// The instruction at `index`, if there's one there entirely in its bank.
+    fn decode(&self, index: usize) -> Option<Opcode> {
+        let end = min(self.rom.len(), (index / BANK_SIZE + 1) * BANK_SIZE);
+        Opcode::decode(&self.rom[index..end])
+    }
+
+    /// Decode all code reachable from `start`, where `mapping` is mapped.
+    fn trace(&mut self, start: usize, mapping: Mapping) {
+        use self::FullMnemonic::*;
+        use self::Mnemonic::*;
+        use self::Parameter::*;
+        use self::Reg8::A;
+
+        let banks = self.banks();
+        let mut paths = vec![(start, mapping)];
+        while let Some((mut index, mut mapping)) = paths.pop() {
+            // the value in A, if it was just loaded with a constant
+            let mut a: Option<u8> = None;
+            loop {
+                if self.code[index] {
+                    break;
+                }
+                let opcode = match self.decode(index) {
+                    Some(x) => x,
+                    None => break,
+                };
+                let len = opcode.len();
+                if self.code[index..index + len].iter().any(|&x| x) {
+                    break;
+                }
+                self.lengths[index] = len as u8;
+                for x in self.code[index..index + len].iter_mut() {
+                    *x = true;
+                }
+
+                let mnemonic = opcode.mnemonic().unwrap();
+                let pc = logical_address(index);
+                if let Some(target) = mnemonic.jump_target(pc) {
+                    if let Some(target_index) = self.resolve(&mapping, target) {
+                        self.targets.insert(index, target_index);
+                        paths.push((target_index, mapping));
+                    }
+                }
+
+                match mnemonic {
+                    TwoParameters(Ld, Reg8(A), U8(n)) => a = Some(n),
+                    TwoParameters(Ld, AddressU16(Address(nn)), Reg8(A)) => {
+                        if let Some(slot) = memory_register_slot(self.mapper, nn) {
+                            mapping[slot as usize] = a.map(|n| n as usize % banks);
+                        }
+                    }
+                    _ => a = None,
+                }
+
+                if ends_flow(mnemonic) {
+                    break;
+                }
+                index = match self.resolve(&mapping, pc.wrapping_add(len as u16)) {
+                    Some(x) => x,
+                    None => break,
+                };
+            }
+        }
+    }
+
+    /// The label for the target of the instruction at `index`, if it has one.
+    fn target_label(&self, index: usize) -> Option<String> {
+        self.targets.get(&index).and_then(|&t| self.label(t))
+    }
+
+    /// WLA-DX syntax for a parameter of an instruction at `index`.
+    fn parameter(&self, mnemonic: Mnemonic, parameter: Parameter, index: usize) -> String {
+        use self::Mnemonic::*;
+        use self::Parameter::*;
+        match (mnemonic, parameter) {
+            (Im, U8(n)) | (Bit, U8(n)) | (Set, U8(n)) | (Res, U8(n)) => format!("{}", n),
+            (Rst, U16(nn)) => format!("${:0>2X}", nn),
+            (Jp, U16(nn)) | (Call, U16(nn)) => self
+                .target_label(index)
+                .unwrap_or_else(|| format!("${:0>4X}", nn)),
+            (_, U8(n)) => format!("${:0>2X}", n),
+            (_, I8(e)) => format!("{}", e),
+            (_, U16(nn)) => format!("${:0>4X}", nn),
+            (_, AddressU16(Address(nn))) => format!("(${:0>4X})", nn),
+            (_, Shift(shift)) if shift.1 < 0 => {
+                format!("({}-${:0>2X})", shift.0, -(shift.1 as i16))
+            }
+            (_, Shift(shift)) => format!("({}+${:0>2X})", shift.0, shift.1),
+            (_, x) => format!("{}", x),
+        }
+    }
+
+    /// WLA-DX syntax for the instruction at `index`, or `None` if it should
+    /// be given as data.
+    fn instruction_source(&self, index: usize, mnemonic: FullMnemonic) -> Option<String> {
+        use self::FullMnemonic::*;
+        use self::Mnemonic::*;
+        use self::Parameter::*;
+        use self::Reg8::{A, C};
+
+        let parameter = |m, p| self.parameter(m, p, index);
+        let relative_label = || {
+            let target = *self.targets.get(&index)?;
+            if target / BANK_SIZE == index / BANK_SIZE {
+                self.label(target)
+            } else {
+                None
+            }
+        };
+
+        Some(match mnemonic {
+            ZeroParameters(m) => format!("{}", m),
+            OneParameter(Jp, Reg16(x)) => format!("jp ({})", x),
+            OneParameter(m @ Jr, I8(_)) | OneParameter(m @ Djnz, I8(_)) => {
+                format!("{} {}", m, relative_label()?)
+            }
+            TwoParameters(Jr, Cc(cc), I8(_)) => format!("jr {},{}", cc, relative_label()?),
+            OneParameter(m, p) => format!("{} {}", m, parameter(m, p)),
+            TwoParameters(In, Reg8(x), Reg8(C)) => format!("in {},(c)", x),
+            TwoParameters(In, Reg8(A), U8(n)) => format!("in a,(${:0>2X})", n),
+            TwoParameters(Out, Reg8(C), Reg8(x)) => format!("out (c),{}", x),
+            TwoParameters(Out, U8(n), Reg8(A)) => format!("out (${:0>2X}),a", n),
+            TwoParameters(m, p1, p2) => format!("{} {},{}", m, parameter(m, p1), parameter(m, p2)),
+            ThreeParameters(m, p1, p2, p3) => format!(
+                "{} {},{},{}",
+                m,
+                parameter(m, p1),
+                parameter(m, p2),
+                parameter(m, p3)
+            ),
+        })
+    }
+}
+
+/// Write `bytes` as a `.db` line, with `comment` if there is one.
+fn write_bytes(f: &mut fmt::Formatter, bytes: &[u8], comment: Option<FullMnemonic>) -> fmt::Result {
+    write!(f, "    .db ")?;
+    for (i, byte) in bytes.iter().enumerate() {
+        if i != 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "${:0>2X}", byte)?;
+    }
+    match comment {
+        Some(mnemonic) => writeln!(f, " ; {}", mnemonic),
+        None => writeln!(f),
+    }
+}
+
+impl<'a> Display for RomDisassembly<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let banks = self.banks();
+        let last_size = self.rom.len() - banks.saturating_sub(1) * BANK_SIZE;
+
+        writeln!(f, ".MEMORYMAP")?;
+        writeln!(f, "DEFAULTSLOT 0")?;
+        writeln!(f, "SLOTSIZE ${:0>4X}", BANK_SIZE)?;
+        for slot in 0..3 {
+            writeln!(f, "SLOT {} ${:0>4X}", slot, slot * BANK_SIZE)?;
+        }
+        writeln!(f, ".ENDME")?;
+        writeln!(f)?;
+        writeln!(f, ".ROMBANKMAP")?;
+        writeln!(f, "BANKSTOTAL {}", banks)?;
+        if banks > 1 {
+            writeln!(f, "BANKSIZE ${:0>4X}", BANK_SIZE)?;
+            writeln!(f, "BANKS {}", banks - 1)?;
+        }
+        if banks > 0 {
+            writeln!(f, "BANKSIZE ${:0>4X}", last_size)?;
+            writeln!(f, "BANKS 1")?;
+        }
+        writeln!(f, ".ENDRO")?;
+
+        for bank in 0..banks {
+            writeln!(f)?;
+            writeln!(f, ".BANK {} SLOT {}", bank, bank_slot(bank))?;
+            writeln!(f, ".ORG $0000")?;
+
+            let end = min(self.rom.len(), (bank + 1) * BANK_SIZE);
+            let mut index = bank * BANK_SIZE;
+            while index < end {
+                if let Some(label) = self.label(index) {
+                    writeln!(f)?;
+                    writeln!(f, "{}:", label)?;
+                }
+                match self.instruction(index) {
+                    Some(opcode) => {
+                        let mnemonic = opcode.mnemonic().unwrap();
+                        let source = if opcode.documented() && mnemonic.opcode() == Some(opcode) {
+                            self.instruction_source(index, mnemonic)
+                        } else {
+                            None
+                        };
+                        match source {
+                            Some(source) => writeln!(f, "    {}", source)?,
+                            None => write_bytes(f, opcode.bytes(), Some(mnemonic))?,
+                        }
+                        index += opcode.len();
+                    }
+                    None => {
+                        let data_end = (index..min(end, index + 16))
+                            .find(|&i| self.code[i])
+                            .unwrap_or_else(|| min(end, index + 16));
+                        write_bytes(f, &self.rom[index..data_end], None)?;
+                        index = data_end;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
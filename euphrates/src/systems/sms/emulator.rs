@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::io;
 use std::marker::PhantomData;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -6,8 +7,9 @@ use std::time::Instant;
 
 use failure::Error;
 
-use host_multimedia::SimpleAudio;
-use memo::Inbox;
+use hardware::sn76489::FakeSn76489;
+use host_multimedia::{FakeAudio, FrameView, HasLastFrame, IndexedFrameView, SimpleAudio};
+use memo::{Inbox, NothingInbox};
 use utilities;
 
 use super::*;
@@ -16,7 +18,11 @@ pub const NTSC_Z80_FREQUENCY: u64 = 10738580 / 3;
 
 pub const PAL_Z80_FREQUENCY: u64 = 10640685 / 3;
 
-#[derive(Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+/// A system's complete state.
+///
+/// It serializes with a version tag, so that states saved by older versions
+/// can still be loaded; see `SMS_STATE_VERSION`.
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct SmsState {
     pub z80: Z80State,
     pub vdp: SmsVdpState,
@@ -50,6 +56,9 @@ impl SmsState {
         };
         state.vdp.set_tv_system(tv_system);
         state.vdp.set_kind(vdp_kind);
+        if vdp_kind == Kind::Gg {
+            state.z80.set_variant(Z80Variant::Cmos);
+        }
 
         // it seems many BIOSes leave SP at this value
         state.z80.set_reg16(Reg16::SP, 0xDFEE);
@@ -60,6 +69,114 @@ impl SmsState {
     }
 }
 
+/// The version of the format `SmsState` is serialized in.
+///
+/// Version 0 had no tag, and its `Z80State` ended with `interrupt_status`.
+/// Version 1 added `memptr`, `variant`, `q`, `flags_written`, and `ld_a_ir`.
+/// Deserializing a version 0 state gives those fields their defaults.
+pub const SMS_STATE_VERSION: u32 = 1;
+
+/// Begins every tagged `SmsState`, followed by the version.
+///
+/// A version 0 state begins with the Z80's cycle count instead, which would
+/// take centuries of emulation to reach this value.
+const SMS_STATE_MAGIC: u64 = 0x5441_5453_4850_5545; // "EUPHSTAT" in little-endian
+
+mod sms_state_serde {
+    use std::fmt;
+
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::ser::SerializeTuple;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::*;
+
+    impl Serialize for SmsState {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut tuple = serializer.serialize_tuple(8)?;
+            tuple.serialize_element(&SMS_STATE_MAGIC)?;
+            tuple.serialize_element(&SMS_STATE_VERSION)?;
+            tuple.serialize_element(&self.z80)?;
+            tuple.serialize_element(&self.vdp)?;
+            tuple.serialize_element(&self.memory)?;
+            tuple.serialize_element(&self.player_input)?;
+            tuple.serialize_element(&self.pause_irq)?;
+            tuple.serialize_element(&self.sn76489)?;
+            tuple.end()
+        }
+    }
+
+    fn next<'de, A, T>(seq: &mut A) -> Result<T, A::Error>
+    where
+        A: SeqAccess<'de>,
+        T: Deserialize<'de>,
+    {
+        seq.next_element()?
+            .ok_or_else(|| A::Error::custom("SmsState ended early"))
+    }
+
+    struct SmsStateVisitor;
+
+    impl<'de> Visitor<'de> for SmsStateVisitor {
+        type Value = SmsState;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an SmsState")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<SmsState, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let first: u64 = next(&mut seq)?;
+            let z80 = if first == SMS_STATE_MAGIC {
+                let version: u32 = next(&mut seq)?;
+                if version != SMS_STATE_VERSION {
+                    return Err(A::Error::custom(format!(
+                        "unknown SmsState version {} (expected {})",
+                        version, SMS_STATE_VERSION
+                    )));
+                }
+                next(&mut seq)?
+            } else {
+                // version 0, where `first` is the Z80's cycle count
+                let mut z80 = Z80State::default();
+                z80.cycles = first;
+                z80.registers = next(&mut seq)?;
+                z80.halted = next(&mut seq)?;
+                z80.iff1 = next(&mut seq)?;
+                z80.iff2 = next(&mut seq)?;
+                z80.prefix = next(&mut seq)?;
+                z80.interrupt_mode = next(&mut seq)?;
+                z80.interrupt_status = next(&mut seq)?;
+                z80
+            };
+            Ok(SmsState {
+                z80,
+                vdp: next(&mut seq)?,
+                memory: next(&mut seq)?,
+                player_input: next(&mut seq)?,
+                pause_irq: next(&mut seq)?,
+                sn76489: next(&mut seq)?,
+            })
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SmsState {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            // long enough for either version; a version 0 state has the Z80's
+            // 8 fields inline, followed by the other 5 fields
+            deserializer.deserialize_tuple(13, SmsStateVisitor)
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct TimeStatus {
     /// Any time before now
@@ -85,7 +202,28 @@ impl TimeStatus {
     }
 }
 
-#[derive(Clone)]
+/// Runs a Master System's Z80 in place of the interpreter, `Z80RunImpler`.
+///
+/// The recompiler in the `euphrates_recompiler` crate is one. Give it to a
+/// system with `Sms::set_z80_runner`.
+pub trait SmsZ80Runner {
+    /// Run instructions until `z80` has at least `target_cycles` cycles,
+    /// exactly as `Z80RunImpler::run` would.
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    );
+
+    /// Memory may have been changed by something other than `run`, so forget
+    /// anything remembered about its contents.
+    fn invalidate(&mut self);
+}
+
 struct SmsS<Graphics, Audio, Sn76489, Mem, Inx> {
     z80: Z80State,
     vdp: SmsVdpState,
@@ -97,6 +235,7 @@ struct SmsS<Graphics, Audio, Sn76489, Mem, Inx> {
     sn76489: Sn76489,
     time_status: TimeStatus,
     inbox: Inx,
+    z80_runner: Option<Box<dyn SmsZ80Runner + Send>>,
 }
 
 pub trait Sms {
@@ -110,25 +249,74 @@ pub trait Sms {
 
     fn memory(&mut self) -> &mut dyn Memory16;
 
+    fn sms_memory(&self) -> &dyn SmsMemory;
+
     fn debugger(&mut self) -> Option<&mut dyn Debugger>;
 
     fn run_frame(&mut self, player_input: SmsPlayerInput) -> Result<(), SmsEmulationError>;
 
+    /// Like `run_frame`, but don't draw anything.
+    ///
+    /// VDP timing, status flags, and interrupts are emulated exactly, so this
+    /// is indistinguishable from `run_frame` to the emulated system. Use it
+    /// when only some frames need to be seen, such as all but the last frame
+    /// of each step in an `SmsEnvironment`.
+    fn run_frame_without_rendering(
+        &mut self,
+        player_input: SmsPlayerInput,
+    ) -> Result<(), SmsEmulationError>;
+
+    /// Like `run_frame`, but call `after_instruction` each time the Z80
+    /// executes an instruction, handles an interrupt, or spends a cycle halted.
+    ///
+    /// The Z80 is run one cycle past where it is each time, so it stops as
+    /// soon as it can, which for a prefixed instruction may be after reading
+    /// just a prefix byte. `after_instruction` is called then too, with the
+    /// prefix recorded in the Z80 state and the instruction not yet executed.
+    ///
+    /// If `after_instruction` returns false, stop immediately, in the middle of
+    /// the frame. Returns true if the frame was finished.
+    fn run_frame_instructions(
+        &mut self,
+        player_input: SmsPlayerInput,
+        after_instruction: &mut dyn FnMut(&dyn Sms) -> bool,
+    ) -> Result<bool, SmsEmulationError>;
+
+    /// The last frame completed by `run_frame`, if the graphics implementation
+    /// keeps it (as `FrameBuffer` does).
+    fn last_frame(&self) -> Option<FrameView<'_>>;
+
+    /// The palette indices and layers of the last frame completed by
+    /// `run_frame`, if the graphics implementation keeps them.
+    fn last_indexed_frame(&self) -> Option<IndexedFrameView<'_>>;
+
+    /// The sprites in the VDP's sprite attribute table.
+    fn sprites(&self) -> Vec<SpriteObservation>;
+
     fn state(&self) -> SmsState;
 
+    /// Return to `state`, without reallocating memory or reopening graphics or
+    /// audio.
+    fn restore_state(&mut self, state: &SmsState) -> Result<(), SmsMemoryLoadError>;
+
     fn hold(&mut self) -> Result<(), SmsEmulationError>;
 
     fn resume(&mut self) -> Result<(), SmsEmulationError>;
+
+    /// Run the Z80 with `runner` instead of the interpreter, or with the
+    /// interpreter again if `runner` is `None`.
+    fn set_z80_runner(&mut self, runner: Option<Box<dyn SmsZ80Runner + Send>>);
 }
 
 impl<Graphics, Audio, Sn76489, Mem, Inx> Sms for SmsS<Graphics, Audio, Sn76489, Mem, Inx>
 where
     for<'a> SmsVdpGraphicsImpler<'a, SmsVdpState, Graphics>: SmsVdpLineImpler,
+    Graphics: HasLastFrame,
     Audio: SimpleAudio,
     Sn76489: Sn76489Interface + HasSn76489State,
     for<'a> Sn76489Impler<'a, Sn76489, Audio>: Sn76489Audio,
     Inx: Inbox<Memo = Z80Memo> + GetDebugger,
-    Mem: Memory16 + SmsMemory,
+    Mem: Memory16 + SmsMemory + SmsMemoryLoad,
 {
     fn z80(&self) -> &dyn Z80Internal {
         &self.z80
@@ -147,16 +335,55 @@ where
     }
 
     fn memory(&mut self) -> &mut dyn Memory16 {
+        // the runner won't see writes through the returned reference
+        if let Some(ref mut runner) = self.z80_runner {
+            runner.invalidate();
+        }
         &mut self.memory
     }
 
+    fn sms_memory(&self) -> &dyn SmsMemory {
+        &self.memory
+    }
+
     fn debugger(&mut self) -> Option<&mut dyn Debugger> {
         self.inbox.debugger()
     }
 
     fn run_frame(&mut self, player_input: SmsPlayerInput) -> Result<(), SmsEmulationError> {
         self.player_input = player_input;
-        run_frame(self)
+        run_frame(self, true, None)?;
+        Ok(())
+    }
+
+    fn run_frame_without_rendering(
+        &mut self,
+        player_input: SmsPlayerInput,
+    ) -> Result<(), SmsEmulationError> {
+        self.player_input = player_input;
+        run_frame(self, false, None)?;
+        Ok(())
+    }
+
+    fn run_frame_instructions(
+        &mut self,
+        player_input: SmsPlayerInput,
+        after_instruction: &mut dyn FnMut(&dyn Sms) -> bool,
+    ) -> Result<bool, SmsEmulationError> {
+        self.player_input = player_input;
+        run_frame(self, true, Some(&mut |sms: &Self| after_instruction(sms)))
+    }
+
+    fn last_frame(&self) -> Option<FrameView<'_>> {
+        self.graphics.last_frame()
+    }
+
+    fn last_indexed_frame(&self) -> Option<IndexedFrameView<'_>> {
+        self.graphics.last_indexed_frame()
+    }
+
+    fn sprites(&self) -> Vec<SpriteObservation> {
+        sms_vdp::sprites(&self.vdp)
     }
 
     fn state(&self) -> SmsState {
@@ -170,6 +397,25 @@ where
         }
     }
 
+    fn restore_state(&mut self, state: &SmsState) -> Result<(), SmsMemoryLoadError> {
+        self.memory.load_in_place(&state.memory)?;
+        self.z80 = state.z80;
+        self.vdp = state.vdp;
+        self.player_input = state.player_input;
+        self.pause_irq = state.pause_irq;
+        self.sn76489 = Sn76489::load(state.sn76489);
+        if let Some(ref mut runner) = self.z80_runner {
+            runner.invalidate();
+        }
+
+        // we don't want time governing to try to catch up to or wait for the
+        // cycles of the restored state
+        self.time_status.start_time = Instant::now();
+        self.time_status.start_cycles = self.z80.cycles();
+
+        Ok(())
+    }
+
     fn hold(&mut self) -> Result<(), SmsEmulationError> {
         if let Some(d) = self.inbox.debugger() {
             d.command(Command::Hold);
@@ -202,6 +448,10 @@ where
 
         Ok(())
     }
+
+    fn set_z80_runner(&mut self, runner: Option<Box<dyn SmsZ80Runner + Send>>) {
+        self.z80_runner = runner;
+    }
 }
 
 #[derive(Debug)]
@@ -224,6 +474,7 @@ pub fn new_sms<Graphics: 'static, Audio: 'static, Sn76489: 'static, Memory: 'sta
 ) -> Result<Box<dyn Sms>, SmsCreationError>
 where
     for<'a> SmsVdpGraphicsImpler<'a, SmsVdpState, Graphics>: SmsVdpLineImpler,
+    Graphics: HasLastFrame,
     Audio: SimpleAudio,
     Sn76489: Sn76489Interface + HasSn76489State,
     for<'a> Sn76489Impler<'a, Sn76489, Audio>: Sn76489Audio,
@@ -243,6 +494,38 @@ where
         memory: <Memory as SmsMemoryLoad>::load(state.memory)?,
         z80: state.z80,
         sn76489: Sn76489::load(state.sn76489),
+        z80_runner: None,
+    }))
+}
+
+/// Create a Master System with no sound, no time governing, and no debugging,
+/// which can be sent to another thread.
+///
+/// This is what to use when running many systems in parallel. Use
+/// `FakeSmsGraphics` for `graphics` if pixels aren't needed, or `FrameBuffer`
+/// if they are.
+pub fn new_headless_sms<Graphics, Memory>(
+    state: SmsState,
+    graphics: Graphics,
+    _mem: TypeWrap<Memory>,
+) -> Result<Box<dyn Sms + Send>, SmsCreationError>
+where
+    for<'a> SmsVdpGraphicsImpler<'a, SmsVdpState, Graphics>: SmsVdpLineImpler,
+    Graphics: HasLastFrame + Send + 'static,
+    Memory: SmsMemory + SmsMemoryLoad + Send + 'static,
+{
+    Ok(Box::new(SmsS {
+        graphics,
+        audio: FakeAudio,
+        inbox: NothingInbox::<Z80Memo>::default(),
+        time_status: TimeStatus::new(state.z80.cycles(), None),
+        player_input: state.player_input,
+        pause_irq: state.pause_irq,
+        vdp: state.vdp,
+        memory: <Memory as SmsMemoryLoad>::load(state.memory)?,
+        z80: state.z80,
+        sn76489: FakeSn76489,
+        z80_runner: None,
     }))
 }
 
@@ -261,6 +544,10 @@ mod sms_emulation_error {
         AudioError(Error),
         #[fail(display = "Graphics Error {}", _0)]
         GraphicsError(#[cause] SmsVdpGraphicsError),
+        #[fail(display = "Memory Load Error {}", _0)]
+        MemoryLoadError(#[cause] SmsMemoryLoadError),
+        #[fail(display = "IO Error {}", _0)]
+        IoError(#[cause] io::Error),
     }
 }
 
@@ -272,6 +559,18 @@ impl From<SmsVdpGraphicsError> for SmsEmulationError {
     }
 }
 
+impl From<SmsMemoryLoadError> for SmsEmulationError {
+    fn from(x: SmsMemoryLoadError) -> Self {
+        SmsEmulationError::MemoryLoadError(x)
+    }
+}
+
+impl From<io::Error> for SmsEmulationError {
+    fn from(x: io::Error) -> Self {
+        SmsEmulationError::IoError(x)
+    }
+}
+
 // This superfluous module with the `allow` attribute is necessary until the
 // `fail` crate begins using `dyn trait` syntax
 #[allow(bare_trait_objects)]
@@ -302,9 +601,15 @@ impl From<SmsMemoryLoadError> for SmsCreationError {
     }
 }
 
+/// Called by `run_frame` after each instruction, if given; see
+/// `Sms::run_frame_instructions`.
+type AfterInstruction<'a, S> = Option<&'a mut dyn FnMut(&S) -> bool>;
+
 fn run_frame<Graphics, Audio, Sn76489, Mem, Inx>(
     sms: &mut SmsS<Graphics, Audio, Sn76489, Mem, Inx>,
-) -> Result<(), SmsEmulationError>
+    render: bool,
+    mut after_instruction: AfterInstruction<'_, SmsS<Graphics, Audio, Sn76489, Mem, Inx>>,
+) -> Result<bool, SmsEmulationError>
 where
     for<'a> SmsVdpGraphicsImpler<'a, SmsVdpState, Graphics>: SmsVdpLineImpler,
     Audio: SimpleAudio,
@@ -317,10 +622,14 @@ where
 
     loop {
         while 3 * sms.z80.cycles() >= 2 * sms.vdp.cycles() {
-            sms_vdp::line(&mut SmsVdpGraphicsImpler {
-                graphics: &mut sms.graphics,
-                vdp: &mut sms.vdp,
-            })?;
+            if render {
+                sms_vdp::line(&mut SmsVdpGraphicsImpler {
+                    graphics: &mut sms.graphics,
+                    vdp: &mut sms.vdp,
+                })?;
+            } else {
+                sms_vdp::line_without_drawing(&mut sms.vdp);
+            }
         }
         let z80_target_cycles = (sms.vdp.cycles() * 2) / 3;
         while sms.z80.cycles() < z80_target_cycles {
@@ -328,28 +637,53 @@ where
                 use std::thread;
                 use std::time::Duration;
                 thread::sleep(Duration::from_millis(10));
-                return Ok(());
+                return Ok(false);
             }
 
-            // use a trait object for this to cut down on code bloat
-            let sn76489: &mut dyn Sn76489Interface = &mut sms.sn76489;
-            let rc_vdp = Rc::new(RefCell::new(&mut sms.vdp));
-            let irq = &mut SmsZ80IrqImpler {
-                pause_interrupt: &mut sms.pause_irq,
-                vdp: rc_vdp.clone(),
+            // run one instruction at a time only if someone is watching
+            let target_cycles = if after_instruction.is_some() {
+                sms.z80.cycles() + 1
+            } else {
+                z80_target_cycles
             };
-            let io = &mut SmsIo16Impler {
-                vdp: rc_vdp,
-                player_input: sms.player_input,
-                sn76489,
-            };
-            Z80RunImpler {
-                z80: &mut sms.z80,
-                memory: &mut sms.memory,
-                inbox: &mut sms.inbox,
-                irq,
-                io,
-            }.run(z80_target_cycles);
+
+            {
+                // use a trait object for this to cut down on code bloat
+                let sn76489: &mut dyn Sn76489Interface = &mut sms.sn76489;
+                let rc_vdp = Rc::new(RefCell::new(&mut sms.vdp));
+                let irq = &mut SmsZ80IrqImpler {
+                    pause_interrupt: &mut sms.pause_irq,
+                    vdp: rc_vdp.clone(),
+                };
+                let io = &mut SmsIo16Impler {
+                    vdp: rc_vdp,
+                    player_input: sms.player_input,
+                    sn76489,
+                };
+                match sms.z80_runner {
+                    Some(ref mut runner) => runner.run(
+                        &mut sms.z80,
+                        &mut sms.memory,
+                        io,
+                        irq,
+                        &mut sms.inbox,
+                        target_cycles,
+                    ),
+                    None => Z80RunImpler {
+                        z80: &mut sms.z80,
+                        memory: &mut sms.memory,
+                        inbox: &mut sms.inbox,
+                        irq,
+                        io,
+                    }.run(target_cycles),
+                }
+            }
+
+            if let Some(ref mut f) = after_instruction {
+                if !f(sms) {
+                    return Ok(false);
+                }
+            }
         }
         if sms.vdp.v() == 0 {
             // we've just finished a frame
@@ -374,7 +708,7 @@ where
                 );
             }
 
-            return Ok(());
+            return Ok(true);
         }
     }
 }
@@ -0,0 +1,266 @@
+//! A reinforcement learning environment in the style of OpenAI Gym.
+
+use super::*;
+
+/// The discrete set of actions available to an agent.
+///
+/// An action is an index into this set.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ActionSpace {
+    actions: Vec<SmsPlayerInput>,
+}
+
+impl ActionSpace {
+    #[inline]
+    pub fn new(actions: Vec<SmsPlayerInput>) -> Self {
+        ActionSpace { actions }
+    }
+
+    /// Every combination of at most one direction on Joypad 1 with any subset
+    /// of its two buttons.
+    ///
+    /// This is 20 actions, with index 0 pressing nothing.
+    pub fn joypad1() -> Self {
+        use self::joypad_a_bits::*;
+        let directions = [0, JOYPAD1_UP, JOYPAD1_DOWN, JOYPAD1_LEFT, JOYPAD1_RIGHT];
+        let buttons = [0, JOYPAD1_A, JOYPAD1_B, JOYPAD1_A | JOYPAD1_B];
+        let mut actions = Vec::with_capacity(directions.len() * buttons.len());
+        for &button in buttons.iter() {
+            for &direction in directions.iter() {
+                let mut input = SmsPlayerInput::default();
+                input.set_joypad_a(!(button | direction));
+                actions.push(input);
+            }
+        }
+        ActionSpace { actions }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    #[inline]
+    pub fn get(&self, action: usize) -> Option<SmsPlayerInput> {
+        self.actions.get(action).cloned()
+    }
+
+    #[inline]
+    pub fn actions(&self) -> &[SmsPlayerInput] {
+        &self.actions
+    }
+}
+
+impl Default for ActionSpace {
+    #[inline]
+    fn default() -> Self {
+        ActionSpace::joypad1()
+    }
+}
+
+/// Extra information returned by `SmsEnvironment::step`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct StepInfo {
+    /// Frames emulated since the last `reset`.
+    pub frame: u64,
+
+    /// Z80 cycles emulated during this step.
+    pub cycles: u64,
+}
+
+pub type RewardFunction = Box<dyn FnMut(&mut dyn Sms) -> f64 + Send>;
+
+pub type DoneFunction = Box<dyn FnMut(&mut dyn Sms) -> bool + Send>;
+
+/// A headless Master System to be driven by an agent.
+///
+/// Each `step` emulates `frames_per_step` frames with the chosen action held
+/// down, and then returns the state of the system as the observation. There is
+/// no sound, no graphics, and no time governing.
+///
+/// The observation is a copy of the whole `SmsState`: the ROM is shared, but
+/// RAM, VRAM, and everything else are copied on every step. An agent that
+/// only needs part of the state can ignore the observation and look at
+/// `master_system` instead.
+///
+/// An `SmsEnvironment` can be sent between threads; see `BatchEnvironment`.
+pub struct SmsEnvironment {
+    sms: Box<dyn Sms + Send>,
+    initial_state: SmsState,
+    action_space: ActionSpace,
+    frames_per_step: u32,
+    frame: u64,
+    reward: RewardFunction,
+    done: DoneFunction,
+    monitor: Option<GameMonitor>,
+}
+
+impl SmsEnvironment {
+    /// Create an environment that starts each episode at `initial_state`.
+    ///
+    /// By default, the action space is `ActionSpace::joypad1()`, each step is
+    /// one frame, the reward is always 0, and episodes never finish.
+    pub fn new<Memory>(
+        initial_state: SmsState,
+        _mem: TypeWrap<Memory>,
+    ) -> Result<Self, SmsCreationError>
+    where
+        Memory: SmsMemory + SmsMemoryLoad + Send + 'static,
+    {
+        let sms = new_headless_sms(
+            initial_state.clone(),
+            FakeSmsGraphics,
+            TypeWrap::<Memory>::default(),
+        )?;
+        Ok(SmsEnvironment {
+            sms,
+            initial_state,
+            action_space: Default::default(),
+            frames_per_step: 1,
+            frame: 0,
+            reward: Box::new(|_| 0.0),
+            done: Box::new(|_| false),
+            monitor: None,
+        })
+    }
+
+    #[inline]
+    pub fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    #[inline]
+    pub fn set_action_space(&mut self, action_space: ActionSpace) {
+        self.action_space = action_space;
+    }
+
+    #[inline]
+    pub fn frames_per_step(&self) -> u32 {
+        self.frames_per_step
+    }
+
+    /// How many frames should each step emulate?
+    ///
+    /// Panics if `frames` is 0.
+    #[inline]
+    pub fn set_frames_per_step(&mut self, frames: u32) {
+        assert!(frames > 0, "a step must emulate at least one frame");
+        self.frames_per_step = frames;
+    }
+
+    /// Set the function computing the reward at the end of each step.
+    #[inline]
+    pub fn set_reward_function(&mut self, reward: RewardFunction) {
+        self.reward = reward;
+    }
+
+    /// Set the function deciding whether the episode is over at the end of
+    /// each step.
+    #[inline]
+    pub fn set_done_function(&mut self, done: DoneFunction) {
+        self.done = done;
+    }
+
+    /// Use a `GameMonitor` to compute the reward and whether the episode is
+    /// over, instead of the reward and done functions.
+    ///
+    /// The monitor reads its variables once at the end of each step, not each
+    /// frame, so with more than one frame per step, `prev` and `delta` in its
+    /// expressions refer to the whole step.
+    pub fn set_game_monitor(&mut self, monitor: Option<GameMonitor>) {
+        self.monitor = monitor;
+        if let Some(ref mut m) = self.monitor {
+            m.reset(self.sms.sms_memory());
+        }
+    }
+
+    #[inline]
+    pub fn game_monitor(&self) -> Option<&GameMonitor> {
+        self.monitor.as_ref()
+    }
+
+    #[inline]
+    pub fn initial_state(&self) -> &SmsState {
+        &self.initial_state
+    }
+
+    #[inline]
+    pub fn set_initial_state(&mut self, state: SmsState) {
+        self.initial_state = state;
+    }
+
+    #[inline]
+    pub fn master_system(&self) -> &dyn Sms {
+        &*self.sms
+    }
+
+    #[inline]
+    pub fn master_system_mut(&mut self) -> &mut dyn Sms {
+        &mut *self.sms
+    }
+
+    /// Start a new episode from the initial state, returning the first
+    /// observation.
+    pub fn reset(&mut self) -> Result<SmsState, SmsMemoryLoadError> {
+        self.sms.restore_state(&self.initial_state)?;
+        self.frame = 0;
+        if let Some(ref mut m) = self.monitor {
+            m.reset(self.sms.sms_memory());
+        }
+        Ok(self.initial_state.clone())
+    }
+
+    /// Emulate one step holding down the action with index `action` in the
+    /// action space.
+    ///
+    /// Returns the observation, reward, whether the episode is done, and extra
+    /// information.
+    ///
+    /// Panics if `action` is not in the action space.
+    pub fn step(
+        &mut self,
+        action: usize,
+    ) -> Result<(SmsState, f64, bool, StepInfo), SmsEmulationError> {
+        let player_input = match self.action_space.get(action) {
+            Some(x) => x,
+            None => panic!(
+                "action {} not in action space of size {}",
+                action,
+                self.action_space.len()
+            ),
+        };
+        self.step_input(player_input)
+    }
+
+    /// Like `step`, but with arbitrary player input rather than an action from
+    /// the action space.
+    pub fn step_input(
+        &mut self,
+        player_input: SmsPlayerInput,
+    ) -> Result<(SmsState, f64, bool, StepInfo), SmsEmulationError> {
+        let start_cycles = self.sms.z80().cycles();
+        // only the last frame of the step is observed
+        for _ in 1..self.frames_per_step {
+            self.sms.run_frame_without_rendering(player_input)?;
+        }
+        self.sms.run_frame(player_input)?;
+        self.frame += self.frames_per_step as u64;
+        let (reward, done) = match self.monitor {
+            Some(ref mut m) => {
+                m.update(self.sms.sms_memory());
+                (m.reward(), m.done())
+            }
+            None => ((self.reward)(&mut *self.sms), (self.done)(&mut *self.sms)),
+        };
+        let info = StepInfo {
+            frame: self.frame,
+            cycles: self.sms.z80().cycles() - start_cycles,
+        };
+        Ok((self.sms.state(), reward, done, info))
+    }
+}
@@ -0,0 +1,377 @@
+use memo::Inbox;
+
+use super::*;
+
+/// A jump back at most this many bytes may be to the head of an idle loop.
+const MAX_LOOP_BYTES: u16 = 64;
+
+/// We stop watching an iteration of a loop after this many steps.
+const MAX_LOOP_STEPS: usize = 32;
+
+/// What an iteration of a loop read.
+#[derive(Clone, Debug, Default)]
+struct Reads {
+    memory: Vec<(u16, u8)>,
+    io: Vec<(u16, u8)>,
+
+    /// Did the iteration write memory or IO, or read IO with side effects?
+    impure: bool,
+}
+
+/// Memory that records what's read from it and whether it's written.
+struct RecordingMemory<'a, M: 'a + ?Sized> {
+    memory: &'a mut M,
+    reads: &'a mut Vec<(u16, u8)>,
+    impure: bool,
+}
+
+impl<'a, M> Memory16 for RecordingMemory<'a, M>
+where
+    M: 'a + Memory16 + ?Sized,
+{
+    #[inline]
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.memory.read(address);
+        self.reads.push((address, value));
+        value
+    }
+
+    #[inline]
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory.write(address, value);
+        self.impure = true;
+    }
+
+    #[inline]
+    fn rom_index(&self, address: u16) -> Option<usize> {
+        self.memory.rom_index(address)
+    }
+}
+
+/// IO that records what's input and whether anything had side effects.
+struct RecordingIo<'a, I: 'a + ?Sized> {
+    io: &'a mut I,
+    reads: &'a mut Vec<(u16, u8)>,
+    impure: bool,
+}
+
+impl<'a, I> Io16 for RecordingIo<'a, I>
+where
+    I: 'a + Io16 + ?Sized,
+{
+    #[inline]
+    fn input(&mut self, address: u16) -> u8 {
+        let peeked = self.io.peek(address);
+        let value = self.io.input(address);
+        if peeked != Some(value) {
+            self.impure = true;
+        }
+        self.reads.push((address, value));
+        value
+    }
+
+    #[inline]
+    fn output(&mut self, address: u16, value: u8) {
+        self.io.output(address, value);
+        self.impure = true;
+    }
+
+    #[inline]
+    fn peek(&mut self, address: u16) -> Option<u8> {
+        self.io.peek(address)
+    }
+}
+
+/// `z80` with its cycles and R zeroed, since those are all an iteration of an
+/// idle loop may change.
+fn normalized(z80: &Z80State) -> Z80State {
+    let mut state = *z80;
+    state.set_cycles(0);
+    state.set_reg8(Reg8::R, 0);
+    state
+}
+
+/// An iteration of a loop we're watching, begun at its head.
+#[derive(Clone, Debug)]
+struct Probe {
+    start: Z80State,
+    steps: usize,
+    reads: Reads,
+}
+
+impl Probe {
+    fn new(z80: &Z80State) -> Self {
+        Probe {
+            start: *z80,
+            steps: 0,
+            reads: Reads::default(),
+        }
+    }
+
+    /// The loop is back at its head in `z80`. Was that iteration idle?
+    fn finish(self, z80: &Z80State) -> Option<IdleLoop> {
+        let state = normalized(&self.start);
+        if self.reads.impure || normalized(z80) != state {
+            return None;
+        }
+        Some(IdleLoop {
+            state,
+            reads: self.reads,
+            cycles: z80.cycles() - self.start.cycles(),
+            r: z80.reg8(Reg8::R).wrapping_sub(self.start.reg8(Reg8::R)) & 0x7F,
+        })
+    }
+}
+
+/// A loop one of whose iterations was idle.
+///
+/// Another iteration will be too if it starts from `state` (ignoring cycles
+/// and R) and reading memory and IO would give the same `reads` without side
+/// effects. It will take `cycles` cycles and increment R by `r`.
+#[derive(Clone, Debug)]
+struct IdleLoop {
+    state: Z80State,
+    reads: Reads,
+    cycles: u64,
+    r: u8,
+}
+
+impl IdleLoop {
+    fn applies<M, I>(&self, z80: &Z80State, memory: &mut M, io: &mut I) -> bool
+    where
+        M: Memory16 + ?Sized,
+        I: Io16 + ?Sized,
+    {
+        z80.reg16(Reg16::PC) == self.state.reg16(Reg16::PC)
+            && normalized(z80) == self.state
+            && self.reads.memory.iter().all(|&(a, x)| memory.read(a) == x)
+            && self.reads.io.iter().all(|&(a, x)| io.peek(a) == Some(x))
+    }
+}
+
+/// An interpreter that fast-forwards through loops that only wait.
+///
+/// Many games wait for the VDP in short loops that poll its status or V
+/// counter, or a flag in RAM set by an interrupt handler. Each time a short
+/// loop jumps back to its head, an `IdleLoopSkipper` watches the next
+/// iteration. If it wrote nothing, read IO only where that has no side effects
+/// (see `Io16::peek`), and came back to the head with nothing changed but
+/// cycles and R, every following iteration will do exactly the same until
+/// something outside the Z80 changes. On the Master System that only happens
+/// between calls to `run`, when the VDP draws a line, so the
+/// `IdleLoopSkipper` adds the cycles of all the whole iterations that fit
+/// before the target at once. Each time the loop comes back around, it checks
+/// again that memory and IO still read the same.
+///
+/// The resulting state is exactly what the usual interpreter would reach.
+/// Interrupts, `halt`, and everything under a tracing inbox are left to the
+/// usual interpreter.
+#[derive(Clone, Debug, Default)]
+pub struct IdleLoopSkipper {
+    idle_loop: Option<IdleLoop>,
+    skipped_cycles: u64,
+}
+
+impl IdleLoopSkipper {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// How many cycles have been skipped in all?
+    #[inline]
+    pub fn skipped_cycles(&self) -> u64 {
+        self.skipped_cycles
+    }
+
+    /// Forget the latest idle loop.
+    pub fn invalidate(&mut self) {
+        self.idle_loop = None;
+    }
+
+    fn run<M, I, Irq, Inb>(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut M,
+        io: &mut I,
+        irq: &mut Irq,
+        inbox: &mut Inb,
+        target_cycles: u64,
+    ) where
+        M: Memory16 + ?Sized,
+        I: Io16 + ?Sized,
+        Irq: Z80Irq + ?Sized,
+        Inb: Inbox<Memo = Z80Memo> + ?Sized,
+    {
+        if z80.interrupt_status() == InterruptStatus::NoCheck {
+            z80.set_interrupt_status(InterruptStatus::Check);
+        }
+
+        let mut probe: Option<Probe> = None;
+
+        while z80.cycles() < target_cycles {
+            if inbox.holding() {
+                return;
+            }
+
+            let plain = z80.interrupt_status() == InterruptStatus::NoCheck
+                && z80.prefix() != Prefix::Halt
+                && !inbox.tracing();
+            if !plain {
+                probe = None;
+                Z80RunImpler {
+                    z80: &mut *z80,
+                    memory: &mut *memory,
+                    io: &mut *io,
+                    irq: &mut *irq,
+                    inbox: &mut *inbox,
+                }.step(target_cycles);
+                continue;
+            }
+
+            let pc = z80.reg16(Reg16::PC);
+            let at_instruction = z80.prefix() == Prefix::NoPrefix;
+
+            if at_instruction {
+                let back_at_head = match probe {
+                    Some(ref p) => p.steps > 0 && p.start.reg16(Reg16::PC) == pc,
+                    None => false,
+                };
+                if back_at_head {
+                    if let Some(idle_loop) = probe.take().and_then(|p| p.finish(z80)) {
+                        self.idle_loop = Some(idle_loop);
+                    }
+                    // and maybe the next iteration will be idle
+                    probe = Some(Probe::new(z80));
+                }
+
+                let iterations = match self.idle_loop {
+                    Some(ref l) if l.applies(z80, memory, io) => {
+                        let iterations = (target_cycles - z80.cycles()) / l.cycles;
+                        let cycles = iterations * l.cycles;
+                        z80.set_cycles(z80.cycles() + cycles);
+                        z80.inc_r((iterations * l.r as u64) as u8 & 0x7F);
+                        self.skipped_cycles += cycles;
+                        iterations
+                    }
+                    _ => 0,
+                };
+                if iterations > 0 {
+                    // the probe's cycles are no longer right
+                    probe = None;
+                    continue;
+                }
+            }
+
+            let abandon = match probe {
+                Some(ref mut p) => {
+                    p.steps += 1;
+                    let mut recording_memory = RecordingMemory {
+                        memory: &mut *memory,
+                        reads: &mut p.reads.memory,
+                        impure: false,
+                    };
+                    let mut recording_io = RecordingIo {
+                        io: &mut *io,
+                        reads: &mut p.reads.io,
+                        impure: false,
+                    };
+                    Z80RunImpler {
+                        z80: &mut *z80,
+                        memory: &mut recording_memory,
+                        io: &mut recording_io,
+                        irq: &mut *irq,
+                        inbox: &mut *inbox,
+                    }.step(target_cycles);
+                    let impure = recording_memory.impure || recording_io.impure;
+                    p.reads.impure |= impure;
+                    p.reads.impure || p.steps > MAX_LOOP_STEPS
+                }
+                None => {
+                    Z80RunImpler {
+                        z80: &mut *z80,
+                        memory: &mut *memory,
+                        io: &mut *io,
+                        irq: &mut *irq,
+                        inbox: &mut *inbox,
+                    }.step(target_cycles);
+                    false
+                }
+            };
+            if abandon {
+                probe = None;
+            }
+
+            // a short jump back may be to the head of an idle loop
+            let new_pc = z80.reg16(Reg16::PC);
+            if probe.is_none()
+                && z80.prefix() == Prefix::NoPrefix
+                && new_pc <= pc
+                && pc - new_pc <= MAX_LOOP_BYTES
+            {
+                probe = Some(Probe::new(z80));
+            }
+        }
+    }
+}
+
+/// Implements `Z80Run` with an `IdleLoopSkipper`.
+pub struct IdleLoopSkipperImpler<
+    'a,
+    M: 'a + ?Sized,
+    Irq: 'a + ?Sized,
+    I: 'a + ?Sized,
+    Inb: 'a + ?Sized,
+> {
+    pub skipper: &'a mut IdleLoopSkipper,
+    pub z80: &'a mut Z80State,
+    pub memory: &'a mut M,
+    pub io: &'a mut I,
+    pub irq: &'a mut Irq,
+    pub inbox: &'a mut Inb,
+}
+
+impl<'a, M, Irq, I, Inb> Z80Run for IdleLoopSkipperImpler<'a, M, Irq, I, Inb>
+where
+    M: 'a + ?Sized + Memory16,
+    Irq: 'a + ?Sized + Z80Irq,
+    I: 'a + ?Sized + Io16,
+    Inb: 'a + ?Sized + Inbox<Memo = Z80Memo>,
+{
+    #[inline]
+    fn run(&mut self, target_cycles: u64) {
+        self.skipper.run(
+            self.z80,
+            self.memory,
+            self.io,
+            self.irq,
+            self.inbox,
+            target_cycles,
+        );
+    }
+}
+
+impl SmsZ80Runner for IdleLoopSkipper {
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        IdleLoopSkipperImpler {
+            skipper: self,
+            z80,
+            memory,
+            io,
+            irq,
+            inbox,
+        }.run(target_cycles);
+    }
+
+    #[inline]
+    fn invalidate(&mut self) {
+        IdleLoopSkipper::invalidate(self);
+    }
+}
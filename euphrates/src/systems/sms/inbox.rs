@@ -14,6 +14,8 @@ pub enum Query {
     Disassembly,
     /// Show the last few memos received
     RecentMemos,
+    /// Report where the Z80's time has gone, if the inbox is profiling
+    Profile(ProfileReport),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -201,7 +203,7 @@ impl Inbox for DebuggingInbox {
             self.status = DebugStatus::Hold
         }
 
-        if let Z80Memo::Instruction { pc, opcode } = memo {
+        if let Z80Memo::Instruction { pc, opcode, .. } = memo {
             let current_info = self.instructions[pc as usize];
             self.instructions[pc as usize] = MemoryLocation {
                 opcode: Some(opcode),
@@ -245,6 +247,7 @@ impl Debugger for DebuggingInbox {
             }
             DisassemblyAt(pc) => self.disassembly_around(pc),
             Disassembly => self.disassembly(None, 0, 0xFFFF),
+            Profile(_) => "Not profiling\n".to_owned(),
         };
         result
     }
@@ -285,6 +288,7 @@ impl GetDebugger for NothingInbox<Z80Memo> {
 pub trait Z80Inbox {
     fn receive_impl(&mut self, memo: Z80Memo);
     fn active(&self) -> bool;
+    fn tracing(&self) -> bool;
 }
 
 impl<T> Z80Inbox for T
@@ -298,6 +302,10 @@ where
     fn active(&self) -> bool {
         self.active()
     }
+
+    fn tracing(&self) -> bool {
+        self.tracing()
+    }
 }
 
 pub trait InboxGetDebugger: Z80Inbox + GetDebugger {}
@@ -335,4 +343,8 @@ impl Inbox for BoxedInbox {
     fn active(&self) -> bool {
         self.0.active()
     }
+
+    fn tracing(&self) -> bool {
+        self.0.tracing()
+    }
 }
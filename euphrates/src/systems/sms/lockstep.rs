@@ -0,0 +1,506 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use super::*;
+
+/// A function which could be installed as
+/// `sms_vdp::replaceable::PATTERN_TO_PALETTE_INDICES`.
+pub type PatternToPaletteIndices = fn([u8; 4]) -> [u8; 8];
+
+/// Options for `find_divergence`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LockstepOptions {
+    /// Compare the Z80s after every instruction, and find the exact
+    /// instruction at which the systems diverged, rather than only comparing
+    /// states at the end of each frame.
+    ///
+    /// This is much slower.
+    pub every_instruction: bool,
+
+    /// If set, this is installed as `PATTERN_TO_PALETTE_INDICES` whenever the
+    /// left system runs. Otherwise, whatever was installed when
+    /// `find_divergence` was called is used.
+    pub left_pattern_to_palette_indices: Option<PatternToPaletteIndices>,
+
+    /// Like `left_pattern_to_palette_indices`, for the right system.
+    pub right_pattern_to_palette_indices: Option<PatternToPaletteIndices>,
+}
+
+/// One field that differs between two `SmsState`s.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct StateDifference {
+    /// Which field, like `z80.pc` or `vdp.vram[0x3F00]`.
+    pub field: String,
+    pub left: String,
+    pub right: String,
+}
+
+impl fmt::Display for StateDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} vs {}", self.field, self.left, self.right)
+    }
+}
+
+/// Where two systems run in lockstep first differed.
+#[derive(Clone)]
+pub struct Divergence {
+    /// Index of the frame during which the systems diverged.
+    pub frame: u64,
+
+    /// How many instructions into that frame the systems diverged, if known.
+    ///
+    /// If the initial states differ, this is `Some(0)` and `frame` is 0.
+    pub instruction: Option<u64>,
+
+    /// The program counter of the left system at the divergence.
+    pub left_pc: u16,
+
+    /// The program counter of the right system at the divergence.
+    pub right_pc: u16,
+
+    pub differences: Vec<StateDifference>,
+
+    pub left_state: SmsState,
+
+    pub right_state: SmsState,
+}
+
+impl Divergence {
+    fn new(frame: u64, instruction: Option<u64>, left: SmsState, right: SmsState) -> Self {
+        Divergence {
+            frame,
+            instruction,
+            left_pc: left.z80.reg16(Reg16::PC),
+            right_pc: right.z80.reg16(Reg16::PC),
+            differences: state_differences(&left, &right),
+            left_state: left,
+            right_state: right,
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Diverged in frame {}", self.frame)?;
+        if let Some(instruction) = self.instruction {
+            write!(f, " after {} instructions", instruction)?;
+        }
+        writeln!(
+            f,
+            ", left PC {:0>4X}, right PC {:0>4X}",
+            self.left_pc, self.right_pc
+        )?;
+        for difference in self.differences.iter() {
+            writeln!(f, "{}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash all of `state`.
+pub fn state_hash(state: &SmsState) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hash everything in `state` except the ROM, which is usually much bigger
+/// than everything else put together.
+fn state_hash_without_rom(state: &SmsState) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    state.z80.hash(&mut hasher);
+    state.vdp.hash(&mut hasher);
+    state.memory.system_ram.hash(&mut hasher);
+    state.memory.main_cartridge_ram.hash(&mut hasher);
+    state.memory.half_cartridge_ram.hash(&mut hasher);
+    state.memory.pages.hash(&mut hasher);
+    state.memory.mapper.hash(&mut hasher);
+    state.player_input.hash(&mut hasher);
+    state.pause_irq.hash(&mut hasher);
+    state.sn76489.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// At most this many differing elements of an array are listed.
+const MAX_ARRAY_DIFFERENCES: usize = 16;
+
+struct Differ {
+    differences: Vec<StateDifference>,
+}
+
+impl Differ {
+    fn field<T: PartialEq + fmt::Debug>(&mut self, field: &str, left: T, right: T) {
+        if left != right {
+            self.differences.push(StateDifference {
+                field: field.to_owned(),
+                left: format!("{:?}", left),
+                right: format!("{:?}", right),
+            });
+        }
+    }
+
+    fn number<T: PartialEq + fmt::UpperHex>(&mut self, field: &str, left: T, right: T) {
+        if left != right {
+            self.differences.push(StateDifference {
+                field: field.to_owned(),
+                left: format!("{:#X}", left),
+                right: format!("{:#X}", right),
+            });
+        }
+    }
+
+    fn array<T: PartialEq + fmt::UpperHex>(&mut self, field: &str, left: &[T], right: &[T]) {
+        if left.len() != right.len() {
+            self.differences.push(StateDifference {
+                field: format!("{}.len()", field),
+                left: format!("{}", left.len()),
+                right: format!("{}", right.len()),
+            });
+            return;
+        }
+        let mut count = 0;
+        for (i, (l, r)) in left.iter().zip(right.iter()).enumerate() {
+            if l == r {
+                continue;
+            }
+            count += 1;
+            if count <= MAX_ARRAY_DIFFERENCES {
+                self.differences.push(StateDifference {
+                    field: format!("{}[{:#X}]", field, i),
+                    left: format!("{:#X}", l),
+                    right: format!("{:#X}", r),
+                });
+            }
+        }
+        if count > MAX_ARRAY_DIFFERENCES {
+            self.differences.push(StateDifference {
+                field: field.to_owned(),
+                left: format!("{} more differences", count - MAX_ARRAY_DIFFERENCES),
+                right: "".to_owned(),
+            });
+        }
+    }
+}
+
+/// Every field that differs between `left` and `right`.
+///
+/// For arrays, like RAM, only the first few differing elements are listed.
+pub fn state_differences(left: &SmsState, right: &SmsState) -> Vec<StateDifference> {
+    use self::Reg16::*;
+
+    let mut d = Differ {
+        differences: Vec::new(),
+    };
+
+    let z1 = &left.z80;
+    let z2 = &right.z80;
+    d.field("z80.cycles", z1.cycles, z2.cycles);
+    for &reg in [AF, BC, DE, HL, AF0, BC0, DE0, HL0, IX, IY, SP, PC].iter() {
+        d.number(&format!("z80.{}", reg), z1.reg16(reg), z2.reg16(reg));
+    }
+    d.number("z80.i", z1.reg8(Reg8::I), z2.reg8(Reg8::I));
+    d.number("z80.r", z1.reg8(Reg8::R), z2.reg8(Reg8::R));
+    d.field("z80.halted", z1.halted, z2.halted);
+    d.field("z80.iff1", z1.iff1, z2.iff1);
+    d.field("z80.iff2", z1.iff2, z2.iff2);
+    d.field("z80.prefix", z1.prefix, z2.prefix);
+    d.field("z80.interrupt_mode", z1.interrupt_mode, z2.interrupt_mode);
+    d.field(
+        "z80.interrupt_status",
+        z1.interrupt_status,
+        z2.interrupt_status,
+    );
+    d.number("z80.memptr", z1.memptr, z2.memptr);
+    d.field("z80.variant", z1.variant, z2.variant);
+    d.number("z80.q", z1.q, z2.q);
+    d.field("z80.flags_written", z1.flags_written, z2.flags_written);
+    d.field("z80.ld_a_ir", z1.ld_a_ir, z2.ld_a_ir);
+
+    let v1 = &left.vdp;
+    let v2 = &right.vdp;
+    d.field("vdp.cycles", v1.cycles, v2.cycles);
+    d.field("vdp.kind", v1.kind, v2.kind);
+    d.field("vdp.tv_system", v1.tv_system, v2.tv_system);
+    d.number("vdp.cram_latch", v1.cram_latch, v2.cram_latch);
+    d.number("vdp.status_flags", v1.status_flags, v2.status_flags);
+    d.number("vdp.other_flags", v1.other_flags, v2.other_flags);
+    d.field("vdp.h", v1.h, v2.h);
+    d.field("vdp.v", v1.v, v2.v);
+    d.number("vdp.address", v1.address, v2.address);
+    d.number("vdp.buffer", v1.buffer, v2.buffer);
+    d.array("vdp.reg", &v1.reg, &v2.reg);
+    d.array("vdp.cram", &v1.cram, &v2.cram);
+    d.array("vdp.vram", &v1.vram, &v2.vram);
+    d.number("vdp.line_counter", v1.line_counter, v2.line_counter);
+    d.number("vdp.y_scroll", v1.y_scroll, v2.y_scroll);
+
+    let m1 = &left.memory;
+    let m2 = &right.memory;
+    d.array("memory.rom", &m1.rom, &m2.rom);
+    d.array("memory.system_ram", &m1.system_ram, &m2.system_ram);
+    {
+        use self::MainCartridgeRam::*;
+        let main_cartridge_ram = |ram: &MainCartridgeRam| -> Vec<u8> {
+            match *ram {
+                Zero => Vec::new(),
+                One(ref a) => a.to_vec(),
+                Two(ref a, ref b) => a.iter().chain(b.iter()).cloned().collect(),
+            }
+        };
+        d.array(
+            "memory.main_cartridge_ram",
+            &main_cartridge_ram(&m1.main_cartridge_ram),
+            &main_cartridge_ram(&m2.main_cartridge_ram),
+        );
+    }
+    match (&m1.half_cartridge_ram, &m2.half_cartridge_ram) {
+        (Some(a), Some(b)) => d.array("memory.half_cartridge_ram", &a[..], &b[..]),
+        (a, b) => d.field("memory.half_cartridge_ram.is_some()", a.is_some(), b.is_some()),
+    }
+    for i in 0..4 {
+        d.field(&format!("memory.pages[{}]", i), m1.pages[i], m2.pages[i]);
+    }
+    d.field("memory.mapper", m1.mapper, m2.mapper);
+
+    d.field("player_input", left.player_input, right.player_input);
+    d.field("pause_irq", left.pause_irq, right.pause_irq);
+
+    let s1 = &left.sn76489;
+    let s2 = &right.sn76489;
+    d.array("sn76489.registers", &s1.registers, &s2.registers);
+    d.number("sn76489.latch", s1.latch, s2.latch);
+    d.number("sn76489.linear_feedback", s1.linear_feedback, s2.linear_feedback);
+    d.array("sn76489.counters", &s1.counters, &s2.counters);
+    d.field("sn76489.polarity", s1.polarity, s2.polarity);
+    d.field("sn76489.cycles", s1.cycles, s2.cycles);
+
+    d.differences
+}
+
+/// Puts back the original `PATTERN_TO_PALETTE_INDICES` when dropped.
+struct PatternGuard(PatternToPaletteIndices);
+
+impl PatternGuard {
+    fn new() -> Self {
+        PatternGuard(unsafe { sms_vdp::replaceable::PATTERN_TO_PALETTE_INDICES })
+    }
+}
+
+impl Drop for PatternGuard {
+    fn drop(&mut self) {
+        unsafe {
+            sms_vdp::replaceable::PATTERN_TO_PALETTE_INDICES = self.0;
+        }
+    }
+}
+
+struct Side<'a> {
+    sms: &'a mut dyn Sms,
+    pattern_to_palette_indices: PatternToPaletteIndices,
+}
+
+impl<'a> Side<'a> {
+    fn install(&self) {
+        unsafe {
+            sms_vdp::replaceable::PATTERN_TO_PALETTE_INDICES = self.pattern_to_palette_indices;
+        }
+    }
+
+    fn run_frame(&mut self, player_input: SmsPlayerInput) -> Result<(), SmsEmulationError> {
+        self.install();
+        self.sms.run_frame(player_input)
+    }
+
+    fn run_frame_instructions(
+        &mut self,
+        player_input: SmsPlayerInput,
+        after_instruction: &mut dyn FnMut(&dyn Sms) -> bool,
+    ) -> Result<bool, SmsEmulationError> {
+        self.install();
+        self.sms
+            .run_frame_instructions(player_input, after_instruction)
+    }
+
+    /// Go back to `state` and run `instructions` instructions of a frame, or
+    /// the whole frame if `instructions` is `None`, recording the hash of the
+    /// state after each.
+    fn hashes(
+        &mut self,
+        state: &SmsState,
+        player_input: SmsPlayerInput,
+        instructions: Option<u64>,
+    ) -> Result<Vec<u64>, SmsEmulationError> {
+        self.sms.restore_state(state)?;
+        let mut hashes = Vec::new();
+        self.run_frame_instructions(player_input, &mut |sms| {
+            hashes.push(state_hash_without_rom(&sms.state()));
+            Some(hashes.len() as u64) != instructions
+        })?;
+        Ok(hashes)
+    }
+}
+
+/// Run both sides through one frame, comparing Z80s after each instruction.
+///
+/// Returns the number of instructions after which they first differed.
+fn first_z80_difference(
+    left: &mut Side,
+    right: &mut Side,
+    player_input: SmsPlayerInput,
+) -> Result<Option<u64>, SmsEmulationError> {
+    let mut z80s = Vec::new();
+    left.run_frame_instructions(player_input, &mut |sms| {
+        z80s.push(sms.z80().state());
+        true
+    })?;
+
+    let mut count = 0;
+    let mut differed = false;
+    right.run_frame_instructions(player_input, &mut |sms| {
+        if z80s.get(count) != Some(&sms.z80().state()) {
+            differed = true;
+            return false;
+        }
+        count += 1;
+        true
+    })?;
+
+    if differed || count != z80s.len() {
+        Ok(Some(count as u64 + 1))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Replay a frame on both sides from `left_state` and `right_state`, comparing
+/// whole states after each instruction, to find exactly where they diverged.
+fn locate(
+    left: &mut Side,
+    right: &mut Side,
+    left_state: &SmsState,
+    right_state: &SmsState,
+    player_input: SmsPlayerInput,
+    frame: u64,
+    instructions: Option<u64>,
+) -> Result<Divergence, SmsEmulationError> {
+    let left_hashes = left.hashes(left_state, player_input, instructions)?;
+    let right_hashes = right.hashes(right_state, player_input, instructions)?;
+    let instruction = left_hashes
+        .iter()
+        .zip(right_hashes.iter())
+        .position(|(l, r)| l != r)
+        .map(|i| i as u64 + 1);
+    let instruction = match instruction {
+        Some(i) => Some(i),
+        None if left_hashes.len() != right_hashes.len() => {
+            Some(left_hashes.len().min(right_hashes.len()) as u64 + 1)
+        }
+        None => None,
+    };
+
+    match instruction {
+        Some(i) => {
+            // now bring both sides to just after the divergent instruction
+            left.hashes(left_state, player_input, Some(i))?;
+            right.hashes(right_state, player_input, Some(i))?;
+        }
+        None => {
+            // the states differ only at the end of the frame
+            left.hashes(left_state, player_input, None)?;
+            right.hashes(right_state, player_input, None)?;
+        }
+    }
+
+    Ok(Divergence::new(
+        frame,
+        instruction,
+        left.sms.state(),
+        right.sms.state(),
+    ))
+}
+
+/// Run `left` and `right` in lockstep with the same player input, and find the
+/// first point at which their states differ.
+///
+/// The two systems should start in the same state, but may differ in other
+/// ways: for instance, in their memory implementations. Each is run one frame
+/// for each element of `player_inputs`, and their state hashes compared after
+/// each frame. With `options.every_instruction`, their Z80s are also compared
+/// after each instruction, and once they diverge, the frame is replayed to
+/// find the exact instruction after which their states first differed.
+///
+/// Returns `None` if they never diverged.
+pub fn find_divergence(
+    left: &mut dyn Sms,
+    right: &mut dyn Sms,
+    player_inputs: &[SmsPlayerInput],
+    options: &LockstepOptions,
+) -> Result<Option<Divergence>, SmsEmulationError> {
+    let guard = PatternGuard::new();
+    let mut left = Side {
+        sms: left,
+        pattern_to_palette_indices: options.left_pattern_to_palette_indices.unwrap_or(guard.0),
+    };
+    let mut right = Side {
+        sms: right,
+        pattern_to_palette_indices: options.right_pattern_to_palette_indices.unwrap_or(guard.0),
+    };
+
+    let mut left_state = left.sms.state();
+    let mut right_state = right.sms.state();
+    // the ROM can't change, so check it once here and leave it out of the
+    // hashes compared after each frame
+    if left_state.memory.rom != right_state.memory.rom
+        || state_hash_without_rom(&left_state) != state_hash_without_rom(&right_state)
+    {
+        return Ok(Some(Divergence::new(0, Some(0), left_state, right_state)));
+    }
+
+    for (frame, &player_input) in player_inputs.iter().enumerate() {
+        let frame = frame as u64;
+        if options.every_instruction {
+            let z80_difference = first_z80_difference(&mut left, &mut right, player_input)?;
+            if let Some(instructions) = z80_difference {
+                return locate(
+                    &mut left,
+                    &mut right,
+                    &left_state,
+                    &right_state,
+                    player_input,
+                    frame,
+                    Some(instructions),
+                ).map(Some);
+            }
+        } else {
+            left.run_frame(player_input)?;
+            right.run_frame(player_input)?;
+        }
+
+        let new_left_state = left.sms.state();
+        let new_right_state = right.sms.state();
+        if state_hash_without_rom(&new_left_state) != state_hash_without_rom(&new_right_state) {
+            if options.every_instruction {
+                return locate(
+                    &mut left,
+                    &mut right,
+                    &left_state,
+                    &right_state,
+                    player_input,
+                    frame,
+                    None,
+                ).map(Some);
+            }
+            return Ok(Some(Divergence::new(
+                frame,
+                None,
+                new_left_state,
+                new_right_state,
+            )));
+        }
+        left_state = new_left_state;
+        right_state = new_right_state;
+    }
+
+    Ok(None)
+}
@@ -13,12 +13,32 @@ pub use hardware::sms_vdp::{self, *};
 pub use hardware::sn76489::*;
 pub use hardware::z80::*;
 
+mod batch;
+mod cached_interpreter;
+mod disassembler;
 mod emulator;
+mod environment;
 mod help;
+mod idle_loop;
 mod inbox;
+mod lockstep;
+mod profiler;
+mod server;
+mod trace;
 mod user_interface;
+mod variables;
 
+pub use self::batch::*;
+pub use self::cached_interpreter::*;
+pub use self::disassembler::*;
 pub use self::emulator::*;
+pub use self::environment::*;
 pub use self::help::*;
+pub use self::idle_loop::*;
 pub use self::inbox::*;
+pub use self::lockstep::*;
+pub use self::profiler::*;
+pub use self::server::*;
+pub use self::trace::*;
 pub use self::user_interface::*;
+pub use self::variables::*;
@@ -0,0 +1,423 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Write};
+
+use memo::Inbox;
+
+use super::*;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Which report `Query::Profile` gives.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ProfileReport {
+    /// Every instruction executed, those taking the most cycles first,
+    /// followed by the cycles spent in each ROM bank.
+    Flat,
+
+    /// The tree of calls, with the cycles spent in each routine by itself and
+    /// along with everything it called.
+    Tree,
+
+    /// A line for each call stack with the cycles spent in it, in the
+    /// collapsed stack format flamegraph tools read, like
+    /// `root;B00_0038;B02_8123 5120`.
+    Collapsed,
+}
+
+/// Where an instruction is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct ProfileLocation {
+    /// The 16 KiB bank of ROM the instruction is in, or `None` if it's not in
+    /// ROM.
+    pub bank: Option<usize>,
+
+    /// The instruction's logical address.
+    pub address: u16,
+}
+
+/// Named like the labels of `RomDisassembly`, so `B03_8123` is at logical
+/// address `8123` in bank 3, and `RAM_C000` is at `C000` outside of ROM.
+impl Display for ProfileLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self.bank {
+            Some(bank) => format!("B{:0>2X}_{:0>4X}", bank, self.address),
+            None => format!("RAM_{:0>4X}", self.address),
+        };
+        f.pad(&s)
+    }
+}
+
+/// The time spent in one instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct InstructionProfile {
+    /// The instruction last executed at its location.
+    pub opcode: Opcode,
+
+    pub executions: u64,
+
+    pub cycles: u64,
+}
+
+/// A routine in the call tree, as called from a particular call stack.
+#[derive(Clone, Debug)]
+struct CallNode {
+    /// The routine's first instruction, or `None` for the root, which is
+    /// whatever was running when profiling began.
+    entry: Option<ProfileLocation>,
+
+    parent: usize,
+
+    children: BTreeMap<ProfileLocation, usize>,
+
+    calls: u64,
+
+    /// Cycles spent in the routine itself, not in what it called.
+    cycles: u64,
+}
+
+/// A call we haven't seen return yet.
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    node: usize,
+
+    /// The stack pointer just after the return address was pushed.
+    sp: u16,
+}
+
+/// The instruction last begun, which can't be counted until we know when it
+/// finished.
+#[derive(Clone, Copy, Debug)]
+struct Executing {
+    location: ProfileLocation,
+    opcode: Opcode,
+    cycles: u64,
+    sp: u16,
+    call: bool,
+}
+
+/// Is `opcode` a `call` or an `rst`?
+fn is_call(opcode: Opcode) -> bool {
+    use self::FullMnemonic::*;
+    use self::Mnemonic::*;
+    matches!(
+        opcode.mnemonic(),
+        Some(OneParameter(Call, _)) | Some(TwoParameters(Call, _, _)) | Some(OneParameter(Rst, _))
+    )
+}
+
+/// An inbox measuring where the Z80 spends its cycles.
+///
+/// Cycles are counted for each instruction by its location in ROM, so the
+/// same logical address in different banks isn't confused. An instruction is
+/// charged for every cycle from when it begins until the next one does, so a
+/// `halt` is charged for the time spent halted, and responding to an
+/// interrupt is charged to the instruction before it.
+///
+/// A call tree is kept by watching for `call`s, `rst`s, and interrupts
+/// that push a return address, and taking a routine to have returned once the
+/// stack pointer is above its return address. A routine is known by its first
+/// instruction.
+///
+/// Since it's `tracing`, a `ProfilingInbox` makes the Z80 run in the
+/// interpreter, even if an `SmsZ80Runner` is set. Its reports are available
+/// from its `Debugger` implementation with `Query::Profile`; it doesn't
+/// respond to other queries or commands.
+#[derive(Clone, Debug)]
+pub struct ProfilingInbox {
+    instructions: HashMap<ProfileLocation, InstructionProfile>,
+
+    /// The call tree; the root is first, and each node comes after its
+    /// parent.
+    nodes: Vec<CallNode>,
+
+    stack: Vec<Frame>,
+
+    executing: Option<Executing>,
+
+    interrupted: bool,
+}
+
+impl Default for ProfilingInbox {
+    fn default() -> Self {
+        ProfilingInbox::new()
+    }
+}
+
+impl ProfilingInbox {
+    pub fn new() -> Self {
+        ProfilingInbox {
+            instructions: HashMap::new(),
+            nodes: vec![CallNode {
+                entry: None,
+                parent: 0,
+                children: BTreeMap::new(),
+                calls: 0,
+                cycles: 0,
+            }],
+            stack: Vec::new(),
+            executing: None,
+            interrupted: false,
+        }
+    }
+
+    /// The time spent in each instruction.
+    pub fn instructions(&self) -> &HashMap<ProfileLocation, InstructionProfile> {
+        &self.instructions
+    }
+
+    /// All the cycles counted.
+    pub fn total_cycles(&self) -> u64 {
+        self.nodes.iter().map(|node| node.cycles).sum()
+    }
+
+    /// The cycles spent in each bank of ROM, with `None` for those outside of
+    /// ROM.
+    pub fn bank_cycles(&self) -> BTreeMap<Option<usize>, u64> {
+        let mut banks = BTreeMap::new();
+        for (location, profile) in self.instructions.iter() {
+            *banks.entry(location.bank).or_insert(0) += profile.cycles;
+        }
+        banks
+    }
+
+    pub fn report(&self, report: ProfileReport) -> String {
+        match report {
+            ProfileReport::Flat => self.flat_report(),
+            ProfileReport::Tree => self.tree_report(),
+            ProfileReport::Collapsed => self.collapsed_stacks(),
+        }
+    }
+
+    fn current_node(&self) -> usize {
+        self.stack.last().map(|frame| frame.node).unwrap_or(0)
+    }
+
+    /// Count `executing`, which finished at `cycles`.
+    fn finish(&mut self, executing: Executing, cycles: u64) {
+        let elapsed = cycles.saturating_sub(executing.cycles);
+        let profile = self
+            .instructions
+            .entry(executing.location)
+            .or_insert(InstructionProfile {
+                opcode: executing.opcode,
+                executions: 0,
+                cycles: 0,
+            });
+        profile.opcode = executing.opcode;
+        profile.executions += 1;
+        profile.cycles += elapsed;
+        let node = self.current_node();
+        self.nodes[node].cycles += elapsed;
+    }
+
+    /// Enter the routine at `entry`, with its return address at `sp`.
+    fn call(&mut self, entry: ProfileLocation, sp: u16) {
+        let parent = self.current_node();
+        let next = self.nodes.len();
+        let node = *self.nodes[parent].children.entry(entry).or_insert(next);
+        if node == next {
+            self.nodes.push(CallNode {
+                entry: Some(entry),
+                parent,
+                children: BTreeMap::new(),
+                calls: 0,
+                cycles: 0,
+            });
+        }
+        self.nodes[node].calls += 1;
+        self.stack.push(Frame { node, sp });
+    }
+
+    /// The cycles spent in each node and everything it called.
+    fn inclusive_cycles(&self) -> Vec<u64> {
+        let mut cycles: Vec<u64> = self.nodes.iter().map(|node| node.cycles).collect();
+        for i in (1..self.nodes.len()).rev() {
+            let parent = self.nodes[i].parent;
+            cycles[parent] += cycles[i];
+        }
+        cycles
+    }
+
+    fn node_name(&self, node: usize) -> String {
+        match self.nodes[node].entry {
+            Some(entry) => entry.to_string(),
+            None => "root".to_owned(),
+        }
+    }
+
+    fn flat_report(&self) -> String {
+        let total = self.total_cycles().max(1) as f64;
+        let mut result = String::new();
+
+        let mut instructions: Vec<_> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        writeln!(
+            result,
+            "{: >12} {: >7} {: >10}  {: <8}  Instruction",
+            "Cycles", "%", "Executions", "Location"
+        ).unwrap();
+        for &(location, profile) in instructions.iter() {
+            let instruction = match profile.opcode.mnemonic() {
+                Some(mnemonic) => mnemonic.to_string(),
+                None => profile.opcode.to_string(),
+            };
+            writeln!(
+                result,
+                "{: >12} {: >6.2}% {: >10}  {: <8}  {}",
+                profile.cycles,
+                100.0 * profile.cycles as f64 / total,
+                profile.executions,
+                location,
+                instruction
+            ).unwrap();
+        }
+
+        writeln!(result).unwrap();
+        writeln!(result, "{: >12} {: >7}  Bank", "Cycles", "%").unwrap();
+        for (bank, cycles) in self.bank_cycles() {
+            let name = match bank {
+                Some(bank) => format!("B{:0>2X}", bank),
+                None => "RAM".to_owned(),
+            };
+            writeln!(
+                result,
+                "{: >12} {: >6.2}%  {}",
+                cycles,
+                100.0 * cycles as f64 / total,
+                name
+            ).unwrap();
+        }
+        writeln!(result, "{: >12} {: >6.2}%  Total", self.total_cycles(), 100.0).unwrap();
+        result
+    }
+
+    fn tree_report(&self) -> String {
+        let inclusive = self.inclusive_cycles();
+        let total = inclusive[0].max(1) as f64;
+        let mut result = String::new();
+        writeln!(
+            result,
+            "{: >12} {: >7} {: >12} {: >8}  Routine",
+            "Inclusive", "%", "Self", "Calls"
+        ).unwrap();
+
+        // depth first, with the most expensive calls first
+        let mut to_visit = vec![(0, 0)];
+        while let Some((node, depth)) = to_visit.pop() {
+            writeln!(
+                result,
+                "{: >12} {: >6.2}% {: >12} {: >8}  {:width$}{}",
+                inclusive[node],
+                100.0 * inclusive[node] as f64 / total,
+                self.nodes[node].cycles,
+                self.nodes[node].calls,
+                "",
+                self.node_name(node),
+                width = 2 * depth
+            ).unwrap();
+            let mut children: Vec<usize> = self.nodes[node].children.values().cloned().collect();
+            children.sort_by_key(|&child| inclusive[child]);
+            to_visit.extend(children.into_iter().map(|child| (child, depth + 1)));
+        }
+        result
+    }
+
+    fn collapsed_stacks(&self) -> String {
+        let mut result = String::new();
+        let mut names: Vec<String> = Vec::with_capacity(self.nodes.len());
+        for i in 0..self.nodes.len() {
+            let name = if i == 0 {
+                self.node_name(0)
+            } else {
+                format!("{};{}", names[self.nodes[i].parent], self.node_name(i))
+            };
+            if self.nodes[i].cycles != 0 {
+                writeln!(result, "{} {}", name, self.nodes[i].cycles).unwrap();
+            }
+            names.push(name);
+        }
+        result
+    }
+}
+
+impl Inbox for ProfilingInbox {
+    type Memo = Z80Memo;
+
+    fn receive_impl(&mut self, memo: Z80Memo) {
+        let (pc, opcode, registers, rom_index) = match memo {
+            Z80Memo::Instruction {
+                pc,
+                opcode,
+                registers,
+                rom_index,
+            } => (pc, opcode, registers, rom_index),
+            _ => {
+                self.interrupted = true;
+                return;
+            }
+        };
+
+        let location = ProfileLocation {
+            bank: rom_index.map(|index| index / BANK_SIZE),
+            address: pc,
+        };
+
+        // An interrupt memo is sent even if the interrupt isn't taken, so
+        // make sure a return address was pushed and we didn't just continue
+        // to the next instruction.
+        let called = match self.executing.take() {
+            Some(executing) => {
+                self.finish(executing, registers.cycles);
+                let pushed = registers.sp == executing.sp.wrapping_sub(2);
+                let next = executing
+                    .location
+                    .address
+                    .wrapping_add(executing.opcode.len() as u16);
+                pushed && (executing.call || (self.interrupted && pc != next))
+            }
+            None => false,
+        };
+
+        while let Some(frame) = self.stack.last().cloned() {
+            if frame.sp >= registers.sp {
+                break;
+            }
+            self.stack.pop();
+        }
+
+        if called {
+            self.call(location, registers.sp);
+        }
+        self.interrupted = false;
+
+        self.executing = Some(Executing {
+            location,
+            opcode,
+            cycles: registers.cycles,
+            sp: registers.sp,
+            call: is_call(opcode),
+        });
+    }
+
+    #[inline]
+    fn tracing(&self) -> bool {
+        true
+    }
+}
+
+impl Debugger for ProfilingInbox {
+    fn command(&mut self, _command: Command) {}
+
+    fn query(&self, query: Query) -> String {
+        match query {
+            Query::Profile(report) => self.report(report),
+            _ => "Only profiles are available while profiling\n".to_owned(),
+        }
+    }
+}
+
+impl GetDebugger for ProfilingInbox {
+    fn debugger(&mut self) -> Option<&mut dyn Debugger> {
+        Some(self)
+    }
+}
@@ -0,0 +1,380 @@
+//! Let agents in other processes drive a headless system over a socket.
+
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use host_multimedia::PixelFormat;
+use save;
+
+use super::*;
+
+/// A request to a server run by a `ServerHelper`.
+///
+/// A client sends a `ServerRequest` and waits for the `ServerResponse`; that is
+/// the whole protocol. Each message, in either direction, is a 4 byte
+/// little-endian length followed by that many bytes of the message serialized
+/// by the `save` module (see `read_message` and `write_message`).
+///
+/// That serialization is bincode's, so clients in other languages can encode
+/// and decode messages by hand: an enum is a 4 byte little-endian variant index
+/// (in the order the variants are declared) followed by its fields in order;
+/// integers and floats are little-endian; a `bool` is one byte; and a
+/// `Vec<u8>` or `String` is an 8 byte little-endian length followed by its
+/// bytes. For example, `Step { action: 3, frames: 4 }` is sent as
+///
+/// ```text
+/// 0c 00 00 00  01 00 00 00  03 00 00 00  04 00 00 00
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerRequest {
+    /// Restore the initial state and start a new episode. Answered by `Ok`.
+    Reset,
+
+    /// Hold down the action with index `action` in the action space for
+    /// `frames` frames. Answered by `Step`.
+    Step { action: u32, frames: u32 },
+
+    /// Answered by `State`.
+    GetState,
+
+    /// Restore a state previously returned in a `State` response. Answered by
+    /// `Ok`.
+    SetState(Vec<u8>),
+
+    /// Answered by `Ram`.
+    GetRam,
+
+    /// Answered by `Frame`.
+    GetFrame,
+}
+
+/// The answer to a `ServerRequest`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerResponse {
+    Ok,
+
+    /// The reward and whether the episode is done are computed by the
+    /// server's `GameMonitor`, if it has one, and are otherwise 0 and false.
+    /// `frame` counts frames since the last `Reset`.
+    Step {
+        reward: f64,
+        done: bool,
+        frame: u64,
+    },
+
+    /// An `SmsState`, serialized by the `save` module.
+    State(Vec<u8>),
+
+    /// The contents of system RAM.
+    Ram(Vec<u8>),
+
+    /// The last frame rendered, as rows of 3 byte red, green, blue pixels.
+    Frame {
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+    },
+
+    /// The request couldn't be carried out, for this reason. The system is
+    /// unchanged.
+    Error(String),
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Read one length-prefixed message.
+///
+/// Returns `None` if the stream ends before the message begins. A length over
+/// `save::MAX_BYTES` is an error, found before anything is allocated for the
+/// message.
+pub fn read_message<T, R>(reader: &mut R) -> io::Result<Option<T>>
+where
+    T: DeserializeOwned,
+    R: Read + ?Sized,
+{
+    let mut length = [0u8; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let length = u32::from_le_bytes(length);
+    if length as u64 > save::MAX_BYTES {
+        return Err(invalid_data(format!(
+            "message length {} is over the limit of {}",
+            length,
+            save::MAX_BYTES
+        )));
+    }
+    let mut bytes = vec![0u8; length as usize];
+    reader.read_exact(&mut bytes)?;
+    save::deserialize(&bytes).map(Some).map_err(invalid_data)
+}
+
+/// Write one length-prefixed message.
+pub fn write_message<T, W>(writer: &mut W, message: &T) -> io::Result<()>
+where
+    T: Serialize + ?Sized,
+    W: Write + ?Sized,
+{
+    let bytes = save::serialize(message).map_err(invalid_data)?;
+    if bytes.len() > u32::MAX as usize {
+        return Err(invalid_data("message too long"));
+    }
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+struct PendingStep {
+    player_input: SmsPlayerInput,
+    frames_left: u32,
+}
+
+/// A `UiHelper` that takes its orders from a client over `stream`.
+///
+/// `frame_update` blocks until a request to step arrives, answering any other
+/// requests in the meantime, and the `Ui` ends when the client closes the
+/// connection.
+pub struct ServerHelper<S> {
+    stream: S,
+    initial_state: SmsState,
+    action_space: ActionSpace,
+    monitor: Option<GameMonitor>,
+    frame: u64,
+    started: bool,
+    step: Option<PendingStep>,
+}
+
+impl<S> ServerHelper<S>
+where
+    S: Read + Write,
+{
+    /// A helper whose episodes start at `initial_state`, using the action space
+    /// `ActionSpace::joypad1()` and no `GameMonitor`.
+    pub fn new(stream: S, initial_state: SmsState) -> Self {
+        ServerHelper {
+            stream,
+            initial_state,
+            action_space: Default::default(),
+            monitor: None,
+            frame: 0,
+            started: false,
+            step: None,
+        }
+    }
+
+    #[inline]
+    pub fn set_action_space(&mut self, action_space: ActionSpace) {
+        self.action_space = action_space;
+    }
+
+    /// Use a `GameMonitor` to compute the reward and whether the episode is
+    /// over after each step.
+    ///
+    /// It's reset when the first request arrives, and at each `Reset`.
+    #[inline]
+    pub fn set_game_monitor(&mut self, monitor: Option<GameMonitor>) {
+        self.monitor = monitor;
+    }
+
+    fn finish_step(&mut self, sms: &dyn Sms) -> ServerResponse {
+        let (reward, done) = match self.monitor {
+            Some(ref mut m) => {
+                m.update(sms.sms_memory());
+                (m.reward(), m.done())
+            }
+            None => (0.0, false),
+        };
+        ServerResponse::Step {
+            reward,
+            done,
+            frame: self.frame,
+        }
+    }
+
+    fn respond(&mut self, request: ServerRequest, sms: &mut dyn Sms) -> ServerResponse {
+        match request {
+            ServerRequest::Reset => match sms.restore_state(&self.initial_state) {
+                Ok(()) => {
+                    self.frame = 0;
+                    if let Some(ref mut m) = self.monitor {
+                        m.reset(sms.sms_memory());
+                    }
+                    ServerResponse::Ok
+                }
+                Err(e) => ServerResponse::Error(e.to_string()),
+            },
+            ServerRequest::Step { .. } => unreachable!(),
+            ServerRequest::GetState => match save::serialize(&sms.state()) {
+                Ok(bytes) => ServerResponse::State(bytes),
+                Err(e) => ServerResponse::Error(e.to_string()),
+            },
+            ServerRequest::SetState(bytes) => {
+                let state: SmsState = match save::deserialize(&bytes) {
+                    Ok(state) => state,
+                    Err(e) => return ServerResponse::Error(e.to_string()),
+                };
+                match sms.restore_state(&state) {
+                    Ok(()) => ServerResponse::Ok,
+                    Err(e) => ServerResponse::Error(e.to_string()),
+                }
+            }
+            ServerRequest::GetRam => {
+                let memory = sms.sms_memory();
+                let ram = (0..memory.system_ram_len())
+                    .map(|i| memory.system_ram_read(i))
+                    .collect();
+                ServerResponse::Ram(ram)
+            }
+            ServerRequest::GetFrame => match sms.last_frame() {
+                Some(frame) => ServerResponse::Frame {
+                    width: frame.width,
+                    height: frame.height,
+                    data: match frame.format {
+                        PixelFormat::Rgb24 => frame.data.to_vec(),
+                        PixelFormat::Rgba => frame
+                            .data
+                            .chunks(4)
+                            .flat_map(|pixel| pixel[..3].iter().cloned())
+                            .collect(),
+                    },
+                },
+                None => ServerResponse::Error("No frame has been rendered".to_owned()),
+            },
+        }
+    }
+}
+
+impl<S> UiHelper for ServerHelper<S>
+where
+    S: Read + Write,
+{
+    fn frame_update(
+        &mut self,
+        ui: &mut UiStatus,
+    ) -> Result<Option<SmsPlayerInput>, SmsEmulationError> {
+        if let Some(ref mut step) = self.step {
+            if step.frames_left > 0 {
+                step.frames_left -= 1;
+                self.frame += 1;
+                return Ok(Some(step.player_input));
+            }
+        }
+
+        if self.step.take().is_some() {
+            let response = self.finish_step(ui.master_system());
+            write_message(&mut self.stream, &response)?;
+        } else if !self.started {
+            self.started = true;
+            if let Some(ref mut m) = self.monitor {
+                m.reset(ui.master_system().sms_memory());
+            }
+        }
+
+        loop {
+            let request: ServerRequest = match read_message(&mut self.stream)? {
+                Some(request) => request,
+                None => return Ok(None),
+            };
+            let response = match request {
+                ServerRequest::Step { action, frames } => {
+                    match self.action_space.get(action as usize) {
+                        Some(player_input) if frames > 0 => {
+                            self.step = Some(PendingStep {
+                                player_input,
+                                frames_left: frames - 1,
+                            });
+                            self.frame += 1;
+                            return Ok(Some(player_input));
+                        }
+                        Some(_) => ServerResponse::Error("Cannot step 0 frames".to_owned()),
+                        None => ServerResponse::Error(format!(
+                            "Action {} not in action space of size {}",
+                            action,
+                            self.action_space.len()
+                        )),
+                    }
+                }
+                request => self.respond(request, ui.master_system_mut()),
+            };
+            write_message(&mut self.stream, &response)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            ServerRequest::Reset,
+            ServerRequest::Step {
+                action: 3,
+                frames: 4,
+            },
+            ServerRequest::SetState(vec![1, 2, 3]),
+        ];
+        let mut bytes = Vec::new();
+        for message in messages.iter() {
+            write_message(&mut bytes, message).unwrap();
+        }
+        // the example in the `ServerRequest` documentation
+        assert_eq!(
+            &bytes[8..24],
+            &[0x0C, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]
+        );
+
+        let mut reader = Cursor::new(bytes);
+        for message in messages.iter() {
+            let read: ServerRequest = read_message(&mut reader).unwrap().unwrap();
+            assert_eq!(&read, message);
+        }
+        assert_eq!(read_message::<ServerRequest, _>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &ServerResponse::Ram(vec![0; 16])).unwrap();
+        for length in 1..bytes.len() {
+            let mut reader = Cursor::new(&bytes[..length]);
+            let error = read_message::<ServerResponse, _>(&mut reader).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn oversized_length() {
+        // only a length, which panics if anything past it is read
+        struct Length(io::Cursor<[u8; 4]>);
+
+        impl Read for Length {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.read(buf)? {
+                    0 => panic!("read past the length"),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        for &length in [save::MAX_BYTES as u32 + 1, u32::MAX].iter() {
+            let mut reader = Length(Cursor::new(length.to_le_bytes()));
+            let error = read_message::<ServerRequest, _>(&mut reader).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
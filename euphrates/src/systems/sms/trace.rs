@@ -0,0 +1,445 @@
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use memo::Inbox;
+
+use super::*;
+
+/// The first bytes of a trace in the binary format.
+pub const TRACE_MAGIC: &[u8; 8] = b"EUPHTRC1";
+
+/// The length of each record in the binary format.
+const RECORD_LEN: usize = 40;
+
+/// How a `TracingInbox` writes its trace.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum TraceFormat {
+    /// `TRACE_MAGIC`, then a fixed size record for each instruction.
+    ///
+    /// See `TraceRecord::write_binary`.
+    Binary,
+
+    /// A line for each instruction, as given by the `Display` implementation
+    /// of `TraceRecord`.
+    Text,
+}
+
+/// One instruction in a trace, with the registers as they were just before
+/// it was executed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub opcode: Opcode,
+    pub registers: Z80Registers,
+}
+
+impl TraceRecord {
+    /// Write this record in the binary format.
+    ///
+    /// It's 40 bytes, with everything little endian: PC; the opcode's length
+    /// and 4 bytes for the opcode, padded with zeros; AF, BC, DE, HL, AF',
+    /// BC', DE', HL', IX, IY, and SP; I; R; a byte with IFF1 in bit 0, IFF2
+    /// in bit 1, and the interrupt mode in bits 2 and 3; and 8 bytes of
+    /// cycles.
+    pub fn write_binary<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
+        let r = &self.registers;
+        let mut bytes = Vec::with_capacity(RECORD_LEN);
+        bytes.extend_from_slice(&r.pc.to_le_bytes());
+        let opcode = self.opcode.bytes();
+        bytes.push(opcode.len() as u8);
+        bytes.extend_from_slice(opcode);
+        bytes.resize(7, 0);
+        for &x in [
+            r.af, r.bc, r.de, r.hl, r.af0, r.bc0, r.de0, r.hl0, r.ix, r.iy, r.sp,
+        ].iter()
+        {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        bytes.push(r.i);
+        bytes.push(r.r);
+        bytes.push(r.iff1 as u8 | (r.iff2 as u8) << 1 | (r.im & 3) << 2);
+        bytes.extend_from_slice(&r.cycles.to_le_bytes());
+        writer.write_all(&bytes)
+    }
+
+    /// Read a record in the binary format, or `None` if `reader` is at its
+    /// end.
+    pub fn read_binary<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut bytes = [0u8; RECORD_LEN];
+        let mut len = 0;
+        while len < RECORD_LEN {
+            match reader.read(&mut bytes[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match len {
+            0 => return Ok(None),
+            RECORD_LEN => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "trace ends in the middle of a record",
+                ))
+            }
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let opcode_len = bytes[2] as usize;
+        if opcode_len == 0 || opcode_len > 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("opcode of {} bytes in trace", opcode_len),
+            ));
+        }
+        let mut cycles = [0u8; 8];
+        cycles.copy_from_slice(&bytes[32..40]);
+        Ok(Some(TraceRecord {
+            opcode: Opcode::from_bytes(&bytes[3..3 + opcode_len]),
+            registers: Z80Registers {
+                pc: u16_at(0),
+                af: u16_at(7),
+                bc: u16_at(9),
+                de: u16_at(11),
+                hl: u16_at(13),
+                af0: u16_at(15),
+                bc0: u16_at(17),
+                de0: u16_at(19),
+                hl0: u16_at(21),
+                ix: u16_at(23),
+                iy: u16_at(25),
+                sp: u16_at(27),
+                i: bytes[29],
+                r: bytes[30],
+                iff1: bytes[31] & 1 != 0,
+                iff2: bytes[31] & 2 != 0,
+                im: bytes[31] >> 2 & 3,
+                cycles: u64::from_le_bytes(cycles),
+            },
+        }))
+    }
+
+    /// Each register, by the name it has in the text format.
+    fn numbers(&self) -> [(&'static str, u64); 18] {
+        let r = &self.registers;
+        [
+            ("PC", r.pc as u64),
+            ("AF", r.af as u64),
+            ("BC", r.bc as u64),
+            ("DE", r.de as u64),
+            ("HL", r.hl as u64),
+            ("IX", r.ix as u64),
+            ("IY", r.iy as u64),
+            ("SP", r.sp as u64),
+            ("AF'", r.af0 as u64),
+            ("BC'", r.bc0 as u64),
+            ("DE'", r.de0 as u64),
+            ("HL'", r.hl0 as u64),
+            ("I", r.i as u64),
+            ("R", r.r as u64),
+            ("IM", r.im as u64),
+            ("IFF1", r.iff1 as u64),
+            ("IFF2", r.iff2 as u64),
+            ("CYC", r.cycles),
+        ]
+    }
+
+    /// Each field that differs between `self` and `other`.
+    pub fn differences(&self, other: &TraceRecord) -> Vec<StateDifference> {
+        let mut differences = Vec::new();
+        if self.opcode != other.opcode {
+            differences.push(StateDifference {
+                field: "opcode".to_owned(),
+                left: opcode_text(self.opcode),
+                right: opcode_text(other.opcode),
+            });
+        }
+        for (&(field, left), &(_, right)) in self.numbers().iter().zip(other.numbers().iter()) {
+            if left != right {
+                differences.push(StateDifference {
+                    field: field.to_owned(),
+                    left: format!("{:#X}", left),
+                    right: format!("{:#X}", right),
+                });
+            }
+        }
+        differences
+    }
+}
+
+/// The bytes of `opcode` in hex, separated by spaces.
+fn opcode_text(opcode: Opcode) -> String {
+    let bytes: Vec<String> = opcode.bytes().iter().map(|b| format!("{:0>2X}", b)).collect();
+    bytes.join(" ")
+}
+
+/// The text format: the PC, the opcode's bytes, and its disassembly, followed
+/// by the registers as `NAME:value`, like
+///
+/// ```text
+/// 0038  F5           push af             AF:1A44 BC:0000 ... IFF1:0 IFF2:0 CYC:53190
+/// ```
+///
+/// Every field but the cycles has a fixed width, as in the traces many other
+/// emulators write, so traces can be compared with the usual text tools.
+impl Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+        let mnemonic = match self.opcode.mnemonic() {
+            Some(mnemonic) => mnemonic.to_string(),
+            None => "?".to_owned(),
+        };
+        write!(
+            f,
+            "{:0>4X}  {: <11}  {: <18} ",
+            r.pc,
+            opcode_text(self.opcode),
+            mnemonic
+        )?;
+        for &(name, value) in self.numbers()[1..12].iter() {
+            write!(f, " {}:{:0>4X}", name, value)?;
+        }
+        write!(
+            f,
+            " I:{:0>2X} R:{:0>2X} IM:{} IFF1:{} IFF2:{} CYC:{}",
+            r.i, r.r, r.im, r.iff1 as u8, r.iff2 as u8, r.cycles
+        )
+    }
+}
+
+/// An inbox writing a trace of every instruction the Z80 executes.
+///
+/// Since it's `tracing`, a `TracingInbox` makes the Z80 run in the
+/// interpreter, even if an `SmsZ80Runner` is set. Interrupts aren't in the
+/// trace, but their effect on the PC and the registers is.
+///
+/// If writing fails, the `TracingInbox` stops tracing, and `flush` returns the
+/// error.
+pub struct TracingInbox {
+    writer: Box<dyn Write + Send>,
+    format: TraceFormat,
+    error: Option<io::Error>,
+}
+
+impl TracingInbox {
+    /// A `TracingInbox` writing to `writer`, which should be buffered.
+    pub fn new<W>(mut writer: W, format: TraceFormat) -> io::Result<Self>
+    where
+        W: 'static + Write + Send,
+    {
+        if format == TraceFormat::Binary {
+            writer.write_all(TRACE_MAGIC)?;
+        }
+        Ok(TracingInbox {
+            writer: Box::new(writer),
+            format,
+            error: None,
+        })
+    }
+
+    /// A `TracingInbox` writing to a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<Self> {
+        TracingInbox::new(BufWriter::new(File::create(path)?), format)
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    /// Flush the writer, or return the error that stopped tracing.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+impl Inbox for TracingInbox {
+    type Memo = Z80Memo;
+
+    fn receive_impl(&mut self, memo: Z80Memo) {
+        if let Z80Memo::Instruction {
+            opcode, registers, ..
+        } = memo
+        {
+            let record = TraceRecord { opcode, registers };
+            let result = match self.format {
+                TraceFormat::Binary => record.write_binary(&mut self.writer),
+                TraceFormat::Text => writeln!(self.writer, "{}", record),
+            };
+            if let Err(e) = result {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    #[inline]
+    fn active(&self) -> bool {
+        self.error.is_none()
+    }
+
+    #[inline]
+    fn tracing(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl GetDebugger for TracingInbox {
+    fn debugger(&mut self) -> Option<&mut dyn Debugger> {
+        None
+    }
+}
+
+/// Iterates through the records of a trace in the binary format.
+pub struct TraceReader<R> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Read the beginning of the trace, making sure it's in the binary
+    /// format.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != TRACE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a binary trace",
+            ));
+        }
+        Ok(TraceReader { reader })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match TraceRecord::read_binary(&mut self.reader) {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Where two traces first differ.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TraceDivergence {
+    /// How many instructions (or, for text traces, lines) the traces have in
+    /// common.
+    pub index: u64,
+
+    /// The left trace's instruction at `index`, in the text format, or `None`
+    /// if the left trace ends before it.
+    pub left: Option<String>,
+
+    /// Like `left`, for the right trace.
+    pub right: Option<String>,
+
+    /// For binary traces with an instruction at `index`, each field that
+    /// differs.
+    pub differences: Vec<StateDifference>,
+}
+
+impl Display for TraceDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Traces diverge after {} matching entries", self.index)?;
+        let line = |x: &Option<String>| x.clone().unwrap_or_else(|| "(end of trace)".to_owned());
+        writeln!(f, "left:  {}", line(&self.left))?;
+        writeln!(f, "right: {}", line(&self.right))?;
+        for difference in self.differences.iter() {
+            writeln!(f, "{}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the first instruction at which traces `left` and `right` differ, or
+/// `None` if they're the same.
+pub fn find_trace_divergence<L, R>(left: L, right: R) -> io::Result<Option<TraceDivergence>>
+where
+    L: IntoIterator<Item = io::Result<TraceRecord>>,
+    R: IntoIterator<Item = io::Result<TraceRecord>>,
+{
+    let mut left = left.into_iter();
+    let mut right = right.into_iter();
+    let mut index = 0;
+    loop {
+        let l = left.next().transpose()?;
+        let r = right.next().transpose()?;
+        let differences = match (&l, &r) {
+            (&None, &None) => return Ok(None),
+            (&Some(ref l), &Some(ref r)) if l == r => {
+                index += 1;
+                continue;
+            }
+            (&Some(ref l), &Some(ref r)) => l.differences(r),
+            _ => Vec::new(),
+        };
+        return Ok(Some(TraceDivergence {
+            index,
+            left: l.map(|x| x.to_string()),
+            right: r.map(|x| x.to_string()),
+            differences,
+        }));
+    }
+}
+
+/// Find the first line at which text traces `left` and `right` differ, or
+/// `None` if they're the same.
+///
+/// Whitespace at the ends of lines doesn't matter, but otherwise lines are
+/// compared exactly, so these can be traces written by any emulator.
+pub fn find_text_trace_divergence<L, R>(left: L, right: R) -> io::Result<Option<TraceDivergence>>
+where
+    L: BufRead,
+    R: BufRead,
+{
+    let mut left = left.lines();
+    let mut right = right.lines();
+    let mut index = 0;
+    loop {
+        let l = left.next().transpose()?;
+        let r = right.next().transpose()?;
+        let same = match (&l, &r) {
+            (&None, &None) => return Ok(None),
+            (&Some(ref l), &Some(ref r)) => l.trim_end() == r.trim_end(),
+            _ => false,
+        };
+        if !same {
+            return Ok(Some(TraceDivergence {
+                index,
+                left: l,
+                right: r,
+                differences: Vec::new(),
+            }));
+        }
+        index += 1;
+    }
+}
+
+/// Find the first divergence between the traces in files `left` and `right`,
+/// which must be in the same format.
+pub fn diff_trace_files<P, Q>(left: P, right: Q) -> io::Result<Option<TraceDivergence>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut left = BufReader::new(File::open(left)?);
+    let mut right = BufReader::new(File::open(right)?);
+    let left_binary = left.fill_buf()?.starts_with(TRACE_MAGIC);
+    let right_binary = right.fill_buf()?.starts_with(TRACE_MAGIC);
+    match (left_binary, right_binary) {
+        (true, true) => find_trace_divergence(TraceReader::new(left)?, TraceReader::new(right)?),
+        (false, false) => find_text_trace_divergence(left, right),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't compare a binary trace with a text trace",
+        )),
+    }
+}
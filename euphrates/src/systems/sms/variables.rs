@@ -0,0 +1,733 @@
+//! Game variables stored in RAM, and rewards computed from them.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use toml;
+
+use super::*;
+
+/// Which memory a `RamVariable` lives in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RamRegion {
+    SystemRam,
+    MainCartridgeRam,
+    HalfCartridgeRam,
+}
+
+impl Default for RamRegion {
+    #[inline]
+    fn default() -> Self {
+        RamRegion::SystemRam
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum VariableType {
+    #[serde(rename = "u8")]
+    U8,
+    #[serde(rename = "i8")]
+    I8,
+    #[serde(rename = "u16le")]
+    U16Le,
+    #[serde(rename = "u16be")]
+    U16Be,
+    /// Binary coded decimal, most significant byte first.
+    #[serde(rename = "bcd")]
+    Bcd,
+}
+
+/// What a variable means to the game.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Meaning {
+    Score,
+    Lives,
+    Level,
+    XPosition,
+    YPosition,
+    Other,
+}
+
+fn default_length() -> usize {
+    1
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct RamVariable {
+    #[serde(default)]
+    pub region: RamRegion,
+
+    /// Index into `region`.
+    pub address: usize,
+
+    #[serde(rename = "type")]
+    pub variable_type: VariableType,
+
+    /// Number of bytes; only used for `VariableType::Bcd`.
+    #[serde(default = "default_length")]
+    pub length: usize,
+
+    #[serde(default)]
+    pub meaning: Option<Meaning>,
+}
+
+fn read_byte(memory: &dyn SmsMemory, region: RamRegion, index: usize) -> u8 {
+    use self::RamRegion::*;
+    // cartridge RAM may not have been allocated yet; treat it as 0
+    match region {
+        SystemRam if index < memory.system_ram_len() => memory.system_ram_read(index),
+        MainCartridgeRam if index < memory.main_cartridge_ram_len() => {
+            memory.main_cartridge_ram_read(index)
+        }
+        HalfCartridgeRam if index < memory.half_cartridge_ram_len() => {
+            memory.half_cartridge_ram_read(index)
+        }
+        _ => 0,
+    }
+}
+
+impl RamVariable {
+    pub fn read(&self, memory: &dyn SmsMemory) -> f64 {
+        use self::VariableType::*;
+        let byte = |i: usize| read_byte(memory, self.region, self.address + i);
+        match self.variable_type {
+            U8 => byte(0) as f64,
+            I8 => byte(0) as i8 as f64,
+            U16Le => (byte(0) as u16 | (byte(1) as u16) << 8) as f64,
+            U16Be => ((byte(0) as u16) << 8 | byte(1) as u16) as f64,
+            Bcd => {
+                let mut value = 0f64;
+                for i in 0..self.length {
+                    let b = byte(i);
+                    value = value * 100.0 + (10 * (b >> 4) + (b & 0xF)) as f64;
+                }
+                value
+            }
+        }
+    }
+}
+
+/// Named RAM variables, and expressions computing a reward and whether the
+/// game is over.
+///
+/// A `GameDefinition` is usually written in TOML, like this:
+///
+/// ```toml
+/// name = "Some Game"
+/// reward = "delta(score) - 100 * (lives < prev(lives))"
+/// done = "lives == 0"
+///
+/// [variables.score]
+/// address = 0x0B60
+/// type = "bcd"
+/// length = 3
+/// meaning = "score"
+///
+/// [variables.lives]
+/// address = 0x0B46
+/// type = "u8"
+/// meaning = "lives"
+/// ```
+///
+/// Each variable has an `address` within a `region` of memory (by default
+/// `system_ram`; also `main_cartridge_ram` or `half_cartridge_ram`), a `type`
+/// (`u8`, `i8`, `u16le`, `u16be`, or `bcd`, where `bcd` is `length` bytes,
+/// most significant first), and optionally a `meaning`.
+///
+/// The `reward` and `done` expressions may use numbers (decimal, like `2`,
+/// `0.5`, or `1e-3`, or hexadecimal, like `0x1F`), variable names,
+/// `prev(name)` for the variable's value at the previous update, `delta(name)`,
+/// `abs`, `min`, `max`, the arithmetic operators `+ - * / %`, the comparisons
+/// `== != < <= > >=`, and `! && ||`. Comparisons and logical operators give 1
+/// or 0; a `done` expression is true if it is not 0. The function names can't
+/// be used as variable names.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct GameDefinition {
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub variables: BTreeMap<String, RamVariable>,
+
+    #[serde(default)]
+    pub reward: Option<String>,
+
+    #[serde(default)]
+    pub done: Option<String>,
+}
+
+impl GameDefinition {
+    pub fn from_toml(s: &str) -> Result<Self, GameDefinitionError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, GameDefinitionError> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let path = path.as_ref();
+        let io_error = |io_error| GameDefinitionError::Io {
+            filename: path.to_string_lossy().into_owned(),
+            io_error,
+        };
+        let mut s = String::new();
+        File::open(path)
+            .map_err(io_error)?
+            .read_to_string(&mut s)
+            .map_err(io_error)?;
+        Self::from_toml(&s)
+    }
+}
+
+// This superfluous module with the `allow` attribute is necessary until the
+// `fail` crate begins using `dyn trait` syntax
+#[allow(bare_trait_objects)]
+mod game_definition_error {
+    use super::*;
+
+    #[derive(Debug, Fail)]
+    pub enum GameDefinitionError {
+        #[fail(display = "invalid game definition: {}", _0)]
+        Toml(#[cause] toml::de::Error),
+
+        #[fail(display = "IO error while reading game definition {}: {}", filename, io_error)]
+        Io {
+            filename: String,
+            #[cause]
+            io_error: std::io::Error,
+        },
+
+        #[fail(display = "in expression '{}': {}", expression, message)]
+        Expression { expression: String, message: String },
+
+        #[fail(display = "variable name '{}' is the name of a function", _0)]
+        ReservedName(String),
+    }
+}
+
+pub use self::game_definition_error::GameDefinitionError;
+
+impl From<toml::de::Error> for GameDefinitionError {
+    fn from(x: toml::de::Error) -> Self {
+        GameDefinitionError::Toml(x)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Min,
+    Max,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Number(f64),
+    Variable(usize),
+    Previous(usize),
+    Neg(Box<Expression>),
+    Not(Box<Expression>),
+    Abs(Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+fn truth(x: bool) -> f64 {
+    if x {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Expression {
+    fn evaluate(&self, values: &[f64], previous: &[f64]) -> f64 {
+        use self::BinaryOp::*;
+        use self::Expression::*;
+        match *self {
+            Number(x) => x,
+            Variable(i) => values[i],
+            Previous(i) => previous[i],
+            Neg(ref e) => -e.evaluate(values, previous),
+            Not(ref e) => truth(e.evaluate(values, previous) == 0.0),
+            Abs(ref e) => e.evaluate(values, previous).abs(),
+            Binary(op, ref e1, ref e2) => {
+                let x = e1.evaluate(values, previous);
+                let y = e2.evaluate(values, previous);
+                match op {
+                    Add => x + y,
+                    Sub => x - y,
+                    Mul => x * y,
+                    Div => x / y,
+                    Rem => x % y,
+                    Eq => truth(x == y),
+                    Ne => truth(x != y),
+                    Lt => truth(x < y),
+                    Le => truth(x <= y),
+                    Gt => truth(x > y),
+                    Ge => truth(x >= y),
+                    And => truth(x != 0.0 && y != 0.0),
+                    Or => truth(x != 0.0 || y != 0.0),
+                    Min => x.min(y),
+                    Max => x.max(y),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 18] = [
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "(", ")", ",",
+    "=",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    'outer: while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '.' {
+            let hex = rest.starts_with("0x") || rest.starts_with("0X");
+            let bytes = rest.as_bytes();
+            let mut end = 1;
+            while end < bytes.len() {
+                let b = bytes[end];
+                let exponent_sign = !hex
+                    && (b == b'+' || b == b'-')
+                    && (bytes[end - 1] == b'e' || bytes[end - 1] == b'E');
+                if !b.is_ascii_alphanumeric() && b != b'.' && !exponent_sign {
+                    break;
+                }
+                end += 1;
+            }
+            let text = &rest[..end];
+            let value = if hex {
+                u64::from_str_radix(&text[2..], 16).map(|x| x as f64).ok()
+            } else {
+                text.parse::<f64>().ok()
+            };
+            match value {
+                Some(x) => tokens.push(Token::Number(x)),
+                None => return Err(format!("invalid number '{}'", text)),
+            }
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else {
+            for op in OPERATORS.iter() {
+                if rest.starts_with(op) {
+                    if *op == "=" {
+                        return Err("unexpected '='; did you mean '=='?".to_owned());
+                    }
+                    tokens.push(Token::Op(op));
+                    rest = &rest[op.len()..];
+                    rest = rest.trim_start();
+                    continue 'outer;
+                }
+            }
+            return Err(format!("unexpected character '{}'", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    names: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            Some(t) => Err(format!("expected '{}' but found {:?}", op, t)),
+            None => Err(format!("expected '{}' but found end of expression", op)),
+        }
+    }
+
+    fn variable(&self, name: &str) -> Result<usize, String> {
+        match self.names.iter().position(|n| n == name) {
+            Some(i) => Ok(i),
+            None => Err(format!("unknown variable '{}'", name)),
+        }
+    }
+
+    fn binary_op(&self, level: usize) -> Option<BinaryOp> {
+        use self::BinaryOp::*;
+        let op = match self.peek() {
+            Some(&Token::Op(op)) => op,
+            _ => return None,
+        };
+        let (op_level, binary_op) = match op {
+            "||" => (0, Or),
+            "&&" => (1, And),
+            "==" => (2, Eq),
+            "!=" => (2, Ne),
+            "<" => (3, Lt),
+            "<=" => (3, Le),
+            ">" => (3, Gt),
+            ">=" => (3, Ge),
+            "+" => (4, Add),
+            "-" => (4, Sub),
+            "*" => (5, Mul),
+            "/" => (5, Div),
+            "%" => (5, Rem),
+            _ => return None,
+        };
+        if op_level == level {
+            Some(binary_op)
+        } else {
+            None
+        }
+    }
+
+    /// Parse a (left associative) expression with binary operators of
+    /// precedence `level` or higher.
+    fn expression(&mut self, level: usize) -> Result<Expression, String> {
+        if level > 5 {
+            return self.unary();
+        }
+        let mut e = self.expression(level + 1)?;
+        while let Some(op) = self.binary_op(level) {
+            self.position += 1;
+            let e2 = self.expression(level + 1)?;
+            e = Expression::Binary(op, Box::new(e), Box::new(e2));
+        }
+        Ok(e)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some(&Token::Op("-")) => {
+                self.position += 1;
+                Ok(Expression::Neg(Box::new(self.unary()?)))
+            }
+            Some(&Token::Op("!")) => {
+                self.position += 1;
+                Ok(Expression::Not(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn argument_name(&mut self) -> Result<usize, String> {
+        self.expect("(")?;
+        let i = match self.next() {
+            Some(Token::Ident(name)) => self.variable(&name)?,
+            _ => return Err("expected a variable name".to_owned()),
+        };
+        self.expect(")")?;
+        Ok(i)
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        use self::Expression::*;
+        match self.next() {
+            Some(Token::Number(x)) => Ok(Number(x)),
+            Some(Token::Op("(")) => {
+                let e = self.expression(0)?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some(Token::Ident(name)) => match name.as_ref() {
+                "prev" => Ok(Previous(self.argument_name()?)),
+                "delta" => {
+                    let i = self.argument_name()?;
+                    Ok(Binary(
+                        BinaryOp::Sub,
+                        Box::new(Variable(i)),
+                        Box::new(Previous(i)),
+                    ))
+                }
+                "abs" => {
+                    self.expect("(")?;
+                    let e = self.expression(0)?;
+                    self.expect(")")?;
+                    Ok(Abs(Box::new(e)))
+                }
+                "min" | "max" => {
+                    self.expect("(")?;
+                    let e1 = self.expression(0)?;
+                    self.expect(",")?;
+                    let e2 = self.expression(0)?;
+                    self.expect(")")?;
+                    let op = if name == "min" {
+                        BinaryOp::Min
+                    } else {
+                        BinaryOp::Max
+                    };
+                    Ok(Binary(op, Box::new(e1), Box::new(e2)))
+                }
+                _ => Ok(Variable(self.variable(&name)?)),
+            },
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err("unexpected end of expression".to_owned()),
+        }
+    }
+}
+
+/// Names which can't be used for variables, since expressions use them for
+/// functions.
+const FUNCTIONS: &[&str] = &["prev", "delta", "abs", "min", "max"];
+
+fn parse(s: &str, names: &[String]) -> Result<Expression, GameDefinitionError> {
+    let error = |message| GameDefinitionError::Expression {
+        expression: s.to_owned(),
+        message,
+    };
+    let mut parser = Parser {
+        tokens: tokenize(s).map_err(error)?,
+        position: 0,
+        names,
+    };
+    let e = parser.expression(0).map_err(error)?;
+    if let Some(t) = parser.peek() {
+        return Err(error(format!("unexpected {:?}", t)));
+    }
+    Ok(e)
+}
+
+/// Keeps track of the variables of a `GameDefinition` from frame to frame.
+///
+/// Call `reset` at the start of an episode and `update` at the end of each
+/// frame (or each step); `prev` in expressions refers to the values read by
+/// the update before.
+#[derive(Clone, Debug)]
+pub struct GameMonitor {
+    definition: GameDefinition,
+    names: Vec<String>,
+    variables: Vec<RamVariable>,
+    reward: Option<Expression>,
+    done: Option<Expression>,
+    values: Vec<f64>,
+    previous: Vec<f64>,
+}
+
+impl GameMonitor {
+    pub fn new(definition: GameDefinition) -> Result<Self, GameDefinitionError> {
+        let names: Vec<String> = definition.variables.keys().cloned().collect();
+        if let Some(name) = names.iter().find(|name| FUNCTIONS.contains(&name.as_ref())) {
+            return Err(GameDefinitionError::ReservedName(name.clone()));
+        }
+        let variables: Vec<RamVariable> = definition.variables.values().cloned().collect();
+        let reward = match definition.reward {
+            Some(ref s) => Some(parse(s, &names)?),
+            None => None,
+        };
+        let done = match definition.done {
+            Some(ref s) => Some(parse(s, &names)?),
+            None => None,
+        };
+        let len = names.len();
+        Ok(GameMonitor {
+            definition,
+            names,
+            variables,
+            reward,
+            done,
+            values: vec![0.0; len],
+            previous: vec![0.0; len],
+        })
+    }
+
+    #[inline]
+    pub fn definition(&self) -> &GameDefinition {
+        &self.definition
+    }
+
+    fn read(&mut self, memory: &dyn SmsMemory) {
+        for (value, variable) in self.values.iter_mut().zip(self.variables.iter()) {
+            *value = variable.read(memory);
+        }
+    }
+
+    /// Read all variables, and make the previous values the same as the current
+    /// ones.
+    pub fn reset(&mut self, memory: &dyn SmsMemory) {
+        self.read(memory);
+        self.previous.clone_from(&self.values);
+    }
+
+    /// Read all variables, remembering the values from the last update.
+    pub fn update(&mut self, memory: &dyn SmsMemory) {
+        ::std::mem::swap(&mut self.values, &mut self.previous);
+        self.read(memory);
+    }
+
+    /// The value of the `reward` expression, or 0 if there isn't one.
+    pub fn reward(&self) -> f64 {
+        match self.reward {
+            Some(ref e) => e.evaluate(&self.values, &self.previous),
+            None => 0.0,
+        }
+    }
+
+    /// Is the `done` expression nonzero? False if there isn't one.
+    pub fn done(&self) -> bool {
+        match self.done {
+            Some(ref e) => e.evaluate(&self.values, &self.previous) != 0.0,
+            None => false,
+        }
+    }
+
+    pub fn value(&self, name: &str) -> Option<f64> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| self.values[i])
+    }
+
+    /// The value of the first variable with this `meaning`, if any.
+    pub fn value_with_meaning(&self, meaning: Meaning) -> Option<f64> {
+        self.variables
+            .iter()
+            .position(|v| v.meaning == Some(meaning))
+            .map(|i| self.values[i])
+    }
+
+    /// All variables' names and current values, sorted by name.
+    pub fn values(&self) -> Vec<(&str, f64)> {
+        self.names
+            .iter()
+            .map(|s| s.as_ref())
+            .zip(self.values.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec!["x".to_owned(), "y".to_owned()]
+    }
+
+    fn evaluate(s: &str) -> f64 {
+        parse(s, &names())
+            .unwrap()
+            .evaluate(&[5.0, -2.0], &[3.0, 4.0])
+    }
+
+    fn error(s: &str) -> String {
+        match parse(s, &names()) {
+            Err(GameDefinitionError::Expression { expression, message }) => {
+                assert_eq!(expression, s);
+                message
+            }
+            x => panic!("expected an expression error for '{}', got {:?}", s, x),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("10 - 4 - 3"), 3.0);
+        assert_eq!(evaluate("7 % 4 * 2"), 6.0);
+        assert_eq!(evaluate("1 + 1 < 3"), 1.0);
+        assert_eq!(evaluate("1 < 2 == 1"), 1.0);
+        assert_eq!(evaluate("1 || 0 && 0"), 1.0);
+        assert_eq!(evaluate("(1 || 0) && 0"), 0.0);
+    }
+
+    #[test]
+    fn unary() {
+        assert_eq!(evaluate("-2 * 3"), -6.0);
+        assert_eq!(evaluate("2 - -3"), 5.0);
+        assert_eq!(evaluate("--2"), 2.0);
+        assert_eq!(evaluate("-x"), -5.0);
+        assert_eq!(evaluate("!0"), 1.0);
+        assert_eq!(evaluate("!x + 1"), 1.0);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(evaluate("0.5"), 0.5);
+        assert_eq!(evaluate(".25"), 0.25);
+        assert_eq!(evaluate("1e-3"), 0.001);
+        assert_eq!(evaluate("2.5E2"), 250.0);
+        assert_eq!(evaluate("1e+2-1"), 99.0);
+        assert_eq!(evaluate("0x1e-1"), 29.0);
+        assert_eq!(evaluate("0X10"), 16.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(evaluate("prev(x)"), 3.0);
+        assert_eq!(evaluate("delta(x)"), 2.0);
+        assert_eq!(evaluate("delta(y)"), -6.0);
+        assert_eq!(evaluate("abs(y)"), 2.0);
+        assert_eq!(evaluate("min(x, y)"), -2.0);
+        assert_eq!(evaluate("max(x, y * -3)"), 6.0);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(error("x = 1").contains("did you mean '=='"));
+        assert_eq!(error("1 +"), "unexpected end of expression");
+        assert!(error("(1").contains("expected ')'"));
+        assert_eq!(error("z"), "unknown variable 'z'");
+        assert_eq!(error("prev(z)"), "unknown variable 'z'");
+        assert_eq!(error("prev(1)"), "expected a variable name");
+        assert!(error("1 2").starts_with("unexpected Number"));
+        assert_eq!(error("1e"), "invalid number '1e'");
+        assert_eq!(error("1e-"), "invalid number '1e-'");
+        assert_eq!(error("0xg"), "invalid number '0xg'");
+        assert_eq!(error("x $ y"), "unexpected character '$'");
+    }
+
+    #[test]
+    fn reserved_names() {
+        for &function in FUNCTIONS {
+            let definition = GameDefinition::from_toml(&format!(
+                "reward = \"1\"\n[variables.{}]\naddress = 0\ntype = \"u8\"\n",
+                function
+            )).unwrap();
+            match GameMonitor::new(definition) {
+                Err(GameDefinitionError::ReservedName(ref name)) if name == function => {}
+                x => panic!("expected '{}' to be rejected, got {:?}", function, x.err()),
+            }
+        }
+        let definition =
+            GameDefinition::from_toml("[variables.maximum]\naddress = 0\ntype = \"u8\"\n")
+                .unwrap();
+        assert!(GameMonitor::new(definition).is_ok());
+    }
+}
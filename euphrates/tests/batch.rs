@@ -0,0 +1,197 @@
+//! `BatchEnvironment`s, checked against the same environments stepped one at a
+//! time.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::systems::sms::{
+    BatchEnvironment, BatchError, Kind, PointerSmsMemory, SmsEnvironment, SmsMemoryMapper,
+    SmsState, StepResult, TvSystem, TypeWrap,
+};
+
+/// Adds up joypad 1 at 0xC000 once a frame.
+const SUM: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+        im 1
+        ; frame interrupts on
+        ld a, 20
+        out (0BF), a
+        ld a, 81
+        out (0BF), a
+        ei
+    main:
+        halt
+        jr main
+        org 38
+        push af
+        push hl
+        in a, (0BF)
+        in a, (0DC)
+        cpl
+        ld hl, 0C000
+        add a, (hl)
+        ld (hl), a
+        pop hl
+        pop af
+        ei
+        reti
+";
+
+fn initial_state() -> SmsState {
+    let mut rom = assemble(SUM).unwrap().bytes;
+    rom.resize(0x8000, 0);
+    SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    )
+}
+
+/// Two frames a step, rewarded with the sum, and done once it's past 0x80.
+fn configure(_: usize, environment: &mut SmsEnvironment) {
+    environment.set_frames_per_step(2);
+    environment.set_reward_function(Box::new(|sms| sms.sms_memory().system_ram_read(0) as f64));
+    environment.set_done_function(Box::new(|sms| sms.sms_memory().system_ram_read(0) > 0x80));
+}
+
+fn environments(count: usize) -> Vec<SmsEnvironment> {
+    (0..count)
+        .map(|i| {
+            let mut environment =
+                SmsEnvironment::new(initial_state(), TypeWrap::<PointerSmsMemory>::default())
+                    .unwrap();
+            configure(i, &mut environment);
+            environment
+        })
+        .collect()
+}
+
+/// A different action for each environment and step.
+fn actions(count: usize, step: usize) -> Vec<usize> {
+    (0..count).map(|i| (3 * i + 7 * step) % 20).collect()
+}
+
+fn assert_same(batched: &[StepResult], sequential: &[StepResult]) {
+    assert_eq!(batched.len(), sequential.len());
+    for (i, (x, y)) in batched.iter().zip(sequential.iter()).enumerate() {
+        assert!(x.0 == y.0, "observations of environment {} differ", i);
+        assert_eq!((x.1, x.2, x.3), (y.1, y.2, y.3), "environment {}", i);
+    }
+}
+
+#[test]
+fn batched_steps_equal_sequential_steps() {
+    const COUNT: usize = 5;
+    const STEPS: usize = 12;
+    let mut batch = BatchEnvironment::from_state(
+        initial_state(),
+        COUNT,
+        2,
+        TypeWrap::<PointerSmsMemory>::default(),
+        configure,
+    ).unwrap();
+    assert_eq!(batch.len(), COUNT);
+    assert_eq!(batch.threads(), 2);
+    let mut sequential = environments(COUNT);
+
+    for _ in 0..2 {
+        let observations = batch.reset().unwrap();
+        for (observation, environment) in observations.iter().zip(sequential.iter_mut()) {
+            assert!(*observation == environment.reset().unwrap());
+        }
+        let mut done = false;
+        for step in 0..STEPS {
+            let actions = actions(COUNT, step);
+            let batched = batch.step(&actions).unwrap();
+            let one_at_a_time: Vec<StepResult> = sequential
+                .iter_mut()
+                .zip(actions.iter())
+                .map(|(e, &a)| e.step(a).unwrap())
+                .collect();
+            assert_same(&batched, &one_at_a_time);
+            done |= batched.iter().any(|x| x.2);
+        }
+        // some episodes finished
+        assert!(done);
+    }
+
+    // the environments come back in order, in the same states
+    let environments = batch.into_environments().unwrap();
+    assert_eq!(environments.len(), COUNT);
+    for (x, y) in environments.iter().zip(sequential.iter()) {
+        assert!(x.master_system().state() == y.master_system().state());
+    }
+}
+
+#[test]
+fn invalid_action() {
+    let mut batch = BatchEnvironment::new(environments(3), 2);
+    let mut sequential = environments(3);
+    batch.reset().unwrap();
+    match batch.step(&[0, 20, 1]) {
+        Err(BatchError::InvalidAction {
+            environment: 1,
+            action: 20,
+            action_count: 20,
+        }) => {}
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected an invalid action error"),
+    }
+
+    // nothing was stepped, and the batch still works
+    for step in 0..3 {
+        let actions = actions(3, step);
+        let batched = batch.step(&actions).unwrap();
+        let one_at_a_time: Vec<StepResult> = sequential
+            .iter_mut()
+            .zip(actions.iter())
+            .map(|(e, &a)| e.step(a).unwrap())
+            .collect();
+        assert_same(&batched, &one_at_a_time);
+    }
+}
+
+fn assert_panicked<T>(result: Result<T, BatchError>) {
+    match result {
+        Err(BatchError::WorkerPanicked) => {}
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected a worker to have panicked"),
+    }
+}
+
+#[test]
+fn worker_panicked() {
+    let mut environments = environments(4);
+    let mut steps = 0;
+    environments[3].set_reward_function(Box::new(move |_| {
+        steps += 1;
+        if steps == 2 {
+            panic!("reward function failed");
+        }
+        0.0
+    }));
+    let mut batch = BatchEnvironment::new(environments, 2);
+    batch.reset().unwrap();
+    assert_eq!(batch.step(&[0; 4]).unwrap().len(), 4);
+
+    assert_panicked(batch.step(&[0; 4]));
+    // every later request fails the same way, rather than getting a stale
+    // response
+    assert_panicked(batch.step(&[0; 4]));
+    assert_panicked(batch.reset());
+    assert_panicked(batch.into_environments());
+}
+
+#[test]
+fn dropped_after_panic() {
+    let mut environments = environments(2);
+    environments[0].set_reward_function(Box::new(|_| panic!("reward function failed")));
+    let mut batch = BatchEnvironment::new(environments, 2);
+    assert_panicked(batch.step(&[0, 0]));
+    drop(batch);
+}
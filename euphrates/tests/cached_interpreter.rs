@@ -0,0 +1,181 @@
+//! Run systems with the cached interpreter in lockstep with systems running
+//! the usual interpreter, and check that their states never differ. Also
+//! check each decoded instruction on its own against the interpreter.
+
+extern crate euphrates;
+extern crate euphrates_testing;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::memory16::Memory16;
+use euphrates::hardware::z80::Reg16::*;
+use euphrates::hardware::z80::Reg8::*;
+use euphrates::hardware::z80::{Z80Internal, Z80Irq, Z80RunImpler, Z80State};
+use euphrates::memo::NothingInbox;
+use euphrates::systems::sms::{CachedInterpreter, LockstepOptions};
+use euphrates_testing::{bank_switching_rom, check_bank_switching, lockstep, random_rom, XorShift};
+
+fn cached_lockstep(rom: &[u8], frames: usize, every_instruction: bool) {
+    let options = LockstepOptions {
+        every_instruction,
+        ..Default::default()
+    };
+    lockstep(rom, frames, Box::new(CachedInterpreter::new()), &options);
+}
+
+/// Random code with plenty of prefixes.
+fn prefixed_rom(seed: u64) -> Vec<u8> {
+    random_rom(seed, |rng| match rng.next_u64() % 8 {
+        0 => 0xDD,
+        1 => 0xFD,
+        _ => rng.next_u64() as u8,
+    })
+}
+
+#[test]
+fn random_code() {
+    for seed in 1..=8u64 {
+        cached_lockstep(&prefixed_rom(seed), 30, false);
+    }
+}
+
+#[test]
+fn random_code_every_instruction() {
+    for seed in 1..=2u64 {
+        cached_lockstep(&prefixed_rom(seed), 3, true);
+    }
+}
+
+#[test]
+fn bank_switching() {
+    let sms = lockstep(
+        &bank_switching_rom(),
+        3,
+        Box::new(CachedInterpreter::new()),
+        &LockstepOptions::default(),
+    );
+    check_bank_switching(&*sms);
+}
+
+struct Ram(Box<[u8; 0x10000]>);
+
+impl Memory16 for Ram {
+    fn read(&mut self, address: u16) -> u8 {
+        self.0[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.0[address as usize] = value;
+    }
+}
+
+/// Inputs depend on the port, and outputs are remembered.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Ports {
+    outputs: Vec<(u16, u8)>,
+}
+
+impl Io16 for Ports {
+    fn input(&mut self, address: u16) -> u8 {
+        (address as u8).wrapping_mul(3) ^ (address >> 8) as u8
+    }
+
+    fn output(&mut self, address: u16, value: u8) {
+        self.outputs.push((address, value));
+    }
+}
+
+struct NoIrq;
+
+impl Z80Irq for NoIrq {
+    fn requesting_mi(&mut self) -> Option<u8> {
+        None
+    }
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+    fn take_nmi(&mut self) {}
+}
+
+fn random_z80(rng: &mut XorShift) -> Z80State {
+    let mut z80 = Z80State::default();
+    for &reg in [AF, BC, DE, HL, AF0, BC0, DE0, HL0, IX, IY, SP, PC].iter() {
+        z80.set_reg16(reg, rng.next_u64() as u16);
+    }
+    z80.set_reg8(I, rng.next_u64() as u8);
+    z80.set_reg8(R, rng.next_u64() as u8);
+    z80.set_iff2(rng.next_u64() & 1 == 0);
+    z80.set_flags_written(rng.next_u64() & 1 == 0);
+    z80
+}
+
+/// Every instruction in the instruction list, decoded and executed, does just
+/// what the interpreter does.
+#[test]
+fn decoded_instructions() {
+    let mut rng = XorShift::new(1);
+    let mut ram = Ram(Box::new([0; 0x10000]));
+    for x in ram.0.iter_mut() {
+        *x = rng.next_u64() as u8;
+    }
+    let prefixes: [&[u8]; 7] = [
+        &[],
+        &[0xCB],
+        &[0xED],
+        &[0xDD],
+        &[0xFD],
+        &[0xDD, 0xCB, 0],
+        &[0xFD, 0xCB, 0],
+    ];
+    for prefix in prefixes.iter() {
+        for opcode in 0..=0xFFu8 {
+            for _ in 0..4 {
+                let z80 = random_z80(&mut rng);
+                let mut bytes = prefix.to_vec();
+                if bytes.len() == 3 {
+                    bytes[2] = rng.next_u64() as u8;
+                }
+                bytes.push(opcode);
+                bytes.push(rng.next_u64() as u8);
+                bytes.push(rng.next_u64() as u8);
+                let mut memory = Ram(ram.0.clone());
+                for (i, &byte) in bytes.iter().enumerate() {
+                    let address = z80.reg16(PC).wrapping_add(i as u16);
+                    memory.0[address as usize] = byte;
+                }
+
+                let mut interpreted = z80;
+                let mut interpreted_memory = Ram(memory.0.clone());
+                let mut interpreted_ports = Ports::default();
+                let decoded = Z80RunImpler {
+                    z80: &mut interpreted,
+                    memory: &mut interpreted_memory,
+                    io: &mut interpreted_ports,
+                    irq: &mut NoIrq,
+                    inbox: &mut NothingInbox::default(),
+                }.step_decoding(u64::MAX);
+                let decoded = match decoded {
+                    Some(x) => x,
+                    // the interpreter handles DD, ED, and FD opcodes that
+                    // aren't in the instruction list
+                    None => match (bytes[0], bytes[1]) {
+                        (0xDD, 0xCB) | (0xFD, 0xCB) => panic!("{:02X?} wasn't decoded", bytes),
+                        (0xDD, _) | (0xED, _) | (0xFD, _) => continue,
+                        _ => panic!("{:02X?} wasn't decoded", bytes),
+                    },
+                };
+
+                let mut executed = z80;
+                let mut executed_ports = Ports::default();
+                decoded.execute(&mut executed, &mut memory, &mut executed_ports);
+
+                assert_eq!(interpreted, executed, "{:02X?}", bytes);
+                assert!(
+                    interpreted_memory.0[..] == memory.0[..],
+                    "memory differs: {:02X?}",
+                    bytes
+                );
+                assert_eq!(interpreted_ports, executed_ports, "{:02X?}", bytes);
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Run small CP/M programs, assembled with the Z80 assembler.
+
+extern crate euphrates;
+extern crate tempdir;
+
+use tempdir::TempDir;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::systems::cpm::{CpmError, CpmMachine, TPA};
+
+/// Assemble `source`, which should begin with `org 100`, run it with `input`
+/// on the console, and return what it printed.
+fn run(source: &str, input: &[u8], args: &[&str]) -> Result<Vec<u8>, CpmError> {
+    let assembly = assemble(source).unwrap();
+    assert_eq!(assembly.origin, TPA);
+    let directory = TempDir::new("cpm").unwrap();
+    let mut output = Vec::new();
+    {
+        let mut machine = CpmMachine::new(directory.path(), input, &mut output);
+        machine.load(&assembly.bytes, args)?;
+        machine.run()?;
+    }
+    Ok(output)
+}
+
+const HELLO: &str = r#"
+    org 100
+    ld de, message
+    ld c, 9         ; print string
+    call 5
+    ld c, 0         ; system reset
+    call 5
+    halt            ; never reached
+message:
+    db "Hello, world!", 0D, 0A, "$"
+"#;
+
+#[test]
+fn hello() {
+    assert_eq!(run(HELLO, b"", &[]).unwrap(), b"Hello, world!\r\n");
+}
+
+#[test]
+fn halt() {
+    let source = "
+        org 100
+        halt
+    ";
+    match run(source, b"", &[]) {
+        // PC is past the halt instruction
+        Err(CpmError::Halted(0x0101)) => {}
+        x => panic!("expected to halt at 0101, got {:?}", x),
+    }
+}
+
+#[test]
+fn return_to_ccp() {
+    // returning from the program warm boots, which ends it
+    let source = "
+        org 100
+        ld e, '!'
+        ld c, 2         ; console output
+        call 5
+        ret
+    ";
+    assert_eq!(run(source, b"", &[]).unwrap(), b"!");
+}
+
+#[test]
+fn echo() {
+    // read a line with function 10 and print it back with function 2
+    let source = r#"
+        org 100
+        ld de, buffer
+        ld c, 0A        ; read console buffer
+        call 5
+        ld hl, buffer + 1
+        ld b, (hl)
+next:
+        inc hl
+        ld e, (hl)
+        push hl
+        push bc
+        ld c, 2
+        call 5
+        pop bc
+        pop hl
+        djnz next
+        jp 0
+        ; the rest of memory is zeroed, so the buffer needs only its capacity
+buffer:
+        db 10
+    "#;
+    assert_eq!(run(source, b"typed\r\nignored", &[]).unwrap(), b"typed");
+}
+
+#[test]
+fn command_tail() {
+    // print the command tail, which the CCP puts at 0080
+    let source = r#"
+        org 100
+        ld hl, 80
+        ld b, (hl)
+next:
+        inc hl
+        ld e, (hl)
+        push hl
+        push bc
+        ld c, 2
+        call 5
+        pop bc
+        pop hl
+        djnz next
+        ret
+    "#;
+    assert_eq!(
+        run(source, b"", &["input.txt", "b:out"]).unwrap(),
+        b" INPUT.TXT B:OUT"
+    );
+}
+
+/// Run ZEXDOC or ZEXALL, whose path is in the environment variable `var`, and
+/// check that every test passed.
+///
+/// These take hours in a debug build, so run them with something like
+/// `EUPHRATES_ZEXDOC=zexdoc.com cargo test --release -- --ignored`.
+fn exerciser(var: &str) {
+    use std::env;
+    use std::fs;
+
+    let path = match env::var_os(var) {
+        Some(path) => path,
+        None => {
+            eprintln!("{} isn't set; skipping", var);
+            return;
+        }
+    };
+    let program = fs::read(path).unwrap();
+    let directory = TempDir::new("cpm").unwrap();
+    let mut output = Vec::new();
+    {
+        let mut machine = CpmMachine::new(directory.path(), &b""[..], &mut output);
+        machine.load(&program, &[]).unwrap();
+        machine.run().unwrap();
+    }
+    let output = String::from_utf8_lossy(&output);
+    print!("{}", output);
+    assert!(output.contains("Tests complete"));
+    assert!(!output.contains("ERROR"));
+}
+
+#[test]
+#[ignore]
+fn zexdoc() {
+    exerciser("EUPHRATES_ZEXDOC");
+}
+
+#[test]
+#[ignore]
+fn zexall() {
+    exerciser("EUPHRATES_ZEXALL");
+}
@@ -0,0 +1,225 @@
+//! Test the static ROM disassembler by assembling its output again: with our
+//! own assembler, and with WLA-DX if `wla-z80` and `wlalink` are installed.
+
+extern crate euphrates;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+
+use euphrates::hardware::z80::assemble;
+use euphrates::systems::sms::{RomDisassembly, SmsMemoryMapper};
+
+const BANKS: [&str; 4] = [
+    "
+        org 0
+        di
+        ld sp, 0DFF0
+        jp main
+        org 38
+        ei
+        reti
+        org 66
+        retn
+        org 100
+    main:
+        ld a, 3
+        ld (0FFFF), a
+        call 8000
+        call 4000
+        ld hl, table
+        ld b, 4
+    loop:
+        ld a, (hl)
+        out (0BE), a
+        inc hl
+        djnz loop
+        ld a, (ix-5)
+        ld (iy+7), 12
+        in a, (0BF)
+        in b, (c)
+        out (c), b
+        bit 7, (hl)
+        jr nz, main
+        rst 38
+        db 0DD, 7C          ; ld a, ixh
+        db 0ED, 4C          ; neg, again
+        db 0ED, 63, 0, 0C0  ; ld (C000), hl, again
+        im 1
+        jp (hl)
+    table:
+        db 0C9, 3E, 1, 0C9
+    ",
+    "
+        org 4000
+        ld a, (ix+0)
+        ex af, af'
+        ret
+    ",
+    "
+        org 8000
+        db \"data\"
+    ",
+    "
+        org 8000
+        ld b, 2
+    spin:
+        djnz spin
+        ret nz
+        jp 8000
+    ",
+];
+
+fn rom() -> Vec<u8> {
+    let mut rom = vec![0xFF; 0xE000];
+    for (bank, source) in BANKS.iter().enumerate() {
+        let assembly = assemble(source).unwrap();
+        let start = bank * 0x4000 + assembly.origin as usize % 0x4000;
+        rom[start..start + assembly.bytes.len()].copy_from_slice(&assembly.bytes);
+    }
+    rom
+}
+
+/// Replace uses (not definitions) of labels like `B03_8123` with their
+/// addresses, since each bank is assembled by itself.
+fn replace_label_uses(line: &str) -> String {
+    if line.ends_with(':') {
+        return line.to_owned();
+    }
+    let mut result = String::new();
+    let mut rest = line;
+    while let Some(i) = rest.find('B') {
+        let candidate = &rest[i..];
+        let is_label = candidate.len() >= 8
+            && candidate.as_bytes()[3] == b'_'
+            && candidate[1..3].chars().all(|c| c.is_ascii_hexdigit())
+            && candidate[4..8].chars().all(|c| c.is_ascii_hexdigit());
+        result.push_str(&rest[..i]);
+        if is_label {
+            result.push('$');
+            result.push_str(&candidate[4..8]);
+            rest = &candidate[8..];
+        } else {
+            result.push('B');
+            rest = &candidate[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[test]
+fn reassemble() {
+    let rom = rom();
+    let disassembly = RomDisassembly::new(&rom, SmsMemoryMapper::Sega);
+    let source = disassembly.to_string();
+
+    assert!(source.contains("BANKSIZE $2000"));
+    assert!(source.contains(".db $DD,$7C ; ld a, ixh"));
+    assert!(source.contains(".db $ED,$4C ; neg"));
+    assert!(source.contains(".db $ED,$63,$00,$C0 ; ld (C000), hl"));
+    assert!(source.contains("call B03_8000"));
+    assert!(source.contains("djnz B00_0110"));
+    assert!(source.contains("ld a,(ix-$05)"));
+    assert!(source.contains("ld (iy+$07),$12"));
+
+    let sections: Vec<&str> = source.split(".BANK ").collect();
+    assert_eq!(sections.len(), 5);
+    for (bank, section) in sections[1..].iter().enumerate() {
+        let slot = bank.min(2);
+        let mut bank_source = format!("org {:X}\n", slot * 0x4000);
+        for line in section.lines().skip(2) {
+            bank_source.push_str(&replace_label_uses(line));
+            bank_source.push('\n');
+        }
+        let assembly = assemble(&bank_source)
+            .unwrap_or_else(|e| panic!("bank {}: {}\n{}", bank, e, bank_source));
+        assert_eq!(assembly.origin as usize, slot * 0x4000);
+        let end = rom.len().min((bank + 1) * 0x4000);
+        assert!(
+            assembly.bytes[..] == rom[bank * 0x4000..end],
+            "bank {}",
+            bank
+        );
+        for (label, &address) in assembly.labels.iter() {
+            assert_eq!(format!("{:0>4X}", address), label[4..], "{}", label);
+        }
+    }
+}
+
+/// Run `program` in `dir`, or return false if it isn't installed.
+fn run(dir: &Path, program: &str, args: &[&str]) -> bool {
+    let output = match Command::new(program).current_dir(dir).args(args).output() {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return false,
+        Err(e) => panic!("{}: {}", program, e),
+    };
+    assert!(
+        output.status.success(),
+        "{} failed:\n{}{}",
+        program,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    true
+}
+
+#[test]
+fn wla_dx() {
+    let rom = rom();
+    let source = RomDisassembly::new(&rom, SmsMemoryMapper::Sega).to_string();
+
+    let dir = env::temp_dir().join(format!("euphrates_disassembler_{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("rom.s"), &source).unwrap();
+    fs::write(dir.join("link"), "[objects]\nrom.o\n").unwrap();
+
+    let assembled = run(&dir, "wla-z80", &["-o", "rom.o", "rom.s"])
+        && run(&dir, "wlalink", &["-r", "link", "rom.sms"]);
+    if assembled {
+        let linked = fs::read(dir.join("rom.sms")).unwrap();
+        assert!(linked == rom, "WLA-DX assembled a different ROM");
+    } else {
+        eprintln!("WLA-DX isn't installed, so its assembly of the disassembly isn't checked");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn code_and_data() {
+    let rom = rom();
+    let disassembly = RomDisassembly::new(&rom, SmsMemoryMapper::Sega);
+
+    let labels = [0x0000, 0x0038, 0x0066, 0x0100, 0x0110, 0x4000, 0xC000, 0xC002];
+    for &index in labels.iter() {
+        assert!(disassembly.label(index).is_some(), "{:X}", index);
+    }
+    assert_eq!(disassembly.label(0xC002).unwrap(), "B03_8002");
+    assert_eq!(disassembly.label(0x0101), None);
+
+    for index in 0..0x100 {
+        let code = index < 0x07 || (0x38..0x3B).contains(&index) || (0x66..0x68).contains(&index);
+        assert_eq!(disassembly.is_code(index), code, "{:X}", index);
+    }
+    // the end of main, then the table
+    assert!(disassembly.is_code(0x132));
+    assert!(!disassembly.is_code(0x133));
+    // bank 2 isn't called, and the rest of bank 1 is padding
+    assert!(!disassembly.is_code(0x8000));
+    assert!(disassembly.is_code(0x4004));
+    assert!(!disassembly.is_code(0x4005));
+}
+
+#[test]
+fn unknown_bank() {
+    // bank 3 is mapped with a value that isn't known, so its code isn't found
+    let mut rom = rom();
+    rom[0x100] = 0x7E; // ld a, (hl)
+    rom[0x101] = 0x00; // nop
+    let disassembly = RomDisassembly::new(&rom, SmsMemoryMapper::Sega);
+    assert!(!disassembly.is_code(0xC000));
+    assert!(disassembly.is_code(0x0107));
+    assert!(disassembly.to_string().contains("call $8000"));
+}
@@ -0,0 +1,135 @@
+//! `SmsEnvironment` episodes, and rewards computed from game variables.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::systems::sms::{
+    GameDefinition, GameMonitor, Kind, PointerSmsMemory, SmsEnvironment, SmsMemoryMapper,
+    SmsState, TvSystem, TypeWrap,
+};
+
+/// Counts frames at 0xC000, and adds up joypad 1 at 0xC001 once a frame, so
+/// that what happens depends on the actions taken.
+const COUNTER: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+        im 1
+        ; frame interrupts on
+        ld a, 20
+        out (0BF), a
+        ld a, 81
+        out (0BF), a
+        ei
+    main:
+        halt
+        jr main
+        org 38
+        push af
+        push hl
+        in a, (0BF)
+        ld hl, 0C000
+        inc (hl)
+        in a, (0DC)
+        cpl
+        ld hl, 0C001
+        add a, (hl)
+        ld (hl), a
+        pop hl
+        pop af
+        ei
+        reti
+";
+
+fn environment() -> SmsEnvironment {
+    let mut rom = assemble(COUNTER).unwrap().bytes;
+    rom.resize(0x8000, 0);
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    SmsEnvironment::new(state, TypeWrap::<PointerSmsMemory>::default()).unwrap()
+}
+
+/// Reset `environment` and take `actions`, returning every observation,
+/// reward, and step's frame.
+fn episode(environment: &mut SmsEnvironment, actions: &[usize]) -> Vec<(SmsState, f64, u64)> {
+    let first = environment.reset().unwrap();
+    let mut results = vec![(first, 0.0, 0)];
+    for &action in actions {
+        let (state, reward, done, info) = environment.step(action).unwrap();
+        assert!(!done);
+        results.push((state, reward, info.frame));
+    }
+    results
+}
+
+#[test]
+fn reset_and_step_are_deterministic() {
+    let actions = [0, 3, 7, 7, 19, 1, 0, 12, 5, 5];
+    let mut environment = environment();
+    environment.set_frames_per_step(2);
+    let first = episode(&mut environment, &actions);
+    // something besides the frame counter depended on the actions
+    let sum = first.last().unwrap().0.memory.system_ram[1];
+    assert_ne!(sum, 0);
+
+    // again, after steps of another episode
+    episode(&mut environment, &[4, 4, 4]);
+    let second = episode(&mut environment, &actions);
+
+    assert_eq!(first.len(), second.len());
+    for (i, (x, y)) in first.iter().zip(second.iter()).enumerate() {
+        assert!(x.0 == y.0, "observations differ after step {}", i);
+        assert_eq!(x.1, y.1);
+        assert_eq!(x.2, y.2);
+        assert_eq!(x.2, 2 * i as u64);
+    }
+    assert!(first[0].0 == *environment.initial_state());
+}
+
+#[test]
+fn reward_from_variables() {
+    let definition = GameDefinition::from_toml(
+        r#"
+        reward = "10 * delta(frames)"
+        done = "frames >= 9"
+
+        [variables.frames]
+        address = 0
+        type = "u8"
+        "#,
+    ).unwrap();
+    let mut environment = environment();
+    environment.set_frames_per_step(3);
+    environment.set_game_monitor(Some(GameMonitor::new(definition).unwrap()));
+    environment.reset().unwrap();
+
+    let mut frames = vec![];
+    let mut rewards = vec![];
+    loop {
+        let (_, reward, done, _) = environment.step(0).unwrap();
+        let monitor = environment.game_monitor().unwrap();
+        frames.push(monitor.value("frames").unwrap());
+        rewards.push(reward);
+        if done {
+            break;
+        }
+    }
+    let mut previous = 0.0;
+    for (&frame, &reward) in frames.iter().zip(rewards.iter()) {
+        // the monitor updates once a step, so the delta spans the whole step
+        assert_eq!(reward, 10.0 * (frame - previous));
+        previous = frame;
+    }
+    assert_eq!(frames, [3.0, 6.0, 9.0]);
+
+    // and again from the start
+    environment.reset().unwrap();
+    let (_, reward, _, _) = environment.step(0).unwrap();
+    assert_eq!(reward, rewards[0]);
+}
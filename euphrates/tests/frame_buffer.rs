@@ -0,0 +1,142 @@
+//! `FrameBuffer`s, alone and as the graphics of Master Systems and Game Gears.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::host_multimedia::{
+    FrameBuffer, HasLastFrame, IndexedPixel, Layer, PixelFormat, SimpleColor, SimpleGraphics,
+};
+use euphrates::systems::sms::{
+    new_headless_sms, Kind, PointerSmsMemory, SmsMemoryMapper, SmsPlayerInput, SmsState, TvSystem,
+    TypeWrap,
+};
+
+fn color(x: u32, y: u32) -> SimpleColor {
+    SimpleColor {
+        red: x as u8,
+        green: y as u8,
+        blue: (x ^ y) as u8,
+    }
+}
+
+fn paint_and_read_back(format: PixelFormat, width: u32, height: u32) {
+    let mut buffer = FrameBuffer::new(format);
+    buffer.set_record_indices(true);
+    assert!(buffer.last_frame().is_none());
+    assert!(buffer.last_indexed_frame().is_none());
+
+    buffer.set_resolution(width, height).unwrap();
+    assert_eq!(buffer.resolution(), (width, height));
+    for y in 0..height {
+        for x in 0..width {
+            buffer.paint(x, y, color(x, y));
+            buffer.paint_indexed(
+                x,
+                y,
+                IndexedPixel::new((x + y) as u8 & 0x1F, Layer::Background),
+            );
+        }
+    }
+    buffer.render().unwrap();
+
+    let frame = buffer.last_frame().unwrap();
+    assert_eq!((frame.width, frame.height), (width, height));
+    assert_eq!(frame.format, format);
+    assert_eq!(
+        frame.data.len(),
+        (width * height) as usize * format.bytes_per_pixel()
+    );
+    let indexed = buffer.last_indexed_frame().unwrap();
+    assert_eq!((indexed.width, indexed.height), (width, height));
+    assert_eq!(indexed.data.len(), (width * height) as usize);
+    let points = [
+        (0, 0),
+        (width - 1, 0),
+        (0, height - 1),
+        (width - 1, height - 1),
+        (77, 55),
+    ];
+    for &(x, y) in points.iter() {
+        assert_eq!(frame.get(x, y), color(x, y));
+        assert_eq!(buffer.get(x, y), color(x, y));
+        let pixel = indexed.get(x, y);
+        assert_eq!(pixel.index(), (x + y) as u8 & 0x1F);
+        assert_eq!(pixel.layer(), Layer::Background);
+    }
+    if format == PixelFormat::Rgba {
+        assert!(frame.data.chunks(4).all(|pixel| pixel[3] == 0xFF));
+    }
+}
+
+#[test]
+fn sms_resolution() {
+    paint_and_read_back(PixelFormat::Rgb24, 256, 192);
+    paint_and_read_back(PixelFormat::Rgba, 256, 192);
+}
+
+#[test]
+fn gg_resolution() {
+    paint_and_read_back(PixelFormat::Rgb24, 160, 144);
+    paint_and_read_back(PixelFormat::Rgba, 160, 144);
+}
+
+#[test]
+fn painting_doesnt_show_until_render() {
+    let mut buffer = FrameBuffer::new(PixelFormat::Rgb24);
+    buffer.set_resolution(256, 192).unwrap();
+    buffer.paint(3, 4, color(3, 4));
+    buffer.render().unwrap();
+    buffer.paint(3, 4, color(9, 9));
+    assert_eq!(buffer.last_frame().unwrap().get(3, 4), color(3, 4));
+    assert_eq!(buffer.get(3, 4), color(9, 9));
+    // indices weren't requested
+    assert!(buffer.last_indexed_frame().is_none());
+}
+
+/// Turns on mode 4, in which the Game Gear shows only part of the screen.
+const MODE_4: &str = "
+        org 0
+        di
+        ld a, 4
+        out (0BF), a
+        ld a, 80
+        out (0BF), a
+    loop:
+        jr loop
+";
+
+/// The dimensions of the last frame of a system of `kind` running `MODE_4`,
+/// and of its indices, after two frames.
+fn system_frame(kind: Kind) -> ((u32, u32), (u32, u32)) {
+    let mut rom = assemble(MODE_4).unwrap().bytes;
+    rom.resize(0x8000, 0);
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        kind,
+    );
+    let mut buffer = FrameBuffer::new(PixelFormat::Rgba);
+    buffer.set_record_indices(true);
+    let mut sms = new_headless_sms(state, buffer, TypeWrap::<PointerSmsMemory>::default()).unwrap();
+    assert!(sms.last_frame().is_none());
+    for _ in 0..2 {
+        sms.run_frame(SmsPlayerInput::default()).unwrap();
+    }
+    let frame = sms.last_frame().unwrap();
+    assert_eq!(frame.data.len(), (frame.width * frame.height * 4) as usize);
+    let indexed = sms.last_indexed_frame().unwrap();
+    assert_eq!(
+        indexed.data.len(),
+        (indexed.width * indexed.height) as usize
+    );
+    ((frame.width, frame.height), (indexed.width, indexed.height))
+}
+
+#[test]
+fn system_resolutions() {
+    assert_eq!(system_frame(Kind::Sms), ((256, 192), (256, 192)));
+    assert_eq!(system_frame(Kind::Gg), ((160, 144), (160, 144)));
+}
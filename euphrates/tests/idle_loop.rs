@@ -0,0 +1,169 @@
+//! Run systems fast-forwarding through idle loops in lockstep with systems
+//! running the usual interpreter, and check that their states never differ.
+
+extern crate euphrates;
+extern crate euphrates_testing;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::z80::{
+    assemble, Prefix, Z80Internal, Z80Irq, Z80Memo, Z80Run, Z80RunImpler, Z80State,
+};
+use euphrates::memo::NothingInbox;
+use euphrates::systems::sms::{IdleLoopSkipper, IdleLoopSkipperImpler, LockstepOptions, Sms};
+use euphrates_testing::{lockstep, random_rom};
+
+/// Run `rom` for `frames` frames with and without skipping idle loops, panic
+/// if they ever differ, and return the system that skipped.
+fn skipping_lockstep(rom: &[u8], frames: usize) -> Box<dyn Sms + Send> {
+    lockstep(
+        rom,
+        frames,
+        Box::new(IdleLoopSkipper::new()),
+        &LockstepOptions::default(),
+    )
+}
+
+const WAITS: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+        im 1
+        ; frame interrupts on
+        ld a, 20
+        out (0BF), a
+        ld a, 81
+        out (0BF), a
+        jp main
+        org 38
+        push af
+        in a, (0BF)
+        ld a, 1
+        ld (0C000), a
+        pop af
+        ei
+        reti
+        org 100
+    main:
+        ; wait for the interrupt handler to set a flag
+        xor a
+        ld (0C000), a
+        ei
+    flag:
+        ld a, (0C000)
+        or a
+        jr z, flag
+        di
+        ; wait for a line
+    line:
+        in a, (7E)
+        cp 0C0
+        jr nz, line
+        ; wait for the end of the frame
+    status:
+        in a, (0BF)
+        and 80
+        jr z, status
+        ld hl, 0C001
+        inc (hl)
+        jr main
+";
+
+#[test]
+fn waits() {
+    let mut rom = assemble(WAITS).unwrap().bytes;
+    rom.resize(0x8000, 0);
+    let sms = skipping_lockstep(&rom, 20);
+    // around the main loop once every two frames
+    let count = sms.sms_memory().system_ram_read(1);
+    assert!((9..=10).contains(&count), "{}", count);
+}
+
+#[test]
+fn random_code() {
+    for seed in 1..=8u64 {
+        // plenty of short jumps back
+        let rom = random_rom(seed, |rng| match rng.next_u64() % 8 {
+            0 => 0x18,
+            1 => 0xF0 | rng.next_u64() as u8,
+            _ => rng.next_u64() as u8,
+        });
+        skipping_lockstep(&rom, 30);
+    }
+}
+
+/// An input port whose value we set between runs, as the VDP changes between
+/// lines.
+struct Port(u8);
+
+impl Io16 for Port {
+    fn input(&mut self, _address: u16) -> u8 {
+        self.0
+    }
+
+    fn output(&mut self, _address: u16, _value: u8) {}
+
+    fn peek(&mut self, _address: u16) -> Option<u8> {
+        Some(self.0)
+    }
+}
+
+struct NoIrq;
+
+impl Z80Irq for NoIrq {
+    fn requesting_mi(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn take_nmi(&mut self) {}
+}
+
+const POLL: &str = "
+        org 0
+    wait:
+        in a, (0)
+        or a
+        jr z, wait
+        ld (8000), a
+        halt
+";
+
+#[test]
+fn skips() {
+    let program = assemble(POLL).unwrap().bytes;
+    let mut memory = [0u8; 0x10000];
+    memory[..program.len()].copy_from_slice(&program);
+    let mut skipping_memory = memory;
+
+    let mut z80 = Z80State::default();
+    let mut skipping_z80 = Z80State::default();
+    let mut skipper = IdleLoopSkipper::new();
+    for line in 1..100 {
+        let target = line * 228;
+        let mut port = Port(if line == 90 { 0x5A } else { 0 });
+        Z80RunImpler {
+            z80: &mut z80,
+            memory: &mut memory,
+            io: &mut port,
+            irq: &mut NoIrq,
+            inbox: &mut NothingInbox::<Z80Memo>::default(),
+        }.run(target);
+        IdleLoopSkipperImpler {
+            skipper: &mut skipper,
+            z80: &mut skipping_z80,
+            memory: &mut skipping_memory,
+            io: &mut port,
+            irq: &mut NoIrq,
+            inbox: &mut NothingInbox::<Z80Memo>::default(),
+        }.run(target);
+        assert_eq!(z80, skipping_z80, "line {}", line);
+    }
+    assert_eq!(&memory[..], &skipping_memory[..]);
+    assert_eq!(memory[0x8000], 0x5A);
+    assert_eq!(z80.prefix(), Prefix::Halt);
+    // all but a few iterations of each line
+    assert!(skipper.skipped_cycles() > 80 * 150, "{}", skipper.skipped_cycles());
+}
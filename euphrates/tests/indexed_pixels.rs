@@ -0,0 +1,146 @@
+//! The palette indices and layers recorded for each pixel.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::host_multimedia::{FrameBuffer, IndexedPixel, Layer, PixelFormat};
+use euphrates::systems::sms::{
+    new_headless_sms, Kind, PointerSmsMemory, SmsMemoryMapper, SmsPlayerInput, SmsState, TvSystem,
+    TypeWrap,
+};
+
+#[test]
+fn packing() {
+    let layers = [
+        Layer::Blank,
+        Layer::Backdrop,
+        Layer::Background,
+        Layer::PriorityBackground,
+        Layer::Sprite,
+        Layer::Transparent,
+    ];
+    for (i, &layer) in layers.iter().enumerate() {
+        for index in 0..32 {
+            let pixel = IndexedPixel::new(index, layer);
+            assert_eq!(pixel.0, index | (i as u8) << 5);
+            assert_eq!(pixel.index(), index);
+            assert_eq!(pixel.layer(), layer);
+        }
+    }
+    // only 5 bits of index
+    assert_eq!(IndexedPixel::new(0x3F, Layer::Sprite).0, 0x9F);
+    assert_eq!(IndexedPixel(0xFF).layer(), Layer::Blank);
+    assert_eq!(IndexedPixel(0xFF).index(), 0x1F);
+}
+
+/// Write `vram`, a list of addresses and bytes to write there, into VRAM,
+/// and then set the VDP's `registers`, and run 3 frames. Returns the indexed
+/// pixels of line `y`.
+fn line(registers: &[u8], vram: &[(u16, &[u8])], y: u32) -> Vec<IndexedPixel> {
+    let mut source = "org 0\ndi\n".to_owned();
+    for &(address, bytes) in vram {
+        source += &format!(
+            "ld a, 0{:X}\nout (0BF), a\nld a, 0{:X}\nout (0BF), a\n",
+            address & 0xFF,
+            address >> 8 | 0x40
+        );
+        for byte in bytes {
+            source += &format!("ld a, 0{:X}\nout (0BE), a\n", byte);
+        }
+    }
+    for (i, register) in registers.iter().enumerate() {
+        source += &format!(
+            "ld a, 0{:X}\nout (0BF), a\nld a, 0{:X}\nout (0BF), a\n",
+            register,
+            0x80 | i
+        );
+    }
+    source += "loop:\njr loop\n";
+    let mut rom = assemble(&source).unwrap().bytes;
+    rom.resize(0x8000, 0);
+
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    let mut buffer = FrameBuffer::new(PixelFormat::Rgb24);
+    buffer.set_record_indices(true);
+    let mut sms = new_headless_sms(state, buffer, TypeWrap::<PointerSmsMemory>::default()).unwrap();
+    for _ in 0..3 {
+        sms.run_frame(SmsPlayerInput::default()).unwrap();
+    }
+    let frame = sms.last_indexed_frame().unwrap();
+    (0..frame.width).map(|x| frame.get(x, y)).collect()
+}
+
+fn assert_pixels(line: &[IndexedPixel], xs: ::std::ops::Range<usize>, index: u8, layer: Layer) {
+    for x in xs {
+        assert_eq!(
+            (line[x].index(), line[x].layer()),
+            (index, layer),
+            "at x = {}",
+            x
+        );
+    }
+}
+
+#[test]
+fn mode_4() {
+    // mode 4 with the left column blank, display on, name table at 0x3800,
+    // sprite attribute table at 0x3F00, sprite patterns at 0x0000, and
+    // backdrop color 16 + 3
+    let registers = [
+        0x24, 0x40, 0xFF, 0xFF, 0xFF, 0xFF, 0xFB, 0x03, 0x00, 0x00, 0xFF,
+    ];
+    // tile 1 is all color 1
+    let tile: Vec<u8> = (0..32).map(|i| if i % 4 == 0 { 0xFF } else { 0 }).collect();
+    let vram: &[(u16, &[u8])] = &[
+        (0x0020, &tile),
+        // tiles 1, then 1 in front of sprites, then 0
+        (0x3800, &[0x01, 0x00, 0x01, 0x10]),
+        // one sprite, tile 1, from line 16 and x 0x40
+        (0x3F00, &[0x0F, 0xD0]),
+        (0x3F80, &[0x40, 0x01]),
+    ];
+
+    let top = line(&registers, vram, 4);
+    assert_pixels(&top, 0..8, 16 + 3, Layer::Backdrop);
+    assert_pixels(&top, 8..16, 1, Layer::PriorityBackground);
+    assert_pixels(&top, 16..256, 0, Layer::Background);
+
+    let sprite = line(&registers, vram, 20);
+    assert_pixels(&sprite, 0..8, 16 + 3, Layer::Backdrop);
+    assert_pixels(&sprite, 8..0x40, 0, Layer::Background);
+    assert_pixels(&sprite, 0x40..0x48, 16 + 1, Layer::Sprite);
+    assert_pixels(&sprite, 0x48..256, 0, Layer::Background);
+}
+
+#[test]
+fn tms_graphics_1() {
+    // graphics 1 with display on, name table at 0x3800, color table at
+    // 0x2000, patterns and sprite patterns at 0x0000, sprite attribute table at
+    // 0x3F00, and backdrop color 4
+    let registers = [0x00, 0x40, 0x0E, 0x80, 0x00, 0x7E, 0x00, 0x04];
+    let vram: &[(u16, &[u8])] = &[
+        // pattern 1 is half foreground and half background
+        (0x0008, &[0xF0; 8]),
+        // patterns 0 to 7 are color 5 on transparent
+        (0x2000, &[0x50]),
+        (0x3800, &[0x01]),
+        // one sprite, pattern 1, color 0xA, from line 16 and x 0x40
+        (0x3F00, &[0x0F, 0x40, 0x01, 0x0A, 0xD0]),
+    ];
+
+    let top = line(&registers, vram, 4);
+    assert_pixels(&top, 0..4, 5, Layer::Background);
+    assert_pixels(&top, 4..256, 0, Layer::Transparent);
+
+    let sprite = line(&registers, vram, 20);
+    assert_pixels(&sprite, 0..0x40, 0, Layer::Transparent);
+    assert_pixels(&sprite, 0x40..0x44, 0xA, Layer::Sprite);
+    assert_pixels(&sprite, 0x44..256, 0, Layer::Transparent);
+}
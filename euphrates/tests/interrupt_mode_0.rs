@@ -0,0 +1,171 @@
+//! Maskable interrupts in mode 0, where the interrupting device supplies an
+//! instruction to execute.
+
+extern crate euphrates;
+
+use euphrates::memo::NothingInbox;
+use euphrates::systems::sms::{
+    InterruptMode, Io16, Memory16, Reg16, Z80Internal, Z80Interrupt, Z80InterruptImpler, Z80Irq,
+    Z80Memo, Z80State,
+};
+
+struct Ram(Vec<u8>);
+
+impl Memory16 for Ram {
+    fn read(&mut self, logical_address: u16) -> u8 {
+        self.0[logical_address as usize]
+    }
+
+    fn write(&mut self, logical_address: u16, value: u8) {
+        self.0[logical_address as usize] = value;
+    }
+}
+
+struct NoIo;
+
+impl Io16 for NoIo {
+    fn input(&mut self, _address: u16) -> u8 {
+        0xFF
+    }
+
+    fn output(&mut self, _address: u16, _value: u8) {}
+}
+
+/// Requests an interrupt and supplies `instruction`, recording which bytes
+/// after the first were asked for.
+struct Device {
+    instruction: Vec<u8>,
+    requested: Vec<u16>,
+}
+
+impl Z80Irq for Device {
+    fn requesting_mi(&mut self) -> Option<u8> {
+        Some(self.instruction[0])
+    }
+
+    fn mi_instruction_byte(&mut self, index: u16) -> u8 {
+        self.requested.push(index);
+        self.instruction[index as usize]
+    }
+
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn take_nmi(&mut self) {}
+}
+
+/// A device that only supplies the first byte, leaving the rest to the
+/// default `mi_instruction_byte`.
+struct OneByteDevice(u8);
+
+impl Z80Irq for OneByteDevice {
+    fn requesting_mi(&mut self) -> Option<u8> {
+        Some(self.0)
+    }
+
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn take_nmi(&mut self) {}
+}
+
+fn z80() -> Z80State {
+    let mut z80 = Z80State::default();
+    z80.set_interrupt_mode(InterruptMode::Im0);
+    z80.set_iff1(true);
+    z80.set_iff2(true);
+    z80.set_reg16(Reg16::PC, 0x1234);
+    z80.set_reg16(Reg16::SP, 0xD000);
+    z80.set_cycles(100);
+    z80
+}
+
+/// Interrupt `z80`, and return the memory afterwards.
+fn interrupt<Irq: Z80Irq>(z80: &mut Z80State, irq: &mut Irq) -> Ram {
+    let mut memory = Ram(vec![0; 0x10000]);
+    Z80InterruptImpler {
+        z80,
+        memory: &mut memory,
+        irq,
+        inbox: &mut NothingInbox::<Z80Memo>::default(),
+        io: &mut NoIo,
+    }.check_interrupts();
+    memory
+}
+
+fn assert_pushed_pc(z80: &Z80State, memory: &Ram) {
+    assert_eq!(z80.reg16(Reg16::SP), 0xCFFE);
+    assert_eq!(memory.0[0xCFFF], 0x12);
+    assert_eq!(memory.0[0xCFFE], 0x34);
+}
+
+#[test]
+fn rst() {
+    for &(opcode, target) in [(0xC7u8, 0x00u16), (0xCF, 0x08), (0xFF, 0x38)].iter() {
+        let mut z80 = z80();
+        let mut device = Device {
+            instruction: vec![opcode],
+            requested: Vec::new(),
+        };
+        let memory = interrupt(&mut z80, &mut device);
+        assert_eq!(z80.reg16(Reg16::PC), target);
+        assert_eq!(z80.cycles(), 100 + 13);
+        assert!(!z80.iff1());
+        assert!(!z80.iff2());
+        assert!(device.requested.is_empty());
+        assert_pushed_pc(&z80, &memory);
+    }
+}
+
+#[test]
+fn call() {
+    let mut z80 = z80();
+    let mut device = Device {
+        instruction: vec![0xCD, 0x78, 0x56],
+        requested: Vec::new(),
+    };
+    let memory = interrupt(&mut z80, &mut device);
+    assert_eq!(z80.reg16(Reg16::PC), 0x5678);
+    assert_eq!(z80.cycles(), 100 + 19);
+    assert_eq!(device.requested, [1, 2]);
+    assert_pushed_pc(&z80, &memory);
+}
+
+#[test]
+fn call_floating_bus() {
+    // a device that doesn't supply the operand leaves 0xFF on the bus
+    let mut z80 = z80();
+    let memory = interrupt(&mut z80, &mut OneByteDevice(0xCD));
+    assert_eq!(z80.reg16(Reg16::PC), 0xFFFF);
+    assert_eq!(z80.cycles(), 100 + 19);
+    assert_pushed_pc(&z80, &memory);
+}
+
+#[test]
+fn prefixed() {
+    // ld ix, 0xABCD: the prefix and both operand bytes come from the device
+    let mut z80 = z80();
+    let mut device = Device {
+        instruction: vec![0xDD, 0x21, 0xCD, 0xAB],
+        requested: Vec::new(),
+    };
+    interrupt(&mut z80, &mut device);
+    assert_eq!(z80.reg16(Reg16::IX), 0xABCD);
+    assert_eq!(z80.reg16(Reg16::PC), 0x1234);
+    assert_eq!(device.requested, [1, 2, 3]);
+}
+
+#[test]
+fn disabled() {
+    let mut z80 = z80();
+    z80.set_iff1(false);
+    let mut device = Device {
+        instruction: vec![0xFF],
+        requested: Vec::new(),
+    };
+    interrupt(&mut z80, &mut device);
+    assert_eq!(z80.reg16(Reg16::PC), 0x1234);
+    assert_eq!(z80.cycles(), 100);
+}
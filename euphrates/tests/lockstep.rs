@@ -0,0 +1,168 @@
+//! Make systems diverge on purpose, and check that `find_divergence` reports
+//! where.
+
+extern crate euphrates;
+extern crate euphrates_testing;
+
+use euphrates::memo::Inbox;
+use euphrates::systems::sms::{
+    find_divergence, state_differences, CachedInterpreter, Io16, LockstepOptions, Reg16,
+    SmsMemory, SmsPlayerInput, SmsZ80Runner, Z80Internal, Z80Irq, Z80Memo, Z80State,
+};
+use euphrates_testing::{new_system, rom};
+
+/// Cycles in an NTSC frame.
+const FRAME_CYCLES: u64 = 228 * 262;
+
+/// `B` is changed once the Z80 has run this many cycles.
+const SABOTAGE_CYCLES: u64 = 2 * FRAME_CYCLES + 1000;
+
+/// Runs the Z80 like `CachedInterpreter`, except that once `SABOTAGE_CYCLES`
+/// have passed, register `B` is set to 0x42.
+struct Saboteur(CachedInterpreter);
+
+impl SmsZ80Runner for Saboteur {
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        self.0.run(z80, memory, io, irq, inbox, target_cycles);
+        if z80.cycles() >= SABOTAGE_CYCLES {
+            let bc = z80.reg16(Reg16::BC);
+            z80.set_reg16(Reg16::BC, 0x4200 | bc & 0xFF);
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.0.invalidate();
+    }
+}
+
+fn spin_rom() -> Vec<u8> {
+    rom(
+        0x8000,
+        &[(
+            0x0000,
+            &[
+                0xF3, // di
+                0x18, 0xFE, // jr -2
+            ],
+        )],
+    )
+}
+
+fn sabotaged(every_instruction: bool) -> euphrates::systems::sms::Divergence {
+    let rom = spin_rom();
+    let mut left = new_system(&rom);
+    let mut right = new_system(&rom);
+    right.set_z80_runner(Some(Box::new(Saboteur(CachedInterpreter::new()))));
+    let options = LockstepOptions {
+        every_instruction,
+        ..Default::default()
+    };
+    let player_inputs = vec![SmsPlayerInput::default(); 5];
+    find_divergence(&mut *left, &mut *right, &player_inputs, &options)
+        .unwrap()
+        .expect("the systems should have diverged")
+}
+
+#[test]
+fn divergence_in_frame() {
+    let divergence = sabotaged(false);
+    assert_eq!(divergence.frame, 2);
+    assert_eq!(divergence.instruction, None);
+    let fields: Vec<&str> = divergence
+        .differences
+        .iter()
+        .map(|d| d.field.as_ref())
+        .collect();
+    assert_eq!(fields, ["z80.bc"]);
+    assert_eq!(divergence.differences[0].right, "0x4200");
+}
+
+#[test]
+fn divergence_at_instruction() {
+    let divergence = sabotaged(true);
+    assert_eq!(divergence.frame, 2);
+    assert!(divergence.instruction.unwrap() > 0);
+
+    // both sides stop just after the first instruction ending at or past
+    // SABOTAGE_CYCLES, which is a 12 cycle jr
+    let cycles = divergence.left_state.z80.cycles();
+    assert_eq!(cycles, divergence.right_state.z80.cycles());
+    assert!(cycles >= SABOTAGE_CYCLES && cycles < SABOTAGE_CYCLES + 12);
+    assert_eq!(divergence.left_pc, divergence.right_pc);
+    assert_eq!(divergence.left_state.z80.reg16(Reg16::BC), 0);
+    assert_eq!(divergence.right_state.z80.reg16(Reg16::BC), 0x4200);
+}
+
+#[test]
+fn divergence_in_initial_states() {
+    let rom = spin_rom();
+    let mut left = new_system(&rom);
+    let mut right = new_system(&rom);
+    let mut state = right.state();
+    state.memory.system_ram[0x10] = 7;
+    right.restore_state(&state).unwrap();
+    let divergence = find_divergence(
+        &mut *left,
+        &mut *right,
+        &[SmsPlayerInput::default()],
+        &Default::default(),
+    ).unwrap()
+    .unwrap();
+    assert_eq!(divergence.frame, 0);
+    assert_eq!(divergence.instruction, Some(0));
+    assert_eq!(divergence.differences.len(), 1);
+    assert_eq!(divergence.differences[0].field, "memory.system_ram[0x10]");
+}
+
+#[test]
+fn no_divergence() {
+    let rom = spin_rom();
+    let mut left = new_system(&rom);
+    let mut right = new_system(&rom);
+    right.set_z80_runner(Some(Box::new(CachedInterpreter::new())));
+    let player_inputs = vec![SmsPlayerInput::default(); 5];
+    let options = LockstepOptions::default();
+    assert!(
+        find_divergence(&mut *left, &mut *right, &player_inputs, &options)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn differences() {
+    let sms = new_system(&spin_rom());
+    let left = sms.state();
+    let mut right = left.clone();
+    assert!(state_differences(&left, &right).is_empty());
+
+    right.z80.set_reg16(Reg16::PC, 0x1234);
+    right.vdp.vram[0x3F00] = 0xAB;
+    right.memory.pages[2] = right.memory.pages[1];
+    let differences = state_differences(&left, &right);
+    let fields: Vec<&str> = differences.iter().map(|d| d.field.as_ref()).collect();
+    assert_eq!(fields, ["z80.pc", "vdp.vram[0x3F00]", "memory.pages[2]"]);
+    assert_eq!(differences[0].left, "0x0");
+    assert_eq!(differences[0].right, "0x1234");
+    assert_eq!(differences[1].right, "0xAB");
+
+    // long runs of differences are summarized
+    for i in 0..20 {
+        right.memory.system_ram[i] = 1;
+    }
+    let differences = state_differences(&left, &right);
+    let ram: Vec<_> = differences
+        .iter()
+        .filter(|d| d.field.starts_with("memory.system_ram"))
+        .collect();
+    assert_eq!(ram.len(), 17);
+    assert_eq!(ram[16].left, "4 more differences");
+}
@@ -0,0 +1,165 @@
+//! Profile a small program with calls, a bank switch, and interrupts.
+
+extern crate euphrates;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::host_multimedia::FakeAudio;
+use euphrates::systems::sms::{
+    self, Debugger, FakeSmsGraphics, FakeSn76489, Kind, ProfileLocation, ProfileReport,
+    ProfilingInbox, Query, Sms, SmsMemoryMapper, SmsMemoryState, SmsPlayerInput, SmsState,
+    TvSystem, TypeWrap,
+};
+
+const BANK_0: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+        im 1
+        ld a, 2
+        ld (0FFFF), a
+        ; frame interrupts on
+        ld a, 20
+        out (0BF), a
+        ld a, 81
+        out (0BF), a
+        jp main
+        org 38
+        push af
+        in a, (0BF)
+        pop af
+        ei
+        reti
+        org 100
+    main:
+        ei
+        call sub
+        call 8000
+    idle:
+        halt
+        jr main
+    sub:
+        ld b, 10
+    wait:
+        djnz wait
+        ret
+";
+
+const BANK_2: &str = "
+        org 8000
+        nop
+        ret
+";
+
+/// Run the program for `frames` frames, and return the system and the
+/// program's labels.
+fn profile(frames: usize) -> (Box<dyn Sms>, BTreeMap<String, u16>) {
+    let mut rom = vec![0; 0xC000];
+    let bank_0 = assemble(BANK_0).unwrap();
+    rom[..bank_0.bytes.len()].copy_from_slice(&bank_0.bytes);
+    let bank_2 = assemble(BANK_2).unwrap().bytes;
+    rom[0x8000..0x8000 + bank_2.len()].copy_from_slice(&bank_2);
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    let mut sms = sms::new_sms(
+        None,
+        state,
+        FakeSmsGraphics,
+        FakeAudio,
+        ProfilingInbox::new(),
+        TypeWrap::<SmsMemoryState>::default(),
+        TypeWrap::<FakeSn76489>::default(),
+    ).unwrap();
+    for _ in 0..frames {
+        sms.run_frame(SmsPlayerInput::default()).unwrap();
+    }
+    (sms, bank_0.labels)
+}
+
+fn report(sms: &mut dyn Sms, report: ProfileReport) -> String {
+    sms.debugger().unwrap().query(Query::Profile(report))
+}
+
+#[test]
+fn collapsed() {
+    let (mut sms, labels) = profile(3);
+    let collapsed = report(&mut *sms, ProfileReport::Collapsed);
+
+    let mut total = 0;
+    let mut stacks = Vec::new();
+    for line in collapsed.lines() {
+        let space = line.rfind(' ').unwrap();
+        total += line[space + 1..].parse::<u64>().unwrap();
+        stacks.push(&line[..space]);
+    }
+    let sub_stack = format!("root;B00_{:0>4X}", labels["sub"]);
+    assert!(stacks.contains(&"root"));
+    assert!(stacks.contains(&&sub_stack[..]));
+    assert!(stacks.contains(&"root;B02_8000"));
+    assert!(stacks.contains(&"root;B00_0038"));
+    assert_eq!(stacks.len(), 4, "{}", collapsed);
+
+    // every cycle but those of the `halt` at the end, which hasn't finished
+    let flat = report(&mut *sms, ProfileReport::Flat);
+    let total_line = flat.lines().last().unwrap();
+    assert!(total_line.ends_with("100.00%  Total"));
+    assert_eq!(total_line.split_whitespace().next(), Some(&total.to_string()[..]));
+    assert!(total < sms.z80().cycles());
+}
+
+#[test]
+fn flat_and_tree() {
+    let (mut sms, labels) = profile(3);
+
+    let flat = report(&mut *sms, ProfileReport::Flat);
+    let lines: Vec<&str> = flat.lines().collect();
+    // the most cycles are spent halted, and then in `djnz`
+    let idle = format!("B00_{:0>4X}  halt", labels["idle"]);
+    let wait = format!("B00_{:0>4X}  djnz", labels["wait"]);
+    assert!(lines[1].ends_with(&idle), "{}", flat);
+    assert!(lines[2].contains(&wait), "{}", flat);
+    assert!(flat.contains("  B00\n"));
+    assert!(flat.contains("  B02\n"));
+    assert!(!flat.contains("  RAM\n"));
+
+    let tree = report(&mut *sms, ProfileReport::Tree);
+    let root = tree.lines().nth(1).unwrap();
+    assert!(root.contains(" 100.00% ") && root.ends_with("  root"), "{}", tree);
+    let calls_of = |name: &str| -> u64 {
+        let line = tree.lines().find(|line| line.ends_with(name)).unwrap();
+        line.split_whitespace().nth(3).unwrap().parse().unwrap()
+    };
+    // once a frame through the main loop, and once more before the first
+    // frame interrupt
+    assert_eq!(calls_of("  B02_8000"), 4);
+    assert_eq!(calls_of(&format!("  B00_{:0>4X}", labels["sub"])), 4);
+    assert_eq!(calls_of("  B00_0038"), 3);
+}
+
+#[test]
+fn locations() {
+    let inbox = ProfilingInbox::new();
+    assert_eq!(inbox.total_cycles(), 0);
+    assert!(inbox.instructions().is_empty());
+    assert_eq!(
+        inbox.query(Query::Disassembly),
+        "Only profiles are available while profiling\n"
+    );
+
+    let location = ProfileLocation {
+        bank: Some(3),
+        address: 0x8123,
+    };
+    assert_eq!(location.to_string(), "B03_8123");
+    let location = ProfileLocation {
+        bank: None,
+        address: 0xC000,
+    };
+    assert_eq!(location.to_string(), "RAM_C000");
+}
@@ -0,0 +1,150 @@
+//! Frames run without rendering, or with `FakeSmsGraphics`, must leave the
+//! system in exactly the state rendering would.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::host_multimedia::{FrameBuffer, PixelFormat};
+use euphrates::systems::sms::{
+    new_headless_sms, FakeSmsGraphics, Kind, PointerSmsMemory, Sms, SmsMemoryMapper,
+    SmsPlayerInput, SmsState, TvSystem, TypeWrap,
+};
+
+/// A ROM which writes `vram`, a list of addresses and bytes to write there,
+/// into VRAM, sets the VDP's `registers`, and then records the VDP status
+/// read by each frame interrupt, from 0xC000 on.
+fn status_recording_rom(registers: &[u8], vram: &[(u16, &[u8])]) -> SmsState {
+    let mut source = "
+        org 0
+        di
+        jp start
+        org 38
+        push af
+        push hl
+        in a, (0BF)
+        ld hl, (0C100)
+        ld (hl), a
+        inc hl
+        ld (0C100), hl
+        pop hl
+        pop af
+        ei
+        reti
+        org 100
+    start:
+        ld sp, 0DFF0
+        ld hl, 0C000
+        ld (0C100), hl
+        im 1
+"
+    .to_owned();
+    for &(address, bytes) in vram {
+        source += &format!(
+            "ld a, 0{:X}\nout (0BF), a\nld a, 0{:X}\nout (0BF), a\n",
+            address & 0xFF,
+            address >> 8 | 0x40
+        );
+        for byte in bytes {
+            source += &format!("ld a, 0{:X}\nout (0BE), a\n", byte);
+        }
+    }
+    for (i, register) in registers.iter().enumerate() {
+        source += &format!(
+            "ld a, 0{:X}\nout (0BF), a\nld a, 0{:X}\nout (0BF), a\n",
+            register,
+            0x80 | i
+        );
+    }
+    source += "ei\nloop:\nhalt\njr loop\n";
+    let mut rom = assemble(&source).unwrap().bytes;
+    rom.resize(0x8000, 0);
+    SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    )
+}
+
+fn rendering(state: &SmsState) -> Box<dyn Sms + Send> {
+    let buffer = FrameBuffer::new(PixelFormat::Rgb24);
+    new_headless_sms(state.clone(), buffer, TypeWrap::<PointerSmsMemory>::default()).unwrap()
+}
+
+/// Run `state` for 8 frames with and without rendering, and headless, checking
+/// the states are equal after each frame. Returns the status flags the ROM
+/// recorded.
+fn check(state: &SmsState) -> Vec<u8> {
+    let mut rendered = rendering(state);
+    let mut skipped = rendering(state);
+    let mut headless =
+        new_headless_sms(state.clone(), FakeSmsGraphics, TypeWrap::<PointerSmsMemory>::default())
+            .unwrap();
+    for frame in 0..8 {
+        rendered.run_frame(SmsPlayerInput::default()).unwrap();
+        skipped
+            .run_frame_without_rendering(SmsPlayerInput::default())
+            .unwrap();
+        headless.run_frame(SmsPlayerInput::default()).unwrap();
+        let state = rendered.state();
+        assert!(
+            state == skipped.state(),
+            "state differs without rendering after frame {}",
+            frame
+        );
+        assert!(
+            state == headless.state(),
+            "state differs with FakeSmsGraphics after frame {}",
+            frame
+        );
+    }
+    let state = rendered.state();
+    let count = state.memory.system_ram[0x100] as usize;
+    state.memory.system_ram[..count].to_vec()
+}
+
+#[test]
+fn mode_4_sprite_flags() {
+    // mode 4 with display and frame interrupts on, sprite attribute table at
+    // 0x3F00 and sprite patterns at 0x0000
+    let registers = [0x04, 0x60, 0xFF, 0xFF, 0xFF, 0xFF, 0xFB, 0x00];
+    // tile 1 is all color 1
+    let tile: Vec<u8> = (0..32).map(|i| if i % 4 == 0 { 0xFF } else { 0 }).collect();
+    // ten sprites on the same lines, the first two overlapping
+    let mut x_patterns = vec![];
+    for i in 0..10u8 {
+        x_patterns.extend_from_slice(&[0x10 + 0x10 * i.saturating_sub(1), 0x01]);
+    }
+    let vram: &[(u16, &[u8])] = &[
+        (0x0020, &tile),
+        (0x3F00, &[0x3F; 10]),
+        (0x3F0A, &[0xD0]),
+        (0x3F80, &x_patterns),
+    ];
+    let statuses = check(&status_recording_rom(&registers, vram));
+    assert!(statuses.len() >= 6, "{:?}", statuses);
+    // every frame after the first sees the frame interrupt, sprite overflow,
+    // and collision flags
+    assert!(statuses[1..].iter().all(|&s| s & 0xE0 == 0xE0), "{:?}", statuses);
+}
+
+#[test]
+fn tms_sprite_flags() {
+    // graphics 1 with display and frame interrupts on, sprite attribute table
+    // at 0x3F00 and sprite patterns at 0x0000
+    let registers = [0x00, 0x60, 0x0E, 0x80, 0x00, 0x7E, 0x00, 0x04];
+    // six sprites on the same lines, the first two overlapping
+    let mut table = vec![];
+    for i in 0..6u8 {
+        table.extend_from_slice(&[0x3F, 0x10 + 0x10 * i.saturating_sub(1), 0x01, 0x0F]);
+    }
+    table.push(0xD0);
+    let vram: &[(u16, &[u8])] = &[(0x0008, &[0xFF; 8]), (0x3F00, &table)];
+    let statuses = check(&status_recording_rom(&registers, vram));
+    assert!(statuses.len() >= 6, "{:?}", statuses);
+    // every frame after the first sees the frame interrupt, fifth sprite, and
+    // collision flags
+    assert!(statuses[1..].iter().all(|&s| s & 0xE0 == 0xE0), "{:?}", statuses);
+}
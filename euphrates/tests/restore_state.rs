@@ -0,0 +1,17 @@
+//! `Sms::restore_state` with each memory implementation in this crate.
+
+extern crate euphrates;
+extern crate euphrates_testing;
+
+use euphrates::systems::sms::{PointerSmsMemory, SmsMemoryState, TypeWrap};
+use euphrates_testing::check_restore_state;
+
+#[test]
+fn sms_memory_state() {
+    check_restore_state(TypeWrap::<SmsMemoryState>::default());
+}
+
+#[test]
+fn pointer_sms_memory() {
+    check_restore_state(TypeWrap::<PointerSmsMemory>::default());
+}
@@ -0,0 +1,96 @@
+//! Serialized `SmsState`s, in the current format and the untagged format from
+//! before `SMS_STATE_VERSION`.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::save;
+use euphrates::systems::sms::{
+    Kind, Recording, Reg16, SmsMemoryMapper, SmsPlayerInput, SmsState, TvSystem, Z80Internal,
+    Z80Variant, SMS_STATE_VERSION,
+};
+
+fn state() -> SmsState {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x100] = 0xC9;
+    let mut state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    state.z80.cycles = 123_456;
+    state.z80.set_reg16(Reg16::HL, 0xBEEF);
+    state.z80.iff1 = true;
+    state.memory.system_ram[0x42] = 0x99;
+    state.vdp.vram[0x3F00] = 0x11;
+    state
+}
+
+/// `state` as version 0 wrote it: the fields of `SmsState` in order, with the
+/// Z80's fields up to `interrupt_status` inline.
+fn version_0_bytes(state: &SmsState) -> Vec<u8> {
+    let z80 = &state.z80;
+    save::serialize(&(
+        z80.cycles,
+        z80.registers,
+        z80.halted,
+        z80.iff1,
+        z80.iff2,
+        z80.prefix,
+        z80.interrupt_mode,
+        z80.interrupt_status,
+        &state.vdp,
+        &state.memory,
+        state.player_input,
+        state.pause_irq,
+        state.sn76489,
+    )).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let mut state = state();
+    state.z80.memptr = 0x1234;
+    state.z80.variant = Z80Variant::Cmos;
+    state.z80.q = 0x28;
+    let bytes = save::serialize(&state).unwrap();
+    let state2: SmsState = save::deserialize(&bytes).unwrap();
+    assert!(state == state2);
+}
+
+#[test]
+fn version_0() {
+    let state = state();
+    let loaded: SmsState = save::deserialize(&version_0_bytes(&state)).unwrap();
+    assert!(loaded == state);
+    assert_eq!(loaded.z80.reg16(Reg16::HL), 0xBEEF);
+    assert_eq!(loaded.z80.variant, Z80Variant::Nmos);
+    assert_eq!(loaded.z80.memptr, 0);
+}
+
+#[test]
+fn version_0_recording() {
+    let state = state();
+    let player_statuses = vec![SmsPlayerInput::default(); 3];
+    let mut bytes = version_0_bytes(&state);
+    bytes.extend(save::serialize(&player_statuses).unwrap());
+    let recording: Recording<SmsState> = save::deserialize(&bytes).unwrap();
+    assert!(recording.state == state);
+    assert_eq!(recording.player_statuses.len(), 3);
+}
+
+#[test]
+fn unknown_version() {
+    let mut bytes = save::serialize(&state()).unwrap();
+    // the version follows the 8 byte tag
+    bytes[8] = 99;
+    let result: Result<SmsState, _> = save::deserialize(&bytes);
+    let message = result.err().unwrap().to_string();
+    let expected = format!(
+        "unknown SmsState version 99 (expected {})",
+        SMS_STATE_VERSION
+    );
+    assert!(message.contains(&expected), "{}", message);
+}
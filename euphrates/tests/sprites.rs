@@ -0,0 +1,174 @@
+//! Sprites as read out of the sprite attribute table by `Sms::sprites`.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::z80::assemble;
+use euphrates::systems::sms::{
+    new_headless_sms, FakeSmsGraphics, Kind, PointerSmsMemory, SmsMemoryMapper, SmsPlayerInput,
+    SmsState, SpriteObservation, TvSystem, TypeWrap,
+};
+
+/// Write `vram`, a list of addresses and bytes to write there, into VRAM,
+/// and then set the VDP's `registers`, and run a frame on a system of `kind`.
+/// Returns the sprites.
+fn sprites(kind: Kind, registers: &[u8], vram: &[(u16, &[u8])]) -> Vec<SpriteObservation> {
+    let mut source = "org 0\ndi\n".to_owned();
+    for &(address, bytes) in vram {
+        source += &format!(
+            "ld a, 0{:X}\nout (0BF), a\nld a, 0{:X}\nout (0BF), a\n",
+            address & 0xFF,
+            address >> 8 | 0x40
+        );
+        for byte in bytes {
+            source += &format!("ld a, 0{:X}\nout (0BE), a\n", byte);
+        }
+    }
+    for (i, register) in registers.iter().enumerate() {
+        source += &format!(
+            "ld a, 0{:X}\nout (0BF), a\nld a, 0{:X}\nout (0BF), a\n",
+            register,
+            0x80 | i
+        );
+    }
+    source += "loop:\njr loop\n";
+    let mut rom = assemble(&source).unwrap().bytes;
+    rom.resize(0x8000, 0);
+
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        kind,
+    );
+    let mut sms =
+        new_headless_sms(state, FakeSmsGraphics, TypeWrap::<PointerSmsMemory>::default()).unwrap();
+    sms.run_frame(SmsPlayerInput::default()).unwrap();
+    sms.sprites()
+}
+
+fn sprite(
+    index: u8,
+    (x, y): (i16, u16),
+    (width, height): (u8, u8),
+    pattern: u16,
+    terminated: bool,
+    visible: bool,
+) -> SpriteObservation {
+    SpriteObservation {
+        index,
+        x,
+        y,
+        width,
+        height,
+        pattern,
+        terminated,
+        visible,
+    }
+}
+
+/// The mode 4 sprite attribute table at 0x3F00: sprites at the top left, the
+/// left edge, and just below the active display, then the 0xD0 marker.
+const MODE_4_TABLE: &[(u16, &[u8])] = &[
+    (0x3F00, &[0x0F, 0x2F, 0xBF, 0xD0, 0x0F]),
+    (0x3F80, &[0x40, 0x01, 0x02, 0x03, 0xF8, 0x05, 0x00, 0x00, 0x10, 0x09]),
+];
+
+/// Mode 4 registers with the given registers 0, 1 and 6, with the sprite
+/// attribute table at 0x3F00.
+fn mode_4_registers(r0: u8, r1: u8, r6: u8) -> [u8; 8] {
+    [r0, r1, 0xFF, 0xFF, 0xFF, 0xFF, r6, 0x00]
+}
+
+#[test]
+fn mode_4() {
+    let sprites = sprites(Kind::Sms, &mode_4_registers(0x04, 0x40, 0xFB), MODE_4_TABLE);
+    assert_eq!(sprites.len(), 64);
+    assert_eq!(sprites[0], sprite(0, (0x40, 0x10), (8, 8), 1, false, true));
+    assert_eq!(sprites[1], sprite(1, (0x02, 0x30), (8, 8), 3, false, true));
+    assert_eq!(sprites[2], sprite(2, (0xF8, 0xC0), (8, 8), 5, false, false));
+    // the marker and everything after it
+    assert_eq!(sprites[3], sprite(3, (0x00, 0xD1), (8, 8), 0, true, false));
+    assert_eq!(sprites[4], sprite(4, (0x10, 0x10), (8, 8), 9, true, false));
+    assert!(sprites[5..].iter().all(|s| s.terminated && !s.visible));
+}
+
+#[test]
+fn mode_4_shifted() {
+    let sprites = sprites(Kind::Sms, &mode_4_registers(0x0C, 0x40, 0xFB), MODE_4_TABLE);
+    assert_eq!(sprites[0].x, 0x38);
+    // partly off the left edge
+    assert_eq!(sprites[1].x, -6);
+    assert!(sprites[1].visible);
+}
+
+#[test]
+fn mode_4_sizes() {
+    let size = |r1| {
+        let sprites = sprites(Kind::Sms, &mode_4_registers(0x04, r1, 0xFB), MODE_4_TABLE);
+        (sprites[0].width, sprites[0].height, sprites[0].pattern)
+    };
+    assert_eq!(size(0x40), (8, 8, 1));
+    // 8x16 sprites use pairs of patterns
+    assert_eq!(size(0x42), (8, 16, 0));
+    assert_eq!(size(0x41), (16, 16, 1));
+    assert_eq!(size(0x43), (16, 32, 0));
+}
+
+#[test]
+fn mode_4_pattern_table() {
+    let sprites = sprites(Kind::Sms, &mode_4_registers(0x04, 0x40, 0xFF), MODE_4_TABLE);
+    assert_eq!(sprites[0].pattern, 0x101);
+    assert_eq!(sprites[1].pattern, 0x103);
+}
+
+#[test]
+fn mode_4_224_lines() {
+    // 0xD0 doesn't end the table in the taller resolutions
+    let sprites = sprites(Kind::Sms2, &mode_4_registers(0x06, 0x50, 0xFB), MODE_4_TABLE);
+    assert_eq!(sprites[2], sprite(2, (0xF8, 0xC0), (8, 8), 5, false, true));
+    assert_eq!(sprites[3], sprite(3, (0x00, 0xD1), (8, 8), 0, false, true));
+    assert_eq!(sprites[4], sprite(4, (0x10, 0x10), (8, 8), 9, false, true));
+}
+
+/// TMS9918 registers with the given register 1, with the sprite attribute
+/// table at 0x3F00.
+fn tms_registers(r1: u8) -> [u8; 8] {
+    [0x00, r1, 0x0E, 0x80, 0x00, 0x7E, 0x00, 0x04]
+}
+
+/// The TMS9918 sprite attribute table at 0x3F00: one sprite, one with the
+/// early clock bit set, then the 0xD0 marker.
+const TMS_TABLE: &[(u16, &[u8])] = &[(
+    0x3F00,
+    &[
+        0x0F, 0x40, 0x07, 0x0A, 0x1F, 0x1C, 0x05, 0x8A, 0xD0, 0x20, 0x01, 0x01, 0x0F, 0x30, 0x02,
+        0x01,
+    ],
+)];
+
+#[test]
+fn tms() {
+    let sprites = sprites(Kind::Sms, &tms_registers(0x40), TMS_TABLE);
+    assert_eq!(sprites.len(), 32);
+    assert_eq!(sprites[0], sprite(0, (0x40, 0x10), (8, 8), 7, false, true));
+    // the early clock bit moves the sprite 32 pixels left, partly off screen
+    assert_eq!(sprites[1], sprite(1, (-4, 0x20), (8, 8), 5, false, true));
+    assert_eq!(sprites[2], sprite(2, (0x20, 0xD1), (8, 8), 1, true, false));
+    assert_eq!(sprites[3], sprite(3, (0x30, 0x10), (8, 8), 2, true, false));
+    assert!(sprites[4..].iter().all(|s| s.terminated && !s.visible));
+}
+
+#[test]
+fn tms_sizes() {
+    let size = |r1| {
+        let sprites = sprites(Kind::Sms, &tms_registers(r1), TMS_TABLE);
+        (sprites[0].width, sprites[0].height, sprites[0].pattern)
+    };
+    assert_eq!(size(0x40), (8, 8, 7));
+    // 16x16 sprites use groups of 4 patterns
+    assert_eq!(size(0x42), (16, 16, 4));
+    assert_eq!(size(0x41), (16, 16, 7));
+    assert_eq!(size(0x43), (32, 32, 4));
+}
@@ -0,0 +1,160 @@
+//! Trace a small program, and find where traces diverge.
+
+extern crate euphrates;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use euphrates::hardware::z80::assemble;
+use euphrates::host_multimedia::FakeAudio;
+use euphrates::systems::sms::{
+    self, find_text_trace_divergence, find_trace_divergence, CachedInterpreter, FakeSmsGraphics,
+    FakeSn76489, Kind, SmsMemoryMapper, SmsMemoryState, SmsPlayerInput, SmsState, TraceFormat,
+    TraceReader, TraceRecord, TracingInbox, TvSystem, TypeWrap,
+};
+
+const PROGRAM: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+        ld a, 5
+    loop:
+        dec a
+        jr nz, loop
+        halt
+";
+
+/// A `Write` whose bytes we can still get at after it's given away.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `PROGRAM` for a frame and return its trace.
+fn trace(format: TraceFormat, cached: bool) -> Vec<u8> {
+    let mut rom = assemble(PROGRAM).unwrap().bytes;
+    rom.resize(0x8000, 0);
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    let buffer = SharedBuffer::default();
+    let inbox = TracingInbox::new(buffer.clone(), format).unwrap();
+    let mut sms = sms::new_sms(
+        None,
+        state,
+        FakeSmsGraphics,
+        FakeAudio,
+        inbox,
+        TypeWrap::<SmsMemoryState>::default(),
+        TypeWrap::<FakeSn76489>::default(),
+    ).unwrap();
+    if cached {
+        sms.set_z80_runner(Some(Box::new(CachedInterpreter::new())));
+    }
+    sms.run_frame(SmsPlayerInput::default()).unwrap();
+    let bytes = buffer.0.lock().unwrap().clone();
+    bytes
+}
+
+fn records(trace: &[u8]) -> Vec<TraceRecord> {
+    TraceReader::new(trace).unwrap().map(|r| r.unwrap()).collect()
+}
+
+#[test]
+fn binary() {
+    let records = records(&trace(TraceFormat::Binary, false));
+
+    // di, ld sp, ld a, 5 times around the loop, and halt
+    assert_eq!(records.len(), 14);
+    let pcs: Vec<u16> = records.iter().map(|r| r.registers.pc).collect();
+    assert_eq!(pcs, vec![0, 1, 4, 6, 7, 6, 7, 6, 7, 6, 7, 6, 7, 9]);
+    assert_eq!(records[1].opcode.bytes(), &[0x31, 0xF0, 0xDF]);
+    assert_eq!(records[13].opcode.bytes(), &[0x76]);
+    assert_eq!(records[2].registers.sp, 0xDFF0);
+    assert_eq!(records[4].registers.af >> 8, 4);
+    assert_eq!(records[13].registers.af >> 8, 0);
+    for pair in records.windows(2) {
+        assert!(pair[0].registers.cycles < pair[1].registers.cycles);
+        assert_eq!(pair[0].registers.r.wrapping_add(1), pair[1].registers.r);
+    }
+    assert!(!records[1].registers.iff1);
+}
+
+#[test]
+fn text() {
+    let text = String::from_utf8(trace(TraceFormat::Text, false)).unwrap();
+    let binary = records(&trace(TraceFormat::Binary, false));
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), binary.len());
+    for (line, record) in lines.iter().zip(binary.iter()) {
+        assert_eq!(*line, record.to_string());
+    }
+    assert!(lines[1].starts_with("0001  31 F0 DF     ld sp, "));
+    assert!(lines[2].contains(" SP:DFF0 "));
+}
+
+#[test]
+fn cached_interpreter_traces() {
+    // a tracing inbox makes the cached interpreter defer to the interpreter
+    assert_eq!(
+        trace(TraceFormat::Binary, true),
+        trace(TraceFormat::Binary, false)
+    );
+}
+
+#[test]
+fn divergence() {
+    let left = records(&trace(TraceFormat::Binary, false));
+    let as_results = |records: &[TraceRecord]| -> Vec<io::Result<TraceRecord>> {
+        records.iter().cloned().map(Ok).collect()
+    };
+
+    let same = find_trace_divergence(as_results(&left), as_results(&left)).unwrap();
+    assert_eq!(same, None);
+
+    let mut right = left.clone();
+    right[6].registers.af ^= 0x0100;
+    right[6].registers.cycles += 1;
+    let d = find_trace_divergence(as_results(&left), as_results(&right))
+        .unwrap()
+        .unwrap();
+    assert_eq!(d.index, 6);
+    assert_eq!(d.left, Some(left[6].to_string()));
+    let fields: Vec<&str> = d.differences.iter().map(|x| &x.field[..]).collect();
+    assert_eq!(fields, vec!["AF", "CYC"]);
+
+    let d = find_trace_divergence(as_results(&left), as_results(&left[..10]))
+        .unwrap()
+        .unwrap();
+    assert_eq!(d.index, 10);
+    assert_eq!(d.right, None);
+    assert!(d.differences.is_empty());
+}
+
+#[test]
+fn text_divergence() {
+    let left = "0000  F3  di\n0001  00  nop\n0002  00  nop\n";
+    let right = "0000  F3  di  \n0001  00  nop\n0002  76  halt\n";
+
+    let same = find_text_trace_divergence(left.as_bytes(), left.as_bytes()).unwrap();
+    assert_eq!(same, None);
+
+    let d = find_text_trace_divergence(left.as_bytes(), right.as_bytes())
+        .unwrap()
+        .unwrap();
+    assert_eq!(d.index, 2);
+    assert_eq!(d.left.unwrap(), "0002  00  nop");
+    assert_eq!(d.right.unwrap(), "0002  76  halt");
+}
@@ -0,0 +1,190 @@
+//! Tests of the Z80 assembler, including that it round trips with the
+//! disassembler.
+
+extern crate euphrates;
+
+use euphrates::hardware::z80::{assemble, assemble_instruction, AssemblerError, Opcode};
+
+/// Every opcode the disassembler knows, with a few different values for
+/// immediates and displacements.
+fn opcodes() -> Vec<Opcode> {
+    let mut opcodes = Vec::new();
+    for &x in [0x00u8, 0x05, 0x7F, 0x80, 0xFE].iter() {
+        for b in 0..=0xFFu8 {
+            let candidates = [
+                Opcode::OneByte([b]),
+                Opcode::TwoBytes([b, x]),
+                Opcode::ThreeBytes([b, x, 0x12]),
+                Opcode::TwoBytes([0xCB, b]),
+                Opcode::TwoBytes([0xED, b]),
+                Opcode::FourBytes([0xED, b, x, 0x12]),
+                Opcode::TwoBytes([0xDD, b]),
+                Opcode::ThreeBytes([0xDD, b, x]),
+                Opcode::FourBytes([0xDD, b, x, 0x12]),
+                Opcode::FourBytes([0xDD, 0xCB, x, b]),
+                Opcode::TwoBytes([0xFD, b]),
+                Opcode::ThreeBytes([0xFD, b, x]),
+                Opcode::FourBytes([0xFD, b, x, 0x12]),
+                Opcode::FourBytes([0xFD, 0xCB, x, b]),
+            ];
+            for &opcode in candidates.iter() {
+                if opcode.mnemonic().is_some() && !opcodes.contains(&opcode) {
+                    opcodes.push(opcode);
+                }
+            }
+        }
+    }
+    opcodes
+}
+
+#[test]
+fn round_trip() {
+    let opcodes = opcodes();
+    assert!(opcodes.len() > 1500);
+    for opcode in opcodes {
+        let mnemonic = opcode.mnemonic().unwrap();
+        let text = mnemonic.to_string();
+        let assembled = assemble_instruction(&text, 0x1234)
+            .unwrap_or_else(|e| panic!("{} ({}): {}", text, opcode, e));
+        assert_eq!(
+            assembled.mnemonic(),
+            Some(mnemonic),
+            "{} ({} assembled to {})",
+            text,
+            opcode,
+            assembled
+        );
+        assert_eq!(mnemonic.opcode(), Some(assembled), "{}", text);
+    }
+}
+
+fn bytes(source: &str) -> Vec<u8> {
+    assemble(source).unwrap().bytes
+}
+
+#[test]
+fn usual_syntax() {
+    let cases: &[(&str, &[u8])] = &[
+        ("jp (hl)", &[0xE9]),
+        ("jp (ix)", &[0xDD, 0xE9]),
+        ("in a, (0BF)", &[0xDB, 0xBF]),
+        ("out (7E), a", &[0xD3, 0x7E]),
+        ("in b, (c)", &[0xED, 0x40]),
+        ("out (c), a", &[0xED, 0x79]),
+        ("ld a, (ix)", &[0xDD, 0x7E, 0x00]),
+        ("ld (iy-2), 0x12", &[0xFD, 0x36, 0xFE, 0x12]),
+        ("LD HL, ($C000)", &[0x2A, 0x00, 0xC0]),
+        ("ex af, af'", &[0x08]),
+        ("bit 7, (hl)", &[0xCB, 0x7E]),
+        ("rst 38", &[0xFF]),
+        ("im 1", &[0xED, 0x56]),
+        ("ld a, FF", &[0x3E, 0xFF]),
+        ("ld a, -1", &[0x3E, 0xFF]),
+        ("ld a, 'A'", &[0x3E, 0x41]),
+        ("ld bc, 1 + 2 * 3", &[0x01, 0x07, 0x00]),
+        ("ld bc, (1 + 2) * 3", &[0x01, 0x09, 0x00]),
+        ("ld de, 1 << 0C | 0FFh", &[0x11, 0xFF, 0x10]),
+        ("jr $", &[0x18, 0xFE]),
+        ("jr -2", &[0x18, 0xFE]),
+        ("jr +FE", &[0x18, 0xFE]),
+    ];
+    for &(text, expected) in cases.iter() {
+        assert_eq!(bytes(text), expected, "{}", text);
+    }
+}
+
+#[test]
+fn program() {
+    let assembly = assemble(
+        "
+        ; a comment
+            org 100
+        start:
+            ld b, end - count   ; forward references
+        loop:
+            djnz loop
+            jp nz, done
+            call table + 1
+        done:
+            halt
+        table:
+            db 1, 2, \"hi\", -1
+            dw start, $
+        count: db 3
+            org 120
+            .db end - start
+        end:
+        ",
+    ).unwrap();
+    assert_eq!(assembly.origin, 0x100);
+    assert_eq!(assembly.labels["start"], 0x100);
+    assert_eq!(assembly.labels["loop"], 0x102);
+    assert_eq!(assembly.labels["done"], 0x10A);
+    assert_eq!(assembly.labels["table"], 0x10B);
+    assert_eq!(assembly.labels["count"], 0x114);
+    assert_eq!(assembly.labels["end"], 0x121);
+    let mut expected = vec![
+        0x06, 0x0D, // ld b, end - count
+        0x10, 0xFE, // djnz loop
+        0xC2, 0x0A, 0x01, // jp nz, done
+        0xCD, 0x0C, 0x01, // call table + 1
+        0x76, // halt
+        0x01, 0x02, b'h', b'i', 0xFF, // db
+        0x00, 0x01, 0x10, 0x01, // dw
+        0x03, // db 3
+    ];
+    expected.resize(0x20, 0);
+    expected.push(0x21);
+    assert_eq!(assembly.bytes, expected);
+}
+
+#[test]
+fn errors() {
+    let error = |source: &str| assemble(source).unwrap_err();
+    assert_eq!(
+        error("nop\nld a, nowhere"),
+        AssemblerError::UnknownLabel {
+            line: 2,
+            label: "nowhere".to_owned()
+        }
+    );
+    assert_eq!(
+        error("ld hl, a"),
+        AssemblerError::NoInstruction {
+            line: 1,
+            text: "ld hl, a".to_owned()
+        }
+    );
+    assert_eq!(
+        error("ld a, 100"),
+        AssemblerError::OutOfRange {
+            line: 1,
+            value: 0x100
+        }
+    );
+    assert_eq!(
+        error("x: nop\nx: nop"),
+        AssemblerError::DuplicateLabel {
+            line: 2,
+            label: "x".to_owned()
+        }
+    );
+    assert_eq!(
+        error("org 10\nnop\norg 0"),
+        AssemblerError::OrgBackwards {
+            line: 3,
+            address: 0
+        }
+    );
+    assert_eq!(
+        error("jr far\norg 100\nfar:"),
+        AssemblerError::OutOfRange {
+            line: 1,
+            value: 0xFE
+        }
+    );
+    match error("ld a, (b") {
+        AssemblerError::Syntax { line: 1, .. } => {}
+        e => panic!("{:?}", e),
+    }
+}
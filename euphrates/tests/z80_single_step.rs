@@ -0,0 +1,448 @@
+//! Test the Z80 against vectors in the format of the SingleStepTests project
+//! (https://github.com/SingleStepTests/z80).
+//!
+//! Each file is a JSON array of tests of one opcode. A test gives the
+//! registers and the relevant bytes of RAM before and after executing one
+//! instruction, what was on the bus during each cycle, and the values read
+//! from or written to ports. We compare the registers, RAM, the number of
+//! cycles, the order and values of memory writes, and the port accesses. We
+//! don't emulate the bus cycle by cycle, so we don't compare anything else
+//! about it.
+//!
+//! The vectors in `tests/z80_vectors` run with `cargo test`. They are not
+//! from the upstream project: they were written by hand for this crate, in
+//! its format, with the expected results worked out from the documented
+//! behavior of the Z80 (the Zilog manual for timing, and "The Undocumented Z80
+//! Documented" for the undocumented flags and MEMPTR) rather than by running
+//! euphrates. There are a few tests of each of the ALU, load, jump, call and
+//! return, and block instructions, plus some others. To run the full upstream
+//! suite, set the environment variable `EUPHRATES_Z80_TESTS` to the directory
+//! holding its files (like `v1`); that takes a while, so you'll want
+//! `--release`.
+
+extern crate euphrates;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::memory16::Memory16;
+use euphrates::hardware::z80::Reg16::*;
+use euphrates::hardware::z80::Reg8::*;
+use euphrates::hardware::z80::{
+    InterruptMode, InterruptStatus, Prefix, Z80Internal, Z80Irq, Z80Run, Z80RunImpler, Z80State,
+};
+use euphrates::memo::NothingInbox;
+
+#[derive(Clone, Debug, Deserialize)]
+struct Test {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    final_state: State,
+    /// Address bus, data bus if anything was on it, and pins: `r`, `w`, `m`
+    /// (MREQ), and `i` (IORQ), or `-` for each one not active.
+    cycles: Vec<(u16, Option<u8>, String)>,
+    /// Address, value, and `r` or `w`.
+    #[serde(default)]
+    ports: Vec<(u16, u8, String)>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct State {
+    pc: u16,
+    sp: u16,
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    f: u8,
+    h: u8,
+    l: u8,
+    i: u8,
+    r: u8,
+    ei: u8,
+    wz: u16,
+    ix: u16,
+    iy: u16,
+    af_: u16,
+    bc_: u16,
+    de_: u16,
+    hl_: u16,
+    im: u8,
+    p: u8,
+    q: u8,
+    iff1: u8,
+    iff2: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+struct TestMemory {
+    ram: Box<[u8; 0x10000]>,
+    writes: Vec<(u16, u8)>,
+}
+
+impl Memory16 for TestMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.ram[address as usize] = value;
+        self.writes.push((address, value));
+    }
+}
+
+/// Answers port reads with the values the test expects to be read, in order.
+struct TestIo {
+    inputs: Vec<u8>,
+    accesses: Vec<(u16, u8, String)>,
+}
+
+impl Io16 for TestIo {
+    fn input(&mut self, address: u16) -> u8 {
+        let value = if self.inputs.is_empty() {
+            0xFF
+        } else {
+            self.inputs.remove(0)
+        };
+        self.accesses.push((address, value, "r".to_owned()));
+        value
+    }
+
+    fn output(&mut self, address: u16, value: u8) {
+        self.accesses.push((address, value, "w".to_owned()));
+    }
+}
+
+struct NoIrq;
+
+impl Z80Irq for NoIrq {
+    fn requesting_mi(&mut self) -> Option<u8> {
+        None
+    }
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+    fn take_nmi(&mut self) {}
+}
+
+fn interrupt_mode(im: u8) -> InterruptMode {
+    match im {
+        0 => InterruptMode::Im0,
+        1 => InterruptMode::Im1,
+        _ => InterruptMode::Im2,
+    }
+}
+
+fn set_up(state: &State) -> Option<Z80State> {
+    let mut z80 = Z80State::default();
+    for &(reg, value) in [
+        (A, state.a),
+        (B, state.b),
+        (C, state.c),
+        (D, state.d),
+        (E, state.e),
+        (F, state.f),
+        (H, state.h),
+        (L, state.l),
+        (I, state.i),
+        (R, state.r),
+    ].iter()
+    {
+        z80.set_reg8(reg, value);
+    }
+    for &(reg, value) in [
+        (PC, state.pc),
+        (SP, state.sp),
+        (IX, state.ix),
+        (IY, state.iy),
+        (AF0, state.af_),
+        (BC0, state.bc_),
+        (DE0, state.de_),
+        (HL0, state.hl_),
+    ].iter()
+    {
+        z80.set_reg16(reg, value);
+    }
+    z80.set_memptr(state.wz);
+    z80.set_iff1(state.iff1 != 0);
+    z80.set_iff2(state.iff2 != 0);
+    z80.set_interrupt_mode(interrupt_mode(state.im));
+
+    // Q is computed from F when the instruction begins, so it can only be 0
+    // or F
+    if state.q != 0 && state.q != state.f {
+        return None;
+    }
+    z80.set_flags_written(state.q != 0);
+    Some(z80)
+}
+
+/// Run one test, and describe each way the result differs from what's
+/// expected.
+///
+/// Returns `None` if the initial state can't be set up.
+fn run_test(test: &Test) -> Option<Vec<String>> {
+    let mut z80 = set_up(&test.initial)?;
+    let mut memory = TestMemory {
+        ram: Box::new([0u8; 0x10000]),
+        writes: Vec::new(),
+    };
+    for &(address, value) in test.initial.ram.iter() {
+        memory.ram[address as usize] = value;
+    }
+    let mut io = TestIo {
+        inputs: test
+            .ports
+            .iter()
+            .filter(|p| p.2 == "r")
+            .map(|p| p.1)
+            .collect(),
+        accesses: Vec::new(),
+    };
+
+    // a prefix byte is executed on its own, so keep going until the whole
+    // instruction is done
+    loop {
+        let cycles = z80.cycles();
+        Z80RunImpler {
+            z80: &mut z80,
+            memory: &mut memory,
+            io: &mut io,
+            irq: &mut NoIrq,
+            inbox: &mut NothingInbox::default(),
+        }.run(cycles + 1);
+        match z80.prefix() {
+            Prefix::NoPrefix | Prefix::Halt => break,
+            _ => {}
+        }
+    }
+
+    let expected = &test.final_state;
+    let mut mismatches = Vec::new();
+    {
+        let mut check = |name: &str, expected: u16, actual: u16| {
+            if expected != actual {
+                mismatches.push(format!(
+                    "{}: expected {:X}, got {:X}",
+                    name, expected, actual
+                ));
+            }
+        };
+
+        for &(name, reg, value) in [
+            ("a", A, expected.a),
+            ("b", B, expected.b),
+            ("c", C, expected.c),
+            ("d", D, expected.d),
+            ("e", E, expected.e),
+            ("f", F, expected.f),
+            ("h", H, expected.h),
+            ("l", L, expected.l),
+            ("i", I, expected.i),
+            ("r", R, expected.r),
+        ].iter()
+        {
+            check(name, value as u16, z80.reg8(reg) as u16);
+        }
+        for &(name, reg, value) in [
+            ("pc", PC, expected.pc),
+            ("sp", SP, expected.sp),
+            ("ix", IX, expected.ix),
+            ("iy", IY, expected.iy),
+            ("af'", AF0, expected.af_),
+            ("bc'", BC0, expected.bc_),
+            ("de'", DE0, expected.de_),
+            ("hl'", HL0, expected.hl_),
+        ].iter()
+        {
+            check(name, value, z80.reg16(reg));
+        }
+        check("wz", expected.wz, z80.memptr());
+        check("iff1", expected.iff1 as u16, z80.iff1() as u16);
+        check("iff2", expected.iff2 as u16, z80.iff2() as u16);
+        check("im", expected.im as u16, z80.interrupt_mode() as u16);
+        let ei = match z80.interrupt_status() {
+            InterruptStatus::Ei(_) => 1,
+            _ => 0,
+        };
+        check("ei", expected.ei as u16, ei);
+        check("p", expected.p as u16, z80.ld_a_ir() as u16);
+        let q = if z80.flags_written() {
+            z80.reg8(F)
+        } else {
+            0
+        };
+        check("q", expected.q as u16, q as u16);
+        check("cycles", test.cycles.len() as u16, z80.cycles() as u16);
+
+        for &(address, value) in expected.ram.iter() {
+            check(
+                &format!("({:04X})", address),
+                value as u16,
+                memory.ram[address as usize] as u16,
+            );
+        }
+    }
+
+    let writes = memory_writes(&test.cycles);
+    if writes != memory.writes {
+        mismatches.push(format!(
+            "memory writes: expected {:X?}, got {:X?}",
+            writes, memory.writes
+        ));
+    }
+    if test.ports != io.accesses {
+        mismatches.push(format!(
+            "ports: expected {:X?}, got {:X?}",
+            test.ports, io.accesses
+        ));
+    }
+
+    Some(mismatches)
+}
+
+/// The memory writes during `cycles`, in order.
+///
+/// A write takes more than one cycle, so consecutive write cycles to the same
+/// address are one write.
+fn memory_writes(cycles: &[(u16, Option<u8>, String)]) -> Vec<(u16, u8)> {
+    let mut writes = Vec::new();
+    let mut last = None;
+    for &(address, value, ref pins) in cycles.iter() {
+        let pins = pins.as_bytes();
+        let writing = pins.len() == 4 && pins[1] == b'w' && pins[2] == b'm';
+        match value {
+            Some(value) if writing => {
+                if last != Some(address) {
+                    writes.push((address, value));
+                }
+                last = Some(address);
+            }
+            _ => last = None,
+        }
+    }
+    writes
+}
+
+#[derive(Default)]
+struct Outcome {
+    tests: usize,
+    skipped: usize,
+    failures: Vec<(String, Vec<String>)>,
+}
+
+fn run_file(path: &Path) -> Outcome {
+    let text = fs::read_to_string(path).unwrap();
+    let tests: Vec<Test> = serde_json::from_str(&text)
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let mut outcome = Outcome::default();
+    for test in tests.iter() {
+        outcome.tests += 1;
+        match run_test(test) {
+            None => outcome.skipped += 1,
+            Some(ref mismatches) if mismatches.is_empty() => {}
+            Some(mismatches) => outcome.failures.push((test.name.clone(), mismatches)),
+        }
+    }
+    outcome
+}
+
+/// Run every `.json` file in `directory`, reporting failures by opcode, and
+/// panic if there were any.
+fn run_directory(directory: &Path) {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap_or_else(|e| panic!("{}: {}", directory.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no tests in {}", directory.display());
+
+    let mut outcomes = BTreeMap::new();
+    for path in paths.iter() {
+        let opcode = path.file_stem().unwrap().to_string_lossy().into_owned();
+        outcomes.insert(opcode, run_file(path));
+    }
+
+    let mut failed_opcodes = 0;
+    let mut tests = 0;
+    let mut skipped = 0;
+    for (opcode, outcome) in outcomes.iter() {
+        tests += outcome.tests;
+        skipped += outcome.skipped;
+        if let Some((name, mismatches)) = outcome.failures.first() {
+            failed_opcodes += 1;
+            println!(
+                "{}: {} of {} tests failed; first, {}:",
+                opcode,
+                outcome.failures.len(),
+                outcome.tests,
+                name
+            );
+            for mismatch in mismatches.iter() {
+                println!("    {}", mismatch);
+            }
+        }
+    }
+    println!(
+        "{} tests of {} opcodes; {} skipped; {} opcodes failed",
+        tests,
+        outcomes.len(),
+        skipped,
+        failed_opcodes
+    );
+    assert_eq!(failed_opcodes, 0, "some opcodes failed");
+}
+
+#[test]
+fn hand_written() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("z80_vectors");
+    run_directory(&directory);
+}
+
+#[test]
+fn upstream() {
+    if let Some(directory) = env::var_os("EUPHRATES_Z80_TESTS") {
+        run_directory(Path::new(&directory));
+    }
+}
+
+#[test]
+fn halt_refresh() {
+    // halted, the Z80 executes `nop`s, each taking 4 cycles and refreshing
+    // once, and R's high bit stays put
+    let mut z80 = Z80State::default();
+    z80.set_reg8(R, 0xFE);
+    let mut memory = TestMemory {
+        ram: Box::new([0u8; 0x10000]),
+        writes: Vec::new(),
+    };
+    memory.ram[0] = 0x76;
+    let mut io = TestIo {
+        inputs: Vec::new(),
+        accesses: Vec::new(),
+    };
+    for &(target, cycles, r) in [(1, 4, 0xFF), (44, 44, 0x89), (46, 48, 0x8A)].iter() {
+        Z80RunImpler {
+            z80: &mut z80,
+            memory: &mut memory,
+            io: &mut io,
+            irq: &mut NoIrq,
+            inbox: &mut NothingInbox::default(),
+        }.run(target);
+        assert_eq!(z80.prefix(), Prefix::Halt);
+        assert_eq!(z80.cycles(), cycles);
+        assert_eq!(z80.reg8(R), r);
+    }
+}
@@ -0,0 +1,179 @@
+//! The undocumented behavior that differs between NMOS, CMOS, and NEC Z80s.
+
+extern crate euphrates;
+
+use std::sync::Arc;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::z80::{
+    assemble, Prefix, Reg16, Reg8, Z80Internal, Z80Irq, Z80Memo, Z80Run, Z80RunImpler, Z80State,
+    Z80Variant, PF, XF, YF,
+};
+use euphrates::memo::NothingInbox;
+use euphrates::systems::sms::{Kind, SmsMemoryMapper, SmsState, TvSystem};
+
+const VARIANTS: [Z80Variant; 3] = [Z80Variant::Nmos, Z80Variant::Cmos, Z80Variant::Nec];
+
+/// Records every output.
+#[derive(Default)]
+struct Outputs(Vec<(u16, u8)>);
+
+impl Io16 for Outputs {
+    fn input(&mut self, _address: u16) -> u8 {
+        0xFF
+    }
+
+    fn output(&mut self, address: u16, value: u8) {
+        self.0.push((address, value));
+    }
+
+    fn peek(&mut self, _address: u16) -> Option<u8> {
+        Some(0xFF)
+    }
+}
+
+/// Requests a maskable interrupt, always, if `on`.
+struct Irq {
+    on: bool,
+}
+
+impl Z80Irq for Irq {
+    fn requesting_mi(&mut self) -> Option<u8> {
+        if self.on {
+            Some(0xFF)
+        } else {
+            None
+        }
+    }
+
+    fn requesting_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn take_nmi(&mut self) {}
+}
+
+/// Run `source` on a Z80 of `variant` until it halts, with a maskable
+/// interrupt requested throughout if `interrupt`. Returns the Z80 and the
+/// outputs.
+fn run(variant: Z80Variant, source: &str, interrupt: bool) -> (Z80State, Vec<(u16, u8)>) {
+    let program = assemble(source).unwrap().bytes;
+    let mut memory = [0u8; 0x10000];
+    memory[..program.len()].copy_from_slice(&program);
+    let mut z80 = Z80State::default();
+    z80.set_variant(variant);
+    let mut outputs = Outputs::default();
+    Z80RunImpler {
+        z80: &mut z80,
+        memory: &mut memory,
+        io: &mut outputs,
+        irq: &mut Irq { on: interrupt },
+        inbox: &mut NothingInbox::<Z80Memo>::default(),
+    }.run(200);
+    assert_eq!(z80.prefix(), Prefix::Halt, "{:?}", variant);
+    (z80, outputs.0)
+}
+
+#[test]
+fn out_c_0() {
+    for &variant in VARIANTS.iter() {
+        let (_, outputs) = run(variant, "ld bc, 1234\nout (c), 0\nhalt\n", false);
+        let expected = if variant == Z80Variant::Cmos { 0xFF } else { 0 };
+        assert_eq!(outputs, [(0x1234, expected)], "{:?}", variant);
+    }
+}
+
+/// `XF` and `YF` after running `source` with `A` as 0 and `F` with only `XF`
+/// and `YF` set, followed by `instruction`.
+fn scf_ccf_xy(variant: Z80Variant, source: &str, instruction: &str) -> u8 {
+    let source = format!(
+        "ld sp, 8000\nld bc, 0028\npush bc\npop af\n{}\n{}\nhalt\n",
+        source, instruction
+    );
+    let (z80, _) = run(variant, &source, false);
+    z80.reg8(Reg8::F) & (XF | YF)
+}
+
+#[test]
+fn scf_ccf() {
+    for &instruction in ["scf", "ccf"].iter() {
+        for &variant in VARIANTS.iter() {
+            // `pop af` doesn't set Q, so NMOS and CMOS parts take the flags
+            // from F, and NEC parts from A
+            let from_f = if variant == Z80Variant::Nec {
+                0
+            } else {
+                XF | YF
+            };
+            assert_eq!(
+                scf_ccf_xy(variant, "", instruction),
+                from_f,
+                "{} on {:?} after pop af",
+                instruction,
+                variant
+            );
+            // after an instruction setting the flags, Q is F and they cancel
+            assert_eq!(
+                scf_ccf_xy(variant, "scf", instruction),
+                0,
+                "{} on {:?} after scf",
+                instruction,
+                variant
+            );
+            // which `nop` clears
+            assert_eq!(
+                scf_ccf_xy(variant, "scf\nnop", instruction),
+                from_f,
+                "{} on {:?} after scf, nop",
+                instruction,
+                variant
+            );
+        }
+    }
+}
+
+#[test]
+fn ld_a_ir_interrupted() {
+    for &instruction in ["ld a, i", "ld a, r"].iter() {
+        for &variant in VARIANTS.iter() {
+            // the interrupt is accepted right after `instruction`, and the
+            // handler at 0x38 is a `halt`
+            let source = format!("im 1\nei\n{}\nnop\nhalt\norg 38\nhalt\n", instruction);
+            let (z80, _) = run(variant, &source, true);
+            assert_eq!(z80.reg16(Reg16::PC), 0x39);
+            let pf = variant == Z80Variant::Cmos;
+            assert_eq!(
+                z80.reg8(Reg8::F) & PF != 0,
+                pf,
+                "{} on {:?}",
+                instruction,
+                variant
+            );
+
+            // without the interrupt, PF is IFF2
+            let (z80, _) = run(variant, &source, false);
+            assert!(
+                z80.reg8(Reg8::F) & PF != 0,
+                "{} on {:?}",
+                instruction,
+                variant
+            );
+        }
+    }
+}
+
+#[test]
+fn game_gear_is_cmos() {
+    let variant = |kind| {
+        let state = SmsState::from_rom(
+            Arc::new(vec![0u8; 0x8000].into_boxed_slice()),
+            SmsMemoryMapper::Sega,
+            TvSystem::Ntsc,
+            kind,
+        );
+        state.z80.variant()
+    };
+    assert_eq!(variant(Kind::Sms), Z80Variant::Nmos);
+    assert_eq!(variant(Kind::Sms2), Z80Variant::Nmos);
+    assert_eq!(variant(Kind::Gg), Z80Variant::Cmos);
+}
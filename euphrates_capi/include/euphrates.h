@@ -0,0 +1,153 @@
+/* Generated by cbindgen from euphrates_capi/src/lib.rs. Do not edit by hand. */
+
+#ifndef EUPHRATES_H
+#define EUPHRATES_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The Sega memory mapper, used by most Master System and Game Gear games.
+#define EUPHRATES_MAPPER_SEGA 0
+
+// The Codemasters memory mapper.
+#define EUPHRATES_MAPPER_CODEMASTERS 1
+
+// The SG-1000 memory map with 1 KiB of RAM.
+#define EUPHRATES_MAPPER_SG1000_1 2
+
+// The SG-1000 memory map with 2 KiB of RAM.
+#define EUPHRATES_MAPPER_SG1000_2 3
+
+// The SG-1000 memory map with 4 KiB of RAM.
+#define EUPHRATES_MAPPER_SG1000_4 4
+
+#define EUPHRATES_TV_NTSC 0
+
+#define EUPHRATES_TV_PAL 1
+
+// The original Master System's VDP; use this for SG-1000 games too.
+#define EUPHRATES_KIND_SMS 0
+
+// The Master System 2's VDP.
+#define EUPHRATES_KIND_SMS2 1
+
+// The Game Gear's VDP.
+#define EUPHRATES_KIND_GG 2
+
+// An emulated system.
+typedef struct EuphratesSms EuphratesSms;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The message describing the last error on this thread, or null if there
+// hasn't been one.
+//
+// The string is owned by the library, and is valid until the next call to an
+// `euphrates_` function on this thread.
+const char *euphrates_last_error(void);
+
+// Create a system running the ROM in `rom[0..rom_len]`, which is copied.
+//
+// `mapper`, `tv_system`, and `kind` are one of the `EUPHRATES_MAPPER_`,
+// `EUPHRATES_TV_`, and `EUPHRATES_KIND_` constants.
+//
+// Returns null on failure.
+//
+// # Safety
+//
+// `rom` must point to `rom_len` readable bytes.
+EuphratesSms *euphrates_sms_new(const uint8_t *rom,
+                                size_t rom_len,
+                                uint32_t mapper,
+                                uint32_t tv_system,
+                                uint32_t kind);
+
+// Destroy a system created by `euphrates_sms_new`. Does nothing if `sms` is
+// null.
+//
+// # Safety
+//
+// `sms` must be null or a pointer returned by `euphrates_sms_new` that hasn't
+// already been freed.
+void euphrates_sms_free(EuphratesSms *sms);
+
+// Emulate `frames` frames with the given joypad port bytes and pause button.
+//
+// As on the hardware, a button is pressed when its bit is *clear*, so `0xFF`
+// presses nothing; see `joypad_a_bits` and `joypad_b_bits` in the `euphrates`
+// crate for the meaning of each bit. Only the last frame is rendered.
+//
+// Returns 0 on success and -1 on failure.
+//
+// # Safety
+//
+// `sms` must be a valid pointer returned by `euphrates_sms_new`.
+int euphrates_sms_run_frames(EuphratesSms *sms,
+                             uint8_t joypad_a,
+                             uint8_t joypad_b,
+                             int pause,
+                             uint32_t frames);
+
+// Copy the last rendered frame into `buffer`, as rows of 3 byte red, green,
+// blue pixels, and store its dimensions in `width` and `height`.
+//
+// Returns the number of bytes in the frame, which is `3 * width * height`.
+// If that is bigger than `buffer_len`, only `buffer_len` bytes are copied.
+// `buffer`, `width`, and `height` may be null, so call this with a null
+// `buffer` to find out how big a buffer to allocate. Before any frame has been
+// rendered, returns 0.
+//
+// # Safety
+//
+// `sms` must be a valid pointer returned by `euphrates_sms_new`. `buffer`
+// must be null or point to `buffer_len` writable bytes, and `width` and
+// `height` must each be null or valid.
+size_t euphrates_sms_frame(const EuphratesSms *sms,
+                           uint8_t *buffer,
+                           size_t buffer_len,
+                           uint32_t *width,
+                           uint32_t *height);
+
+// Copy the system RAM into `buffer`.
+//
+// Returns the size of the system RAM: 8 KiB for the Master System and Game
+// Gear, and 1, 2, or 4 KiB for the SG-1000. If that is bigger than
+// `buffer_len`, only `buffer_len` bytes are copied. `buffer` may be null.
+//
+// # Safety
+//
+// `sms` must be a valid pointer returned by `euphrates_sms_new`, and `buffer`
+// must be null or point to `buffer_len` writable bytes.
+size_t euphrates_sms_ram(const EuphratesSms *sms, uint8_t *buffer, size_t buffer_len);
+
+// Serialize the state of the system, including its ROM, into `buffer`.
+//
+// Returns the size of the serialized state, or 0 on failure. If it is bigger
+// than `buffer_len`, nothing is copied. `buffer` may be null.
+//
+// # Safety
+//
+// `sms` must be a valid pointer returned by `euphrates_sms_new`, and `buffer`
+// must be null or point to `buffer_len` writable bytes.
+size_t euphrates_sms_save_state(const EuphratesSms *sms, uint8_t *buffer, size_t buffer_len);
+
+// Restore a state serialized by `euphrates_sms_save_state`.
+//
+// The state may come from a different system, even one running a different
+// ROM. Returns 0 on success and -1 on failure.
+//
+// # Safety
+//
+// `sms` must be a valid pointer returned by `euphrates_sms_new`, and `buffer`
+// must point to `buffer_len` readable bytes.
+int euphrates_sms_restore_state(EuphratesSms *sms, const uint8_t *buffer, size_t buffer_len);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* EUPHRATES_H */
@@ -0,0 +1,382 @@
+//! A C interface to Euphrates.
+//!
+//! A Master System, Game Gear, or SG-1000 is an opaque `EuphratesSms` handle,
+//! created from ROM bytes by `euphrates_sms_new` and destroyed by
+//! `euphrates_sms_free`. There is no sound, and graphics are rendered into
+//! memory, to be copied out with `euphrates_sms_frame`.
+//!
+//! Functions that can fail return a negative number, 0, or a null pointer, as
+//! documented, and set a message available from `euphrates_last_error`. Panics
+//! are caught and reported the same way rather than unwinding into C.
+//!
+//! The header `include/euphrates.h` is generated from this file by cbindgen;
+//! see the README.
+
+#![deny(bare_trait_objects, anonymous_parameters)]
+
+extern crate euphrates;
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use euphrates::host_multimedia::{FrameBuffer, PixelFormat};
+use euphrates::save;
+use euphrates::systems::sms::{
+    new_headless_sms, Kind, PointerSmsMemory, Sms, SmsMemoryMapper, SmsPlayerInput, SmsState,
+    TvSystem, TypeWrap,
+};
+
+/// The Sega memory mapper, used by most Master System and Game Gear games.
+pub const EUPHRATES_MAPPER_SEGA: u32 = 0;
+/// The Codemasters memory mapper.
+pub const EUPHRATES_MAPPER_CODEMASTERS: u32 = 1;
+/// The SG-1000 memory map with 1 KiB of RAM.
+pub const EUPHRATES_MAPPER_SG1000_1: u32 = 2;
+/// The SG-1000 memory map with 2 KiB of RAM.
+pub const EUPHRATES_MAPPER_SG1000_2: u32 = 3;
+/// The SG-1000 memory map with 4 KiB of RAM.
+pub const EUPHRATES_MAPPER_SG1000_4: u32 = 4;
+
+pub const EUPHRATES_TV_NTSC: u32 = 0;
+pub const EUPHRATES_TV_PAL: u32 = 1;
+
+/// The original Master System's VDP; use this for SG-1000 games too.
+pub const EUPHRATES_KIND_SMS: u32 = 0;
+/// The Master System 2's VDP.
+pub const EUPHRATES_KIND_SMS2: u32 = 1;
+/// The Game Gear's VDP.
+pub const EUPHRATES_KIND_GG: u32 = 2;
+
+/// An emulated system.
+pub struct EuphratesSms {
+    sms: Box<dyn Sms + Send>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // a message with a nul byte in it would be cut short anyway
+    let message = message.replace('\0', " ");
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(message).ok());
+}
+
+/// Call `f`, catching panics. Errors and panics are recorded for
+/// `euphrates_last_error`, and turned into `failure`.
+fn guard<T, F>(failure: T, f: F) -> T
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(t)) => t,
+        Ok(Err(message)) => {
+            set_last_error(message);
+            failure
+        }
+        Err(payload) => {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_owned()
+            };
+            set_last_error(format!("panic: {}", message));
+            failure
+        }
+    }
+}
+
+/// Copy as much of `data` as fits into `buffer`, unless `buffer` is null.
+unsafe fn copy_out(data: &[u8], buffer: *mut u8, buffer_len: usize) {
+    if !buffer.is_null() {
+        let len = data.len().min(buffer_len);
+        ptr::copy_nonoverlapping(data.as_ptr(), buffer, len);
+    }
+}
+
+/// The message describing the last error on this thread, or null if there
+/// hasn't been one.
+///
+/// The string is owned by the library, and is valid until the next call to an
+/// `euphrates_` function on this thread.
+#[no_mangle]
+pub extern "C" fn euphrates_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| match *e.borrow() {
+        Some(ref s) => s.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Create a system running the ROM in `rom[0..rom_len]`, which is copied.
+///
+/// `mapper`, `tv_system`, and `kind` are one of the `EUPHRATES_MAPPER_`,
+/// `EUPHRATES_TV_`, and `EUPHRATES_KIND_` constants.
+///
+/// Returns null on failure.
+///
+/// # Safety
+///
+/// `rom` must point to `rom_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_new(
+    rom: *const u8,
+    rom_len: usize,
+    mapper: u32,
+    tv_system: u32,
+    kind: u32,
+) -> *mut EuphratesSms {
+    guard(ptr::null_mut(), || {
+        if rom.is_null() {
+            return Err("null ROM".to_owned());
+        }
+        let mapper = match mapper {
+            EUPHRATES_MAPPER_SEGA => SmsMemoryMapper::Sega,
+            EUPHRATES_MAPPER_CODEMASTERS => SmsMemoryMapper::Codemasters,
+            EUPHRATES_MAPPER_SG1000_1 => SmsMemoryMapper::Sg1000(1),
+            EUPHRATES_MAPPER_SG1000_2 => SmsMemoryMapper::Sg1000(2),
+            EUPHRATES_MAPPER_SG1000_4 => SmsMemoryMapper::Sg1000(4),
+            x => return Err(format!("unknown memory mapper {}", x)),
+        };
+        let tv_system = match tv_system {
+            EUPHRATES_TV_NTSC => TvSystem::Ntsc,
+            EUPHRATES_TV_PAL => TvSystem::Pal,
+            x => return Err(format!("unknown TV system {}", x)),
+        };
+        let kind = match kind {
+            EUPHRATES_KIND_SMS => Kind::Sms,
+            EUPHRATES_KIND_SMS2 => Kind::Sms2,
+            EUPHRATES_KIND_GG => Kind::Gg,
+            x => return Err(format!("unknown VDP kind {}", x)),
+        };
+        let rom = slice::from_raw_parts(rom, rom_len).to_vec();
+        let state = SmsState::from_rom(Arc::new(rom.into_boxed_slice()), mapper, tv_system, kind);
+        let sms = new_headless_sms(
+            state,
+            FrameBuffer::new(PixelFormat::Rgb24),
+            TypeWrap::<PointerSmsMemory>::default(),
+        ).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(EuphratesSms { sms })))
+    })
+}
+
+/// Destroy a system created by `euphrates_sms_new`. Does nothing if `sms` is
+/// null.
+///
+/// # Safety
+///
+/// `sms` must be null or a pointer returned by `euphrates_sms_new` that hasn't
+/// already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_free(sms: *mut EuphratesSms) {
+    if !sms.is_null() {
+        drop(Box::from_raw(sms));
+    }
+}
+
+/// Emulate `frames` frames with the given joypad port bytes and pause button.
+///
+/// As on the hardware, a button is pressed when its bit is *clear*, so `0xFF`
+/// presses nothing; see `joypad_a_bits` and `joypad_b_bits` in the `euphrates`
+/// crate for the meaning of each bit. Only the last frame is rendered.
+///
+/// Returns 0 on success and -1 on failure.
+///
+/// # Safety
+///
+/// `sms` must be a valid pointer returned by `euphrates_sms_new`.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_run_frames(
+    sms: *mut EuphratesSms,
+    joypad_a: u8,
+    joypad_b: u8,
+    pause: c_int,
+    frames: u32,
+) -> c_int {
+    let sms = &mut *(*sms).sms;
+    guard(-1, || {
+        let mut input = SmsPlayerInput::default();
+        input.set_joypad_a(joypad_a);
+        input.set_joypad_b(joypad_b);
+        input.set_pause(pause != 0);
+        for i in 0..frames {
+            let result = if i + 1 == frames {
+                sms.run_frame(input)
+            } else {
+                sms.run_frame_without_rendering(input)
+            };
+            result.map_err(|e| e.to_string())?;
+        }
+        Ok(0)
+    })
+}
+
+/// Copy the last rendered frame into `buffer`, as rows of 3 byte red, green,
+/// blue pixels, and store its dimensions in `width` and `height`.
+///
+/// Returns the number of bytes in the frame, which is `3 * width * height`.
+/// If that is bigger than `buffer_len`, only `buffer_len` bytes are copied.
+/// `buffer`, `width`, and `height` may be null, so call this with a null
+/// `buffer` to find out how big a buffer to allocate. Before any frame has been
+/// rendered, returns 0.
+///
+/// # Safety
+///
+/// `sms` must be a valid pointer returned by `euphrates_sms_new`. `buffer`
+/// must be null or point to `buffer_len` writable bytes, and `width` and
+/// `height` must each be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_frame(
+    sms: *const EuphratesSms,
+    buffer: *mut u8,
+    buffer_len: usize,
+    width: *mut u32,
+    height: *mut u32,
+) -> usize {
+    let (w, h, data) = match (*sms).sms.last_frame() {
+        Some(frame) => (frame.width, frame.height, frame.data),
+        None => (0, 0, &[][..]),
+    };
+    if !width.is_null() {
+        *width = w;
+    }
+    if !height.is_null() {
+        *height = h;
+    }
+    copy_out(data, buffer, buffer_len);
+    data.len()
+}
+
+/// Copy the system RAM into `buffer`.
+///
+/// Returns the size of the system RAM: 8 KiB for the Master System and Game
+/// Gear, and 1, 2, or 4 KiB for the SG-1000. If that is bigger than
+/// `buffer_len`, only `buffer_len` bytes are copied. `buffer` may be null.
+///
+/// # Safety
+///
+/// `sms` must be a valid pointer returned by `euphrates_sms_new`, and `buffer`
+/// must be null or point to `buffer_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_ram(
+    sms: *const EuphratesSms,
+    buffer: *mut u8,
+    buffer_len: usize,
+) -> usize {
+    let memory = (*sms).sms.sms_memory();
+    let len = memory.system_ram_len();
+    if !buffer.is_null() {
+        for i in 0..len.min(buffer_len) {
+            *buffer.add(i) = memory.system_ram_read(i);
+        }
+    }
+    len
+}
+
+/// Serialize the state of the system, including its ROM, into `buffer`.
+///
+/// Returns the size of the serialized state, or 0 on failure. If it is bigger
+/// than `buffer_len`, nothing is copied. `buffer` may be null.
+///
+/// # Safety
+///
+/// `sms` must be a valid pointer returned by `euphrates_sms_new`, and `buffer`
+/// must be null or point to `buffer_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_save_state(
+    sms: *const EuphratesSms,
+    buffer: *mut u8,
+    buffer_len: usize,
+) -> usize {
+    let sms = &*(*sms).sms;
+    guard(0, || {
+        let bytes = save::serialize(&sms.state()).map_err(|e| e.to_string())?;
+        if bytes.len() <= buffer_len {
+            copy_out(&bytes, buffer, buffer_len);
+        }
+        Ok(bytes.len())
+    })
+}
+
+/// Restore a state serialized by `euphrates_sms_save_state`.
+///
+/// The state may come from a different system, even one running a different
+/// ROM. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+///
+/// `sms` must be a valid pointer returned by `euphrates_sms_new`, and `buffer`
+/// must point to `buffer_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn euphrates_sms_restore_state(
+    sms: *mut EuphratesSms,
+    buffer: *const u8,
+    buffer_len: usize,
+) -> c_int {
+    let sms = &mut *(*sms).sms;
+    guard(-1, || {
+        if buffer.is_null() {
+            return Err("null state buffer".to_owned());
+        }
+        let bytes = slice::from_raw_parts(buffer, buffer_len);
+        let state: SmsState = save::deserialize(bytes).map_err(|e| e.to_string())?;
+        sms.restore_state(&state).map_err(|e| e.to_string())?;
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut rom = vec![0u8; 0x8000];
+        // DI; loop: IN A, (0xDC); CPL; LD (0xC000), A; JR loop
+        rom[..9].copy_from_slice(&[0xF3, 0xDB, 0xDC, 0x2F, 0x32, 0x00, 0xC0, 0x18, 0xF8]);
+        unsafe {
+            let bad = euphrates_sms_new(rom.as_ptr(), rom.len(), 99, 0, 0);
+            assert!(bad.is_null());
+            let message = CStr::from_ptr(euphrates_last_error());
+            assert_eq!(message.to_str().unwrap(), "unknown memory mapper 99");
+
+            let sms = euphrates_sms_new(
+                rom.as_ptr(),
+                rom.len(),
+                EUPHRATES_MAPPER_SEGA,
+                EUPHRATES_TV_NTSC,
+                EUPHRATES_KIND_SMS,
+            );
+            assert!(!sms.is_null());
+
+            let len = euphrates_sms_save_state(sms, ptr::null_mut(), 0);
+            let mut state = vec![0u8; len];
+            assert_eq!(euphrates_sms_save_state(sms, state.as_mut_ptr(), len), len);
+
+            assert_eq!(euphrates_sms_run_frames(sms, !0x30, 0xFF, 0, 2), 0);
+            let mut ram = [0u8; 0x2000];
+            assert_eq!(euphrates_sms_ram(sms, ram.as_mut_ptr(), ram.len()), 0x2000);
+            assert_eq!(ram[0], 0x30);
+
+            let (mut width, mut height) = (0, 0);
+            let frame_len = euphrates_sms_frame(sms, ptr::null_mut(), 0, &mut width, &mut height);
+            assert_eq!((width, height), (256, 192));
+            assert_eq!(frame_len, 3 * 256 * 192);
+
+            assert_eq!(euphrates_sms_restore_state(sms, state.as_ptr(), len), 0);
+            euphrates_sms_ram(sms, ram.as_mut_ptr(), ram.len());
+            assert_eq!(ram[0], 0);
+            assert_eq!(euphrates_sms_restore_state(sms, state.as_ptr(), 10), -1);
+
+            euphrates_sms_free(sms);
+        }
+    }
+}
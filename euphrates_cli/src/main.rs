@@ -2,6 +2,7 @@
 
 extern crate clap;
 extern crate euphrates;
+extern crate euphrates_recompiler;
 extern crate euphrates_sdl2;
 #[cfg(feature = "euphrates_virtual_memory")]
 extern crate euphrates_virtual_memory;
@@ -10,22 +11,35 @@ extern crate euphrates_x64;
 extern crate failure;
 extern crate sdl2;
 
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use failure::Error;
 use sdl2::Sdl;
 
 use euphrates::hardware::sms_roms;
+use euphrates::hardware::sms_vdp::replaceable;
 use euphrates::hardware::sn76489::{FakeSn76489, Sn76489State};
-use euphrates::host_multimedia::FakeAudio;
+use euphrates::host_multimedia::{FakeAudio, FrameBuffer, PixelFormat};
 use euphrates::memo::NothingInbox;
 use euphrates::save;
+use euphrates::systems::cpm::CpmMachine;
 use euphrates::systems::sms::{
-    self, DebuggingInbox, FakeSmsGraphics, Kind, Recording, Sms, SmsMemoryMapper, SmsState,
-    TvSystem, TypeWrap,
+    self, write_message, CachedInterpreter, DebuggingInbox, FakeSmsGraphics, GameDefinition,
+    GameMonitor, IdleLoopSkipper, Kind, LockstepOptions, PatternToPaletteIndices, PlaybackStatus,
+    ProfileReport, ProfilingInbox, Query, Recording, RomDisassembly, ServerHelper, ServerResponse,
+    Sms, SmsEmulationError, SmsMemoryMapper, SmsPlayerInput, SmsState, SmsZ80Runner, TraceFormat,
+    TracingInbox, TvSystem, TypeWrap, Ui, UiHelper, UiStatus,
 };
+use euphrates_recompiler::Recompiler;
 
 use euphrates_sdl2::sms_user_interface;
 use euphrates_sdl2::{simple_audio::Audio, simple_graphics::Window};
@@ -74,8 +88,18 @@ fn new_sms(sdl: &Sdl, state: SmsState, matches: &ArgMatches) -> Result<Box<dyn S
             }
         };
         ($sn76489:expr, $audio:expr) => {
-            match matches.value_of("debug").expect("unwrapping debug") {
-                "true" => eval_args!($sn76489, $audio, DebuggingInbox::default()),
+            match (matches.value_of("trace"), matches.value_of("debug")) {
+                (Some(filename), _) => {
+                    let format = match matches.value_of("trace_format") {
+                        Some("binary") => TraceFormat::Binary,
+                        _ => TraceFormat::Text,
+                    };
+                    eval_args!($sn76489, $audio, TracingInbox::create(filename, format)?)
+                }
+                (None, _) if matches.is_present("profile") => {
+                    eval_args!($sn76489, $audio, ProfilingInbox::new())
+                }
+                (None, Some("true")) => eval_args!($sn76489, $audio, DebuggingInbox::default()),
                 _ => eval_args!($sn76489, $audio, NothingInbox::default()),
             }
         };
@@ -87,10 +111,26 @@ fn new_sms(sdl: &Sdl, state: SmsState, matches: &ArgMatches) -> Result<Box<dyn S
         };
     }
 
-    eval_args!()
+    let sms: Result<Box<dyn Sms>> = eval_args!();
+    let mut sms = sms?;
+    sms.set_z80_runner(z80_runner(matches.value_of("z80").unwrap()));
+    Ok(sms)
 }
 
-fn run_rom(matches: &ArgMatches) -> Result<()> {
+/// The Z80 implementation named by a `z80` argument, or `None` for the
+/// interpreter.
+fn z80_runner(name: &str) -> Option<Box<dyn SmsZ80Runner + Send>> {
+    match name {
+        "cached" => Some(Box::new(CachedInterpreter::new())),
+        "idle" => Some(Box::new(IdleLoopSkipper::new())),
+        "recompiler" => Some(Box::new(Recompiler::new())),
+        _ => None,
+    }
+}
+
+/// The initial state for the ROM given by the `rom`, `tv`, `kind`, and
+/// `memory_map` arguments.
+fn state_from_rom(matches: &ArgMatches) -> Result<SmsState> {
     let rom = {
         let filename = matches.value_of("rom").unwrap();
         sms_roms::from_file(&filename)?
@@ -104,15 +144,24 @@ fn run_rom(matches: &ArgMatches) -> Result<()> {
         "sms2" => Kind::Sms2,
         _ => Kind::Gg,
     };
-    let memory_mapper = match matches.value_of("memory_map").unwrap() {
+    let memory_mapper = memory_mapper(matches);
+
+    Ok(SmsState::from_rom(Arc::new(rom), memory_mapper, tv_system, kind))
+}
+
+/// The memory mapper given by the `memory_map` argument.
+fn memory_mapper(matches: &ArgMatches) -> SmsMemoryMapper {
+    match matches.value_of("memory_map").unwrap() {
         "sg1000_1" => SmsMemoryMapper::Sg1000(1),
         "sg1000_2" => SmsMemoryMapper::Sg1000(2),
         "sg1000_4" => SmsMemoryMapper::Sg1000(4),
         "codemasters" => SmsMemoryMapper::Codemasters,
         _ => SmsMemoryMapper::Sega,
-    };
+    }
+}
 
-    let state = SmsState::from_rom(Arc::new(rom), memory_mapper, tv_system, kind);
+fn run_rom(matches: &ArgMatches) -> Result<()> {
+    let state = state_from_rom(matches)?;
 
     let sdl = sdl2::init().unwrap();
 
@@ -129,6 +178,53 @@ fn run_rom(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+/// Plays back a recording while keeping track of game variables.
+struct VariablesHelper {
+    playback: PlaybackStatus,
+    monitor: GameMonitor,
+    frame: u64,
+    total_reward: f64,
+    done_frame: Option<u64>,
+}
+
+impl VariablesHelper {
+    fn report(&self) {
+        println!("Frames: {}", self.frame);
+        for (name, value) in self.monitor.values() {
+            println!("{}: {}", name, value);
+        }
+        println!("Total reward: {}", self.total_reward);
+        match self.done_frame {
+            Some(frame) => println!("Done at frame {}", frame),
+            None => println!("Not done"),
+        }
+    }
+}
+
+impl UiHelper for VariablesHelper {
+    fn frame_update(
+        &mut self,
+        status: &mut UiStatus,
+    ) -> std::result::Result<Option<SmsPlayerInput>, SmsEmulationError> {
+        if self.frame == 0 {
+            self.monitor.reset(status.master_system().sms_memory());
+        } else {
+            self.monitor.update(status.master_system().sms_memory());
+            self.total_reward += self.monitor.reward();
+            if self.done_frame.is_none() && self.monitor.done() {
+                self.done_frame = Some(self.frame);
+            }
+        }
+        let player_input = self.playback.pop();
+        if player_input.is_some() {
+            self.frame += 1;
+        } else {
+            self.report();
+        }
+        Ok(player_input)
+    }
+}
+
 fn run_playback(matches: &ArgMatches) -> Result<()> {
     use std::time::Instant;
 
@@ -140,14 +236,37 @@ fn run_playback(matches: &ArgMatches) -> Result<()> {
 
     let sms = new_sms(&sdl, recording.state, matches)?;
 
-    let mut user_interface =
-        euphrates_sdl2::sms_user_interface::playback_ui(sms, &recording.player_statuses);
+    let mut user_interface = match matches.value_of("variables") {
+        Some(filename) => {
+            let definition = GameDefinition::from_file(filename)?;
+            let helper = VariablesHelper {
+                playback: PlaybackStatus::from_recorded(&recording.player_statuses),
+                monitor: GameMonitor::new(definition)?,
+                frame: 0,
+                total_reward: 0.0,
+                done_frame: None,
+            };
+            Ui::new(sms, Box::new(helper), None)
+        }
+        None => euphrates_sdl2::sms_user_interface::playback_ui(sms, &recording.player_statuses),
+    };
 
     let start_cycles = user_interface.master_system().z80().cycles();
     let start_time = Instant::now();
 
     user_interface.run()?;
 
+    if let Some(filename) = matches.value_of("profile") {
+        let report = match matches.value_of("profile_format") {
+            Some("tree") => ProfileReport::Tree,
+            Some("collapsed") => ProfileReport::Collapsed,
+            _ => ProfileReport::Flat,
+        };
+        if let Some(debugger) = user_interface.master_system_mut().debugger() {
+            fs::write(filename, debugger.query(Query::Profile(report)))?;
+        }
+    }
+
     let end_cycles = user_interface.master_system().z80().cycles();
     let end_time = Instant::now();
 
@@ -204,6 +323,245 @@ fn run_record(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+/// A system with no graphics, sound, debugging or time governing, using the
+/// memory implementation named by `memory`, the value of a memory argument of
+/// the `lockstep` subcommand.
+fn headless_sms_from_memory_arg(state: SmsState, memory: &str) -> Result<Box<dyn Sms>> {
+    macro_rules! with_memory {
+        ($memory_type:ty) => {
+            Ok(sms::new_sms(
+                None,
+                state,
+                FakeSmsGraphics::default(),
+                FakeAudio,
+                NothingInbox::default(),
+                TypeWrap::<$memory_type>::default(),
+                TypeWrap::<FakeSn76489>::default(),
+            )?)
+        };
+    }
+
+    match memory {
+        "state" => with_memory!(sms::SmsMemoryState),
+        #[cfg(feature = "euphrates_virtual_memory")]
+        "virtual" => with_memory!(euphrates_virtual_memory::SmsVirtualMemory),
+        _ => with_memory!(sms::PointerSmsMemory),
+    }
+}
+
+fn pattern_to_palette_indices(name: &str) -> Result<Option<PatternToPaletteIndices>> {
+    match name {
+        "simple" => Ok(Some(replaceable::simple_pattern_to_palette_indices)),
+        #[cfg(feature = "euphrates_x64")]
+        "x64" => {
+            if euphrates_x64::supports_pattern_to_palette_indices() {
+                Ok(Some(euphrates_x64::pattern_to_palette_indices))
+            } else {
+                Err(failure::err_msg("This processor can't use the x64 implementation"))
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+fn run_lockstep(matches: &ArgMatches) -> Result<()> {
+    let load_filename = matches.value_of("loadfile").unwrap();
+
+    let recording: Recording<SmsState> = save::deserialize_at(&load_filename)?;
+
+    let mut left = headless_sms_from_memory_arg(
+        recording.state.clone(),
+        matches.value_of("left_memory").unwrap(),
+    )?;
+    let mut right = headless_sms_from_memory_arg(
+        recording.state,
+        matches.value_of("right_memory").unwrap(),
+    )?;
+    left.set_z80_runner(z80_runner(matches.value_of("left_z80").unwrap()));
+    right.set_z80_runner(z80_runner(matches.value_of("right_z80").unwrap()));
+
+    let options = LockstepOptions {
+        every_instruction: matches.is_present("every_instruction"),
+        left_pattern_to_palette_indices: pattern_to_palette_indices(
+            matches.value_of("left_pattern").unwrap(),
+        )?,
+        right_pattern_to_palette_indices: pattern_to_palette_indices(
+            matches.value_of("right_pattern").unwrap(),
+        )?,
+    };
+
+    let divergence =
+        sms::find_divergence(&mut *left, &mut *right, &recording.player_statuses, &options)?;
+
+    match divergence {
+        Some(d) => print!("{}", d),
+        None => println!(
+            "No divergence in {} frames",
+            recording.player_statuses.len()
+        ),
+    }
+
+    Ok(())
+}
+
+/// One of the connections `run_serve` allows at once, given back when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts the connections being served, and refuses more than `max`.
+struct ConnectionLimit {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize) -> Self {
+        ConnectionLimit {
+            count: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Only the listening thread takes slots, so the count can't go over `max`
+    /// between checking it and adding to it.
+    fn take(&self) -> Option<ConnectionSlot> {
+        if self.count.load(Ordering::SeqCst) >= self.max {
+            return None;
+        }
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Some(ConnectionSlot(self.count.clone()))
+    }
+}
+
+/// Serve one client on its own thread, with its own system starting at `state`,
+/// or, if there are already as many connections as `limit` allows, answer the
+/// client's first request with an error and close the connection.
+fn serve_connection<S>(
+    mut stream: S,
+    state: SmsState,
+    monitor: Option<GameMonitor>,
+    limit: &ConnectionLimit,
+) where
+    S: Read + Write + Send + 'static,
+{
+    let slot = match limit.take() {
+        Some(slot) => slot,
+        None => {
+            eprintln!("Refusing a connection: already serving {}", limit.max);
+            let response = ServerResponse::Error(format!(
+                "The server is already serving {} connections",
+                limit.max
+            ));
+            let _ = write_message(&mut stream, &response);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let _slot = slot;
+        let run = || -> Result<()> {
+            let sms = sms::new_sms(
+                None,
+                state.clone(),
+                FrameBuffer::new(PixelFormat::Rgb24),
+                FakeAudio,
+                NothingInbox::default(),
+                TypeWrap::<MemoryType>::default(),
+                TypeWrap::<FakeSn76489>::default(),
+            )?;
+            let mut helper = ServerHelper::new(stream, state);
+            helper.set_game_monitor(monitor);
+            Ui::new(sms, Box::new(helper), None).run()?;
+            Ok(())
+        };
+        if let Err(e) = run() {
+            eprintln!("Connection failed: {}", e);
+        }
+    });
+}
+
+fn run_serve(matches: &ArgMatches) -> Result<()> {
+    let state = match matches.value_of("loadfile") {
+        Some(filename) => save::deserialize_at(filename)?,
+        None => state_from_rom(matches)?,
+    };
+
+    let monitor = match matches.value_of("variables") {
+        Some(filename) => Some(GameMonitor::new(GameDefinition::from_file(filename)?)?),
+        None => None,
+    };
+
+    let limit = ConnectionLimit::new(
+        matches
+            .value_of("max_connections")
+            .unwrap()
+            .parse::<usize>()?,
+    );
+
+    if let Some(address) = matches.value_of("tcp") {
+        let listener = TcpListener::bind(address)?;
+        println!("Listening on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            stream.set_nodelay(true)?;
+            serve_connection(stream, state.clone(), monitor.clone(), &limit);
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(path) = matches.value_of("unix") {
+            let listener = UnixListener::bind(path)?;
+            println!("Listening on {}", path);
+            for stream in listener.incoming() {
+                serve_connection(stream?, state.clone(), monitor.clone(), &limit);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_cpm(matches: &ArgMatches) -> Result<()> {
+    let program = fs::read(matches.value_of("program").unwrap())?;
+    let args: Vec<&str> = matches
+        .values_of("args")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let directory = matches.value_of("directory").unwrap_or(".");
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut machine = CpmMachine::new(directory, stdin.lock(), stdout.lock());
+    machine.load(&program, &args)?;
+    machine.run()?;
+    Ok(())
+}
+
+fn run_trace_diff(matches: &ArgMatches) -> Result<()> {
+    let left = matches.value_of("left").unwrap();
+    let right = matches.value_of("right").unwrap();
+    match sms::diff_trace_files(left, right)? {
+        Some(d) => print!("{}", d),
+        None => println!("The traces are the same"),
+    }
+    Ok(())
+}
+
+fn run_disasm(matches: &ArgMatches) -> Result<()> {
+    // not `sms_roms::from_file`, so the source assembles to this very file
+    let rom = fs::read(matches.value_of("rom").unwrap())?;
+    let source = RomDisassembly::new(&rom, memory_mapper(matches)).to_string();
+    match matches.value_of("output") {
+        Some(filename) => fs::write(filename, source)?,
+        None => io::stdout().write_all(source.as_bytes())?,
+    }
+    Ok(())
+}
+
 fn run() -> Result<()> {
     let memory_map_arg = Arg::with_name("memory_map")
         .long("memory_map")
@@ -235,6 +593,20 @@ fn run() -> Result<()> {
         .possible_values(&["true", "false"])
         .default_value("true");
 
+    let trace_arg = Arg::with_name("trace")
+        .long("trace")
+        .value_name("FILE")
+        .help("Write a trace of every Z80 instruction executed to this file, instead of debugging")
+        .takes_value(true);
+
+    let trace_format_arg = Arg::with_name("trace_format")
+        .long("trace_format")
+        .value_name("(text|binary)")
+        .help("The format of the trace")
+        .takes_value(true)
+        .possible_values(&["text", "binary"])
+        .default_value("text");
+
     let tv_arg = Arg::with_name("tv")
         .long("tv")
         .value_name("(ntsc|pal)")
@@ -277,6 +649,44 @@ fn run() -> Result<()> {
         .possible_values(&["true", "false"])
         .help("Should graphics be displayed?");
 
+    let mut memory_values = vec!["state", "pointer"];
+    if cfg!(feature = "euphrates_virtual_memory") {
+        memory_values.push("virtual");
+    }
+    let memory_arg = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("(state|pointer|virtual)")
+            .help(help)
+            .takes_value(true)
+            .possible_values(&memory_values)
+            .default_value("pointer")
+    };
+
+    let mut pattern_values = vec!["default", "simple"];
+    if cfg!(feature = "euphrates_x64") {
+        pattern_values.push("x64");
+    }
+    let pattern_arg = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("(default|simple|x64)")
+            .help(help)
+            .takes_value(true)
+            .possible_values(&pattern_values)
+            .default_value("default")
+    };
+
+    let z80_arg = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("(interpreter|cached|idle|recompiler)")
+            .help(help)
+            .takes_value(true)
+            .possible_values(&["interpreter", "cached", "idle", "recompiler"])
+            .default_value("interpreter")
+    };
+
     let app = App::new("Euphrates")
         .version("0.1.0")
         .author("Michael Benfield")
@@ -294,17 +704,22 @@ fn run() -> Result<()> {
                 )
                 .arg(tv_arg.clone())
                 .arg(debug_arg.clone())
+                .arg(trace_arg.clone())
+                .arg(trace_format_arg.clone())
                 .arg(memory_map_arg.clone())
                 .arg(save_directory_arg.clone())
                 .arg(kind_arg.clone())
                 .arg(sound_arg.clone())
                 .arg(graphics_arg.clone())
-                .arg(frequency_arg.clone()),
+                .arg(frequency_arg.clone())
+                .arg(z80_arg("z80", "Which Z80 implementation to run")),
         )
         .subcommand(
             SubCommand::with_name("load")
                 .about("Load a saved state")
                 .arg(debug_arg.clone())
+                .arg(trace_arg.clone())
+                .arg(trace_format_arg.clone())
                 .arg(save_directory_arg.clone())
                 .arg(
                     Arg::with_name("loadfile")
@@ -316,13 +731,16 @@ fn run() -> Result<()> {
                 )
                 .arg(frequency_arg.clone())
                 .arg(sound_arg.clone())
-                .arg(graphics_arg.clone()),
+                .arg(graphics_arg.clone())
+                .arg(z80_arg("z80", "Which Z80 implementation to run")),
         )
         .subcommand(
             SubCommand::with_name("loadrecord")
                 .about("Load recorded gameplay")
                 .arg(save_directory_arg.clone())
                 .arg(debug_arg.clone())
+                .arg(trace_arg.clone())
+                .arg(trace_format_arg.clone())
                 .arg(
                     Arg::with_name("loadfile")
                         .long("loadfile")
@@ -333,12 +751,39 @@ fn run() -> Result<()> {
                 )
                 .arg(frequency_arg.clone())
                 .arg(sound_arg.clone())
-                .arg(graphics_arg.clone()),
+                .arg(graphics_arg.clone())
+                .arg(z80_arg("z80", "Which Z80 implementation to run")),
         )
         .subcommand(
             SubCommand::with_name("playback")
                 .about("Play back and time recorded gameplay")
                 .arg(debug_arg.clone())
+                .arg(trace_arg.clone())
+                .arg(trace_format_arg.clone())
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .value_name("FILE")
+                        .help("Profile where the Z80's cycles go, and write a report to this file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("profile_format")
+                        .long("profile_format")
+                        .value_name("(flat|tree|collapsed)")
+                        .help("The profile report: hot instructions and ROM banks, the call \
+                               tree, or collapsed stacks for flamegraph tools")
+                        .takes_value(true)
+                        .possible_values(&["flat", "tree", "collapsed"])
+                        .default_value("flat"),
+                )
+                .arg(
+                    Arg::with_name("variables")
+                        .long("variables")
+                        .value_name("FILE")
+                        .help("Report the game variables and reward defined in this TOML file")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("loadfile")
                         .long("loadfile")
@@ -349,7 +794,160 @@ fn run() -> Result<()> {
                 )
                 .arg(frequency_arg.clone())
                 .arg(sound_arg.clone())
-                .arg(graphics_arg.clone()),
+                .arg(graphics_arg.clone())
+                .arg(z80_arg("z80", "Which Z80 implementation to run")),
+        )
+        .subcommand(
+            SubCommand::with_name("lockstep")
+                .about("Find where two differently configured systems diverge playing a recording")
+                .arg(
+                    Arg::with_name("loadfile")
+                        .long("loadfile")
+                        .value_name("FILE")
+                        .help("Specify the recorded gameplay file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(memory_arg(
+                    "left_memory",
+                    "Memory implementation for the left system",
+                ))
+                .arg(memory_arg(
+                    "right_memory",
+                    "Memory implementation for the right system",
+                ))
+                .arg(pattern_arg(
+                    "left_pattern",
+                    "Implementation of the VDP's pattern lookup for the left system",
+                ))
+                .arg(pattern_arg(
+                    "right_pattern",
+                    "Implementation of the VDP's pattern lookup for the right system",
+                ))
+                .arg(z80_arg("left_z80", "Z80 implementation for the left system"))
+                .arg(z80_arg("right_z80", "Z80 implementation for the right system"))
+                .arg(
+                    Arg::with_name("every_instruction")
+                        .long("every_instruction")
+                        .help("Compare after every instruction, not just every frame"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Let agents in other processes drive headless systems over a socket")
+                .arg(
+                    Arg::with_name("rom")
+                        .long("rom")
+                        .value_name("FILE")
+                        .help("Start each connection's system running this ROM image")
+                        .takes_value(true)
+                        .required_unless("loadfile")
+                        .conflicts_with("loadfile"),
+                )
+                .arg(
+                    Arg::with_name("loadfile")
+                        .long("loadfile")
+                        .value_name("FILE")
+                        .help("Start each connection's system at this saved state")
+                        .takes_value(true),
+                )
+                .arg(tv_arg.clone())
+                .arg(memory_map_arg.clone())
+                .arg(kind_arg.clone())
+                .arg(
+                    Arg::with_name("tcp")
+                        .long("tcp")
+                        .value_name("ADDRESS")
+                        .help("Listen on this TCP address, like 127.0.0.1:4000")
+                        .takes_value(true)
+                        .required_unless("unix")
+                        .conflicts_with("unix"),
+                )
+                .arg(
+                    Arg::with_name("unix")
+                        .long("unix")
+                        .value_name("PATH")
+                        .help("Listen on a Unix domain socket at this path")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("variables")
+                        .long("variables")
+                        .value_name("FILE")
+                        .help("Compute rewards and when episodes end from this TOML file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max_connections")
+                        .long("max-connections")
+                        .value_name("COUNT")
+                        .help("Refuse clients while this many connections are being served")
+                        .takes_value(true)
+                        .default_value("16")
+                        .validator(|s| match s.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("max-connections must be a positive integer".to_owned()),
+                        }),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cpm")
+                .about("Run a CP/M .COM program, with the console on standard input and output")
+                .arg(
+                    Arg::with_name("program")
+                        .value_name("PROGRAM")
+                        .help("The .COM file to run")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .value_name("ARGS")
+                        .help("The rest of the command line, passed to the program")
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("directory")
+                        .long("dir")
+                        .value_name("DIRECTORY")
+                        .help("Use this directory as drive A (default: the current directory)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassemble a whole ROM image into source for the WLA-DX assembler")
+                .arg(
+                    Arg::with_name("rom")
+                        .long("rom")
+                        .value_name("FILE")
+                        .help("Specify the filename containing a ROM image")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(memory_map_arg.clone())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Write the source to this file instead of standard output")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trace-diff")
+                .about("Find the first instruction at which two traces differ")
+                .arg(
+                    Arg::with_name("left")
+                        .value_name("LEFT")
+                        .help("The first trace")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("right")
+                        .value_name("RIGHT")
+                        .help("The second trace")
+                        .required(true),
+                ),
         );
     let matches = app.get_matches();
 
@@ -358,6 +956,11 @@ fn run() -> Result<()> {
         ("load", Some(sub)) => run_load(&sub),
         ("loadrecord", Some(sub)) => run_record(&sub),
         ("playback", Some(sub)) => run_playback(&sub),
+        ("lockstep", Some(sub)) => run_lockstep(&sub),
+        ("serve", Some(sub)) => run_serve(&sub),
+        ("cpm", Some(sub)) => run_cpm(&sub),
+        ("disasm", Some(sub)) => run_disasm(&sub),
+        ("trace-diff", Some(sub)) => run_trace_diff(&sub),
         (x, _) => {
             eprintln!("Unknown subcommand {}", x);
             eprintln!("{}", matches.usage());
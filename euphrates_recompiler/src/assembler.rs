@@ -0,0 +1,298 @@
+//! Just enough of an x86-64 assembler for the code the compiler generates.
+//!
+//! Registers are only ever used as 32 bit registers (or their low bytes), and
+//! memory is only ever addressed relative to `rbx`, which holds a pointer to
+//! the `Z80State`, or `rbp`, which holds a pointer to the `BusHeader`.
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Register {
+    Eax = 0,
+    Ecx = 1,
+    Edx = 2,
+    Esi = 6,
+    Edi = 7,
+}
+
+/// Where a memory operand is relative to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Base {
+    /// `rbx`
+    State,
+
+    /// `rbp`
+    Bus,
+}
+
+impl Base {
+    fn code(self) -> u8 {
+        match self {
+            Base::State => 3,
+            Base::Bus => 5,
+        }
+    }
+}
+
+/// A memory operand: `[base + offset]`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Memory(pub Base, pub usize);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Arithmetic {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// The condition codes of `jcc` and `setcc`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Condition {
+    Equal = 0x4,
+    NotEqual = 0x5,
+}
+
+/// A jump whose target hasn't been decided yet; see `Assembler::bind`.
+#[must_use]
+#[derive(Debug)]
+pub struct Fixup(usize);
+
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    #[inline]
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    fn byte(&mut self, x: u8) {
+        self.code.push(x);
+    }
+
+    fn dword(&mut self, x: u32) {
+        self.code.extend_from_slice(&x.to_le_bytes());
+    }
+
+    /// A ModRM byte (and displacement) for `reg` and the memory operand `m`.
+    fn memory(&mut self, reg: u8, m: Memory) {
+        let Memory(base, offset) = m;
+        // mod 10: a 32 bit displacement
+        self.byte(0x80 | reg << 3 | base.code());
+        self.dword(offset as u32);
+    }
+
+    /// A ModRM byte for two registers.
+    fn registers(&mut self, reg: u8, rm: u8) {
+        self.byte(0xC0 | reg << 3 | rm);
+    }
+
+    /// `movzx r, byte [m]`
+    pub fn load8(&mut self, r: Register, m: Memory) {
+        self.byte(0x0F);
+        self.byte(0xB6);
+        self.memory(r as u8, m);
+    }
+
+    /// `movzx r, word [m]`
+    pub fn load16(&mut self, r: Register, m: Memory) {
+        self.byte(0x0F);
+        self.byte(0xB7);
+        self.memory(r as u8, m);
+    }
+
+    /// `mov byte [m], r`
+    pub fn store8(&mut self, m: Memory, r: Register) {
+        if r == Register::Esi || r == Register::Edi {
+            // without a REX prefix, these would be `dh` and `bh`
+            self.byte(0x40);
+        }
+        self.byte(0x88);
+        self.memory(r as u8, m);
+    }
+
+    /// `mov word [m], r`
+    pub fn store16(&mut self, m: Memory, r: Register) {
+        self.byte(0x66);
+        self.byte(0x89);
+        self.memory(r as u8, m);
+    }
+
+    /// `mov byte [m], x`
+    pub fn store8_immediate(&mut self, m: Memory, x: u8) {
+        self.byte(0xC6);
+        self.memory(0, m);
+        self.byte(x);
+    }
+
+    /// `mov word [m], x`
+    pub fn store16_immediate(&mut self, m: Memory, x: u16) {
+        self.byte(0x66);
+        self.byte(0xC7);
+        self.memory(0, m);
+        self.code.extend_from_slice(&x.to_le_bytes());
+    }
+
+    /// `add qword [m], x`
+    pub fn add64_immediate(&mut self, m: Memory, x: u32) {
+        self.byte(0x48);
+        self.byte(0x81);
+        self.memory(0, m);
+        self.dword(x);
+    }
+
+    /// `cmp byte [m], x`
+    pub fn cmp8_immediate(&mut self, m: Memory, x: u8) {
+        self.byte(0x80);
+        self.memory(7, m);
+        self.byte(x);
+    }
+
+    /// `mov r, x`
+    pub fn mov_immediate(&mut self, r: Register, x: u32) {
+        self.byte(0xB8 + r as u8);
+        self.dword(x);
+    }
+
+    /// `mov dest, source`
+    pub fn mov(&mut self, dest: Register, source: Register) {
+        self.byte(0x89);
+        self.registers(source as u8, dest as u8);
+    }
+
+    /// `op dest, source`
+    pub fn arithmetic(&mut self, op: Arithmetic, dest: Register, source: Register) {
+        self.byte(op as u8 * 8 + 1);
+        self.registers(source as u8, dest as u8);
+    }
+
+    /// `op r, x`
+    pub fn arithmetic_immediate(&mut self, op: Arithmetic, r: Register, x: u32) {
+        self.byte(0x81);
+        self.registers(op as u8, r as u8);
+        self.dword(x);
+    }
+
+    /// `shl r, n`
+    pub fn shl(&mut self, r: Register, n: u8) {
+        self.byte(0xC1);
+        self.registers(4, r as u8);
+        self.byte(n);
+    }
+
+    /// `shr r, n`
+    pub fn shr(&mut self, r: Register, n: u8) {
+        self.byte(0xC1);
+        self.registers(5, r as u8);
+        self.byte(n);
+    }
+
+    /// `not r`
+    pub fn not(&mut self, r: Register) {
+        self.byte(0xF7);
+        self.registers(2, r as u8);
+    }
+
+    /// `neg r`
+    pub fn neg(&mut self, r: Register) {
+        self.byte(0xF7);
+        self.registers(3, r as u8);
+    }
+
+    /// `movzx dest, source`, with the low byte of `source`.
+    ///
+    /// `source` may not be `esi` or `edi`.
+    pub fn zero_extend8(&mut self, dest: Register, source: Register) {
+        assert!(source != Register::Esi && source != Register::Edi);
+        self.byte(0x0F);
+        self.byte(0xB6);
+        self.registers(dest as u8, source as u8);
+    }
+
+    /// `test r, x`
+    pub fn test_immediate(&mut self, r: Register, x: u32) {
+        self.byte(0xF7);
+        self.registers(0, r as u8);
+        self.dword(x);
+    }
+
+    /// `test al, al`
+    pub fn test_al(&mut self) {
+        self.byte(0x84);
+        self.byte(0xC0);
+    }
+
+    /// `lahf`, which copies the sign, zero, auxiliary carry, parity, and carry
+    /// flags into `ah`, conveniently at the same bits as the corresponding
+    /// Z80 flags.
+    pub fn lahf(&mut self) {
+        self.byte(0x9F);
+    }
+
+    /// `movzx r, ah`
+    pub fn load_ah(&mut self, r: Register) {
+        self.byte(0x0F);
+        self.byte(0xB6);
+        self.registers(r as u8, 4);
+    }
+
+    /// `setcc` into the low byte of `r` and zero extend it.
+    ///
+    /// `r` may not be `esi` or `edi`.
+    pub fn set(&mut self, condition: Condition, r: Register) {
+        assert!(r != Register::Esi && r != Register::Edi);
+        self.byte(0x0F);
+        self.byte(0x90 + condition as u8);
+        self.registers(0, r as u8);
+        self.zero_extend8(r, r);
+    }
+
+    /// The entry of a compiled block: save `rbx` and `rbp`, which will hold
+    /// the two arguments of the function, and align the stack.
+    pub fn prologue(&mut self) {
+        // push rbx; push rbp; sub rsp, 8
+        self.code.extend_from_slice(&[0x53, 0x55, 0x48, 0x83, 0xEC, 0x08]);
+        // mov rbx, rdi; mov rbp, rsi
+        self.code.extend_from_slice(&[0x48, 0x89, 0xFB, 0x48, 0x89, 0xF5]);
+    }
+
+    /// Undo `prologue` and return.
+    pub fn epilogue(&mut self) {
+        // add rsp, 8; pop rbp; pop rbx; ret
+        self.code.extend_from_slice(&[0x48, 0x83, 0xC4, 0x08, 0x5D, 0x5B, 0xC3]);
+    }
+
+    /// Call the function pointed to by `[m]` with the `BusHeader` pointer as
+    /// its first argument.
+    pub fn call(&mut self, m: Memory) {
+        // mov rdi, rbp
+        self.code.extend_from_slice(&[0x48, 0x89, 0xEF]);
+        self.byte(0xFF);
+        self.memory(2, m);
+    }
+
+    /// `jcc` to a target to be bound later.
+    pub fn jump_if(&mut self, condition: Condition) -> Fixup {
+        self.byte(0x0F);
+        self.byte(0x80 + condition as u8);
+        self.dword(0);
+        Fixup(self.code.len())
+    }
+
+    /// Make the jump `fixup` go to the next instruction assembled.
+    pub fn bind(&mut self, fixup: Fixup) {
+        let Fixup(end) = fixup;
+        let relative = (self.code.len() - end) as u32;
+        self.code[end - 4..end].copy_from_slice(&relative.to_le_bytes());
+    }
+}
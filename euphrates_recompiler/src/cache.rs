@@ -0,0 +1,404 @@
+//! Finding, compiling, and running blocks, and knowing when to forget them.
+
+use std::collections::HashMap;
+use std::mem::transmute;
+use std::ptr;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::memory16::Memory16;
+use euphrates::hardware::sms_memory::{MemoryPage, SmsMemory};
+use euphrates::hardware::z80::{
+    InterruptStatus, Prefix, Z80Internal, Z80Irq, Z80Memo, Z80RunImpler, Z80State,
+};
+use euphrates::memo::Inbox;
+use euphrates_virtual_memory::{LogicalMemory, Main, UnsafeMemory, VirtualMemory};
+
+use compile::{self, BlockFn, BusHeader};
+use decode::{self, Instruction};
+
+/// How many bytes of compiled code to keep before starting over.
+const CODE_LEN: usize = 0x40_0000;
+
+/// The most instructions in a block.
+const BLOCK_INSTRUCTIONS: usize = 32;
+
+/// How many bytes of RAM there can be: 16 KiB of system RAM (really 8 KiB,
+/// mirrored), two 16 KiB pages of main cartridge RAM, and 8 KiB of half
+/// cartridge RAM.
+const RAM_LEN: usize = 0xE000;
+
+/// Where the byte at `offset` in `page` is in our numbering of all RAM, or
+/// `None` if it's ROM.
+fn ram_index(page: MemoryPage, offset: usize, system_ram_len: usize) -> Option<usize> {
+    use self::MemoryPage::*;
+    match page {
+        SystemRam => Some(offset % system_ram_len),
+        FirstCartridgeRam(_) => Some(0x4000 + offset),
+        SecondCartridgeRam(_) => Some(0x8000 + offset),
+        HalfCartridgeRam(_) if offset >= 0x2000 => Some(0xC000 + offset - 0x2000),
+        _ => None,
+    }
+}
+
+/// Watches writes to memory for bank switches and writes to RAM holding code
+/// we've compiled.
+pub struct Watch {
+    pages: [MemoryPage; 4],
+
+    /// One bit for each byte of RAM, set if the byte was read to compile a
+    /// block.
+    code: Box<[u64]>,
+
+    /// Has any bit of `code` been set?
+    has_code: bool,
+
+    /// Was a byte with its bit set in `code` written?
+    invalidated: bool,
+}
+
+impl Watch {
+    fn new() -> Self {
+        Watch {
+            pages: Default::default(),
+            code: vec![0; RAM_LEN / 64].into_boxed_slice(),
+            has_code: false,
+            invalidated: false,
+        }
+    }
+
+    /// Notice any changes to the memory map made while we weren't watching.
+    fn sync<M>(&mut self, memory: &M)
+    where
+        M: SmsMemory + ?Sized,
+    {
+        for slot in 0..4 {
+            self.pages[slot] = memory.page(slot as u8);
+        }
+    }
+
+    /// Call after the Z80 writes to `address`.
+    ///
+    /// Returns whether compiled code needs to stop: either because the
+    /// memory map changed, or because the write changed code.
+    fn after_write<M>(&mut self, memory: &M, address: u16) -> bool
+    where
+        M: SmsMemory + ?Sized,
+    {
+        let mut stop = false;
+        for slot in 0..4 {
+            let page = memory.page(slot as u8);
+            if page != self.pages[slot] {
+                self.pages[slot] = page;
+                stop = true;
+            }
+        }
+        if self.has_code {
+            let page = self.pages[address as usize >> 14];
+            let offset = address as usize & 0x3FFF;
+            if let Some(i) = ram_index(page, offset, memory.system_ram_len()) {
+                if self.code[i / 64] & 1 << (i % 64) != 0 {
+                    self.invalidated = true;
+                    stop = true;
+                }
+            }
+        }
+        stop
+    }
+
+    fn mark(&mut self, i: usize) {
+        self.code[i / 64] |= 1 << (i % 64);
+        self.has_code = true;
+    }
+
+    fn clear(&mut self) {
+        for x in self.code.iter_mut() {
+            *x = 0;
+        }
+        self.has_code = false;
+        self.invalidated = false;
+    }
+}
+
+/// Memory that tells a `Watch` about writes, for when the interpreter runs.
+struct Watched<'a, M: 'a + ?Sized> {
+    memory: &'a mut M,
+    watch: &'a mut Watch,
+}
+
+impl<'a, M> Memory16 for Watched<'a, M>
+where
+    M: 'a + SmsMemory + ?Sized,
+{
+    #[inline]
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory.read(address)
+    }
+
+    #[inline]
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory.write(address, value);
+        self.watch.after_write(self.memory, address);
+    }
+
+    #[inline]
+    fn rom_index(&self, address: u16) -> Option<usize> {
+        self.memory.rom_index(address)
+    }
+}
+
+/// What compiled code is given to access memory.
+///
+/// Since the `BusHeader` is first, compiled code can be given a pointer to
+/// the whole `Bus` and treat it as a pointer to the `BusHeader`; the
+/// functions it calls know better.
+#[repr(C)]
+struct Bus<'a, M: 'a + ?Sized> {
+    header: BusHeader,
+    memory: &'a mut M,
+    watch: &'a mut Watch,
+}
+
+unsafe extern "sysv64" fn read<M>(header: *mut BusHeader, address: u16) -> u8
+where
+    M: SmsMemory + ?Sized,
+{
+    let bus = &mut *(header as *mut Bus<M>);
+    bus.memory.read(address)
+}
+
+unsafe extern "sysv64" fn write<M>(header: *mut BusHeader, address: u16, value: u8)
+where
+    M: SmsMemory + ?Sized,
+{
+    let bus = &mut *(header as *mut Bus<M>);
+    bus.memory.write(address, value);
+    if bus.watch.after_write(bus.memory, address) {
+        bus.header.stop = true;
+    }
+}
+
+struct Block {
+    /// Where the block's code is in the code buffer, or `None` if the first
+    /// instruction couldn't be compiled.
+    code: Option<usize>,
+
+    /// How many cycles after the block begins that the interpreter would
+    /// begin its last step of the block.
+    last_step: u64,
+
+    /// Is this code from RAM?
+    ram: bool,
+}
+
+pub struct Cache {
+    /// Allocated the first time we compile something.
+    code: Option<LogicalMemory<u8>>,
+
+    /// How many bytes of `code` are in use.
+    code_len: usize,
+
+    /// Blocks by the page mapped where they begin and their address.
+    blocks: HashMap<(MemoryPage, u16), Block>,
+
+    watch: Watch,
+}
+
+// `LogicalMemory` holds a raw pointer, but it owns the memory it points to.
+unsafe impl Send for Cache {}
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache {
+            code: None,
+            code_len: 0,
+            blocks: HashMap::new(),
+            watch: Watch::new(),
+        }
+    }
+
+    /// Forget every block.
+    pub fn invalidate(&mut self) {
+        self.blocks.clear();
+        self.code_len = 0;
+        self.watch.clear();
+    }
+
+    /// Forget every block from RAM.
+    fn invalidate_ram(&mut self) {
+        self.blocks.retain(|_, block| !block.ram);
+        self.watch.clear();
+    }
+
+    /// Find or compile the block at `pc`.
+    ///
+    /// Returns where its code is and its `last_step`.
+    fn block<M>(&mut self, memory: &mut M, pc: u16) -> Option<(usize, u64)>
+    where
+        M: SmsMemory + ?Sized,
+    {
+        let page = memory.page((pc >> 14) as u8);
+        if let Some(block) = self.blocks.get(&(page, pc)) {
+            return block.code.map(|code| (code, block.last_step));
+        }
+
+        // decode no further than the end of this 8 KiB: that way it's all ROM
+        // or all RAM, and all in the same slot
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut end = pc as usize;
+        let mut address = pc;
+        while instructions.len() < BLOCK_INSTRUCTIONS {
+            let instruction = {
+                let mut read = |a: u16| {
+                    if a >> 13 != pc >> 13 {
+                        return None;
+                    }
+                    end = ::std::cmp::max(end, a as usize + 1);
+                    Some(memory.read(a))
+                };
+                decode::decode(&mut read, address)
+            };
+            match instruction {
+                Some(instruction) => {
+                    address = instruction.next;
+                    instructions.push(instruction);
+                    if instruction.op.branches() {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let system_ram_len = memory.system_ram_len();
+        let ram = ram_index(page, pc as usize & 0x3FFF, system_ram_len).is_some();
+        if ram {
+            for a in pc as usize..end {
+                let i = ram_index(page, a & 0x3FFF, system_ram_len).unwrap();
+                self.watch.mark(i);
+            }
+        }
+
+        let last_step = match instructions.split_last() {
+            Some((last, rest)) => rest.iter().map(|i| i.cycles).sum::<u64>() + last.last_step,
+            None => 0,
+        };
+        let code = if instructions.is_empty() {
+            None
+        } else {
+            Some(self.emit(&compile::compile(&instructions)))
+        };
+        self.blocks.insert(
+            (page, pc),
+            Block {
+                code,
+                last_step,
+                ram,
+            },
+        );
+        code.map(|code| (code, last_step))
+    }
+
+    /// Copy `machine_code` into the code buffer, and return where it is.
+    fn emit(&mut self, machine_code: &[u8]) -> usize {
+        if self.code.is_none() {
+            self.code = Some(unsafe {
+                <Main as VirtualMemory<u8>>::allocate_logical(CODE_LEN, true, true)
+            });
+        }
+        if self.code_len + machine_code.len() > CODE_LEN {
+            // out of room; start over, but keep what we know about RAM so
+            // blocks aren't compiled from RAM without marking it again
+            self.blocks.clear();
+            self.code_len = 0;
+        }
+        let start = self.code_len;
+        unsafe {
+            let code = self.code.as_mut().unwrap();
+            ptr::copy_nonoverlapping(
+                machine_code.as_ptr(),
+                code.as_mut_ptr().add(start),
+                machine_code.len(),
+            );
+        }
+        // keep blocks 16 byte aligned
+        self.code_len = (start + machine_code.len() + 15) & !15;
+        start
+    }
+
+    /// Run until `z80` has at least `target_cycles` cycles, as
+    /// `Z80RunImpler::run` would.
+    pub fn run<M, I, Irq, Inb>(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut M,
+        io: &mut I,
+        irq: &mut Irq,
+        inbox: &mut Inb,
+        target_cycles: u64,
+    ) where
+        M: SmsMemory + ?Sized,
+        I: Io16 + ?Sized,
+        Irq: Z80Irq + ?Sized,
+        Inb: Inbox<Memo = Z80Memo> + ?Sized,
+    {
+        if z80.interrupt_status() == InterruptStatus::NoCheck {
+            z80.set_interrupt_status(InterruptStatus::Check);
+        }
+        self.watch.sync(memory);
+
+        while z80.cycles() < target_cycles {
+            if inbox.holding() {
+                return;
+            }
+
+            // compiled code doesn't check interrupts or send memos, so only
+            // run it when the interpreter wouldn't either
+            let block = if z80.prefix() == Prefix::NoPrefix
+                && z80.interrupt_status() == InterruptStatus::NoCheck
+                && !inbox.tracing()
+            {
+                let pc = z80.reg16(::euphrates::hardware::z80::Reg16::PC);
+                self.block(memory, pc)
+            } else {
+                None
+            };
+
+            match block {
+                // every step of the block has to begin before
+                // `target_cycles`, as it would with the interpreter
+                Some((start, last_step)) if z80.cycles() + last_step < target_cycles => {
+                    let f: BlockFn = unsafe {
+                        let code = self.code.as_mut().unwrap();
+                        transmute(code.as_mut_ptr().add(start))
+                    };
+                    let mut bus = Bus {
+                        header: BusHeader {
+                            stop: false,
+                            read: read::<M>,
+                            write: write::<M>,
+                        },
+                        memory: &mut *memory,
+                        watch: &mut self.watch,
+                    };
+                    unsafe {
+                        f(z80, &mut bus as *mut Bus<M> as *mut BusHeader);
+                    }
+                }
+                _ => Z80RunImpler {
+                    z80: &mut *z80,
+                    memory: &mut Watched {
+                        memory: &mut *memory,
+                        watch: &mut self.watch,
+                    },
+                    io: &mut *io,
+                    irq: &mut *irq,
+                    inbox: &mut *inbox,
+                }.step(target_cycles),
+            }
+
+            if self.watch.invalidated {
+                self.invalidate_ram();
+            }
+        }
+    }
+}
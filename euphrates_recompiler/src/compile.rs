@@ -0,0 +1,807 @@
+//! Translating blocks of decoded instructions to x86-64.
+//!
+//! A compiled block is a function taking a pointer to the `Z80State` and a
+//! pointer to a `BusHeader`. Z80 registers live in the `Z80State` the whole
+//! time; the only things kept back and written at once are the cycles, R, PC,
+//! and `flags_written`, and those are written before anything that might end
+//! the block early.
+
+use euphrates::hardware::z80::ConditionCode::*;
+use euphrates::hardware::z80::Reg16::*;
+use euphrates::hardware::z80::Reg8::*;
+use euphrates::hardware::z80::{offsets, ConditionCode, Reg16, Reg8, Z80State};
+use euphrates::hardware::z80::{CF, HF, NF, PF, SF, XF, YF, ZF};
+
+use assembler::Register::*;
+use assembler::{self as x86, Assembler, Base, Condition, Register};
+use decode::{Arithmetic, Instruction, Memory, Op, Operand, Rotation};
+
+/// The part of the bus that compiled code sees.
+///
+/// Compiled code reads and writes memory by calling `read` and `write`, and
+/// gives up after any instruction that writes memory if `stop` is then set.
+#[repr(C)]
+pub struct BusHeader {
+    pub stop: bool,
+    pub read: unsafe extern "sysv64" fn(*mut BusHeader, u16) -> u8,
+    pub write: unsafe extern "sysv64" fn(*mut BusHeader, u16, u8),
+}
+
+/// The type of a compiled block.
+pub type BlockFn = unsafe extern "sysv64" fn(*mut Z80State, *mut BusHeader);
+
+const STOP: x86::Memory = x86::Memory(Base::Bus, ::std::mem::offset_of!(BusHeader, stop));
+const READ: x86::Memory = x86::Memory(Base::Bus, ::std::mem::offset_of!(BusHeader, read));
+const WRITE: x86::Memory = x86::Memory(Base::Bus, ::std::mem::offset_of!(BusHeader, write));
+
+const CYCLES: x86::Memory = x86::Memory(Base::State, offsets::CYCLES);
+const MEMPTR: x86::Memory = x86::Memory(Base::State, offsets::MEMPTR);
+const MEMPTR_HIGH: x86::Memory = x86::Memory(Base::State, offsets::MEMPTR + 1);
+const Q: x86::Memory = x86::Memory(Base::State, offsets::Q);
+const FLAGS_WRITTEN: x86::Memory = x86::Memory(Base::State, offsets::FLAGS_WRITTEN);
+const LD_A_IR: x86::Memory = x86::Memory(Base::State, offsets::LD_A_IR);
+
+fn reg8(r: Reg8) -> x86::Memory {
+    x86::Memory(Base::State, offsets::C - C as usize + r as usize)
+}
+
+fn reg16(r: Reg16) -> x86::Memory {
+    x86::Memory(Base::State, offsets::BC + 2 * r as usize)
+}
+
+/// The high and low halves of a register pair.
+fn halves(r: Reg16) -> (Reg8, Reg8) {
+    match r {
+        BC => (B, C),
+        DE => (D, E),
+        HL => (H, L),
+        AF => (A, F),
+        IX => (IXH, IXL),
+        IY => (IYH, IYL),
+        SP => (SPH, SPL),
+        PC => (PCH, PCL),
+        _ => unreachable!(),
+    }
+}
+
+/// Does this instruction write to memory?
+fn writes_memory(op: Op) -> bool {
+    use self::Op::*;
+    matches!(
+        op,
+        Ld(Operand::Memory(_), _)
+            | Ld16Store(..)
+            | Inc(Operand::Memory(_))
+            | Dec(Operand::Memory(_))
+            | Rotate(_, Operand::Memory(_))
+            | Res(_, Operand::Memory(_))
+            | Set(_, Operand::Memory(_))
+            | Push(_)
+            | ExSp(_)
+            | Call(..)
+            | Rst(_)
+    )
+}
+
+/// Compile `instructions`, which must be consecutive, into the machine code of
+/// a `BlockFn`.
+///
+/// Only the last instruction may branch.
+pub fn compile(instructions: &[Instruction]) -> Vec<u8> {
+    let mut compiler = Compiler {
+        asm: Assembler::new(),
+        exits: Vec::new(),
+        cycles: 0,
+        refresh: 0,
+        flags_written: None,
+    };
+    compiler.block(instructions);
+    compiler.asm.code().to_vec()
+}
+
+struct Compiler {
+    asm: Assembler,
+
+    /// Jumps to the end of the block.
+    exits: Vec<x86::Fixup>,
+
+    /// Cycles not yet added to the `Z80State`.
+    cycles: u64,
+
+    /// Increments to R not yet made.
+    refresh: u8,
+
+    /// Did the last instruction compiled write flags? `None` before the first
+    /// instruction.
+    flags_written: Option<bool>,
+}
+
+impl Compiler {
+    fn block(&mut self, instructions: &[Instruction]) {
+        self.asm.prologue();
+        self.asm.store8_immediate(LD_A_IR, 0);
+        for (i, instruction) in instructions.iter().enumerate() {
+            let last = i + 1 == instructions.len();
+            assert!(last || !instruction.op.branches());
+            self.begin_instruction();
+            self.cycles += instruction.cycles;
+            self.refresh = self.refresh.wrapping_add(instruction.refresh);
+            self.flags_written = Some(instruction.op.writes_flags());
+            if instruction.op.branches() {
+                self.flush(instruction.next);
+                self.branch(instruction);
+            } else {
+                self.op(instruction.op);
+                if last {
+                    self.flush(instruction.next);
+                } else if writes_memory(instruction.op) {
+                    // the write may have switched banks or changed code
+                    self.flush(instruction.next);
+                    self.asm.cmp8_immediate(STOP, 0);
+                    let exit = self.asm.jump_if(Condition::NotEqual);
+                    self.exits.push(exit);
+                }
+            }
+        }
+        for exit in ::std::mem::take(&mut self.exits) {
+            self.asm.bind(exit);
+        }
+        self.asm.epilogue();
+    }
+
+    /// Set Q the way `Z80Internal::begin_instruction` does.
+    fn begin_instruction(&mut self) {
+        match self.flags_written {
+            None => {
+                self.asm.load8(Eax, FLAGS_WRITTEN);
+                self.asm.neg(Eax);
+                self.asm.load8(Ecx, reg8(F));
+                self.asm.arithmetic(x86::Arithmetic::And, Eax, Ecx);
+                self.asm.store8(Q, Eax);
+            }
+            Some(true) => {
+                self.asm.load8(Eax, reg8(F));
+                self.asm.store8(Q, Eax);
+            }
+            Some(false) => self.asm.store8_immediate(Q, 0),
+        }
+    }
+
+    /// Bring the cycles, R, PC, and `flags_written` up to date, as of the end
+    /// of the instruction whose next instruction is at `pc`.
+    fn flush(&mut self, pc: u16) {
+        use assembler::Arithmetic::*;
+
+        if self.cycles != 0 {
+            self.asm.add64_immediate(CYCLES, self.cycles as u32);
+            self.cycles = 0;
+        }
+        if self.refresh != 0 {
+            // R's high bit stays put
+            self.asm.load8(Eax, reg8(R));
+            self.asm.mov(Ecx, Eax);
+            self.asm.arithmetic_immediate(And, Ecx, 0x80);
+            self.asm.arithmetic_immediate(Add, Eax, self.refresh as u32);
+            self.asm.arithmetic_immediate(And, Eax, 0x7F);
+            self.asm.arithmetic(Or, Eax, Ecx);
+            self.asm.store8(reg8(R), Eax);
+            self.refresh = 0;
+        }
+        self.asm.store16_immediate(reg16(PC), pc);
+        let flags_written = self.flags_written.unwrap_or(false);
+        self.asm.store8_immediate(FLAGS_WRITTEN, flags_written as u8);
+    }
+
+    fn read(&mut self) {
+        self.asm.call(READ);
+        self.asm.zero_extend8(Eax, Eax);
+    }
+
+    fn write(&mut self) {
+        self.asm.call(WRITE);
+    }
+
+    /// Put SP plus `offset` into `esi`.
+    fn stack_address(&mut self, offset: u16) {
+        use assembler::Arithmetic::*;
+        self.asm.load16(Esi, reg16(SP));
+        if offset != 0 {
+            self.asm.arithmetic_immediate(Add, Esi, offset as u32);
+            self.asm.arithmetic_immediate(And, Esi, 0xFFFF);
+        }
+    }
+
+    fn add_sp(&mut self, x: u16) {
+        use assembler::Arithmetic::*;
+        self.asm.load16(Eax, reg16(SP));
+        self.asm.arithmetic_immediate(Add, Eax, x as u32);
+        self.asm.store16(reg16(SP), Eax);
+    }
+
+    /// Put the address of `m` into `esi`.
+    fn address(&mut self, m: Memory) {
+        use assembler::Arithmetic::*;
+        match m {
+            Memory::Register(r) => self.asm.load16(Esi, reg16(r)),
+            Memory::Index(r, d) => {
+                self.asm.load16(Esi, reg16(r));
+                self.asm.arithmetic_immediate(Add, Esi, d as i32 as u32);
+                self.asm.arithmetic_immediate(And, Esi, 0xFFFF);
+            }
+            Memory::Absolute(nn) => self.asm.mov_immediate(Esi, nn as u32),
+        }
+    }
+
+    /// Set MEMPTR as reading the byte at `m`, whose address is in `esi`,
+    /// does.
+    fn memptr_read(&mut self, m: Memory) {
+        use assembler::Arithmetic::*;
+        match m {
+            Memory::Register(HL) => {}
+            Memory::Index(..) => self.asm.store16(MEMPTR, Esi),
+            _ => {
+                self.asm.mov(Ecx, Esi);
+                self.asm.arithmetic_immediate(Add, Ecx, 1);
+                self.asm.store16(MEMPTR, Ecx);
+            }
+        }
+    }
+
+    /// Set MEMPTR as writing `edx` to the byte at `m`, whose address is in
+    /// `esi`, does.
+    fn memptr_write(&mut self, m: Memory) {
+        use assembler::Arithmetic::*;
+        match m {
+            Memory::Register(HL) => {}
+            Memory::Index(..) => self.asm.store16(MEMPTR, Esi),
+            _ => {
+                self.asm.mov(Ecx, Esi);
+                self.asm.arithmetic_immediate(Add, Ecx, 1);
+                self.asm.arithmetic_immediate(And, Ecx, 0xFF);
+                self.asm.mov(Edi, Edx);
+                self.asm.shl(Edi, 8);
+                self.asm.arithmetic(Or, Ecx, Edi);
+                self.asm.store16(MEMPTR, Ecx);
+            }
+        }
+    }
+
+    /// Put the value of `operand` into `eax`.
+    fn load(&mut self, operand: Operand) {
+        match operand {
+            Operand::Register(r) => self.asm.load8(Eax, reg8(r)),
+            Operand::Immediate(n) => self.asm.mov_immediate(Eax, n as u32),
+            Operand::Memory(m) => {
+                self.address(m);
+                self.memptr_read(m);
+                self.read();
+            }
+        }
+    }
+
+    /// Store the low byte of `eax` into `operand`.
+    fn store(&mut self, operand: Operand) {
+        match operand {
+            Operand::Register(r) => self.asm.store8(reg8(r), Eax),
+            Operand::Immediate(_) => unreachable!(),
+            Operand::Memory(m) => {
+                self.asm.zero_extend8(Edx, Eax);
+                self.address(m);
+                self.memptr_write(m);
+                self.write();
+            }
+        }
+    }
+
+    /// Add the bits of `r` in `mask` to the flags in `edi`.
+    fn flags_from(&mut self, r: Register, mask: u8) {
+        use assembler::Arithmetic::*;
+        self.asm.mov(Esi, r);
+        self.asm.arithmetic_immediate(And, Esi, mask as u32);
+        self.asm.arithmetic(Or, Edi, Esi);
+    }
+
+    /// Add the sign, zero, and parity flags in `mask` of the low byte of `eax`
+    /// to the flags in `edi`.
+    ///
+    /// This clobbers `ah`.
+    fn flags_szp(&mut self, mask: u8) {
+        use assembler::Arithmetic::*;
+        self.asm.test_al();
+        self.asm.lahf();
+        self.asm.load_ah(Esi);
+        self.asm.arithmetic_immediate(And, Esi, mask as u32);
+        self.asm.arithmetic(Or, Edi, Esi);
+    }
+
+    /// Start the flags in `edi` with the bits of F in `mask`.
+    fn flags_keep(&mut self, mask: u8) {
+        use assembler::Arithmetic::*;
+        self.asm.load8(Edi, reg8(F));
+        self.asm.arithmetic_immediate(And, Edi, mask as u32);
+    }
+
+    fn store_flags(&mut self) {
+        self.asm.store8(reg8(F), Edi);
+    }
+
+    fn op(&mut self, op: Op) {
+        use assembler::Arithmetic::{Add, And, Or, Sub};
+        use decode::Op::*;
+
+        match op {
+            Nop => {}
+            Ld(dest, source) => {
+                self.load(source);
+                self.store(dest);
+            }
+            Ld16Immediate(r, nn) => self.asm.store16_immediate(reg16(r), nn),
+            Ld16Load(r, nn) => {
+                let (high, low) = halves(r);
+                self.asm.mov_immediate(Esi, nn as u32);
+                self.read();
+                self.asm.store8(reg8(low), Eax);
+                self.asm.mov_immediate(Esi, nn.wrapping_add(1) as u32);
+                self.read();
+                self.asm.store8(reg8(high), Eax);
+                self.asm.store16_immediate(MEMPTR, nn.wrapping_add(1));
+            }
+            Ld16Store(nn, r) => {
+                let (high, low) = halves(r);
+                self.asm.mov_immediate(Esi, nn as u32);
+                self.asm.load8(Edx, reg8(low));
+                self.write();
+                self.asm.mov_immediate(Esi, nn.wrapping_add(1) as u32);
+                self.asm.load8(Edx, reg8(high));
+                self.write();
+                self.asm.store16_immediate(MEMPTR, nn.wrapping_add(1));
+            }
+            LdSp(r) => {
+                self.asm.load16(Eax, reg16(r));
+                self.asm.store16(reg16(SP), Eax);
+            }
+            Inc16(r) => {
+                self.asm.load16(Eax, reg16(r));
+                self.asm.arithmetic_immediate(Add, Eax, 1);
+                self.asm.store16(reg16(r), Eax);
+            }
+            Dec16(r) => {
+                self.asm.load16(Eax, reg16(r));
+                self.asm.arithmetic_immediate(Sub, Eax, 1);
+                self.asm.store16(reg16(r), Eax);
+            }
+            Inc(operand) => self.inc_dec(operand, false),
+            Dec(operand) => self.inc_dec(operand, true),
+            Arithmetic(a, operand) => {
+                self.load(operand);
+                self.asm.mov(Edx, Eax);
+                self.asm.load8(Ecx, reg8(A));
+                self.arithmetic(a);
+            }
+            Neg => {
+                self.asm.load8(Edx, reg8(A));
+                self.asm.mov_immediate(Ecx, 0);
+                self.arithmetic(self::Arithmetic::Sub);
+            }
+            Cpl => {
+                self.asm.load8(Eax, reg8(A));
+                self.asm.not(Eax);
+                self.asm.store8(reg8(A), Eax);
+                self.flags_keep(SF | ZF | PF | CF);
+                self.asm.arithmetic_immediate(Or, Edi, (HF | NF) as u32);
+                self.flags_from(Eax, XF | YF);
+                self.store_flags();
+            }
+            RotateA(rotation) => {
+                self.asm.load8(Eax, reg8(A));
+                self.rotate(rotation);
+                self.asm.load8(Esi, reg8(F));
+                self.asm.arithmetic_immediate(And, Esi, (SF | ZF | PF) as u32);
+                self.asm.arithmetic(Or, Edi, Esi);
+                self.flags_from(Eax, XF | YF);
+                self.asm.store8(reg8(A), Eax);
+                self.store_flags();
+            }
+            Rotate(rotation, operand) => {
+                self.load(operand);
+                self.rotate(rotation);
+                self.flags_from(Eax, XF | YF);
+                self.flags_szp(SF | ZF | PF);
+                self.store_flags();
+                self.store(operand);
+            }
+            Bit(n, operand) => {
+                self.load(operand);
+                match operand {
+                    // the undocumented flags come from MEMPTR, which reading
+                    // the operand may have just set
+                    Operand::Memory(_) => self.asm.load8(Ecx, MEMPTR_HIGH),
+                    _ => self.asm.mov(Ecx, Eax),
+                }
+                self.flags_keep(CF);
+                self.asm.arithmetic_immediate(Or, Edi, HF as u32);
+                self.flags_from(Ecx, XF | YF);
+                self.asm.arithmetic_immediate(And, Eax, 1 << n);
+                // only bit 7 can set the sign flag, and parity is even just
+                // when the bit is clear
+                self.flags_szp(SF | ZF | PF);
+                self.store_flags();
+            }
+            Res(n, operand) => {
+                self.load(operand);
+                self.asm.arithmetic_immediate(And, Eax, !(1u32 << n));
+                self.store(operand);
+            }
+            Set(n, operand) => {
+                self.load(operand);
+                self.asm.arithmetic_immediate(Or, Eax, 1 << n);
+                self.store(operand);
+            }
+            Add16(dest, source) => {
+                self.asm.load16(Ecx, reg16(dest));
+                self.asm.load16(Edx, reg16(source));
+                self.asm.mov(Eax, Ecx);
+                self.asm.arithmetic(Add, Eax, Edx);
+                self.memptr_plus_one();
+                self.flags_keep(SF | ZF | PF);
+                self.carry16();
+                self.half_carry16();
+                self.asm.mov(Esi, Eax);
+                self.asm.shr(Esi, 8);
+                self.asm.arithmetic_immediate(And, Esi, (XF | YF) as u32);
+                self.asm.arithmetic(Or, Edi, Esi);
+                self.asm.store16(reg16(dest), Eax);
+                self.store_flags();
+            }
+            Adc16(source) => self.arithmetic16(source, false),
+            Sbc16(source) => self.arithmetic16(source, true),
+            Push(r) => {
+                let (high, low) = halves(r);
+                self.push(high, low);
+            }
+            Pop(r) => self.pop(r),
+            ExDeHl => self.swap(DE, HL),
+            Exx => {
+                self.swap(BC, BC0);
+                self.swap(DE, DE0);
+                self.swap(HL, HL0);
+            }
+            ExSp(r) => {
+                let (high, low) = halves(r);
+                // MEMPTR ends up as the word on the stack, so keep it there
+                // in the meantime
+                self.stack_address(0);
+                self.read();
+                self.asm.store8(MEMPTR, Eax);
+                self.stack_address(1);
+                self.read();
+                self.asm.store8(MEMPTR_HIGH, Eax);
+                self.stack_address(0);
+                self.asm.load8(Edx, reg8(low));
+                self.write();
+                self.stack_address(1);
+                self.asm.load8(Edx, reg8(high));
+                self.write();
+                self.asm.load16(Eax, MEMPTR);
+                self.asm.store16(reg16(r), Eax);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn swap(&mut self, r1: Reg16, r2: Reg16) {
+        self.asm.load16(Eax, reg16(r1));
+        self.asm.load16(Ecx, reg16(r2));
+        self.asm.store16(reg16(r1), Ecx);
+        self.asm.store16(reg16(r2), Eax);
+    }
+
+    /// Set MEMPTR to one more than `ecx`.
+    fn memptr_plus_one(&mut self) {
+        self.asm.mov(Esi, Ecx);
+        self.asm.arithmetic_immediate(x86::Arithmetic::Add, Esi, 1);
+        self.asm.store16(MEMPTR, Esi);
+    }
+
+    /// Add the carry out of the 16 bit result in `eax` to `edi`.
+    fn carry16(&mut self) {
+        use assembler::Arithmetic::*;
+        self.asm.mov(Esi, Eax);
+        self.asm.shr(Esi, 16);
+        self.asm.arithmetic_immediate(And, Esi, 1);
+        self.asm.arithmetic(Or, Edi, Esi);
+    }
+
+    /// Add the half carry of the 16 bit operation `ecx` and `edx` giving `eax`
+    /// to `edi`.
+    fn half_carry16(&mut self) {
+        use assembler::Arithmetic::*;
+        self.asm.mov(Esi, Ecx);
+        self.asm.arithmetic(Xor, Esi, Edx);
+        self.asm.arithmetic(Xor, Esi, Eax);
+        self.asm.shr(Esi, 8);
+        self.asm.arithmetic_immediate(And, Esi, HF as u32);
+        self.asm.arithmetic(Or, Edi, Esi);
+    }
+
+    /// `adc hl, source` or `sbc hl, source`.
+    fn arithmetic16(&mut self, source: Reg16, subtract: bool) {
+        use assembler::Arithmetic::*;
+        let op = if subtract { Sub } else { Add };
+        self.asm.load16(Ecx, reg16(HL));
+        self.asm.load16(Edx, reg16(source));
+        self.asm.mov(Eax, Ecx);
+        self.asm.arithmetic(op, Eax, Edx);
+        self.asm.load8(Esi, reg8(F));
+        self.asm.arithmetic_immediate(And, Esi, CF as u32);
+        self.asm.arithmetic(op, Eax, Esi);
+        self.memptr_plus_one();
+        self.asm.mov_immediate(Edi, 0);
+        self.carry16();
+        self.half_carry16();
+        self.asm.mov(Esi, Eax);
+        self.asm.shr(Esi, 8);
+        self.asm.arithmetic_immediate(And, Esi, (SF | XF | YF) as u32);
+        self.asm.arithmetic(Or, Edi, Esi);
+        self.overflow(subtract, 13);
+        self.asm.mov(Esi, Eax);
+        self.asm.arithmetic_immediate(And, Esi, 0xFFFF);
+        self.asm.arithmetic_immediate(Cmp, Esi, 0);
+        self.asm.set(Condition::Equal, Edx);
+        self.asm.shl(Edx, 6);
+        self.asm.arithmetic(Or, Edi, Edx);
+        if subtract {
+            self.asm.arithmetic_immediate(Or, Edi, NF as u32);
+        }
+        self.asm.store16(reg16(HL), Eax);
+        self.store_flags();
+    }
+
+    /// Add the overflow flag of the operation `ecx` and `edx` giving `eax` to
+    /// `edi`, where the sign bit is bit `shift + 2`.
+    ///
+    /// This clobbers `ecx` and `edx`.
+    fn overflow(&mut self, subtract: bool, shift: u8) {
+        use assembler::Arithmetic::*;
+        self.asm.mov(Esi, Ecx);
+        self.asm.arithmetic(Xor, Esi, Edx);
+        if subtract {
+            // the operands have different signs, and the result's sign
+            // differs from the first's
+            self.asm.arithmetic(Xor, Ecx, Eax);
+            self.asm.arithmetic(And, Esi, Ecx);
+        } else {
+            // the operands have the same sign, and the result's sign differs
+            // from theirs
+            self.asm.not(Esi);
+            self.asm.arithmetic(Xor, Edx, Eax);
+            self.asm.arithmetic(And, Esi, Edx);
+        }
+        self.asm.shr(Esi, shift);
+        self.asm.arithmetic_immediate(And, Esi, PF as u32);
+        self.asm.arithmetic(Or, Edi, Esi);
+    }
+
+    /// Do `a` with A in `ecx` and the operand in `edx`.
+    fn arithmetic(&mut self, a: Arithmetic) {
+        use assembler::Arithmetic::*;
+        use decode::Arithmetic as Z;
+
+        let x86_op = match a {
+            Z::Add | Z::Adc => Add,
+            Z::Sub | Z::Sbc | Z::Cp => Sub,
+            Z::And => And,
+            Z::Xor => Xor,
+            Z::Or => Or,
+        };
+        self.asm.mov(Eax, Ecx);
+        self.asm.arithmetic(x86_op, Eax, Edx);
+        match a {
+            Z::And | Z::Xor | Z::Or => {
+                let h = if a == Z::And { HF } else { 0 };
+                self.asm.mov_immediate(Edi, h as u32);
+                self.flags_from(Eax, XF | YF);
+                self.flags_szp(SF | ZF | PF);
+            }
+            _ => {
+                if a == Z::Adc || a == Z::Sbc {
+                    self.asm.load8(Esi, reg8(F));
+                    self.asm.arithmetic_immediate(And, Esi, CF as u32);
+                    self.asm.arithmetic(x86_op, Eax, Esi);
+                }
+                // for a subtraction, bit 8 is set by a borrow
+                self.asm.mov(Edi, Eax);
+                self.asm.shr(Edi, 8);
+                self.asm.arithmetic_immediate(And, Edi, CF as u32);
+                self.asm.mov(Esi, Ecx);
+                self.asm.arithmetic(Xor, Esi, Edx);
+                self.asm.arithmetic(Xor, Esi, Eax);
+                self.asm.arithmetic_immediate(And, Esi, HF as u32);
+                self.asm.arithmetic(Or, Edi, Esi);
+                // `cp` takes the undocumented flags from the operand
+                let xy_source = if a == Z::Cp { Edx } else { Eax };
+                self.flags_from(xy_source, XF | YF);
+                self.overflow(x86_op == Sub, 5);
+                if x86_op == Sub {
+                    self.asm.arithmetic_immediate(Or, Edi, NF as u32);
+                }
+                self.flags_szp(SF | ZF);
+            }
+        }
+        if a != Z::Cp {
+            self.asm.store8(reg8(A), Eax);
+        }
+        self.store_flags();
+    }
+
+    /// Increment or decrement `operand`.
+    fn inc_dec(&mut self, operand: Operand, decrement: bool) {
+        use assembler::Arithmetic::*;
+        self.load(operand);
+        self.asm.mov(Ecx, Eax);
+        let (op, overflow_from) = if decrement { (Sub, 0x80) } else { (Add, 0x7F) };
+        self.asm.arithmetic_immediate(op, Eax, 1);
+        self.flags_keep(CF);
+        self.asm.mov(Esi, Ecx);
+        self.asm.arithmetic(Xor, Esi, Eax);
+        self.asm.arithmetic_immediate(And, Esi, HF as u32);
+        self.asm.arithmetic(Or, Edi, Esi);
+        self.asm.arithmetic_immediate(Cmp, Ecx, overflow_from);
+        self.asm.set(Condition::Equal, Edx);
+        self.asm.shl(Edx, 2);
+        self.asm.arithmetic(Or, Edi, Edx);
+        if decrement {
+            self.asm.arithmetic_immediate(Or, Edi, NF as u32);
+        }
+        self.flags_from(Eax, XF | YF);
+        self.flags_szp(SF | ZF);
+        self.store_flags();
+        self.store(operand);
+    }
+
+    /// Rotate or shift `eax`, leaving the result in `eax` and the carry in
+    /// `edi`.
+    fn rotate(&mut self, rotation: Rotation) {
+        use assembler::Arithmetic::*;
+
+        let left = matches!(
+            rotation,
+            Rotation::Rlc | Rotation::Rl | Rotation::Sla | Rotation::Sll
+        );
+        self.asm.mov(Edi, Eax);
+        if left {
+            self.asm.shr(Edi, 7);
+            self.asm.shl(Eax, 1);
+        } else {
+            self.asm.arithmetic_immediate(And, Edi, 1);
+            self.asm.shr(Eax, 1);
+        }
+        match rotation {
+            Rotation::Rlc => self.asm.arithmetic(Or, Eax, Edi),
+            Rotation::Rrc => {
+                self.asm.mov(Ecx, Edi);
+                self.asm.shl(Ecx, 7);
+                self.asm.arithmetic(Or, Eax, Ecx);
+            }
+            Rotation::Rl | Rotation::Rr => {
+                self.asm.load8(Ecx, reg8(F));
+                self.asm.arithmetic_immediate(And, Ecx, CF as u32);
+                if !left {
+                    self.asm.shl(Ecx, 7);
+                }
+                self.asm.arithmetic(Or, Eax, Ecx);
+            }
+            Rotation::Sra => {
+                // the sign bit stays put
+                self.asm.mov(Ecx, Eax);
+                self.asm.arithmetic_immediate(And, Ecx, 0x40);
+                self.asm.shl(Ecx, 1);
+                self.asm.arithmetic(Or, Eax, Ecx);
+            }
+            Rotation::Sll => self.asm.arithmetic_immediate(Or, Eax, 1),
+            Rotation::Sla | Rotation::Srl => {}
+        }
+        self.asm.arithmetic_immediate(And, Eax, 0xFF);
+    }
+
+    /// Push the registers `high` and `low`.
+    fn push(&mut self, high: Reg8, low: Reg8) {
+        self.stack_address(0xFFFF);
+        self.asm.load8(Edx, reg8(high));
+        self.write();
+        self.stack_address(0xFFFE);
+        self.asm.load8(Edx, reg8(low));
+        self.write();
+        self.add_sp(0xFFFE);
+    }
+
+    fn pop(&mut self, r: Reg16) {
+        let (high, low) = halves(r);
+        self.stack_address(0);
+        self.read();
+        self.asm.store8(reg8(low), Eax);
+        self.stack_address(1);
+        self.read();
+        self.asm.store8(reg8(high), Eax);
+        self.add_sp(2);
+    }
+
+    /// Emit a jump that's taken if `cc` is false.
+    fn unless(&mut self, cc: ConditionCode) -> x86::Fixup {
+        let (mask, set) = match cc {
+            NZcc => (ZF, false),
+            Zcc => (ZF, true),
+            NCcc => (CF, false),
+            Ccc => (CF, true),
+            POcc => (PF, false),
+            PEcc => (PF, true),
+            Pcc => (SF, false),
+            Mcc => (SF, true),
+        };
+        self.asm.load8(Eax, reg8(F));
+        self.asm.test_immediate(Eax, mask as u32);
+        self.asm.jump_if(if set {
+            Condition::Equal
+        } else {
+            Condition::NotEqual
+        })
+    }
+
+    /// Jump to `target`, setting MEMPTR to it.
+    fn jump(&mut self, target: u16) {
+        self.asm.store16_immediate(reg16(PC), target);
+        self.asm.store16_immediate(MEMPTR, target);
+    }
+
+    /// A branch, which ends the block. PC and the cycles have already been
+    /// set as though it weren't taken.
+    fn branch(&mut self, instruction: &Instruction) {
+        use decode::Op::*;
+
+        let extra_cycles = instruction.taken_cycles - instruction.cycles;
+        let skip = match instruction.op {
+            Jp(Some(cc), _) | Jr(Some(cc), _) | Call(Some(cc), _) | Ret(Some(cc)) => {
+                if let Call(_, nn) = instruction.op {
+                    self.asm.store16_immediate(MEMPTR, nn);
+                }
+                if let Jp(_, nn) = instruction.op {
+                    self.asm.store16_immediate(MEMPTR, nn);
+                }
+                Some(self.unless(cc))
+            }
+            Djnz(_) => {
+                self.asm.load8(Eax, reg8(B));
+                self.asm.arithmetic_immediate(x86::Arithmetic::Sub, Eax, 1);
+                self.asm.store8(reg8(B), Eax);
+                self.asm.test_al();
+                Some(self.asm.jump_if(Condition::Equal))
+            }
+            _ => None,
+        };
+        if extra_cycles != 0 {
+            self.asm.add64_immediate(CYCLES, extra_cycles as u32);
+        }
+        let (high, low) = halves(PC);
+        match instruction.op {
+            Jp(_, nn) | Jr(_, nn) | Djnz(nn) => self.jump(nn),
+            JpRegister(r) => {
+                self.asm.load16(Eax, reg16(r));
+                self.asm.store16(reg16(PC), Eax);
+            }
+            Call(_, nn) | Rst(nn) => {
+                // PC is already the return address
+                self.push(high, low);
+                self.jump(nn);
+            }
+            Ret(_) => {
+                self.pop(PC);
+                self.asm.load16(Eax, reg16(PC));
+                self.asm.store16(MEMPTR, Eax);
+            }
+            _ => unreachable!(),
+        }
+        if let Some(skip) = skip {
+            self.asm.bind(skip);
+        }
+    }
+}
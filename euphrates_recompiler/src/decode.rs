@@ -0,0 +1,530 @@
+//! Decoding the Z80 instructions the compiler knows how to translate.
+
+use euphrates::hardware::z80::ConditionCode::*;
+use euphrates::hardware::z80::Reg16::*;
+use euphrates::hardware::z80::Reg8::*;
+use euphrates::hardware::z80::{ConditionCode, Reg16, Reg8};
+
+/// A byte in memory, addressed the way one of the Z80's addressing modes does
+/// it.
+///
+/// The addressing mode matters beyond the address: it decides what happens to
+/// MEMPTR.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Memory {
+    /// `(bc)`, `(de)`, or `(hl)`.
+    Register(Reg16),
+
+    /// `(ix+d)` or `(iy+d)`.
+    Index(Reg16, i8),
+
+    /// `(nn)`.
+    Absolute(u16),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operand {
+    Register(Reg8),
+    Immediate(u8),
+    Memory(Memory),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arithmetic {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+const ARITHMETIC: [Arithmetic; 8] = [
+    Arithmetic::Add,
+    Arithmetic::Adc,
+    Arithmetic::Sub,
+    Arithmetic::Sbc,
+    Arithmetic::And,
+    Arithmetic::Xor,
+    Arithmetic::Or,
+    Arithmetic::Cp,
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rotation {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Sll,
+    Srl,
+}
+
+const ROTATIONS: [Rotation; 8] = [
+    Rotation::Rlc,
+    Rotation::Rrc,
+    Rotation::Rl,
+    Rotation::Rr,
+    Rotation::Sla,
+    Rotation::Sra,
+    Rotation::Sll,
+    Rotation::Srl,
+];
+
+const CONDITIONS: [ConditionCode; 8] = [NZcc, Zcc, NCcc, Ccc, POcc, PEcc, Pcc, Mcc];
+
+/// What an instruction does, with its operands.
+///
+/// Jump targets are absolute addresses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    Nop,
+    Ld(Operand, Operand),
+    Ld16Immediate(Reg16, u16),
+    Ld16Load(Reg16, u16),
+    Ld16Store(u16, Reg16),
+    LdSp(Reg16),
+    Inc16(Reg16),
+    Dec16(Reg16),
+    Inc(Operand),
+    Dec(Operand),
+    Arithmetic(Arithmetic, Operand),
+    Neg,
+    Cpl,
+
+    /// `rlca`, `rrca`, `rla`, or `rra`.
+    RotateA(Rotation),
+    Rotate(Rotation, Operand),
+    Bit(u8, Operand),
+    Res(u8, Operand),
+    Set(u8, Operand),
+    Add16(Reg16, Reg16),
+    Adc16(Reg16),
+    Sbc16(Reg16),
+    Push(Reg16),
+    Pop(Reg16),
+    ExDeHl,
+    Exx,
+
+    /// `ex (sp), hl` and friends.
+    ExSp(Reg16),
+    Jp(Option<ConditionCode>, u16),
+
+    /// `jp (hl)` and friends.
+    JpRegister(Reg16),
+    Jr(Option<ConditionCode>, u16),
+    Djnz(u16),
+    Call(Option<ConditionCode>, u16),
+    Ret(Option<ConditionCode>),
+    Rst(u16),
+}
+
+impl Op {
+    /// Does this instruction set any flags?
+    ///
+    /// That decides Q for the instruction after it.
+    pub fn writes_flags(&self) -> bool {
+        use self::Op::*;
+        matches!(
+            *self,
+            Inc(_) | Dec(_) | Arithmetic(..) | Neg | Cpl | RotateA(_) | Rotate(..) | Bit(..)
+                | Add16(..) | Adc16(_) | Sbc16(_)
+        )
+    }
+
+    /// Might this instruction jump anywhere?
+    pub fn branches(&self) -> bool {
+        use self::Op::*;
+        matches!(
+            *self,
+            Jp(..) | JpRegister(_) | Jr(..) | Djnz(_) | Call(..) | Ret(_) | Rst(_)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+
+    /// The address of the byte after the instruction.
+    pub next: u16,
+
+    /// How many bytes long?
+    pub len: u16,
+
+    /// How many cycles it takes, or, for a conditional branch, how many when
+    /// the branch isn't taken.
+    pub cycles: u64,
+
+    /// How many cycles a conditional branch takes when it's taken.
+    pub taken_cycles: u64,
+
+    /// The interpreter executes a DD or FD prefix in a separate step from the
+    /// rest of the instruction. This is how many cycles after the instruction
+    /// begins that the interpreter begins its last step.
+    pub last_step: u64,
+
+    /// How much the instruction increments R by: once for each opcode fetch,
+    /// so 2 with a prefix (even for DDCB and FDCB instructions) and 1
+    /// otherwise.
+    pub refresh: u8,
+}
+
+/// Decode the instruction at `address`, if it's one we can compile.
+///
+/// `read` gives the byte at an address, or `None` if that byte shouldn't be
+/// part of the instruction.
+///
+/// The cycles of each instruction are the interpreter's, even where the
+/// interpreter is wrong, since compiled code must keep exactly in step with
+/// it.
+pub fn decode<F>(read: &mut F, address: u16) -> Option<Instruction>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    let mut reader = Reader {
+        read,
+        address,
+        len: 0,
+    };
+    let opcode = reader.byte()?;
+    let (op, cycles, taken_cycles, last_step, refresh) = match opcode {
+        0xCB => {
+            let (op, cycles) = cb(&mut reader)?;
+            (op, cycles, cycles, 0, 2)
+        }
+        0xED => {
+            let (op, cycles) = ed(&mut reader)?;
+            (op, cycles, cycles, 0, 2)
+        }
+        0xDD => {
+            let (op, cycles) = index(&mut reader, IX)?;
+            (op, cycles + 4, cycles + 4, 4, 2)
+        }
+        0xFD => {
+            let (op, cycles) = index(&mut reader, IY)?;
+            (op, cycles + 4, cycles + 4, 4, 2)
+        }
+        _ => {
+            let (op, cycles, taken_cycles) = noprefix(&mut reader, opcode)?;
+            (op, cycles, taken_cycles, 0, 1)
+        }
+    };
+    Some(Instruction {
+        op,
+        next: reader.address,
+        len: reader.len,
+        cycles,
+        taken_cycles,
+        last_step,
+        refresh,
+    })
+}
+
+struct Reader<'a, F: 'a> {
+    read: &'a mut F,
+    address: u16,
+    len: u16,
+}
+
+impl<'a, F> Reader<'a, F>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    fn byte(&mut self) -> Option<u8> {
+        let x = (self.read)(self.address)?;
+        self.address = self.address.wrapping_add(1);
+        self.len += 1;
+        Some(x)
+    }
+
+    fn word(&mut self) -> Option<u16> {
+        let lo = self.byte()?;
+        let hi = self.byte()?;
+        Some(lo as u16 | (hi as u16) << 8)
+    }
+
+    /// Read a displacement and return the address it's relative to.
+    fn relative(&mut self) -> Option<u16> {
+        let e = self.byte()? as i8;
+        Some(self.address.wrapping_add(e as i16 as u16))
+    }
+}
+
+/// The operands of the form `r[z]`: `b`, `c`, `d`, `e`, `h`, `l`, `(hl)`, `a`.
+fn register(z: u8) -> Operand {
+    match z {
+        0 => Operand::Register(B),
+        1 => Operand::Register(C),
+        2 => Operand::Register(D),
+        3 => Operand::Register(E),
+        4 => Operand::Register(H),
+        5 => Operand::Register(L),
+        6 => Operand::Memory(Memory::Register(HL)),
+        _ => Operand::Register(A),
+    }
+}
+
+/// The register pairs of the form `rp[p]`, with `hl` replaced by `hl`.
+fn pair(p: u8, hl: Reg16) -> Reg16 {
+    [BC, DE, hl, SP][p as usize]
+}
+
+/// The register pairs of the form `rp2[p]`, with `hl` replaced by `hl`.
+fn pair2(p: u8, hl: Reg16) -> Reg16 {
+    [BC, DE, hl, AF][p as usize]
+}
+
+/// Returns the op, its cycles, and its cycles if it's a branch that's taken.
+fn noprefix<F>(r: &mut Reader<F>, opcode: u8) -> Option<(Op, u64, u64)>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    use self::Op::*;
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let p = y >> 1;
+    let q = y & 1;
+    let memory = |i| i == 6;
+
+    let (op, cycles) = match (x, z) {
+        (0, 0) => match y {
+            0 => (Nop, 4),
+            // the interpreter's `ex af, af'` is wrong, and we don't copy it
+            1 => return None,
+            2 => {
+                let target = r.relative()?;
+                return Some((Djnz(target), 8, 13));
+            }
+            3 => (Jr(None, r.relative()?), 12),
+            _ => {
+                let target = r.relative()?;
+                return Some((Jr(Some(CONDITIONS[y as usize - 4]), target), 7, 12));
+            }
+        },
+        (0, 1) if q == 0 => (Ld16Immediate(pair(p, HL), r.word()?), 10),
+        (0, 1) => (Add16(HL, pair(p, HL)), 11),
+        (0, 2) => {
+            let a = Operand::Register(A);
+            match (q, p) {
+                (0, 0) => (Ld(Operand::Memory(Memory::Register(BC)), a), 7),
+                (0, 1) => (Ld(Operand::Memory(Memory::Register(DE)), a), 7),
+                (0, 2) => (Ld16Store(r.word()?, HL), 16),
+                (0, _) => (Ld(Operand::Memory(Memory::Absolute(r.word()?)), a), 13),
+                (_, 0) => (Ld(a, Operand::Memory(Memory::Register(BC))), 7),
+                (_, 1) => (Ld(a, Operand::Memory(Memory::Register(DE))), 7),
+                (_, 2) => (Ld16Load(HL, r.word()?), 16),
+                (_, _) => (Ld(a, Operand::Memory(Memory::Absolute(r.word()?))), 13),
+            }
+        }
+        (0, 3) if q == 0 => (Inc16(pair(p, HL)), 6),
+        (0, 3) => (Dec16(pair(p, HL)), 6),
+        (0, 4) => (Inc(register(y)), if memory(y) { 11 } else { 4 }),
+        (0, 5) => (Dec(register(y)), if memory(y) { 11 } else { 4 }),
+        (0, 6) => (
+            Ld(register(y), Operand::Immediate(r.byte()?)),
+            if memory(y) { 10 } else { 7 },
+        ),
+        (0, _) => match y {
+            0 => (RotateA(Rotation::Rlc), 4),
+            1 => (RotateA(Rotation::Rrc), 4),
+            2 => (RotateA(Rotation::Rl), 4),
+            3 => (RotateA(Rotation::Rr), 4),
+            5 => (Cpl, 4),
+            // daa, scf, and ccf
+            _ => return None,
+        },
+        (1, _) if memory(y) && memory(z) => return None,
+        (1, _) => (
+            Ld(register(y), register(z)),
+            if memory(y) || memory(z) { 7 } else { 4 },
+        ),
+        (2, _) => (
+            Arithmetic(ARITHMETIC[y as usize], register(z)),
+            if memory(z) { 7 } else { 4 },
+        ),
+        (_, 0) => return Some((Ret(Some(CONDITIONS[y as usize])), 5, 11)),
+        (_, 1) => match (q, p) {
+            (0, _) => (Pop(pair2(p, HL)), 10),
+            (_, 0) => (Ret(None), 10),
+            (_, 1) => (Exx, 4),
+            (_, 2) => (JpRegister(HL), 4),
+            (_, _) => (LdSp(HL), 6),
+        },
+        (_, 2) => (Jp(Some(CONDITIONS[y as usize]), r.word()?), 10),
+        (_, 3) => match y {
+            0 => (Jp(None, r.word()?), 10),
+            4 => (ExSp(HL), 19),
+            5 => (ExDeHl, 4),
+            // CB, in, out, di, and ei
+            _ => return None,
+        },
+        (_, 4) => {
+            let target = r.word()?;
+            return Some((Call(Some(CONDITIONS[y as usize]), target), 10, 17));
+        }
+        (_, 5) => match (q, p) {
+            (0, _) => (Push(pair2(p, HL)), 11),
+            (_, 0) => (Call(None, r.word()?), 17),
+            // DD, ED, and FD
+            _ => return None,
+        },
+        (_, 6) => (
+            Arithmetic(ARITHMETIC[y as usize], Operand::Immediate(r.byte()?)),
+            7,
+        ),
+        (_, _) => (Rst(y as u16 * 8), 11),
+    };
+    Some((op, cycles, cycles))
+}
+
+/// Decode the rest of an instruction with the CB prefix.
+fn cb<F>(r: &mut Reader<F>) -> Option<(Op, u64)>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    let opcode = r.byte()?;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let operand = register(z);
+    let memory = z == 6;
+    Some(match opcode >> 6 {
+        0 => (
+            Op::Rotate(ROTATIONS[y as usize], operand),
+            if memory { 15 } else { 8 },
+        ),
+        1 => (Op::Bit(y, operand), if memory { 12 } else { 8 }),
+        2 => (Op::Res(y, operand), if memory { 15 } else { 8 }),
+        _ => (Op::Set(y, operand), if memory { 15 } else { 8 }),
+    })
+}
+
+/// Decode the rest of an instruction with the ED prefix.
+///
+/// Only the 16 bit arithmetic and loads and `neg` are supported.
+fn ed<F>(r: &mut Reader<F>) -> Option<(Op, u64)>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    let opcode = r.byte()?;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let p = y >> 1;
+    let q = y & 1;
+    if opcode >> 6 != 1 {
+        return None;
+    }
+    Some(match (z, q) {
+        (2, 0) => (Op::Sbc16(pair(p, HL)), 15),
+        (2, _) => (Op::Adc16(pair(p, HL)), 15),
+        (3, 0) => (Op::Ld16Store(r.word()?, pair(p, HL)), 20),
+        (3, _) => (Op::Ld16Load(pair(p, HL), r.word()?), 20),
+        (4, _) => (Op::Neg, 8),
+        _ => return None,
+    })
+}
+
+/// Decode the rest of an instruction with the DD or FD prefix, using index
+/// register `xy`.
+///
+/// Only instructions that use the index register are supported. The cycles
+/// returned don't include the 4 of the prefix.
+fn index<F>(r: &mut Reader<F>, xy: Reg16) -> Option<(Op, u64)>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    use self::Op::*;
+
+    let (xyh, xyl) = if xy == IX { (IXH, IXL) } else { (IYH, IYL) };
+    // `h` and `l` become the halves of the index register
+    let half = |operand| match operand {
+        Operand::Register(H) => Operand::Register(xyh),
+        Operand::Register(L) => Operand::Register(xyl),
+        operand => operand,
+    };
+
+    let opcode = r.byte()?;
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let p = y >> 1;
+    let q = y & 1;
+
+    Some(match (x, z) {
+        (0, 1) if q == 0 && p == 2 => (Ld16Immediate(xy, r.word()?), 10),
+        (0, 1) if q == 1 => (Add16(xy, pair(p, xy)), 11),
+        (0, 2) if p == 2 && q == 0 => (Ld16Store(r.word()?, xy), 16),
+        (0, 2) if p == 2 => (Ld16Load(xy, r.word()?), 16),
+        (0, 3) if p == 2 && q == 0 => (Inc16(xy), 6),
+        (0, 3) if p == 2 => (Dec16(xy), 6),
+        (0, 4) | (0, 5) | (0, 6) if y == 6 => {
+            let d = r.byte()? as i8;
+            let operand = Operand::Memory(Memory::Index(xy, d));
+            match z {
+                4 => (Inc(operand), 19),
+                5 => (Dec(operand), 19),
+                _ => (Ld(operand, Operand::Immediate(r.byte()?)), 15),
+            }
+        }
+        (0, 4) if p == 2 => (Inc(half(register(y))), 4),
+        (0, 5) if p == 2 => (Dec(half(register(y))), 4),
+        (0, 6) if p == 2 => (Ld(half(register(y)), Operand::Immediate(r.byte()?)), 7),
+        (0, _) => return None,
+        (1, 6) if y == 6 => return None,
+        (1, 6) => {
+            let d = r.byte()? as i8;
+            (Ld(register(y), Operand::Memory(Memory::Index(xy, d))), 15)
+        }
+        (1, _) if y == 6 => {
+            let d = r.byte()? as i8;
+            (Ld(Operand::Memory(Memory::Index(xy, d)), register(z)), 15)
+        }
+        (1, _) => (Ld(half(register(y)), half(register(z))), 4),
+        (2, 6) => {
+            let d = r.byte()? as i8;
+            (
+                Arithmetic(ARITHMETIC[y as usize], Operand::Memory(Memory::Index(xy, d))),
+                15,
+            )
+        }
+        (2, _) => (Arithmetic(ARITHMETIC[y as usize], half(register(z))), 4),
+        (3, 3) if opcode == 0xCB => index_cb(r, xy)?,
+        (3, _) => match opcode {
+            0xE1 => (Pop(xy), 10),
+            0xE3 => (ExSp(xy), 19),
+            0xE5 => (Push(xy), 11),
+            0xE9 => (JpRegister(xy), 4),
+            0xF9 => (LdSp(xy), 6),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Decode the rest of an instruction with the DDCB or FDCB prefix.
+///
+/// The undocumented forms that also copy the result to a register aren't
+/// supported.
+fn index_cb<F>(r: &mut Reader<F>, xy: Reg16) -> Option<(Op, u64)>
+where
+    F: FnMut(u16) -> Option<u8>,
+{
+    let d = r.byte()? as i8;
+    let opcode = r.byte()?;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let operand = Operand::Memory(Memory::Index(xy, d));
+    Some(match opcode >> 6 {
+        1 => (Op::Bit(y, operand), 16),
+        _ if z != 6 => return None,
+        0 => (Op::Rotate(ROTATIONS[y as usize], operand), 19),
+        2 => (Op::Res(y, operand), 19),
+        _ => (Op::Set(y, operand), 19),
+    })
+}
@@ -0,0 +1,159 @@
+//! A recompiler for the Master System's Z80, translating blocks of Z80 code to
+//! x86-64.
+//!
+//! A `Recompiler` is an alternative to the interpreter in the `euphrates`
+//! crate, `Z80RunImpler`, for systems whose memory implements `SmsMemory`. It
+//! compiles straight-line runs of instructions ending at a branch, and leaves
+//! everything else (interrupts, I/O, `halt`, block instructions, and so on) to
+//! the interpreter. Compiled code keeps exactly the interpreter's count of
+//! cycles and stops where the interpreter would, so the VDP and the sound chip
+//! see the same timing either way.
+//!
+//! Blocks are found by the page mapped where they start, so after a bank
+//! switch through `SmsMemory::map_page`, code from the page switched out isn't
+//! run. A block compiled from RAM is forgotten when any byte it was compiled
+//! from is written.
+//!
+//! On hosts other than x86-64 Unix, a `Recompiler` just runs the interpreter;
+//! see `Recompiler::supported`.
+
+#![deny(bare_trait_objects, anonymous_parameters)]
+
+extern crate euphrates;
+#[cfg(all(unix, target_arch = "x86_64"))]
+extern crate euphrates_virtual_memory;
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod assembler;
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod cache;
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod compile;
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod decode;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::sms_memory::SmsMemory;
+use euphrates::hardware::z80::{Z80Irq, Z80Memo, Z80Run, Z80State};
+use euphrates::memo::Inbox;
+use euphrates::systems::sms::SmsZ80Runner;
+
+/// Compiled code, and what's needed to know when it's out of date.
+///
+/// A `Recompiler` should only ever run code from one memory, and it needs to
+/// see every write to that memory; after anything else changes the memory,
+/// call `invalidate`.
+pub struct Recompiler {
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    cache: cache::Cache,
+}
+
+impl Default for Recompiler {
+    #[inline]
+    fn default() -> Self {
+        Recompiler::new()
+    }
+}
+
+impl Recompiler {
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    pub fn new() -> Self {
+        Recompiler {
+            cache: cache::Cache::new(),
+        }
+    }
+
+    #[cfg(not(all(unix, target_arch = "x86_64")))]
+    pub fn new() -> Self {
+        Recompiler {}
+    }
+
+    /// Can this host run compiled code?
+    ///
+    /// If not, a `Recompiler` runs the interpreter.
+    #[inline]
+    pub fn supported() -> bool {
+        cfg!(all(unix, target_arch = "x86_64"))
+    }
+
+    /// Forget all compiled code.
+    #[inline]
+    pub fn invalidate(&mut self) {
+        #[cfg(all(unix, target_arch = "x86_64"))]
+        self.cache.invalidate();
+    }
+}
+
+/// Implements `Z80Run` with a `Recompiler`.
+pub struct RecompilerImpler<
+    'a,
+    M: 'a + ?Sized,
+    Irq: 'a + ?Sized,
+    I: 'a + ?Sized,
+    Inb: 'a + ?Sized,
+> {
+    pub recompiler: &'a mut Recompiler,
+    pub z80: &'a mut Z80State,
+    pub memory: &'a mut M,
+    pub io: &'a mut I,
+    pub irq: &'a mut Irq,
+    pub inbox: &'a mut Inb,
+}
+
+impl<'a, M, Irq, I, Inb> Z80Run for RecompilerImpler<'a, M, Irq, I, Inb>
+where
+    M: 'a + ?Sized + SmsMemory,
+    Irq: 'a + ?Sized + Z80Irq,
+    I: 'a + ?Sized + Io16,
+    Inb: 'a + ?Sized + Inbox<Memo = Z80Memo>,
+{
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    fn run(&mut self, target_cycles: u64) {
+        self.recompiler.cache.run(
+            self.z80,
+            self.memory,
+            self.io,
+            self.irq,
+            self.inbox,
+            target_cycles,
+        );
+    }
+
+    #[cfg(not(all(unix, target_arch = "x86_64")))]
+    fn run(&mut self, target_cycles: u64) {
+        use euphrates::hardware::z80::Z80RunImpler;
+        Z80RunImpler {
+            z80: self.z80,
+            memory: self.memory,
+            io: self.io,
+            irq: self.irq,
+            inbox: self.inbox,
+        }.run(target_cycles);
+    }
+}
+
+impl SmsZ80Runner for Recompiler {
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        RecompilerImpler {
+            recompiler: self,
+            z80,
+            memory,
+            io,
+            irq,
+            inbox,
+        }.run(target_cycles);
+    }
+
+    #[inline]
+    fn invalidate(&mut self) {
+        Recompiler::invalidate(self);
+    }
+}
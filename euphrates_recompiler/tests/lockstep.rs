@@ -0,0 +1,83 @@
+//! Run systems with the recompiler in lockstep with systems running the
+//! interpreter, and check that their states never differ.
+
+extern crate euphrates;
+extern crate euphrates_recompiler;
+extern crate euphrates_testing;
+
+use euphrates::systems::sms::{LockstepOptions, Sms};
+use euphrates_recompiler::Recompiler;
+use euphrates_testing::{bank_switching_rom, check_bank_switching, lockstep, random_rom, rom};
+
+/// Run `rom` for `frames` frames on the interpreter and on the recompiler,
+/// panic if they ever differ, and return the system with the recompiler.
+fn recompiler_lockstep(rom: &[u8], frames: usize) -> Box<dyn Sms + Send> {
+    lockstep(
+        rom,
+        frames,
+        Box::new(Recompiler::new()),
+        &LockstepOptions::default(),
+    )
+}
+
+#[test]
+fn random_code() {
+    for seed in 1..=8u64 {
+        let rom = random_rom(seed, |rng| rng.next_u64() as u8);
+        recompiler_lockstep(&rom, 30);
+    }
+}
+
+#[test]
+fn self_modifying_code() {
+    let rom = rom(
+        0x8000,
+        &[
+            (
+                0x0000,
+                &[
+                    0xF3, // di
+                    0x31, 0xF0, 0xDF, // ld sp, 0xDFF0
+                    0x21, 0x40, 0x00, // ld hl, 0x0040
+                    0x11, 0x00, 0xC1, // ld de, 0xC100
+                    0x01, 0x0B, 0x00, // ld bc, 11
+                    0xED, 0xB0, // ldir
+                    0x06, 0xC8, // ld b, 200
+                    // loop:
+                    0xCD, 0x00, 0xC1, // call 0xC100
+                    0x32, 0x00, 0xC0, // ld (0xC000), a
+                    0x21, 0x01, 0xC1, // ld hl, 0xC101
+                    0x34, // inc (hl)
+                    0x10, 0xF4, // djnz loop
+                    0xCD, 0x04, 0xC1, // call 0xC104
+                    0x32, 0x01, 0xC0, // ld (0xC001), a
+                    0x76, // halt
+                ],
+            ),
+            (
+                0x0040,
+                &[
+                    // 0xC100: the immediate is changed after each call
+                    0x3E, 0x00, // ld a, 0
+                    0x3C, // inc a
+                    0xC9, // ret
+                    // 0xC104: changes an instruction later in the same block
+                    0xAF, // xor a
+                    0x32, 0x09, 0xC1, // ld (0xC109), a
+                    0x3C, // inc a
+                    0x3C, // inc a, which becomes nop
+                    0xC9, // ret
+                ],
+            ),
+        ],
+    );
+    let sms = recompiler_lockstep(&rom, 3);
+    assert_eq!(sms.sms_memory().system_ram_read(0), 200);
+    assert_eq!(sms.sms_memory().system_ram_read(1), 1);
+}
+
+#[test]
+fn bank_switching() {
+    let sms = recompiler_lockstep(&bank_switching_rom(), 3);
+    check_bank_switching(&*sms);
+}
@@ -3,7 +3,7 @@ use std;
 use failure::Error;
 use sdl2;
 
-use euphrates::host_multimedia::{SimpleColor, SimpleGraphics};
+use euphrates::host_multimedia::{HasLastFrame, SimpleColor, SimpleGraphics};
 
 const DEFAULT_SIZE: usize = 256;
 
@@ -139,6 +139,8 @@ impl Window {
     }
 }
 
+impl HasLastFrame for Window {}
+
 impl SimpleGraphics for Window {
     #[inline]
     fn set_resolution(&mut self, width: u32, height: u32) -> Result<(), Error> {
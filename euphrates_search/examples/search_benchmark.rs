@@ -0,0 +1,101 @@
+//! Play a game for a few steps with each kind of search, and report how fast
+//! the snapshot/step pipeline runs.
+//!
+//! Usage: `search_benchmark PATH_TO_ROM [PATH_TO_TOML]`
+//!
+//! Without a TOML file, every state has value 0, which is still a fair test of
+//! speed.
+
+extern crate euphrates;
+extern crate euphrates_search;
+extern crate rand;
+
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::process;
+use std::sync::Arc;
+use std::time::Instant;
+
+use euphrates::systems::sms::{
+    new_headless_sms, FakeSmsGraphics, GameDefinition, GameMonitor, Kind, Meaning,
+    PointerSmsMemory, Sms, SmsMemoryMapper, SmsState, TvSystem, TypeWrap,
+};
+use euphrates_search::{
+    advance, BeamSearch, Mcts, SearchResult, ValueFunction, WeightedVariables,
+};
+
+/// How many actions to choose with each search.
+const STEPS: usize = 10;
+
+fn fail<E: Display>(e: E) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+fn play<F>(name: &str, sms: &mut dyn Sms, value: &mut dyn ValueFunction, mut search: F)
+where
+    F: FnMut(&mut dyn Sms, &mut dyn ValueFunction) -> SearchResult,
+{
+    let start = Instant::now();
+    let mut frames = 0;
+    let mut result = SearchResult::default();
+    for _ in 0..STEPS {
+        result = search(sms, value);
+        frames += result.frames;
+        if let Some(&input) = result.inputs.first() {
+            advance(sms, input, 1).unwrap_or_else(|e| fail(e));
+            frames += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    println!(
+        "{}: {} frames in {:.3} s ({:.0} frames per second); last value {}",
+        name,
+        frames,
+        seconds,
+        frames as f64 / seconds,
+        result.value
+    );
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        fail("Usage: search_benchmark PATH_TO_ROM [PATH_TO_TOML]");
+    }
+
+    let rom = fs::read(&args[1]).unwrap_or_else(|e| fail(e));
+    let state = SmsState::from_rom(
+        Arc::new(rom.into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    let mut sms = new_headless_sms(state, FakeSmsGraphics, TypeWrap::<PointerSmsMemory>::default())
+        .unwrap_or_else(|e| fail(e));
+
+    let mut value: Box<dyn ValueFunction> = match args.get(2) {
+        Some(path) => {
+            let definition = GameDefinition::from_file(path).unwrap_or_else(|e| fail(e));
+            let monitor = GameMonitor::new(definition).unwrap_or_else(|e| fail(e));
+            Box::new(WeightedVariables::with_meaning(monitor, Meaning::Score))
+        }
+        None => Box::new(|_: &mut dyn Sms| 0.0),
+    };
+
+    let start = sms.state();
+
+    let beam = BeamSearch::default();
+    play("beam search", &mut *sms, &mut *value, |sms, value| {
+        beam.search(sms, value).unwrap_or_else(|e| fail(e))
+    });
+
+    sms.restore_state(&start).unwrap_or_else(|e| fail(e));
+    let mcts = Mcts::default();
+    let mut rng = rand::thread_rng();
+    play("Monte Carlo tree search", &mut *sms, &mut *value, |sms, value| {
+        mcts.search(sms, value, &mut rng).unwrap_or_else(|e| fail(e))
+    });
+}
@@ -0,0 +1,104 @@
+use euphrates::systems::sms::{ActionSpace, Sms, SmsEmulationError, SmsState};
+
+use super::*;
+
+/// Beam search over sequences of actions.
+///
+/// Starting from the current state, each round tries every action from each
+/// state in the beam, and keeps the `width` resulting states with the highest
+/// value. After `depth` rounds, the best sequence in the beam is the result.
+///
+/// This emulates `width * depth * action_space.len() * frames_per_action`
+/// frames, give or take the first round.
+#[derive(Clone, Debug)]
+pub struct BeamSearch {
+    pub action_space: ActionSpace,
+
+    /// How many sequences to keep after each round.
+    pub width: usize,
+
+    /// How many actions long the sequences are.
+    pub depth: usize,
+
+    /// How many frames to hold each action down for.
+    pub frames_per_action: u32,
+}
+
+impl Default for BeamSearch {
+    fn default() -> Self {
+        BeamSearch {
+            action_space: ActionSpace::joypad1(),
+            width: 8,
+            depth: 8,
+            frames_per_action: 4,
+        }
+    }
+}
+
+struct Candidate {
+    actions: Vec<usize>,
+    state: SmsState,
+    value: f64,
+}
+
+impl BeamSearch {
+    /// Search ahead from the current state of `sms`.
+    ///
+    /// When this returns, `sms` is back in the state it was in when called.
+    ///
+    /// Panics if the action space is empty, or if `width`, `depth`, or
+    /// `frames_per_action` is 0.
+    pub fn search<V>(
+        &self,
+        sms: &mut dyn Sms,
+        value: &mut V,
+    ) -> Result<SearchResult, SmsEmulationError>
+    where
+        V: ValueFunction + ?Sized,
+    {
+        assert!(!self.action_space.is_empty(), "empty action space");
+        assert!(self.width > 0, "beam width must be positive");
+        assert!(self.depth > 0, "search depth must be positive");
+
+        let mut root = Root::new(sms);
+        let mut beam = vec![Candidate {
+            actions: Vec::new(),
+            state: root.state.clone(),
+            value: 0.0,
+        }];
+
+        for _ in 0..self.depth {
+            let mut next = Vec::with_capacity(beam.len() * self.action_space.len());
+            for candidate in beam.iter() {
+                for (action, &input) in self.action_space.actions().iter().enumerate() {
+                    root.restore(&candidate.state)?;
+                    root.advance(input, self.frames_per_action)?;
+                    let mut actions = candidate.actions.clone();
+                    actions.push(action);
+                    next.push(Candidate {
+                        actions,
+                        state: root.sms.state(),
+                        value: value.value(root.sms),
+                    });
+                }
+            }
+            // highest value first; NaNs last. The sort is stable, so among
+            // equal values the earliest action wins.
+            next.sort_by(|a, b| {
+                b.value
+                    .partial_cmp(&a.value)
+                    .unwrap_or_else(|| a.value.is_nan().cmp(&b.value.is_nan()))
+            });
+            next.truncate(self.width);
+            beam = next;
+        }
+
+        let best = beam.swap_remove(0);
+        Ok(SearchResult {
+            inputs: inputs(&self.action_space, &best.actions),
+            actions: best.actions,
+            value: best.value,
+            frames: root.frames,
+        })
+    }
+}
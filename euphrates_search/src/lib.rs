@@ -0,0 +1,240 @@
+//! Agents that choose player input for an emulated Master System by searching
+//! ahead with saved states.
+//!
+//! Both searches here take an `Sms` in some state, try sequences of actions
+//! from an `ActionSpace` by repeatedly restoring and stepping it, judge the
+//! resulting states with a `ValueFunction`, and leave the `Sms` in the state it
+//! started in.
+
+#![deny(bare_trait_objects, anonymous_parameters)]
+
+extern crate euphrates;
+extern crate rand;
+
+mod beam;
+mod mcts;
+
+use euphrates::systems::sms::{
+    ActionSpace, GameMonitor, Meaning, Sms, SmsEmulationError, SmsMemoryLoadError, SmsPlayerInput,
+    SmsState,
+};
+
+pub use beam::*;
+pub use mcts::*;
+
+/// How good is the current state of the system? Bigger is better.
+pub trait ValueFunction {
+    fn value(&mut self, sms: &mut dyn Sms) -> f64;
+}
+
+impl<F> ValueFunction for F
+where
+    F: FnMut(&mut dyn Sms) -> f64,
+{
+    #[inline]
+    fn value(&mut self, sms: &mut dyn Sms) -> f64 {
+        self(sms)
+    }
+}
+
+/// A `ValueFunction` adding up game variables, each multiplied by a weight.
+///
+/// Variables not defined by the monitor's `GameDefinition` count as 0.
+#[derive(Clone, Debug)]
+pub struct WeightedVariables {
+    monitor: GameMonitor,
+    weights: Vec<(String, f64)>,
+}
+
+impl WeightedVariables {
+    #[inline]
+    pub fn new(monitor: GameMonitor, weights: Vec<(String, f64)>) -> Self {
+        WeightedVariables { monitor, weights }
+    }
+
+    /// Weight every variable with the given `meaning` by 1, and ignore the
+    /// rest.
+    pub fn with_meaning(monitor: GameMonitor, meaning: Meaning) -> Self {
+        let weights = monitor
+            .definition()
+            .variables
+            .iter()
+            .filter(|&(_, v)| v.meaning == Some(meaning))
+            .map(|(name, _)| (name.clone(), 1.0))
+            .collect();
+        WeightedVariables { monitor, weights }
+    }
+
+    #[inline]
+    pub fn weights(&self) -> &[(String, f64)] {
+        &self.weights
+    }
+}
+
+impl ValueFunction for WeightedVariables {
+    fn value(&mut self, sms: &mut dyn Sms) -> f64 {
+        self.monitor.reset(sms.sms_memory());
+        let monitor = &self.monitor;
+        self.weights
+            .iter()
+            .map(|&(ref name, weight)| weight * monitor.value(name).unwrap_or(0.0))
+            .sum()
+    }
+}
+
+/// Emulate `frames` frames holding down `player_input`.
+///
+/// Only the last frame is rendered. Panics if `frames` is 0.
+pub fn advance(
+    sms: &mut dyn Sms,
+    player_input: SmsPlayerInput,
+    frames: u32,
+) -> Result<(), SmsEmulationError> {
+    assert!(frames > 0, "must advance at least one frame");
+    for _ in 1..frames {
+        sms.run_frame_without_rendering(player_input)?;
+    }
+    sms.run_frame(player_input)
+}
+
+/// The outcome of a search.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchResult {
+    /// The best sequence of actions found, as indices into the action space.
+    pub actions: Vec<usize>,
+
+    /// The player input corresponding to `actions`.
+    pub inputs: Vec<SmsPlayerInput>,
+
+    /// How good the search thinks `actions` are. For beam search this is the
+    /// value of the state they lead to; for Monte Carlo tree search it's the
+    /// average value seen after taking the first action.
+    pub value: f64,
+
+    /// How many frames were emulated during the search.
+    pub frames: u64,
+}
+
+fn inputs(action_space: &ActionSpace, actions: &[usize]) -> Vec<SmsPlayerInput> {
+    actions.iter().map(|&a| action_space.actions()[a]).collect()
+}
+
+/// Restores the system to the state it was in when the search started, even
+/// if the search fails partway through.
+struct Root<'a> {
+    sms: &'a mut dyn Sms,
+    state: SmsState,
+    frames: u64,
+}
+
+impl<'a> Root<'a> {
+    fn new(sms: &'a mut dyn Sms) -> Self {
+        let state = sms.state();
+        Root {
+            sms,
+            state,
+            frames: 0,
+        }
+    }
+
+    fn restore(&mut self, state: &SmsState) -> Result<(), SmsMemoryLoadError> {
+        self.sms.restore_state(state)
+    }
+
+    fn advance(
+        &mut self,
+        player_input: SmsPlayerInput,
+        frames: u32,
+    ) -> Result<(), SmsEmulationError> {
+        advance(self.sms, player_input, frames)?;
+        self.frames += frames as u64;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Root<'a> {
+    fn drop(&mut self) {
+        // if this fails, restoring states has been failing all along and the
+        // search has already returned the error
+        let _ = self.sms.restore_state(&self.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use euphrates::systems::sms::{
+        new_headless_sms, FakeSmsGraphics, Kind, SmsMemoryMapper, SmsMemoryState, TvSystem,
+        TypeWrap,
+    };
+    use rand::prng::XorShiftRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// A system that keeps writing the Joypad 1 buttons being pressed to the
+    /// first byte of RAM.
+    fn buttons_sms() -> Box<dyn Sms + Send> {
+        let mut rom = vec![0u8; 0x8000];
+        rom[..9].copy_from_slice(&[
+            0xF3, // DI
+            0xDB, 0xDC, // IN A, (0xDC)
+            0x2F, // CPL
+            0x32, 0x00, 0xC0, // LD (0xC000), A
+            0x18, 0xF8, // JR -8
+        ]);
+        let state = SmsState::from_rom(
+            Arc::new(rom.into_boxed_slice()),
+            SmsMemoryMapper::Sega,
+            TvSystem::Ntsc,
+            Kind::Sms,
+        );
+        new_headless_sms(state, FakeSmsGraphics, TypeWrap::<SmsMemoryState>::default()).unwrap()
+    }
+
+    fn buttons(sms: &mut dyn Sms) -> f64 {
+        sms.sms_memory().system_ram_read(0) as f64
+    }
+
+    // right, A, and B
+    const BEST: f64 = 0x38 as f64;
+
+    #[test]
+    fn beam() {
+        let mut sms = buttons_sms();
+        let start = sms.state();
+        let search = BeamSearch {
+            width: 2,
+            depth: 2,
+            frames_per_action: 1,
+            ..Default::default()
+        };
+        let result = search.search(&mut *sms, &mut buttons).unwrap();
+        assert_eq!(result.value, BEST);
+        assert_eq!(result.actions.len(), 2);
+        assert_eq!(!result.inputs[1].joypad_a() as f64, BEST);
+        assert_eq!(result.frames, 20 + 2 * 20);
+        assert!(sms.state() == start);
+    }
+
+    #[test]
+    fn mcts() {
+        let mut sms = buttons_sms();
+        let start = sms.state();
+        let search = Mcts {
+            iterations: 100,
+            max_depth: 1,
+            rollout_depth: 0,
+            frames_per_action: 1,
+            ..Default::default()
+        };
+        let mut rng = XorShiftRng::from_seed([1; 16]);
+        let result = search.search(&mut *sms, &mut buttons, &mut rng).unwrap();
+        assert_eq!(result.value, BEST);
+        assert_eq!(result.actions.len(), 1);
+        assert_eq!(!result.inputs[0].joypad_a() as f64, BEST);
+        assert_eq!(result.frames, 20);
+        assert!(sms.state() == start);
+    }
+}
@@ -0,0 +1,189 @@
+use rand::Rng;
+
+use euphrates::systems::sms::{ActionSpace, Sms, SmsEmulationError, SmsState};
+
+use super::*;
+
+/// Monte Carlo tree search over sequences of actions, using UCT to choose which
+/// part of the tree to explore.
+///
+/// Each iteration descends the tree, expands one new node by trying an action
+/// not yet tried from its parent, takes `rollout_depth` random actions from
+/// there, and credits the value of the state reached to every node on the way
+/// down. The result follows the most visited child from the root on down.
+///
+/// Every node of the tree holds a saved state, so memory use grows with
+/// `iterations`.
+#[derive(Clone, Debug)]
+pub struct Mcts {
+    pub action_space: ActionSpace,
+
+    /// How many nodes to add to the tree.
+    pub iterations: usize,
+
+    /// How many actions deep the tree may grow.
+    pub max_depth: usize,
+
+    /// How many random actions to take after leaving the tree, before judging
+    /// the state.
+    pub rollout_depth: usize,
+
+    /// How much to favor rarely tried actions over ones with high average
+    /// value. This should be on the same scale as differences in value.
+    pub exploration: f64,
+
+    /// How many frames to hold each action down for.
+    pub frames_per_action: u32,
+}
+
+impl Default for Mcts {
+    fn default() -> Self {
+        Mcts {
+            action_space: ActionSpace::joypad1(),
+            iterations: 256,
+            max_depth: 16,
+            rollout_depth: 8,
+            exploration: ::std::f64::consts::SQRT_2,
+            frames_per_action: 4,
+        }
+    }
+}
+
+struct Node {
+    state: SmsState,
+    depth: usize,
+    parent: Option<usize>,
+    /// Action and node index.
+    children: Vec<(usize, usize)>,
+    untried: Vec<usize>,
+    visits: u64,
+    total: f64,
+}
+
+impl Node {
+    fn new(state: SmsState, depth: usize, parent: Option<usize>, actions: usize) -> Self {
+        Node {
+            state,
+            depth,
+            parent,
+            children: Vec::new(),
+            untried: (0..actions).collect(),
+            visits: 0,
+            total: 0.0,
+        }
+    }
+
+    #[inline]
+    fn mean(&self) -> f64 {
+        self.total / self.visits as f64
+    }
+}
+
+impl Mcts {
+    /// Search ahead from the current state of `sms`, drawing random actions
+    /// from `rng`.
+    ///
+    /// When this returns, `sms` is back in the state it was in when called.
+    ///
+    /// Panics if the action space is empty, or if `iterations`, `max_depth`,
+    /// or `frames_per_action` is 0.
+    pub fn search<V, R>(
+        &self,
+        sms: &mut dyn Sms,
+        value: &mut V,
+        rng: &mut R,
+    ) -> Result<SearchResult, SmsEmulationError>
+    where
+        V: ValueFunction + ?Sized,
+        R: Rng + ?Sized,
+    {
+        let action_count = self.action_space.len();
+        assert!(action_count > 0, "empty action space");
+        assert!(self.iterations > 0, "need at least one iteration");
+        assert!(self.max_depth > 0, "search depth must be positive");
+
+        let mut root = Root::new(sms);
+        let mut nodes = vec![Node::new(root.state.clone(), 0, None, action_count)];
+
+        for _ in 0..self.iterations {
+            // selection
+            let mut index = 0;
+            while nodes[index].untried.is_empty() && !nodes[index].children.is_empty() {
+                index = self.select(&nodes, index);
+            }
+
+            // expansion
+            root.restore(&nodes[index].state)?;
+            if !nodes[index].untried.is_empty() && nodes[index].depth < self.max_depth {
+                let i = rng.gen_range(0, nodes[index].untried.len());
+                let action = nodes[index].untried.swap_remove(i);
+                root.advance(self.action_space.actions()[action], self.frames_per_action)?;
+                let child = Node::new(
+                    root.sms.state(),
+                    nodes[index].depth + 1,
+                    Some(index),
+                    action_count,
+                );
+                nodes.push(child);
+                let child_index = nodes.len() - 1;
+                nodes[index].children.push((action, child_index));
+                index = child_index;
+            }
+
+            // rollout
+            for _ in 0..self.rollout_depth {
+                let action = rng.gen_range(0, action_count);
+                root.advance(self.action_space.actions()[action], self.frames_per_action)?;
+            }
+            let v = value.value(root.sms);
+
+            // backpropagation
+            let mut current = Some(index);
+            while let Some(i) = current {
+                nodes[i].visits += 1;
+                nodes[i].total += v;
+                current = nodes[i].parent;
+            }
+        }
+
+        let mut actions = Vec::new();
+        let mut index = 0;
+        let mut result_value = nodes[0].mean();
+        while let Some(&(action, child)) = nodes[index]
+            .children
+            .iter()
+            .max_by_key(|&&(_, child)| nodes[child].visits)
+        {
+            if index == 0 {
+                result_value = nodes[child].mean();
+            }
+            actions.push(action);
+            index = child;
+        }
+
+        Ok(SearchResult {
+            inputs: inputs(&self.action_space, &actions),
+            actions,
+            value: result_value,
+            frames: root.frames,
+        })
+    }
+
+    /// The child of `nodes[index]` with the highest upper confidence bound.
+    fn select(&self, nodes: &[Node], index: usize) -> usize {
+        let log_visits = (nodes[index].visits as f64).ln();
+        let bound = |child: &Node| {
+            child.mean() + self.exploration * (log_visits / child.visits as f64).sqrt()
+        };
+        let mut best = nodes[index].children[0].1;
+        let mut best_bound = bound(&nodes[best]);
+        for &(_, child) in nodes[index].children[1..].iter() {
+            let b = bound(&nodes[child]);
+            if b > best_bound {
+                best = child;
+                best_bound = b;
+            }
+        }
+        best
+    }
+}
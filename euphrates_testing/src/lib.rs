@@ -0,0 +1,288 @@
+//! Test fixtures shared by the tests of the Euphrates crates, in particular
+//! the lockstep tests of each `SmsZ80Runner`.
+
+#![deny(bare_trait_objects, anonymous_parameters)]
+
+extern crate euphrates;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use euphrates::hardware::io16::Io16;
+use euphrates::hardware::z80::{assemble, Z80Irq, Z80Memo, Z80Run, Z80RunImpler, Z80State};
+use euphrates::host_multimedia::FakeAudio;
+use euphrates::memo::{Inbox, NothingInbox};
+use euphrates::systems::sms::{
+    find_divergence, new_headless_sms, new_sms, FakeSmsGraphics, FakeSn76489, Kind,
+    LockstepOptions, Sms, SmsMemory, SmsMemoryLoad, SmsMemoryMapper, SmsMemoryState,
+    SmsPlayerInput, SmsState, SmsZ80Runner, TvSystem, TypeWrap,
+};
+
+/// A headless NTSC Master System running `rom` with the Sega mapper.
+pub fn new_system(rom: &[u8]) -> Box<dyn Sms + Send> {
+    let state = SmsState::from_rom(
+        Arc::new(rom.to_vec().into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    new_headless_sms(
+        state,
+        FakeSmsGraphics,
+        TypeWrap::<SmsMemoryState>::default(),
+    ).unwrap()
+}
+
+/// Run `rom` for `frames` frames on the interpreter and with `runner`,
+/// panic if they ever differ, and return the system with `runner`.
+pub fn lockstep(
+    rom: &[u8],
+    frames: usize,
+    runner: Box<dyn SmsZ80Runner + Send>,
+    options: &LockstepOptions,
+) -> Box<dyn Sms + Send> {
+    let mut interpreter = new_system(rom);
+    let mut running = new_system(rom);
+    running.set_z80_runner(Some(runner));
+    let player_inputs = vec![SmsPlayerInput::default(); frames];
+    let divergence =
+        find_divergence(&mut *interpreter, &mut *running, &player_inputs, options).unwrap();
+    if let Some(divergence) = divergence {
+        panic!("{}", divergence);
+    }
+    running
+}
+
+/// A xorshift generator, so random ROMs are the same every time.
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        XorShift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// A 32 KiB ROM, each byte of which is chosen by `byte`.
+pub fn random_rom<F>(seed: u64, mut byte: F) -> Vec<u8>
+where
+    F: FnMut(&mut XorShift) -> u8,
+{
+    let mut rng = XorShift::new(seed);
+    (0..0x8000).map(|_| byte(&mut rng)).collect()
+}
+
+/// A ROM of `len` bytes of 0 with `code` placed at the given addresses.
+pub fn rom(len: usize, code: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut rom = vec![0u8; len];
+    for &(address, bytes) in code.iter() {
+        rom[address..address + bytes.len()].copy_from_slice(bytes);
+    }
+    rom
+}
+
+/// A ROM that runs the same routine from each of 4 pages, mapped
+/// alternately into slots 1 and 2, and writes what each returns to
+/// system RAM. See `check_bank_switching`.
+pub fn bank_switching_rom() -> Vec<u8> {
+    let mut code: Vec<(usize, &[u8])> = vec![(
+        0x0000,
+        &[
+            0xF3, // di
+            0x31, 0xF0, 0xDF, // ld sp, 0xDFF0
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x06, 0x10, // ld b, 16
+            // loop:
+            0x78, // ld a, b
+            0xE6, 0x03, // and 3
+            0x32, 0xFE, 0xFF, // ld (0xFFFE), a
+            0x32, 0xFF, 0xFF, // ld (0xFFFF), a
+            0xCB, 0x40, // bit 0, b
+            0x28, 0x05, // jr z, slot2
+            0xCD, 0x00, 0x41, // call 0x4100
+            0x18, 0x03, // jr done
+            // slot2:
+            0xCD, 0x00, 0x81, // call 0x8100
+            // done:
+            0x77, // ld (hl), a
+            0x23, // inc hl
+            0x10, 0xE7, // djnz loop
+            0x76, // halt
+        ],
+    )];
+    let routines: [[u8; 7]; 4] = [
+        // ld ix, 0x00A0 + page; ld a, ixl; ret
+        [0xDD, 0x21, 0xA0, 0x00, 0xDD, 0x7D, 0xC9],
+        [0xDD, 0x21, 0xA1, 0x00, 0xDD, 0x7D, 0xC9],
+        [0xDD, 0x21, 0xA2, 0x00, 0xDD, 0x7D, 0xC9],
+        [0xDD, 0x21, 0xA3, 0x00, 0xDD, 0x7D, 0xC9],
+    ];
+    for (page, routine) in routines.iter().enumerate() {
+        code.push((page * 0x4000 + 0x100, routine));
+    }
+    rom(0x10000, &code)
+}
+
+/// Panic unless `sms` ran `bank_switching_rom` to the end, calling the
+/// routine in the right page each time.
+pub fn check_bank_switching(sms: &dyn Sms) {
+    for i in 0..16 {
+        let page = (16 - i) & 3;
+        assert_eq!(
+            sms.sms_memory().system_ram_read(i),
+            0xA0 + page as u8,
+            "byte {}",
+            i
+        );
+    }
+}
+
+const RESTORE_STATE: &str = "
+        org 0
+        di
+        ld sp, 0DFF0
+        im 1
+        ; cartridge RAM in slot 2
+        ld a, 8
+        ld (0FFFC), a
+        ; frame interrupts on
+        ld a, 20
+        out (0BF), a
+        ld a, 81
+        out (0BF), a
+        ei
+    main:
+        halt
+        jr main
+        org 38
+        push af
+        push hl
+        in a, (0BF)
+        ; count frames
+        ld hl, 0C000
+        inc (hl)
+        ld a, (hl)
+        ; write the count to cartridge RAM
+        ld l, a
+        ld h, 80
+        ld (hl), a
+        ; map a different page into slot 1 each frame, and copy its marker
+        and 3
+        ld (0FFFE), a
+        ld a, (7000)
+        ld (0C001), a
+        pop hl
+        pop af
+        ei
+        reti
+";
+
+/// A 64 KiB ROM that writes to system and cartridge RAM and maps a different
+/// page into slot 1 each frame. Byte 0x3000 of each page is its number.
+pub fn restore_state_rom() -> Vec<u8> {
+    let mut rom = assemble(RESTORE_STATE).unwrap().bytes;
+    rom.resize(0x10000, 0);
+    for page in 0..4 {
+        rom[page * 0x4000 + 0x3000] = page as u8;
+    }
+    rom
+}
+
+/// The interpreter, counting how many times it's invalidated.
+struct CountingRunner(Arc<AtomicUsize>);
+
+impl SmsZ80Runner for CountingRunner {
+    fn run(
+        &mut self,
+        z80: &mut Z80State,
+        memory: &mut dyn SmsMemory,
+        io: &mut dyn Io16,
+        irq: &mut dyn Z80Irq,
+        inbox: &mut dyn Inbox<Memo = Z80Memo>,
+        target_cycles: u64,
+    ) {
+        Z80RunImpler {
+            z80,
+            memory,
+            io,
+            irq,
+            inbox,
+        }.run(target_cycles);
+    }
+
+    fn invalidate(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn run_frames(sms: &mut dyn Sms, frames: usize) {
+    for _ in 0..frames {
+        sms.run_frame(SmsPlayerInput::default()).unwrap();
+    }
+}
+
+/// Panic unless `Sms::restore_state` works with `Memory`: a system that
+/// returns to a state it was in has that state again, and runs on from it
+/// exactly as it did before, and its `SmsZ80Runner` is invalidated.
+pub fn check_restore_state<Memory>(_mem: TypeWrap<Memory>)
+where
+    Memory: SmsMemory + SmsMemoryLoad + 'static,
+{
+    let rom = restore_state_rom();
+    let state = SmsState::from_rom(
+        Arc::new(rom.clone().into_boxed_slice()),
+        SmsMemoryMapper::Sega,
+        TvSystem::Ntsc,
+        Kind::Sms,
+    );
+    let mut sms = new_sms(
+        None,
+        state,
+        FakeSmsGraphics,
+        FakeAudio,
+        NothingInbox::<Z80Memo>::default(),
+        TypeWrap::<Memory>::default(),
+        TypeWrap::<FakeSn76489>::default(),
+    ).unwrap();
+    let invalidations = Arc::new(AtomicUsize::new(0));
+    sms.set_z80_runner(Some(Box::new(CountingRunner(invalidations.clone()))));
+
+    run_frames(&mut *sms, 5);
+    let earlier = sms.state();
+    run_frames(&mut *sms, 6);
+    let later = sms.state();
+    assert!(earlier != later);
+    assert_eq!(later.memory.system_ram[1], 3);
+
+    sms.restore_state(&earlier).unwrap();
+    assert_eq!(invalidations.load(Ordering::SeqCst), 1);
+    assert!(sms.state() == earlier);
+    run_frames(&mut *sms, 6);
+    assert!(sms.state() == later);
+
+    // the same ROM, but not the same `Arc`
+    let mut copied = earlier.clone();
+    copied.memory.rom = Arc::new(rom.clone().into_boxed_slice());
+    sms.restore_state(&copied).unwrap();
+    assert_eq!(invalidations.load(Ordering::SeqCst), 2);
+    assert!(sms.state() == earlier);
+    run_frames(&mut *sms, 6);
+    assert!(sms.state() == later);
+
+    // a different ROM
+    let mut different = earlier.clone();
+    let mut rom = rom;
+    rom[3 * 0x4000 + 0x3000] = 0x33;
+    different.memory.rom = Arc::new(rom.into_boxed_slice());
+    sms.restore_state(&different).unwrap();
+    assert_eq!(invalidations.load(Ordering::SeqCst), 3);
+    assert_eq!(sms.sms_memory().rom_read(3 * 0x4000 + 0x3000), 0x33);
+    run_frames(&mut *sms, 6);
+    assert_eq!(sms.state().memory.system_ram[1], 0x33);
+}
@@ -28,6 +28,23 @@ unsafe fn dup<T: Copy>(slice: &[T], execute: bool) -> Physical<T> {
     <Main as VirtualMemory<T>>::dup(slice, execute)
 }
 
+/// The length of the physical memory `dup` allocates for a slice of length
+/// `len`.
+#[inline(always)]
+fn rounded_len(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// Copy `slice` into `phys` if `phys` is the right size to have come from
+/// `dup`; otherwise `dup` a replacement.
+unsafe fn dup_into(phys: &mut Physical<u8>, slice: &[u8]) {
+    if phys.len() == rounded_len(slice.len()) {
+        ptr::copy_nonoverlapping(slice.as_ptr(), phys.as_mut_ptr(), slice.len());
+    } else {
+        *phys = dup::<u8>(slice, false);
+    }
+}
+
 #[inline(always)]
 fn create_slice<T: Copy>(phys: &Physical<T>) -> Box<[T]> {
     <Main as VirtualMemory<T>>::create_slice(phys)
@@ -64,6 +81,12 @@ pub enum MainCartridgeRam {
 
 pub struct SmsVirtualMemory {
     rom: Arc<Rom>,
+
+    /// The ROM of the state this was loaded from, as long as `rom` hasn't been
+    /// written to since, so that restoring that state again can tell the ROM
+    /// is the same without comparing it, and `state` needn't copy it.
+    rom_state: Option<Arc<Box<[u8]>>>,
+
     system_ram: Physical<u8>,
     main_cartridge_ram: MainCartridgeRam,
     half_cartridge_ram: Option<Physical<u8>>,
@@ -322,6 +345,10 @@ impl Memory16 for SmsVirtualMemory {
                 .set_unchecked(logical_address as usize, value);
         }
     }
+
+    fn rom_index(&self, logical_address: u16) -> Option<usize> {
+        sms_memory::mapped_rom_index(self, logical_address)
+    }
 }
 
 impl SmsMemory for SmsVirtualMemory {
@@ -369,6 +396,7 @@ impl SmsMemory for SmsVirtualMemory {
 
     #[inline(always)]
     fn rom_write(&mut self, index: usize, value: u8) {
+        self.rom_state = None;
         unsafe {
             Arc::make_mut(&mut self.rom).0.set(index, value);
         }
@@ -494,7 +522,10 @@ impl SmsMemory for SmsVirtualMemory {
         };
 
         SmsMemoryState {
-            rom: Arc::new(create_slice(&self.rom.0)),
+            rom: match self.rom_state {
+                Some(ref rom) => rom.clone(),
+                None => Arc::new(create_slice(&self.rom.0)),
+            },
             system_ram: create_slice(&self.system_ram),
             main_cartridge_ram,
             half_cartridge_ram,
@@ -535,6 +566,7 @@ impl SmsMemoryLoad for SmsVirtualMemory {
 
             let mut vm = SmsVirtualMemory {
                 rom: Arc::new(Rom(rom)),
+                rom_state: Some(state.rom.clone()),
                 system_ram,
                 main_cartridge_ram,
                 half_cartridge_ram,
@@ -550,6 +582,69 @@ impl SmsMemoryLoad for SmsVirtualMemory {
             Ok(vm)
         }
     }
+
+    fn load_in_place(&mut self, state: &SmsMemoryState) -> Result<(), SmsMemoryLoadError> {
+        use std::slice;
+
+        if let Some(e) = state.check_valid() {
+            return Err(e);
+        }
+
+        unsafe {
+            let same_rom = match self.rom_state {
+                Some(ref rom) if Arc::ptr_eq(rom, &state.rom) => true,
+                _ => {
+                    let rom = &self.rom.deref().0;
+                    rom.len() == rounded_len(state.rom.len())
+                        && slice::from_raw_parts(rom.as_ptr(), state.rom.len())
+                            == state.rom.deref().deref()
+                }
+            };
+            if !same_rom {
+                self.rom = Arc::new(Rom(dup::<u8>(state.rom.deref(), false)));
+            }
+            self.rom_state = Some(state.rom.clone());
+
+            dup_into(&mut self.system_ram, state.system_ram.deref());
+
+            match (&mut self.main_cartridge_ram, &state.main_cartridge_ram) {
+                (&mut MainCartridgeRam::Zero, &sms_memory::MainCartridgeRam::Zero) => {}
+                (
+                    &mut MainCartridgeRam::One(ref mut x),
+                    &sms_memory::MainCartridgeRam::One(ref y),
+                ) => dup_into(x, y.deref()),
+                (
+                    &mut MainCartridgeRam::Two(ref mut x0, ref mut x1),
+                    &sms_memory::MainCartridgeRam::Two(ref y0, ref y1),
+                ) => {
+                    dup_into(x0, y0.deref());
+                    dup_into(x1, y1.deref());
+                }
+                (x, &sms_memory::MainCartridgeRam::Zero) => *x = MainCartridgeRam::Zero,
+                (x, &sms_memory::MainCartridgeRam::One(ref y)) => {
+                    *x = MainCartridgeRam::One(dup::<u8>(y.deref(), false))
+                }
+                (x, &sms_memory::MainCartridgeRam::Two(ref y0, ref y1)) => {
+                    *x = MainCartridgeRam::Two(
+                        dup::<u8>(y0.deref(), false),
+                        dup::<u8>(y1.deref(), false),
+                    )
+                }
+            }
+
+            match (&mut self.half_cartridge_ram, &state.half_cartridge_ram) {
+                (&mut Some(ref mut x), &Some(ref y)) => dup_into(x, y.deref()),
+                (x, &Some(ref y)) => *x = Some(dup::<u8>(y.deref(), false)),
+                (x, &None) => *x = None,
+            }
+        }
+
+        self.pages = state.pages;
+        self.mapper = state.mapper;
+        self.remap();
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -8,6 +8,6 @@ mod external;
 mod posix;
 mod traits;
 
-use posix::*;
-
 pub use external::*;
+pub use posix::{LogicalMemory, Main, PhysicalMemory};
+pub use traits::{Protect, UnsafeMemory, VirtualMemory};
@@ -227,7 +227,7 @@ impl<T: Copy> VirtualMemory<T> for Main {
             prot |= libc::PROT_WRITE;
         }
         if execute {
-            prot |= libc::PROT_READ;
+            prot |= libc::PROT_EXEC;
         }
         check_error(
             libc::mmap(
@@ -0,0 +1,14 @@
+//! `Sms::restore_state` with `SmsVirtualMemory`.
+
+extern crate euphrates;
+extern crate euphrates_testing;
+extern crate euphrates_virtual_memory;
+
+use euphrates::systems::sms::TypeWrap;
+use euphrates_testing::check_restore_state;
+use euphrates_virtual_memory::SmsVirtualMemory;
+
+#[test]
+fn sms_virtual_memory() {
+    check_restore_state(TypeWrap::<SmsVirtualMemory>::default());
+}
@@ -13,7 +13,10 @@ pub fn supports_pattern_to_palette_indices() -> bool {
     }
 }
 
-fn pattern_to_palette_indices(pattern: [u8; 4]) -> [u8; 8] {
+/// The fast implementation of `PATTERN_TO_PALETTE_INDICES`.
+///
+/// Only call this if `supports_pattern_to_palette_indices()` returns true.
+pub fn pattern_to_palette_indices(pattern: [u8; 4]) -> [u8; 8] {
     unsafe {
         euphrates_x64_pattern_to_palette_indices(pattern)
     }